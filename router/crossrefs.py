@@ -2,6 +2,7 @@
 # files documented in `crossref.md`.
 
 from __future__ import absolute_import
+import gzip
 import json
 import sys
 import mmap
@@ -39,6 +40,7 @@ NEWLINE_ORD = ord('\n')
 ID_START_ORD = ord('!')
 INLINE_STORED_STR = ':'
 EXTERNALLY_STORED_STR = '@'
+GZIP_COMPRESSED_FLAG = 'gz'
 
 def get_id_line(mm, pos):
     '''
@@ -150,11 +152,17 @@ def lookup_raw(tree_name, sym):
         # Fail if we're seeing something other than an external ref.
         return None
 
-    (braceOffset, lengthWithNewline) = payload[1:].split(' ')
-    (braceOffset, lengthWithNewline) = (int(braceOffset, 16), int(lengthWithNewline, 16))
+    # The pointer may have a trailing flag indicating how the payload is
+    # compressed.
+    pieces = payload[1:].split()
+    (braceOffset, lengthWithNewline) = (int(pieces[0], 16), int(pieces[1], 16))
 
     # exclude the newline
     data = extra_mm[braceOffset:(braceOffset + lengthWithNewline - 1)]
+    if len(pieces) > 2:
+        if pieces[2] != GZIP_COMPRESSED_FLAG:
+            return None
+        data = gzip.decompress(data)
 
     result = json.loads(data)
     return result
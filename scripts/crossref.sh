@@ -14,7 +14,13 @@ cd $INDEX_ROOT/analysis
 find . -type f | cut -c 3- > ${TMPDIR:-/tmp}/files
 cd -
 
-$MOZSEARCH_PATH/tools/target/release/crossref $CONFIG_FILE $TREE_NAME ${TMPDIR:-/tmp}/files
+# If CROSSREF_CHANGED_FILES names a file listing the analysis files that have
+# changed or been removed since the last run, only those files are reread.
+# If CROSSREF_SHARDS is set to a number greater than 0, the hits are sharded
+# across that many threads and spilled to disk instead of being held in memory.
+# If CROSSREF_FORMAT is "binary", the crossref is written in the binary format
+# which only the rust tools (and not router.py) can read.
+$MOZSEARCH_PATH/tools/target/release/crossref $CONFIG_FILE $TREE_NAME ${TMPDIR:-/tmp}/files ${CROSSREF_CHANGED_FILES:-}
 
 # Re-sort the identifiers file so that it's case-insensitive.  (It was written
 # to disk from a case-sensitive BTreeMap.)
@@ -72,6 +72,14 @@ $MOZSEARCH_PATH/scripts/build-codesearch.py $CONFIG_FILE $TREE_NAME
 
 date
 
+# Small trees can opt into a trigram index so that the local searchfox tools
+# can perform full-text searches without the codesearch daemon.
+if [ "$(jq -r ".trees[\"${TREE_NAME}\"].trigram_index // false" ${CONFIG_FILE})" = "true" ]; then
+  $MOZSEARCH_PATH/tools/target/release/build-trigram-index $CONFIG_FILE $TREE_NAME
+
+  date
+fi
+
 # this depends on INDEX_ROOT already being available
 $MOZSEARCH_PATH/scripts/compress-outputs.sh
 
@@ -217,6 +217,9 @@ for repo in config['trees']:
     location('/%(repo)s/hgrev', ['proxy_pass http://localhost:8001;'])
     location('/%(repo)s/complete', ['proxy_pass http://localhost:8001;'])
     location('/%(repo)s/commit-info', ['proxy_pass http://localhost:8001;'])
+    location('/%(repo)s/crossref-lookup', ['proxy_pass http://localhost:8001;'])
+    location('/%(repo)s/search-identifiers', ['proxy_pass http://localhost:8001;'])
+    location('/%(repo)s/source-records-at', ['proxy_pass http://localhost:8001;'])
 
     del fmt['repo']
     del fmt['head']
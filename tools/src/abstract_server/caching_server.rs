@@ -0,0 +1,581 @@
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+
+use async_stream::try_stream;
+use async_trait::async_trait;
+use futures_core::stream::BoxStream;
+use serde_json::{from_str, json, to_string, Value};
+use tokio::fs::{create_dir_all, metadata, read_to_string, remove_file, rename, write, File};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio_stream::StreamExt;
+
+use super::local_index::collect_source_records_at;
+use super::server_interface::{
+    AbstractServer, CrossrefLookupOptions, ErrorDetails, ErrorLayer, FileListKind, Result,
+    ServerError,
+};
+use crate::file_format::analysis::Jump;
+
+/// The size of the chunks we produce when streaming cached HTML.
+const HTML_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Used to give concurrent writes of the same cache entry distinct temporary
+/// files.
+static TEMP_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Where and how `make_caching_server` caches responses.
+#[derive(Clone, Debug)]
+pub struct CacheConfig {
+    /// The root of the on-disk cache.  Entries are stored under
+    /// `<dir>/<tree>/<revision>/`.
+    pub dir: PathBuf,
+    /// The revision of the tree that the server is serving.  The server has no
+    /// way to tell us this, so it's up to the user to pick a new value (or rely
+    /// on `ttl`) when the server's index is updated.
+    pub revision: String,
+    /// How long cache entries are used for before being re-fetched.  None means
+    /// forever.
+    pub ttl: Option<Duration>,
+    /// Only serve responses from the cache, treating cache misses as errors
+    /// rather than contacting the server.
+    pub offline: bool,
+}
+
+/// 64-bit FNV-1a, used to derive stable cache file names from arbitrary query
+/// strings.  (`DefaultHasher`'s output isn't guaranteed to be stable across
+/// Rust releases.)
+fn fnv1a_64(s: &str) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in s.bytes() {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+/// Is this a tree-relative path we can safely mirror under the cache dir?
+fn is_cacheable_path(sf_path: &str) -> bool {
+    !sf_path.is_empty()
+        && Path::new(sf_path)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+}
+
+/// A unique temporary file name next to the cache entry at `path`.  Entries
+/// are written to one of these and then renamed into place so that a crash or
+/// a concurrent writer never leaves a truncated entry behind.
+fn temp_path_for(path: &Path) -> PathBuf {
+    PathBuf::from(format!(
+        "{}.tmp-{}-{}",
+        path.display(),
+        std::process::id(),
+        TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+/// Removes a temporary cache file when dropped unless it's been renamed into
+/// place, covering streams that fail or are dropped part way through.
+struct TempFileGuard {
+    path: Option<PathBuf>,
+}
+
+impl Drop for TempFileGuard {
+    fn drop(&mut self) {
+        if let Some(path) = self.path.take() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Write a complete cache entry to `path` via a temporary file.
+async fn write_entry(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent).await?;
+    }
+    let temp_path = temp_path_for(path);
+    let result = match write(&temp_path, contents).await {
+        Ok(()) => rename(&temp_path, path).await,
+        Err(err) => Err(err),
+    };
+    if result.is_err() {
+        let _ = remove_file(&temp_path).await;
+    }
+    result
+}
+
+/// Write the items of `stream` to `path` as they pass through, using `encode`
+/// to serialize each item.  The entry is written to a temporary file that's
+/// only renamed into place once the stream has been fully consumed, so a
+/// partially consumed or failed stream never results in a truncated entry.
+/// Failing to write the cache entry doesn't fail the stream.
+fn tee_to_cache<'a, T: Send + 'a>(
+    mut stream: BoxStream<'a, Result<T>>,
+    path: PathBuf,
+    encode: fn(&T) -> Vec<u8>,
+) -> BoxStream<'a, Result<T>> {
+    Box::pin(try_stream! {
+        let temp_path = temp_path_for(&path);
+        let mut file = match path.parent() {
+            Some(parent) if create_dir_all(parent).await.is_ok() => {
+                File::create(&temp_path).await.ok()
+            }
+            _ => None,
+        };
+        let mut guard = TempFileGuard {
+            path: file.as_ref().map(|_| temp_path.clone()),
+        };
+
+        while let Some(item) = stream.next().await {
+            let item = item?;
+            if let Some(f) = &mut file {
+                if f.write_all(&encode(&item)).await.is_err() {
+                    file = None;
+                }
+            }
+            yield item;
+        }
+
+        if let Some(mut f) = file {
+            if f.flush().await.is_ok() && rename(&temp_path, &path).await.is_ok() {
+                guard.path = None;
+            }
+        }
+    })
+}
+
+/// `AbstractServer` decorator that caches `fetch_raw_analysis`, `fetch_html`
+/// and `perform_query` responses on disk, intended for use with remote servers
+/// so that repeated investigations and tests are fast and reproducible.
+///
+/// Other methods are passed through to the wrapped server, except in offline
+/// mode where they are unsupported.
+struct CachingServer {
+    inner: Box<dyn AbstractServer + Send + Sync>,
+    config: CacheConfig,
+    tree_dir: PathBuf,
+}
+
+impl CachingServer {
+    /// Is there a cache entry at `path` that hasn't outlived the TTL?
+    async fn is_fresh(&self, path: &Path) -> bool {
+        let modified = match metadata(path).await.and_then(|m| m.modified()) {
+            Ok(modified) => modified,
+            Err(_) => return false,
+        };
+        match self.config.ttl {
+            // A modification time in the future is weird but fresh.
+            Some(ttl) => SystemTime::now()
+                .duration_since(modified)
+                .map_or(true, |age| age <= ttl),
+            None => true,
+        }
+    }
+
+    fn offline_miss(&self, what: &str) -> ServerError {
+        ServerError::StickyProblem(ErrorDetails {
+            layer: ErrorLayer::ServerLayer,
+            message: format!("{} is not in the cache and we are offline", what),
+        })
+    }
+
+    /// Return the cache path for the given tree-relative file, or None if the
+    /// path can't be cached.
+    fn file_entry_path(&self, kind: &str, sf_path: &str) -> Option<PathBuf> {
+        if is_cacheable_path(sf_path) {
+            Some(self.tree_dir.join(kind).join(sf_path))
+        } else {
+            None
+        }
+    }
+}
+
+#[async_trait]
+impl AbstractServer for CachingServer {
+    fn translate_analysis_path(&self, sf_path: &str) -> Result<String> {
+        self.inner.translate_analysis_path(sf_path)
+    }
+
+    async fn fetch_raw_analysis(&self, sf_path: &str) -> Result<BoxStream<Result<Value>>> {
+        let path = match self.file_entry_path("analysis", sf_path) {
+            Some(path) => path,
+            None if self.config.offline => return Err(self.offline_miss(sf_path)),
+            None => return self.inner.fetch_raw_analysis(sf_path).await,
+        };
+
+        if self.is_fresh(&path).await {
+            let mut lines = BufReader::new(File::open(&path).await?).lines();
+            return Ok(Box::pin(try_stream! {
+                while let Some(line) = lines.next_line().await? {
+                    let value: Value = from_str(&line)?;
+                    yield value;
+                }
+            }));
+        }
+        if self.config.offline {
+            return Err(self.offline_miss(sf_path));
+        }
+
+        let values = self.inner.fetch_raw_analysis(sf_path).await?;
+        Ok(tee_to_cache(values, path, |value| {
+            let mut line = value.to_string().into_bytes();
+            line.push(b'\n');
+            line
+        }))
+    }
+
+    async fn source_records_at(&self, sf_path: &str, lineno: u32, col: u32) -> Result<Vec<Value>> {
+        // A cached copy of the file's analysis can answer this without
+        // contacting the server.
+        if let Some(path) = self.file_entry_path("analysis", sf_path) {
+            if self.is_fresh(&path).await {
+                let values = self.fetch_raw_analysis(sf_path).await?;
+                return collect_source_records_at(values, lineno, col).await;
+            }
+        }
+        if self.config.offline {
+            return Err(self.offline_miss(sf_path));
+        }
+        self.inner.source_records_at(sf_path, lineno, col).await
+    }
+
+    async fn fetch_html(&self, sf_path: &str) -> Result<BoxStream<Result<Vec<u8>>>> {
+        let path = match self.file_entry_path("html", sf_path) {
+            Some(path) => path,
+            None if self.config.offline => return Err(self.offline_miss(sf_path)),
+            None => return self.inner.fetch_html(sf_path).await,
+        };
+
+        if self.is_fresh(&path).await {
+            let mut file = File::open(&path).await?;
+            return Ok(Box::pin(try_stream! {
+                let mut buf = vec![0; HTML_CHUNK_SIZE];
+                loop {
+                    let n = file.read(&mut buf).await?;
+                    if n == 0 {
+                        break;
+                    }
+                    yield buf[..n].to_vec();
+                }
+            }));
+        }
+        if self.config.offline {
+            return Err(self.offline_miss(sf_path));
+        }
+
+        let chunks = self.inner.fetch_html(sf_path).await?;
+        Ok(tee_to_cache(chunks, path, |chunk| chunk.clone()))
+    }
+
+    async fn fetch_file_list(&self, list: FileListKind) -> Result<Vec<String>> {
+        if self.config.offline {
+            return Err(ServerError::Unsupported);
+        }
+        self.inner.fetch_file_list(list).await
+    }
+
+    async fn crossref_lookup(
+        &self,
+        symbol: &str,
+        options: &CrossrefLookupOptions,
+    ) -> Result<Value> {
+        if self.config.offline {
+            return Err(ServerError::Unsupported);
+        }
+        self.inner.crossref_lookup(symbol, options).await
+    }
+
+    async fn stream_crossref(&self) -> Result<BoxStream<Result<(String, Value)>>> {
+        if self.config.offline {
+            return Err(ServerError::Unsupported);
+        }
+        self.inner.stream_crossref().await
+    }
+
+    async fn jump_lookup(&self, symbol: &str) -> Result<Option<Jump>> {
+        if self.config.offline {
+            return Err(ServerError::Unsupported);
+        }
+        self.inner.jump_lookup(symbol).await
+    }
+
+    async fn search_identifiers(
+        &self,
+        needle: &str,
+        exact_match: bool,
+        ignore_case: bool,
+        match_limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        if self.config.offline {
+            return Err(ServerError::Unsupported);
+        }
+        self.inner
+            .search_identifiers(needle, exact_match, ignore_case, match_limit)
+            .await
+    }
+
+    async fn search_text(
+        &self,
+        pattern: &str,
+        fold_case: bool,
+        pathre: Option<&str>,
+        context_lines: u32,
+        max_matches: usize,
+    ) -> Result<Value> {
+        if self.config.offline {
+            return Err(ServerError::Unsupported);
+        }
+        self.inner
+            .search_text(pattern, fold_case, pathre, context_lines, max_matches)
+            .await
+    }
+
+    async fn perform_query(&self, q: &str) -> Result<Value> {
+        // The query is stored alongside the results so that a hash collision
+        // is just a cache miss.
+        let path = self
+            .tree_dir
+            .join("query")
+            .join(format!("{:016x}.json", fnv1a_64(q)));
+
+        if self.is_fresh(&path).await {
+            // An entry we can't read or parse is just a miss that gets
+            // overwritten.
+            let entry = read_to_string(&path)
+                .await
+                .ok()
+                .and_then(|contents| from_str::<Value>(&contents).ok());
+            if let Some(entry) = entry {
+                if entry["q"] == q {
+                    return Ok(entry["results"].clone());
+                }
+            }
+        }
+        if self.config.offline {
+            return Err(self.offline_miss(&format!("Query {:?}", q)));
+        }
+
+        let results = self.inner.perform_query(q).await?;
+        let entry = to_string(&json!({ "q": q, "results": results }))?;
+        // Failing to write the cache entry shouldn't fail the query.
+        let _ = write_entry(&path, entry.as_bytes()).await;
+        Ok(results)
+    }
+}
+
+/// Wrap the given server for `tree` so that its responses are cached on disk
+/// according to `config`.
+pub fn make_caching_server(
+    inner: Box<dyn AbstractServer + Send + Sync>,
+    tree: &str,
+    config: CacheConfig,
+) -> Box<dyn AbstractServer + Send + Sync> {
+    let tree_dir = config.dir.join(tree).join(&config.revision);
+    Box::new(CachingServer {
+        inner,
+        config,
+        tree_dir,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::abstract_server::stub_server::StubServer;
+    use futures_util::stream;
+    use std::sync::Arc;
+
+    /// A fresh cache directory for a test, removed when dropped.
+    struct TestCacheDir(PathBuf);
+
+    impl TestCacheDir {
+        fn new(name: &str) -> TestCacheDir {
+            let dir = std::env::temp_dir().join(format!(
+                "caching-server-test-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = std::fs::remove_dir_all(&dir);
+            TestCacheDir(dir)
+        }
+
+        fn config(&self, ttl: Option<Duration>, offline: bool) -> CacheConfig {
+            CacheConfig {
+                dir: self.0.clone(),
+                revision: "latest".to_string(),
+                ttl,
+                offline,
+            }
+        }
+
+        /// The files in the given cache subdirectory of the test tree.
+        fn entries(&self, kind: &str) -> Vec<String> {
+            let mut names: Vec<String> = std::fs::read_dir(self.0.join("t/latest").join(kind))
+                .map(|dir| {
+                    dir.map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+                        .collect()
+                })
+                .unwrap_or_default();
+            names.sort();
+            names
+        }
+    }
+
+    impl Drop for TestCacheDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// A caching server over a stub that knows the query "foo", along with the
+    /// stub's query count.
+    fn caching_stub(
+        config: CacheConfig,
+    ) -> (Box<dyn AbstractServer + Send + Sync>, Arc<AtomicUsize>) {
+        let mut stub = StubServer::default();
+        stub.queries
+            .insert("foo".to_string(), json!({ "results": 1 }));
+        stub.analysis
+            .insert("a.cpp".to_string(), vec![json!({ "loc": "1:0-3" })]);
+        let count = stub.query_count.clone();
+        (make_caching_server(Box::new(stub), "t", config), count)
+    }
+
+    #[test]
+    fn test_is_cacheable_path() {
+        assert!(is_cacheable_path("dom/base/nsINode.cpp"));
+        assert!(!is_cacheable_path(""));
+        assert!(!is_cacheable_path("/etc/passwd"));
+        assert!(!is_cacheable_path("dom/../../secrets"));
+    }
+
+    #[tokio::test]
+    async fn test_query_hit_and_miss() {
+        let dir = TestCacheDir::new("hit");
+        let (server, count) = caching_stub(dir.config(None, false));
+
+        assert_eq!(
+            server.perform_query("foo").await.unwrap(),
+            json!({ "results": 1 })
+        );
+        assert_eq!(
+            server.perform_query("foo").await.unwrap(),
+            json!({ "results": 1 })
+        );
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+
+        // Errors aren't cached.
+        assert!(server.perform_query("bar").await.is_err());
+        assert!(server.perform_query("bar").await.is_err());
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+
+        // Only the entry itself is left behind.
+        assert_eq!(dir.entries("query").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_query_ttl() {
+        let dir = TestCacheDir::new("ttl");
+        let (server, count) = caching_stub(dir.config(Some(Duration::from_secs(0)), false));
+
+        server.perform_query("foo").await.unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        server.perform_query("foo").await.unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+
+        // A long TTL uses the (refreshed) entry.
+        let (server, count) = caching_stub(dir.config(Some(Duration::from_secs(3600)), false));
+        server.perform_query("foo").await.unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_offline() {
+        let dir = TestCacheDir::new("offline");
+        let (server, _) = caching_stub(dir.config(None, false));
+        server.perform_query("foo").await.unwrap();
+        let values: Vec<Value> = server
+            .fetch_raw_analysis("a.cpp")
+            .await
+            .unwrap()
+            .collect::<Result<_>>()
+            .await
+            .unwrap();
+        assert_eq!(values.len(), 1);
+
+        let (server, count) = caching_stub(dir.config(None, true));
+        assert_eq!(
+            server.perform_query("foo").await.unwrap(),
+            json!({ "results": 1 })
+        );
+        let cached: Vec<Value> = server
+            .fetch_raw_analysis("a.cpp")
+            .await
+            .unwrap()
+            .collect::<Result<_>>()
+            .await
+            .unwrap();
+        assert_eq!(cached, values);
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+
+        assert!(matches!(
+            server.perform_query("bar").await,
+            Err(ServerError::StickyProblem(_))
+        ));
+        assert!(matches!(
+            server.fetch_raw_analysis("b.cpp").await,
+            Err(ServerError::StickyProblem(_))
+        ));
+        assert!(matches!(
+            server.fetch_file_list(FileListKind::Repo).await,
+            Err(ServerError::Unsupported)
+        ));
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_query_entry() {
+        let dir = TestCacheDir::new("corrupt");
+        let (server, count) = caching_stub(dir.config(None, false));
+        server.perform_query("foo").await.unwrap();
+
+        // Truncate the entry like an interrupted write would have.
+        let entry = dir.0.join("t/latest/query").join(&dir.entries("query")[0]);
+        let contents = std::fs::read_to_string(&entry).unwrap();
+        std::fs::write(&entry, &contents[..contents.len() / 2]).unwrap();
+
+        assert_eq!(
+            server.perform_query("foo").await.unwrap(),
+            json!({ "results": 1 })
+        );
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+        assert_eq!(std::fs::read_to_string(&entry).unwrap(), contents);
+    }
+
+    #[tokio::test]
+    async fn test_tee_to_cache_cleans_up() {
+        let dir = TestCacheDir::new("tee");
+        let path = dir.0.join("t/latest/html/a.cpp");
+        let encode: fn(&u32) -> Vec<u8> = |n| n.to_string().into_bytes();
+
+        // A failed stream doesn't leave an entry or a temporary file.
+        let failing: BoxStream<Result<u32>> =
+            Box::pin(stream::iter(vec![Ok(1), Err(ServerError::Unsupported)]));
+        let results: Vec<Result<u32>> = tee_to_cache(failing, path.clone(), encode).collect().await;
+        assert!(results[1].is_err());
+        assert_eq!(dir.entries("html"), Vec::<String>::new());
+
+        // Nor does a stream that's dropped part way through.
+        let partial: BoxStream<Result<u32>> = Box::pin(stream::iter(vec![Ok(1), Ok(2)]));
+        let mut teed = tee_to_cache(partial, path.clone(), encode);
+        assert!(teed.next().await.is_some());
+        drop(teed);
+        assert_eq!(dir.entries("html"), Vec::<String>::new());
+
+        // A complete stream results in just the entry.
+        let complete: BoxStream<Result<u32>> = Box::pin(stream::iter(vec![Ok(1), Ok(2)]));
+        let _: Vec<Result<u32>> = tee_to_cache(complete, path.clone(), encode).collect().await;
+        assert_eq!(dir.entries("html"), vec!["a.cpp"]);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "12");
+    }
+}
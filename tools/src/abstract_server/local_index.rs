@@ -1,16 +1,29 @@
+use std::path::Path;
+
+use async_compression::tokio::bufread::GzipDecoder;
+use async_stream::try_stream;
 use async_trait::async_trait;
-use flate2::read::GzDecoder;
 use futures_core::stream::BoxStream;
 use serde_json::{from_str, Value};
-use std::io::Read;
-use tokio::fs::File;
-use tokio::io::AsyncReadExt;
+use tokio::fs::{read_to_string, File};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio::sync::OnceCell;
+use tokio::task::spawn_blocking;
+use tokio_stream::StreamExt;
+use ustr::{ustr, UstrMap};
 
-use super::server_interface::{AbstractServer, ErrorDetails, ErrorLayer, Result, ServerError};
+use super::local_query::LocalQueryContext;
+use super::local_text_search::search_text;
+use super::server_interface::{
+    AbstractServer, CrossrefLookupOptions, ErrorDetails, ErrorLayer, FileListKind, Result,
+    ServerError,
+};
 
 use crate::config::{load, TreeConfigPaths};
+use crate::file_format::analysis::{read_source_records_at, source_record_covers, Jump};
 use crate::file_format::crossref_lookup::CrossrefLookupMap;
 use crate::file_format::identifiers::IdentMap;
+use crate::file_format::trigram_index::TrigramIndex;
 
 /// IO errors amount to a 404 for our purposes which means a sticky problem.
 impl From<std::io::Error> for ServerError {
@@ -22,31 +35,25 @@ impl From<std::io::Error> for ServerError {
     }
 }
 
-/// Read newline-delimited JSON that's been gzip-compressed.
-async fn read_gzipped_ndjson_from_file(path: &str) -> Result<Vec<Value>> {
-    let mut f = File::open(path).await?;
-    // We read the entirety to a buffer because
-    // https://github.com/serde-rs/json/issues/160 suggests that the buffered
-    // reader performance is likely to be much worse.
-    //
-    // When we want to go async here,
-    // https://github.com/rust-lang/flate2-rs/pull/214 suggests that we want to
-    // use the `async-compression` crate.
-    let mut buffer = Vec::new();
-    f.read_to_end(&mut buffer).await?;
-
-    let mut gz = GzDecoder::new(&buffer[..]);
-
-    let mut raw_str = String::new();
-    gz.read_to_string(&mut raw_str)?;
+/// The size of the decompressed chunks we produce when streaming HTML.
+const HTML_CHUNK_SIZE: usize = 64 * 1024;
 
-    // let mut raw_str = String::new();
-    // f.read_to_string(&mut raw_str).await?;
+/// Open a gzip-compressed file for streaming decompression.
+async fn open_gzipped_file(path: &str) -> Result<GzipDecoder<BufReader<File>>> {
+    let f = File::open(path).await?;
+    Ok(GzipDecoder::new(BufReader::new(f)))
+}
 
-    raw_str
-        .lines()
-        .map(|s| from_str(s).map_err(|e| ServerError::from(e)))
-        .collect()
+/// Stream newline-delimited JSON that's been gzip-compressed a record at a
+/// time.
+async fn read_gzipped_ndjson_from_file(path: &str) -> Result<BoxStream<'static, Result<Value>>> {
+    let mut lines = BufReader::new(open_gzipped_file(path).await?).lines();
+    Ok(Box::pin(try_stream! {
+        while let Some(line) = lines.next_line().await? {
+            let value: Value = from_str(&line)?;
+            yield value;
+        }
+    }))
 }
 
 #[allow(dead_code)]
@@ -68,6 +75,53 @@ struct LocalIndex {
     ident_map: IdentMap,
     // But for crossref, it's on us.
     crossref_lookup_map: Option<CrossrefLookupMap>,
+    // The jumps file is only needed by a few commands, so it's loaded on first
+    // use.
+    jumps: OnceCell<UstrMap<Jump>>,
+    // The trigram index only exists for trees that opt into it.
+    trigram_index: Option<TrigramIndex>,
+}
+
+/// Collect the source records covering the given position from a stream of a
+/// file's analysis records.
+pub(super) async fn collect_source_records_at(
+    mut values: BoxStream<'_, Result<Value>>,
+    lineno: u32,
+    col: u32,
+) -> Result<Vec<Value>> {
+    let mut records = vec![];
+    while let Some(value) = values.next().await {
+        let value = value?;
+        if source_record_covers(&value, lineno, col) {
+            records.push(value);
+        }
+    }
+    Ok(records)
+}
+
+/// Load the jumps file, whose lines are `[id, path, lineno, pretty]` arrays.
+/// A missing jumps file is treated as empty.
+async fn load_jumps(index_path: &str) -> Result<UstrMap<Jump>> {
+    let mut jumps = UstrMap::default();
+    let contents = match read_to_string(format!("{}/jumps", index_path)).await {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(jumps),
+        Err(err) => return Err(err.into()),
+    };
+    for line in contents.lines() {
+        let (id, path, lineno, pretty): (String, String, u64, String) = from_str(line)?;
+        let id = ustr(&id);
+        jumps.insert(
+            id,
+            Jump {
+                id,
+                path,
+                lineno,
+                pretty,
+            },
+        );
+    }
+    Ok(jumps)
 }
 
 #[async_trait]
@@ -79,44 +133,73 @@ impl AbstractServer for LocalIndex {
         ))
     }
 
-    async fn fetch_raw_analysis(&self, sf_path: &str) -> Result<BoxStream<Value>> {
+    async fn fetch_raw_analysis(&self, sf_path: &str) -> Result<BoxStream<Result<Value>>> {
         let full_path = format!("{}/analysis/{}.gz", self.config_paths.index_path, sf_path);
-        let values = read_gzipped_ndjson_from_file(&full_path).await?;
-        Ok(Box::pin(tokio_stream::iter(values)))
+        read_gzipped_ndjson_from_file(&full_path).await
     }
 
-    async fn fetch_html(&self, sf_path: &str) -> Result<String> {
-        let full_path = format!("{}/file/{}.gz", self.config_paths.index_path, sf_path);
-
-        // If we were dealing with uncompressed files.
-        /*
-        let mut f = File::open(full_path).await?;
-        let mut raw_str = String::new();
-        f.read_to_string(&mut raw_str).await?;
-        */
-
-        let mut f = File::open(full_path).await?;
-        let mut buffer = Vec::new();
-        f.read_to_end(&mut buffer).await?;
-
-        // When we want to go async here,
-        // https://github.com/rust-lang/flate2-rs/pull/214 suggests that we want
-        // to use the `async-compression` crate.
-        let mut gz = GzDecoder::new(&buffer[..]);
+    async fn source_records_at(&self, sf_path: &str, lineno: u32, col: u32) -> Result<Vec<Value>> {
+        let full_path = format!("{}/analysis/{}.gz", self.config_paths.index_path, sf_path);
+        // This skips parsing the records on other lines, which is most of them,
+        // but it's synchronous.
+        let records = spawn_blocking(move || read_source_records_at(&full_path, lineno, col))
+            .await
+            .map_err(|err| {
+                ServerError::StickyProblem(ErrorDetails {
+                    layer: ErrorLayer::ServerLayer,
+                    message: err.to_string(),
+                })
+            })??;
+        Ok(records)
+    }
 
-        let mut raw_str = String::new();
-        gz.read_to_string(&mut raw_str)?;
+    async fn fetch_html(&self, sf_path: &str) -> Result<BoxStream<Result<Vec<u8>>>> {
+        let full_path = format!("{}/file/{}.gz", self.config_paths.index_path, sf_path);
+        let mut gz = open_gzipped_file(&full_path).await?;
+        Ok(Box::pin(try_stream! {
+            let mut buf = vec![0; HTML_CHUNK_SIZE];
+            loop {
+                let n = gz.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                yield buf[..n].to_vec();
+            }
+        }))
+    }
 
-        Ok(raw_str)
+    async fn fetch_file_list(&self, list: FileListKind) -> Result<Vec<String>> {
+        let full_path = format!("{}/{}", self.config_paths.index_path, list.file_name());
+        let contents = read_to_string(full_path).await?;
+        Ok(contents.lines().map(|s| s.to_string()).collect())
     }
 
-    async fn crossref_lookup(&self, symbol: &str) -> Result<Value> {
+    async fn crossref_lookup(
+        &self,
+        symbol: &str,
+        options: &CrossrefLookupOptions,
+    ) -> Result<Value> {
         match &self.crossref_lookup_map {
-            Some(crossref) => crossref.lookup(symbol),
+            Some(crossref) => crossref.lookup_with_options(symbol, options),
             None => Ok(Value::Null),
         }
     }
 
+    async fn stream_crossref(&self) -> Result<BoxStream<Result<(String, Value)>>> {
+        match &self.crossref_lookup_map {
+            Some(crossref) => Ok(Box::pin(tokio_stream::iter(crossref.entries()))),
+            None => Ok(Box::pin(tokio_stream::empty())),
+        }
+    }
+
+    async fn jump_lookup(&self, symbol: &str) -> Result<Option<Jump>> {
+        let jumps = self
+            .jumps
+            .get_or_try_init(|| load_jumps(&self.config_paths.index_path))
+            .await?;
+        Ok(jumps.get(&ustr(symbol)).cloned())
+    }
+
     async fn search_identifiers(
         &self,
         needle: &str,
@@ -134,12 +217,36 @@ impl AbstractServer for LocalIndex {
         Ok(results)
     }
 
-    async fn perform_query(&self, _q: &str) -> Result<Value> {
-        // TODO: For this to work, we want to be able to directly invoke the
-        // underpinnings of the web server, which entails porting router.py into
-        // web-server.rs, an act which may involve building it on some of this
-        // infrastructure...
-        Err(ServerError::Unsupported)
+    async fn search_text(
+        &self,
+        pattern: &str,
+        fold_case: bool,
+        pathre: Option<&str>,
+        context_lines: u32,
+        max_matches: usize,
+    ) -> Result<Value> {
+        match &self.trigram_index {
+            Some(index) => search_text(
+                index,
+                &self.config_paths,
+                pattern,
+                fold_case,
+                pathre,
+                context_lines,
+                max_matches,
+            ),
+            None => Err(ServerError::Unsupported),
+        }
+    }
+
+    async fn perform_query(&self, q: &str) -> Result<Value> {
+        let context = LocalQueryContext {
+            ident_map: &self.ident_map,
+            crossref_lookup_map: self.crossref_lookup_map.as_ref(),
+            trigram_index: self.trigram_index.as_ref(),
+            config_paths: &self.config_paths,
+        };
+        context.perform_query(q)
     }
 }
 
@@ -158,19 +265,85 @@ pub fn make_local_server(
         }
     };
 
-    let ident_path = format!("{}/identifiers", tree_config.paths.index_path);
+    // We don't need the blame_map and hg_map (yet)
+    Ok(Box::new(make_local_index(tree_config.paths, tree_name)))
+}
+
+/// Create a local server for an index directory without a config file.  Only
+/// the index contents are available, so this is suitable for tools like
+/// `index-diff` that compare the results of indexing, but not for anything
+/// that needs the source or objdir.
+pub fn make_local_server_for_index(
+    index_path: &str,
+) -> Result<Box<dyn AbstractServer + Send + Sync>> {
+    if !Path::new(index_path).is_dir() {
+        return Err(ServerError::StickyProblem(ErrorDetails {
+            layer: ErrorLayer::BadInput,
+            message: format!("not an index directory: {}", index_path),
+        }));
+    }
+
+    let paths = TreeConfigPaths {
+        index_path: index_path.to_string(),
+        files_path: String::new(),
+        git_path: None,
+        git_blame_path: None,
+        objdir_path: String::new(),
+        hg_root: None,
+        ccov_root: None,
+        wpt_root: None,
+        github_repo: None,
+    };
+    Ok(Box::new(make_local_index(paths, "")))
+}
+
+fn make_local_index(paths: TreeConfigPaths, tree_name: &str) -> LocalIndex {
+    let ident_path = format!("{}/identifiers", paths.index_path);
     let ident_map = IdentMap::new(&ident_path);
 
-    let crossref_path = format!("{}/crossref", tree_config.paths.index_path);
-    let crossref_extra_path = format!("{}/crossref-extra", tree_config.paths.index_path);
+    let crossref_path = format!("{}/crossref", paths.index_path);
+    let crossref_extra_path = format!("{}/crossref-extra", paths.index_path);
 
     let crossref_lookup_map = CrossrefLookupMap::new(&crossref_path, &crossref_extra_path);
 
-    Ok(Box::new(LocalIndex {
-        // We don't need the blame_map and hg_map (yet)
-        config_paths: tree_config.paths,
+    let trigram_index = TrigramIndex::open(&paths.index_path);
+
+    LocalIndex {
+        config_paths: paths,
         tree_name: tree_name.to_string(),
         ident_map,
         crossref_lookup_map,
-    }))
+        jumps: OnceCell::new(),
+        trigram_index,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::stream;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_collect_source_records_at() {
+        let values = vec![
+            json!({ "loc": "00012:4-9", "source": 1, "sym": "late" }),
+            json!({ "loc": "12", "source": 1, "sym": "bad" }),
+            json!({ "loc": "00003:0-3", "source": 1, "sym": "early" }),
+        ];
+        let collect = |lineno, col| {
+            let values = values.clone();
+            async move {
+                collect_source_records_at(
+                    Box::pin(stream::iter(values.into_iter().map(Ok))),
+                    lineno,
+                    col,
+                )
+                .await
+                .unwrap()
+            }
+        };
+        assert_eq!(collect(3, 2).await, vec![values[2].clone()]);
+        assert_eq!(collect(12, 4).await, vec![values[0].clone()]);
+    }
 }
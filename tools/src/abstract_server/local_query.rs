@@ -0,0 +1,730 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use lazy_static::lazy_static;
+use regex::{Captures, Regex, RegexBuilder};
+use serde_json::{json, Map, Value};
+
+use super::local_text_search::search_text;
+use super::server_interface::{Result, ServerError};
+
+use crate::config::TreeConfigPaths;
+use crate::file_format::crossref_lookup::CrossrefLookupMap;
+use crate::file_format::identifiers::IdentMap;
+use crate::file_format::trigram_index::TrigramIndex;
+
+// This is a port of the `router.py` search logic (`get_json_search_results`
+// and its helpers) so that `LocalIndex::perform_query` can produce the same
+// JSON results the web server would, with full-text search provided by the
+// trigram index in place of the `codesearch` daemon.  The structure
+// intentionally mirrors the python so that the two can be compared
+// side-by-side until `router.py` goes away.
+
+/// Simple globbing implementation, except `^` and `$` are also allowed.  This
+/// is a port of `router.py`'s `parse_path_filter` and returns a regular
+/// expression string.
+pub fn parse_path_filter(filter: &str) -> String {
+    lazy_static! {
+        static ref BRACES_RE: Regex = Regex::new(r"\{([^}]*)\}").unwrap();
+    }
+
+    let mut escaped = String::with_capacity(filter.len());
+    let mut chars = filter.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '(' | ')' | '|' | '.' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '*' => {
+                if chars.peek() == Some(&'*') {
+                    chars.next();
+                    escaped.push_str(".*");
+                } else {
+                    escaped.push_str("[^/]*");
+                }
+            }
+            '?' => escaped.push('.'),
+            _ => escaped.push(c),
+        }
+    }
+
+    BRACES_RE
+        .replace_all(&escaped, |caps: &Captures| {
+            format!("({})", caps[1].replace(',', "|"))
+        })
+        .into_owned()
+}
+
+/// A version of regex escaping that doesn't escape every non-ASCII character.
+/// See https://bugzilla.mozilla.org/show_bug.cgi?id=1446220 for the python
+/// backstory.
+fn escape_regex(search_string: &str) -> String {
+    let mut escaped = String::with_capacity(search_string.len());
+    for c in search_string.chars() {
+        match c {
+            '(' | ')' | '{' | '}' | '[' | ']' | '.' | '*' | '?' | '|' | '^' | '$' | '\\' | '+'
+            | '-' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The result of parsing a searchfox search string like
+/// `path:dom/ symbol:_ZN3foo3barEv` into its constituent filters.
+#[derive(Debug, Default, PartialEq)]
+pub struct ParsedQuery {
+    /// Path regular expression derived from `path:` (via `parse_path_filter`)
+    /// or `pathre:`.
+    pub pathre: Option<String>,
+    /// Number of context lines requested via `context:`, clamped to [0, 10].
+    pub context_lines: Option<u32>,
+    /// Comma-delimited symbol list from `symbol:`.
+    pub symbol: Option<String>,
+    /// Regular expression from `re:` or an escaped `text:`.
+    pub re: Option<String>,
+    /// Exact identifier from `id:`.
+    pub id: Option<String>,
+    /// Everything else, escaped as a regular expression.
+    pub default: Option<String>,
+}
+
+/// Port of `router.py`'s `parse_search`.
+pub fn parse_search(search_string: &str) -> ParsedQuery {
+    let pieces: Vec<&str> = search_string.split(' ').collect();
+    let mut result = ParsedQuery::default();
+    for i in 0..pieces.len() {
+        let piece = pieces[i];
+        if let Some(filter) = piece.strip_prefix("path:") {
+            result.pathre = Some(parse_path_filter(filter));
+        } else if let Some(pathre) = piece.strip_prefix("pathre:") {
+            result.pathre = Some(pathre.to_string());
+        } else if let Some(context) = piece.strip_prefix("context:") {
+            // Require the context to be an integer <= 10.
+            if let Ok(context_lines) = context.parse::<i64>() {
+                result.context_lines = Some(context_lines.clamp(0, 10) as u32);
+            }
+        } else if piece.starts_with("symbol:") {
+            // Note that like the python, we don't stop processing here.
+            let rest = pieces[i..].join(" ");
+            result.symbol = Some(rest["symbol:".len()..].trim().replace('.', "#"));
+        } else if piece.starts_with("re:") {
+            let rest = pieces[i..].join(" ");
+            result.re = Some(rest["re:".len()..].to_string());
+            break;
+        } else if piece.starts_with("text:") {
+            let rest = pieces[i..].join(" ");
+            result.re = Some(escape_regex(&rest["text:".len()..]));
+            break;
+        } else if let Some(id) = piece.strip_prefix("id:") {
+            result.id = Some(id.to_string());
+        } else {
+            result.default = Some(escape_regex(&pieces[i..].join(" ")));
+            break;
+        }
+    }
+
+    result
+}
+
+fn is_trivial_search(parsed: &ParsedQuery) -> bool {
+    if parsed.symbol.is_some() {
+        return false;
+    }
+
+    ![&parsed.pathre, &parsed.re, &parsed.id, &parsed.default]
+        .iter()
+        .filter_map(|value| value.as_ref())
+        .any(|s| s.chars().count() >= 3)
+}
+
+/// Given a path, decide whether it's "normal"/"thirdparty"/"test"/"generated".
+/// These are the top-level groups by which results are categorized.
+///
+/// These are hardcoded heuristics that probably could be better defined in the
+/// `config.json` metadata, with a means for trees like gecko to be able to
+/// leverage in-tree build meta-information like moz.build and the various
+/// mochitest.ini files, etc.
+pub fn categorize_path(path: &str) -> &'static str {
+    fn is_test(p: &str) -> bool {
+        // Except /unit/ and /androidTest/, all other paths contain the
+        // substring 'test', so we can exit early in case it is not present.
+        if p.contains("/unit/") || p.contains("/androidTest/") {
+            return true;
+        }
+        if !p.contains("test") {
+            return false;
+        }
+        [
+            "/test/",
+            "/tests/",
+            "/mochitest/",
+            "testing/",
+            "/jsapi-tests/",
+            "/reftests/",
+            "/reftest/",
+            "/crashtests/",
+            "/crashtest/",
+            "/googletest/",
+            "/gtest/",
+            "/gtests/",
+            "/imptests/",
+        ]
+        .iter()
+        .any(|needle| p.contains(needle))
+    }
+
+    if path.contains("__GENERATED__") {
+        "generated"
+    } else if path.starts_with("third_party/") {
+        "thirdparty"
+    } else if is_test(path) {
+        "test"
+    } else {
+        "normal"
+    }
+}
+
+const MAX_COUNT: usize = 1000;
+const MAX_WORK: usize = 750;
+const PATH_PRECEDENCES: [&str; 4] = ["normal", "thirdparty", "test", "generated"];
+const KEY_PRECEDENCES: [&str; 11] = [
+    "Files",
+    "IDL",
+    "Definitions",
+    "Overrides",
+    "Overridden By",
+    "Superclasses",
+    "Subclasses",
+    "Assignments",
+    "Uses",
+    "Declarations",
+    "Textual Occurrences",
+];
+
+/// The python `line_modifier` closure only ever exists to truncate the bounds
+/// of identifier search results to the length of the searched prefix, so we
+/// just store that length.
+type LineModifier = Option<usize>;
+
+/// Lines for a given path paired with the line modifier to apply to them.
+type PathResults = BTreeMap<String, (Vec<Value>, LineModifier)>;
+
+fn apply_line_modifier(line: &mut Value, modifier: LineModifier) {
+    let prefix_len = match modifier {
+        Some(len) => len,
+        None => return,
+    };
+    if let Some(start) = line.pointer("/bounds/0").and_then(|v| v.as_u64()) {
+        line["bounds"] = json!([start, start + prefix_len as u64]);
+    }
+}
+
+/// Port of `router.py`'s `SearchResults`.  `compiled` is a map of
+/// {pathkind: [(qkind, {path: (lines, line_modifier)})]} where the qkind
+/// vector preserves insertion order like the python `OrderedDict`.
+#[derive(Default)]
+struct SearchResults {
+    results: Vec<Map<String, Value>>,
+    qualified_results: Vec<(String, Map<String, Value>, LineModifier)>,
+    pathre: Option<Regex>,
+    compiled: HashMap<&'static str, Vec<(String, PathResults)>>,
+}
+
+impl SearchResults {
+    fn set_path_filter(&mut self, path: Option<&str>) {
+        let path = match path {
+            Some(p) if !p.is_empty() && p != ".*" => p,
+            _ => {
+                self.pathre = None;
+                return;
+            }
+        };
+
+        // In case the pattern is not a valid RE, treat it as literal string.
+        self.pathre = RegexBuilder::new(path)
+            .case_insensitive(true)
+            .build()
+            .or_else(|_| {
+                RegexBuilder::new(&regex::escape(path))
+                    .case_insensitive(true)
+                    .build()
+            })
+            .ok();
+    }
+
+    fn add_results(&mut self, results: Map<String, Value>) {
+        self.results.push(results);
+    }
+
+    fn add_qualified_results(&mut self, qual: String, results: Map<String, Value>, modifier: LineModifier) {
+        self.qualified_results.push((qual, results, modifier));
+    }
+
+    /// Categorize the path of the given path-binned results and nest them
+    /// under the [pathkind, qkind, path] hierarchy, applying the path filter.
+    fn compile_result(&mut self, kind: &str, qual: Option<&str>, pathr: &Value, modifier: LineModifier) {
+        let qkind = match qual {
+            Some(q) => format!("{} ({})", kind, q),
+            None => kind.to_string(),
+        };
+
+        let path = match pathr["path"].as_str() {
+            Some(p) => p,
+            None => return,
+        };
+
+        let pathkind = categorize_path(path);
+
+        if let Some(pathre) = &self.pathre {
+            if !pathre.is_match(path) {
+                return;
+            }
+        }
+
+        let kind_list = self.compiled.entry(pathkind).or_default();
+        let idx = match kind_list.iter().position(|(k, _)| k == &qkind) {
+            Some(idx) => idx,
+            None => {
+                kind_list.push((qkind, BTreeMap::new()));
+                kind_list.len() - 1
+            }
+        };
+        let path_results = kind_list[idx]
+            .1
+            .entry(path.to_string())
+            .or_insert_with(|| (vec![], modifier));
+        if let Some(lines) = pathr["lines"].as_array() {
+            path_results.0.extend(lines.iter().cloned());
+        }
+    }
+
+    /// Traverse `compiled` in `PATH_PRECEDENCES` order, de-duplicating
+    /// (path, lno) tuples, applying line modifiers and the `MAX_COUNT` limit.
+    fn sort_compiled(&mut self) -> Map<String, Value> {
+        let mut count = 0;
+        let mut line_hash = HashSet::new();
+        let mut result = Map::new();
+
+        'pathkinds: for pathkind in PATH_PRECEDENCES.iter() {
+            let kind_list = match self.compiled.remove(pathkind) {
+                Some(kl) => kl,
+                None => continue,
+            };
+            for (qkind, paths) in kind_list {
+                for (path, (mut lines, modifier)) in paths {
+                    lines.sort_by_key(|l| l["lno"].as_u64().unwrap_or(0));
+                    let mut lines_out = vec![];
+                    for mut line in lines {
+                        let lno = line["lno"].as_u64().unwrap_or(0);
+                        if !line_hash.insert((path.clone(), lno)) {
+                            continue;
+                        }
+                        apply_line_modifier(&mut line, modifier);
+                        lines_out.push(line);
+                        count += 1;
+                        if count == MAX_COUNT {
+                            break;
+                        }
+                    }
+
+                    if !lines_out.is_empty() || qkind == "Files" {
+                        let kind_obj = result
+                            .entry(pathkind.to_string())
+                            .or_insert_with(|| Value::Object(Map::new()));
+                        let path_list = kind_obj
+                            .as_object_mut()
+                            .unwrap()
+                            .entry(qkind.clone())
+                            .or_insert_with(|| json!([]));
+                        path_list.as_array_mut().unwrap().push(json!({
+                            "path": path,
+                            "lines": lines_out,
+                        }));
+                    }
+                    if count == MAX_COUNT {
+                        break 'pathkinds;
+                    }
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Work-limiting/result-bounding logic to process the accumulated results,
+    /// capping them based on some heuristics.
+    fn get(mut self, work_limit: bool) -> Map<String, Value> {
+        let mut qualified_results = std::mem::take(&mut self.qualified_results);
+        qualified_results.sort_by(|a, b| a.0.cmp(&b.0));
+        let results = std::mem::take(&mut self.results);
+
+        for kind in KEY_PRECEDENCES.iter() {
+            let mut work = 0;
+            for (qual, qresults, modifier) in &qualified_results {
+                if work > MAX_WORK && work_limit {
+                    info!("WORK LIMIT HIT");
+                    break;
+                }
+                if let Some(pathrs) = qresults.get(*kind).and_then(|v| v.as_array()) {
+                    for pathr in pathrs {
+                        self.compile_result(kind, Some(qual), pathr, *modifier);
+                        work += 1;
+                    }
+                }
+            }
+
+            for results in &results {
+                if let Some(pathrs) = results.get(*kind).and_then(|v| v.as_array()) {
+                    for pathr in pathrs {
+                        self.compile_result(kind, None, pathr, None);
+                    }
+                }
+            }
+        }
+
+        self.sort_compiled()
+    }
+}
+
+/// The pieces of a local index needed to answer a query.
+pub struct LocalQueryContext<'a> {
+    pub ident_map: &'a IdentMap,
+    pub crossref_lookup_map: Option<&'a CrossrefLookupMap>,
+    pub trigram_index: Option<&'a TrigramIndex>,
+    pub config_paths: &'a TreeConfigPaths,
+}
+
+impl<'a> LocalQueryContext<'a> {
+    fn lookup_raw(&self, symbol: &str) -> Result<Value> {
+        match self.crossref_lookup_map {
+            Some(crossref) => crossref.lookup(symbol),
+            None => Ok(Value::Null),
+        }
+    }
+
+    /// Split `symbols` on commas, and lookup all of the requested symbols,
+    /// merging their results.  If any of the symbols are unknown, an empty
+    /// result is returned; see `crossrefs.py` for the rationale.
+    fn lookup_merging(&self, symbols: &str) -> Result<Map<String, Value>> {
+        let mut results = Map::new();
+        for symbol in symbols.split(',') {
+            let result = match self.lookup_raw(symbol)? {
+                Value::Object(obj) => obj,
+                _ => return Ok(Map::new()),
+            };
+
+            for (k, v) in result {
+                if k == "callees" {
+                    continue;
+                }
+                // expand_keys expects aggregated meta, so wrap the meta obj.
+                let v = if k == "meta" { json!([v]) } else { v };
+                let existing = results.entry(k).or_insert_with(|| json!([]));
+                if let (Some(existing), Value::Array(mut more)) = (existing.as_array_mut(), v) {
+                    existing.append(&mut more);
+                }
+            }
+        }
+
+        Ok(results)
+    }
+
+    /// Build an aggregate path hit list to be stored as `as_key` in
+    /// `mix_target` consisting of the definitions for each provided symbol,
+    /// augmented with an "upsearch" hint for the UI.
+    fn merge_defs_from_symbols_as(
+        &self,
+        mix_target: &mut Map<String, Value>,
+        symbol_names: Vec<&str>,
+        as_key: &str,
+    ) -> Result<()> {
+        // Do not do anything if there's too many results!
+        if symbol_names.len() >= 50 {
+            return Ok(());
+        }
+
+        let mut aggr_defs = vec![];
+        for symbol_name in symbol_names {
+            let mut info = self.lookup_raw(symbol_name)?;
+            if let Some(Value::Array(defs)) = info.get_mut("defs").map(Value::take) {
+                for mut path_hit in defs {
+                    if let Some(first_line) = path_hit.pointer_mut("/lines/0") {
+                        first_line["upsearch"] = json!(format!("symbol:{}", symbol_name));
+                    }
+                    aggr_defs.push(path_hit);
+                }
+            }
+        }
+
+        if !aggr_defs.is_empty() {
+            mix_target.insert(as_key.to_string(), Value::Array(aggr_defs));
+        }
+        Ok(())
+    }
+
+    /// Converts from the new uses/defs/assignments/decls/idl rep to the old
+    /// Uses/Definitions/Assignments/Declarations/IDL rep, dropping 'callees',
+    /// and, if `traverse_relations` is set, inducing synthetic "Overrides",
+    /// "Overridden By", "Superclasses", and "Subclasses" keys from "meta".
+    fn expand_keys(
+        &self,
+        mut new_keyed: Map<String, Value>,
+        traverse_relations: bool,
+    ) -> Result<Map<String, Value>> {
+        let key_remapping = [
+            ("uses", Some("Uses")),
+            ("defs", Some("Definitions")),
+            ("assignments", Some("Assignments")),
+            ("decls", Some("Declarations")),
+            ("idl", Some("IDL")),
+            ("callees", None),
+        ];
+        for (new_name, old_name) in key_remapping.iter() {
+            if let Some(value) = new_keyed.remove(*new_name) {
+                if let Some(old_name) = old_name {
+                    new_keyed.insert(old_name.to_string(), value);
+                }
+            }
+        }
+
+        if let Some(Value::Array(meta_arr)) = new_keyed.remove("meta") {
+            if traverse_relations {
+                for meta in &meta_arr {
+                    // "overrides" and "supers" are objects of { sym, pretty }
+                    // whereas the derived "overriddenBy" and "subclasses" are
+                    // just symbols.
+                    let obj_syms = |key: &str| -> Option<Vec<&str>> {
+                        meta.get(key).and_then(|v| v.as_array()).map(|arr| {
+                            arr.iter().filter_map(|x| x["sym"].as_str()).collect()
+                        })
+                    };
+                    let bare_syms = |key: &str| -> Option<Vec<&str>> {
+                        meta.get(key)
+                            .and_then(|v| v.as_array())
+                            .map(|arr| arr.iter().filter_map(|x| x.as_str()).collect())
+                    };
+
+                    if let Some(syms) = obj_syms("overrides") {
+                        self.merge_defs_from_symbols_as(&mut new_keyed, syms, "Overrides")?;
+                    }
+                    if let Some(syms) = bare_syms("overriddenBy") {
+                        self.merge_defs_from_symbols_as(&mut new_keyed, syms, "Overridden By")?;
+                    }
+                    if let Some(syms) = obj_syms("supers") {
+                        self.merge_defs_from_symbols_as(&mut new_keyed, syms, "Superclasses")?;
+                    }
+                    if let Some(syms) = bare_syms("subclasses") {
+                        self.merge_defs_from_symbols_as(&mut new_keyed, syms, "Subclasses")?;
+                    }
+                }
+            }
+        }
+
+        Ok(new_keyed)
+    }
+
+    /// Port of `router.py`'s `search_files` which greps the `repo-files` and
+    /// `objdir-files` lists for the given (case-insensitive) regexp.
+    fn search_files(&self, path: &str) -> Vec<Value> {
+        let re = match RegexBuilder::new(path).case_insensitive(true).build() {
+            Ok(re) => re,
+            // grep would have failed and the python returns no results.
+            Err(_) => return vec![],
+        };
+
+        let mut results = vec![];
+        for list_name in ["repo-files", "objdir-files"].iter() {
+            let list_path = format!("{}/{}", self.config_paths.index_path, list_name);
+            let contents = match std::fs::read_to_string(&list_path) {
+                Ok(contents) => contents,
+                Err(_) => continue,
+            };
+            for file in contents.lines().filter(|l| re.is_match(l)) {
+                results.push(json!({
+                    "path": file,
+                    "lines": [],
+                }));
+                if results.len() == MAX_COUNT {
+                    return results;
+                }
+            }
+        }
+        results
+    }
+
+    fn identifier_search(
+        &self,
+        search: &mut SearchResults,
+        needle: &str,
+        complete: bool,
+        fold_case: bool,
+    ) -> Result<()> {
+        lazy_static! {
+            static ref UNESCAPE_RE: Regex = Regex::new(r"\\(.)").unwrap();
+            static ref SCOPE_RE: Regex = Regex::new(r"\.|::").unwrap();
+        }
+
+        let needle = UNESCAPE_RE.replace_all(needle, "$1");
+        let last_piece_len = SCOPE_RE.split(&needle).last().unwrap_or("").len();
+        // If the last segment of the search needle is too short, return no
+        // results because we're worried that would return too many results.
+        if !complete && last_piece_len < 3 {
+            return Ok(());
+        }
+
+        // The python processes the first 501 identifiers.  `IdentMap::lookup`
+        // already takes care of demangling for us.
+        for ir in self.ident_map.lookup(&needle, complete, fold_case, 501) {
+            let results = self.expand_keys(self.lookup_merging(&ir.symbol)?, true)?;
+            search.add_qualified_results(ir.id, results, Some(last_piece_len));
+        }
+
+        Ok(())
+    }
+
+    /// Port of `codesearch.py`'s `search` using the trigram index, returning
+    /// None if the tree doesn't have a trigram index.
+    fn text_search(
+        &self,
+        pattern: &str,
+        fold_case: bool,
+        pathre: Option<&str>,
+        context_lines: u32,
+    ) -> Result<Option<Value>> {
+        match self.trigram_index {
+            Some(index) => Ok(Some(search_text(
+                index,
+                self.config_paths,
+                pattern,
+                fold_case,
+                pathre,
+                context_lines,
+                MAX_COUNT,
+            )?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Port of `router.py`'s `get_json_search_results` for the case where only
+    /// the "q" parameter is provided.
+    ///
+    /// Full-text search is provided by the tree's trigram index rather than
+    /// the `codesearch` daemon.  If the tree doesn't have a trigram index (it's
+    /// opt-in via `trigram_index` in the tree config), queries that can only
+    /// be answered by text search (`re:`, `text:`, or a default search with a
+    /// path filter) return `ServerError::Unsupported`, and default searches
+    /// omit the "Textual Occurrences" that the web server would have merged
+    /// in, which is generally invisible because those results get
+    /// de-duplicated against the semantic results.
+    pub fn perform_query(&self, search_string: &str) -> Result<Value> {
+        let fold_case = true;
+        let mut parsed = parse_search(search_string);
+
+        if parsed.default.as_deref() == Some("") {
+            parsed.default = None;
+        }
+
+        if is_trivial_search(&parsed) {
+            return Ok(json!({}));
+        }
+
+        let context_lines = parsed.context_lines.unwrap_or(0);
+        let mut title = search_string.to_string();
+        let mut search = SearchResults::default();
+        let mut work_limit = false;
+
+        if let Some(symbols) = &parsed.symbol {
+            search.set_path_filter(parsed.pathre.as_deref());
+            title = format!("Symbol {}", symbols);
+            search.add_results(self.expand_keys(self.lookup_merging(symbols)?, true)?);
+        } else if let Some(re) = &parsed.re {
+            let substr_results = self
+                .text_search(re, fold_case, parsed.pathre.as_deref(), context_lines)?
+                .ok_or(ServerError::Unsupported)?;
+            let mut text_results = Map::new();
+            text_results.insert("Textual Occurrences".to_string(), substr_results);
+            search.add_results(text_results);
+        } else if let Some(id) = &parsed.id {
+            search.set_path_filter(parsed.pathre.as_deref());
+            self.identifier_search(&mut search, id, true, fold_case)?;
+        } else if let Some(default) = &parsed.default {
+            work_limit = true;
+            match self.text_search(default, fold_case, parsed.pathre.as_deref(), context_lines)? {
+                Some(substr_results) => {
+                    let mut text_results = Map::new();
+                    text_results.insert("Textual Occurrences".to_string(), substr_results);
+                    search.add_results(text_results);
+                }
+                None if parsed.pathre.is_some() => return Err(ServerError::Unsupported),
+                None => {}
+            }
+            if parsed.pathre.is_none() {
+                let mut file_results = Map::new();
+                file_results.insert("Files".to_string(), Value::Array(self.search_files(default)));
+                search.add_results(file_results);
+
+                self.identifier_search(&mut search, default, false, fold_case)?;
+            }
+        } else if let Some(pathre) = &parsed.pathre {
+            let mut file_results = Map::new();
+            file_results.insert("Files".to_string(), Value::Array(self.search_files(pathre)));
+            search.add_results(file_results);
+        }
+
+        let mut results = search.get(work_limit);
+        results.insert("*title*".to_string(), json!(title));
+        results.insert("*timedout*".to_string(), json!(false));
+        Ok(Value::Object(results))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_path_filter() {
+        assert_eq!(parse_path_filter("dom/*.cpp"), r"dom/[^/]*\.cpp");
+        assert_eq!(parse_path_filter("dom/**/test_?.js"), r"dom/.*/test_.\.js");
+        assert_eq!(parse_path_filter("^{dom,layout}/"), r"^(dom|layout)/");
+    }
+
+    #[test]
+    fn test_parse_search() {
+        assert_eq!(
+            parse_search("path:dom/ symbol:_ZN3foo3barEv"),
+            ParsedQuery {
+                pathre: Some("dom/".to_string()),
+                symbol: Some("_ZN3foo3barEv".to_string()),
+                ..ParsedQuery::default()
+            }
+        );
+        assert_eq!(
+            parse_search("context:20 text:a.b c"),
+            ParsedQuery {
+                context_lines: Some(10),
+                re: Some(r"a\.b c".to_string()),
+                ..ParsedQuery::default()
+            }
+        );
+        assert_eq!(
+            parse_search("Foo::Bar"),
+            ParsedQuery {
+                default: Some("Foo::Bar".to_string()),
+                ..ParsedQuery::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_categorize_path() {
+        assert_eq!(categorize_path("dom/base/nsINode.cpp"), "normal");
+        assert_eq!(categorize_path("dom/base/test/test_foo.html"), "test");
+        assert_eq!(categorize_path("third_party/rust/foo.rs"), "thirdparty");
+        assert_eq!(categorize_path("__GENERATED__/dist/include/foo.h"), "generated");
+    }
+}
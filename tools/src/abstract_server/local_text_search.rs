@@ -0,0 +1,548 @@
+use std::collections::BTreeSet;
+
+use regex::{Regex, RegexBuilder};
+use serde_json::{json, Value};
+
+use super::server_interface::{ErrorDetails, ErrorLayer, Result, ServerError};
+
+use crate::config::TreeConfigPaths;
+use crate::file_format::trigram_index::{TrigramIndex, TrigramQuery};
+use crate::find_source_file;
+
+// Full-text search against the trigram index, producing the same results
+// `codesearch.py` produces from the `codesearch` (livegrep) daemon so that it
+// can be used in place of the daemon for local indexes.
+//
+// A search works in two steps:
+// - We analyze the regular expression to derive a `TrigramQuery` that any
+//   matching line must satisfy, and evaluate that against the trigram index to
+//   get the candidate files.
+// - We run the actual regular expression over each line of the candidate
+//   files to find the matches.
+//
+// The analysis only needs to be conservative, not precise: anything it doesn't
+// understand just becomes `TrigramQuery::All` which means more files get
+// scanned.  This is in the spirit of Russ Cox's "Regular Expression Matching
+// with a Trigram Index" but doesn't bother tracking prefixes and suffixes.
+
+/// The maximum number of alternative strings we track for a sub-expression
+/// before giving up and converting them into a trigram query.
+const MAX_EXACT_SET: usize = 16;
+
+/// What we know about the strings matched by a sub-expression.
+struct Info {
+    /// If known, the complete set of strings the sub-expression can match,
+    /// with ASCII letters lowercased.
+    exact: Option<BTreeSet<String>>,
+    /// The query any match must satisfy when `exact` is None.
+    query: TrigramQuery,
+}
+
+impl Info {
+    fn any() -> Info {
+        Info {
+            exact: None,
+            query: TrigramQuery::All,
+        }
+    }
+
+    fn empty() -> Info {
+        Info::exact_chars(std::iter::once(None))
+    }
+
+    /// The info for a set of single characters, where None is the empty
+    /// string.  Because the index lowercases ASCII but not other characters,
+    /// and we don't know if case-folding is in effect, any non-ASCII
+    /// characters make the set unknowable.
+    fn exact_chars(chars: impl Iterator<Item = Option<char>>) -> Info {
+        let mut exact = BTreeSet::new();
+        for c in chars {
+            match c {
+                Some(c) if !c.is_ascii() || c == '\n' => return Info::any(),
+                Some(c) => exact.insert(c.to_ascii_lowercase().to_string()),
+                None => exact.insert(String::new()),
+            };
+        }
+        Info {
+            exact: Some(exact),
+            query: TrigramQuery::All,
+        }
+    }
+
+    fn into_query(self) -> TrigramQuery {
+        match self.exact {
+            Some(exact) => {
+                TrigramQuery::or(exact.iter().map(|s| TrigramQuery::for_string(s)).collect())
+            }
+            None => self.query,
+        }
+    }
+
+    /// The info for `self` followed by `other` if both are exact and the
+    /// resulting set isn't too big.
+    fn concat_exact(&self, other: &Info) -> Option<Info> {
+        let (a, b) = (self.exact.as_ref()?, other.exact.as_ref()?);
+        if a.len() * b.len() > MAX_EXACT_SET {
+            return None;
+        }
+        let mut exact = BTreeSet::new();
+        for x in a {
+            for y in b {
+                exact.insert(format!("{}{}", x, y));
+            }
+        }
+        Some(Info {
+            exact: Some(exact),
+            query: TrigramQuery::All,
+        })
+    }
+
+    /// The info for `self` or `other`.
+    fn alternate(self, other: Info) -> Info {
+        if let (Some(a), Some(b)) = (&self.exact, &other.exact) {
+            if a.len() + b.len() <= MAX_EXACT_SET {
+                return Info {
+                    exact: Some(a.union(b).cloned().collect()),
+                    query: TrigramQuery::All,
+                };
+            }
+        }
+        Info {
+            exact: None,
+            query: TrigramQuery::or(vec![self.into_query(), other.into_query()]),
+        }
+    }
+
+    /// The info for one or more repetitions of `self`.
+    fn repeated(self) -> Info {
+        Info {
+            exact: None,
+            query: self.into_query(),
+        }
+    }
+}
+
+/// A recursive descent parser over the regular expression syntax accepted by
+/// the `regex` crate that computes the `Info` of the expression.  The pattern
+/// has already been validated by the `regex` crate, so we don't need to worry
+/// about reporting errors; all of the parsing methods just return None when
+/// they see something they don't understand.
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        Some(c)
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Skip past the next `close` character.
+    fn skip_past(&mut self, close: char) -> Option<()> {
+        while self.next()? != close {}
+        Some(())
+    }
+
+    fn parse_alternation(&mut self) -> Option<Info> {
+        let mut info = self.parse_concat()?;
+        while self.eat('|') {
+            info = info.alternate(self.parse_concat()?);
+        }
+        Some(info)
+    }
+
+    /// Parse a sequence of atoms.  We extend the exact set of the current run
+    /// of exact atoms for as long as we can, and otherwise require the queries
+    /// of the runs and of the non-exact atoms.
+    fn parse_concat(&mut self) -> Option<Info> {
+        let mut run = Info::empty();
+        let mut required = vec![];
+        while !matches!(self.peek(), None | Some('|') | Some(')')) {
+            let atom = self.parse_atom()?;
+            let atom = self.parse_repetitions(atom)?;
+            if let Some(extended) = run.concat_exact(&atom) {
+                run = extended;
+                continue;
+            }
+            required.push(std::mem::replace(&mut run, Info::empty()).into_query());
+            if atom.exact.is_some() {
+                run = atom;
+            } else {
+                required.push(atom.into_query());
+            }
+        }
+        if required.is_empty() {
+            return Some(run);
+        }
+        required.push(run.into_query());
+        Some(Info {
+            exact: None,
+            query: TrigramQuery::and(required),
+        })
+    }
+
+    fn parse_atom(&mut self) -> Option<Info> {
+        match self.next()? {
+            '(' => self.parse_group(),
+            '[' => self.parse_class(),
+            '\\' => self.parse_escape(),
+            '^' | '$' => Some(Info::empty()),
+            '.' => Some(Info::any()),
+            '*' | '+' | '?' | '{' => None,
+            c => Some(Info::exact_chars(std::iter::once(Some(c)))),
+        }
+    }
+
+    fn parse_repetitions(&mut self, mut atom: Info) -> Option<Info> {
+        loop {
+            let (min, max) = match self.peek() {
+                Some('*') => (0, None),
+                Some('+') => (1, None),
+                Some('?') => (0, Some(1)),
+                Some('{') => {
+                    self.pos += 1;
+                    let start = self.pos;
+                    self.skip_past('}')?;
+                    let spec: String = self.chars[start..self.pos - 1].iter().collect();
+                    let mut bounds = spec.splitn(2, ',');
+                    let min: u32 = bounds.next()?.trim().parse().ok()?;
+                    let max = match bounds.next() {
+                        None => Some(min),
+                        Some(max) if max.trim().is_empty() => None,
+                        Some(max) => Some(max.trim().parse::<u32>().ok()?),
+                    };
+                    // Compensate for the increment below.
+                    self.pos -= 1;
+                    (min, max)
+                }
+                _ => return Some(atom),
+            };
+            self.pos += 1;
+            // Skip the non-greedy marker.
+            self.eat('?');
+
+            atom = match (min, max) {
+                (0, Some(0)) => Info::empty(),
+                (0, Some(1)) => atom.alternate(Info::empty()),
+                (0, _) => Info::any(),
+                _ => atom.repeated(),
+            };
+        }
+    }
+
+    fn parse_group(&mut self) -> Option<Info> {
+        if self.eat('?') {
+            if self.eat('P') || self.peek() == Some('<') {
+                // A named capture group.
+                self.skip_past('>')?;
+            } else {
+                let mut flags = String::new();
+                loop {
+                    match self.next()? {
+                        ':' => break,
+                        ')' => {
+                            // Flags for the rest of the group.  Verbose mode
+                            // changes what the remaining characters mean, so
+                            // just give up on it.
+                            if flags.split('-').next()?.contains('x') {
+                                return None;
+                            }
+                            return Some(Info::empty());
+                        }
+                        c => flags.push(c),
+                    }
+                }
+                if flags.split('-').next()?.contains('x') {
+                    return None;
+                }
+            }
+        }
+        let info = self.parse_alternation()?;
+        if !self.eat(')') {
+            return None;
+        }
+        Some(info)
+    }
+
+    /// Parse a bracketed character class after its `[`.  We only compute the
+    /// exact set for simple classes made up of ASCII characters and ranges.
+    fn parse_class(&mut self) -> Option<Info> {
+        let negated = self.eat('^');
+        let mut simple = !negated;
+        let mut chars = vec![];
+        let mut first = true;
+        loop {
+            let c = self.next()?;
+            let c = match c {
+                ']' if !first => break,
+                '[' => {
+                    // A nested class or an ASCII class like `[:alpha:]`.
+                    simple = false;
+                    self.parse_class()?;
+                    first = false;
+                    continue;
+                }
+                '\\' => match self.next()? {
+                    c if c.is_ascii_punctuation() => c,
+                    _ => {
+                        simple = false;
+                        self.pos -= 1;
+                        self.parse_escape()?;
+                        first = false;
+                        continue;
+                    }
+                },
+                '&' | '~' | '-' if self.peek() == Some(c) => {
+                    // Set operations.
+                    simple = false;
+                    c
+                }
+                c => c,
+            };
+            first = false;
+
+            if self.peek() == Some('-') && self.chars.get(self.pos + 1) != Some(&']') {
+                self.pos += 1;
+                let end = match self.next()? {
+                    '\\' => self.next()?,
+                    '[' => return None,
+                    end => end,
+                };
+                if (end as u32).saturating_sub(c as u32) as usize >= MAX_EXACT_SET {
+                    simple = false;
+                } else {
+                    chars.extend(c..=end);
+                }
+            } else {
+                chars.push(c);
+            }
+        }
+
+        let lowered: BTreeSet<char> = chars.iter().map(|c| c.to_ascii_lowercase()).collect();
+        if !simple || lowered.len() > MAX_EXACT_SET {
+            return Some(Info::any());
+        }
+        Some(Info::exact_chars(lowered.into_iter().map(Some)))
+    }
+
+    /// Parse an escape sequence after its `\`.
+    fn parse_escape(&mut self) -> Option<Info> {
+        let c = self.next()?;
+        match c {
+            // Word boundary assertions, possibly in their `\b{start}` form.
+            'b' | 'B' => {
+                if self.peek() == Some('{') {
+                    self.skip_past('}')?;
+                }
+                Some(Info::empty())
+            }
+            'A' | 'z' | '<' | '>' => Some(Info::empty()),
+            c if c.is_ascii_punctuation() || c == ' ' => {
+                Some(Info::exact_chars(std::iter::once(Some(c))))
+            }
+            'x' | 'u' | 'U' | 'p' | 'P' => {
+                if self.eat('{') {
+                    self.skip_past('}')?;
+                } else if c == 'p' || c == 'P' {
+                    self.next()?;
+                } else {
+                    while self.peek().map(|c| c.is_ascii_hexdigit()) == Some(true) {
+                        self.pos += 1;
+                    }
+                }
+                Some(Info::any())
+            }
+            c if c.is_ascii_digit() => {
+                while self.peek().map(|c| c.is_ascii_digit()) == Some(true) {
+                    self.pos += 1;
+                }
+                Some(Info::any())
+            }
+            _ => Some(Info::any()),
+        }
+    }
+}
+
+/// Derive the trigram query that any line matching the regular expression
+/// `pattern` must satisfy.
+pub fn regex_trigram_query(pattern: &str) -> TrigramQuery {
+    let mut parser = Parser {
+        chars: pattern.chars().collect(),
+        pos: 0,
+    };
+    match parser.parse_alternation() {
+        Some(info) if parser.pos == parser.chars.len() => info.into_query(),
+        _ => TrigramQuery::All,
+    }
+}
+
+fn build_regex(pattern: &str, fold_case: bool) -> Result<Regex> {
+    RegexBuilder::new(pattern)
+        .case_insensitive(fold_case)
+        .build()
+        .map_err(|err| {
+            ServerError::StickyProblem(ErrorDetails {
+                layer: ErrorLayer::BadInput,
+                message: err.to_string(),
+            })
+        })
+}
+
+/// Search the lines of the files in the trigram index for the regular
+/// expression `pattern`, optionally limited to the files whose paths match the
+/// regular expression `pathre`.  At most `max_matches` matching lines are
+/// returned.
+///
+/// The results are in the format `codesearch.py` produces: a list of
+/// `{ path, icon, lines: [{ lno, bounds, line, context_before?,
+/// context_after? }] }` where `bounds` are byte offsets into the line.
+pub fn search_text(
+    index: &TrigramIndex,
+    paths: &TreeConfigPaths,
+    pattern: &str,
+    fold_case: bool,
+    pathre: Option<&str>,
+    context_lines: u32,
+    max_matches: usize,
+) -> Result<Value> {
+    let re = build_regex(pattern, fold_case)?;
+    let pathre = match pathre {
+        Some(pathre) => Some(build_regex(pathre, fold_case)?),
+        None => None,
+    };
+
+    let candidates = match index.evaluate(&regex_trigram_query(pattern)) {
+        Some(ids) => ids,
+        None => (0..index.file_count() as u32).collect(),
+    };
+
+    let context_lines = context_lines as usize;
+    let mut results = vec![];
+    let mut match_count = 0;
+    for file_id in candidates {
+        if match_count == max_matches {
+            break;
+        }
+        let path = match index.file_path(file_id) {
+            Some(path) => path,
+            None => continue,
+        };
+        if let Some(pathre) = &pathre {
+            if !pathre.is_match(path) {
+                continue;
+            }
+        }
+        // The file may have gone away since the index was built, in which
+        // case it's not like we can report anything about it.
+        let contents = match std::fs::read(find_source_file(
+            path,
+            &paths.files_path,
+            &paths.objdir_path,
+        )) {
+            Ok(contents) => contents,
+            Err(_) => continue,
+        };
+        let contents = String::from_utf8_lossy(&contents);
+        let lines: Vec<&str> = contents.lines().collect();
+
+        let mut line_results = vec![];
+        for (i, line) in lines.iter().enumerate() {
+            let m = match re.find(line) {
+                Some(m) => m,
+                None => continue,
+            };
+            let mut line_result = json!({
+                "lno": i + 1,
+                "bounds": [m.start(), m.end()],
+                "line": line,
+            });
+            if context_lines > 0 {
+                let before = &lines[i.saturating_sub(context_lines)..i];
+                if !before.is_empty() {
+                    line_result["context_before"] = json!(before);
+                }
+                let after = &lines[i + 1..(i + 1 + context_lines).min(lines.len())];
+                if !after.is_empty() {
+                    line_result["context_after"] = json!(after);
+                }
+            }
+            line_results.push(line_result);
+
+            match_count += 1;
+            if match_count == max_matches {
+                break;
+            }
+        }
+
+        if !line_results.is_empty() {
+            results.push(json!({
+                "path": path,
+                "icon": "",
+                "lines": line_results,
+            }));
+        }
+    }
+
+    Ok(Value::Array(results))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_format::trigram_index::pack_trigram;
+
+    fn tri(s: &str) -> TrigramQuery {
+        TrigramQuery::Trigram(pack_trigram(s.as_bytes()))
+    }
+
+    #[test]
+    fn test_regex_trigram_query() {
+        assert_eq!(
+            regex_trigram_query("Foo::Bar"),
+            TrigramQuery::And(vec![
+                tri("foo"),
+                tri("oo:"),
+                tri("o::"),
+                tri("::b"),
+                tri(":ba"),
+                tri("bar"),
+            ])
+        );
+        assert_eq!(
+            regex_trigram_query(r"abc.*def"),
+            TrigramQuery::And(vec![tri("abc"), tri("def")])
+        );
+        assert_eq!(
+            regex_trigram_query("(abc|xyz)d?"),
+            TrigramQuery::Or(vec![
+                tri("abc"),
+                TrigramQuery::And(vec![tri("abc"), tri("bcd")]),
+                tri("xyz"),
+                TrigramQuery::And(vec![tri("xyz"), tri("yzd")]),
+            ])
+        );
+        assert_eq!(regex_trigram_query(r"[Nn]s\w+Foo"), tri("foo"));
+        assert_eq!(regex_trigram_query(r"ab+c"), TrigramQuery::All);
+        assert_eq!(regex_trigram_query(r"abc|x"), TrigramQuery::All);
+        assert_eq!(regex_trigram_query(r"(?x) a b c d"), TrigramQuery::All);
+        assert_eq!(
+            regex_trigram_query(r"(?i)\bfoo\(\)"),
+            TrigramQuery::And(vec![tri("foo"), tri("oo("), tri("o()")])
+        );
+    }
+}
@@ -1,7 +1,23 @@
+mod caching_server;
 mod local_index;
+mod local_query;
+mod local_text_search;
+mod recording_server;
 mod remote_server;
+mod retrying_server;
 mod server_interface;
+#[cfg(test)]
+pub(crate) mod stub_server;
 
-pub use local_index::make_local_server;
+pub use caching_server::{make_caching_server, CacheConfig};
+pub use local_index::{make_local_server, make_local_server_for_index};
+pub use local_query::{categorize_path, parse_path_filter, parse_search, ParsedQuery};
+pub use recording_server::{make_recording_server, make_replay_server};
 pub use remote_server::make_remote_server;
-pub use server_interface::{AbstractServer, ErrorDetails, ErrorLayer, Result, ServerError};
+pub use retrying_server::{
+    make_retrying_server, OperationRetries, RetryLog, RetryPolicy, RetrySummary,
+};
+pub use server_interface::{
+    AbstractServer, CrossrefLookupOptions, ErrorDetails, ErrorLayer, FileListKind, Result,
+    ServerError,
+};
@@ -0,0 +1,519 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use futures_core::stream::BoxStream;
+use futures_util::stream;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{from_str, from_value, to_string, to_string_pretty, to_value, Value};
+use tokio_stream::StreamExt;
+
+use super::server_interface::{
+    AbstractServer, CrossrefLookupOptions, ErrorDetails, ErrorLayer, FileListKind, Result,
+    ServerError,
+};
+use crate::file_format::analysis::Jump;
+
+/// The name of the file in a fixture directory that holds the recordings.
+const FIXTURE_FILE_NAME: &str = "fixture.json";
+
+/// A single `AbstractServer` call and its arguments.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "method", rename_all = "snake_case")]
+enum Request {
+    TranslateAnalysisPath {
+        path: String,
+    },
+    FetchRawAnalysis {
+        path: String,
+    },
+    SourceRecordsAt {
+        path: String,
+        lineno: u32,
+        col: u32,
+    },
+    FetchHtml {
+        path: String,
+    },
+    FetchFileList {
+        list: String,
+    },
+    CrossrefLookup {
+        symbol: String,
+        // Skipped when unfiltered so that older recordings still match.
+        #[serde(default, skip_serializing_if = "CrossrefLookupOptions::is_unfiltered")]
+        options: CrossrefLookupOptions,
+    },
+    JumpLookup {
+        symbol: String,
+    },
+    SearchIdentifiers {
+        needle: String,
+        exact_match: bool,
+        ignore_case: bool,
+        match_limit: usize,
+    },
+    SearchText {
+        pattern: String,
+        fold_case: bool,
+        pathre: Option<String>,
+        context_lines: u32,
+        max_matches: usize,
+    },
+    PerformQuery {
+        q: String,
+    },
+}
+
+impl Request {
+    /// The key recordings are looked up by.  Serialization follows the field
+    /// order above, so equal requests always produce the same key.
+    fn key(&self) -> String {
+        // Serializing a struct of strings, bools and integers can't fail.
+        to_string(self).unwrap()
+    }
+}
+
+/// A recorded `ServerError`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "problem", rename_all = "snake_case")]
+enum RecordedError {
+    Sticky { layer: String, message: String },
+    Transient { layer: String, message: String },
+    Unsupported,
+}
+
+fn layer_name(layer: &ErrorLayer) -> String {
+    format!("{:?}", layer)
+}
+
+fn layer_from_name(name: &str) -> ErrorLayer {
+    match name {
+        "BadInput" => ErrorLayer::BadInput,
+        "ServerLayer" => ErrorLayer::ServerLayer,
+        "DataLayer" => ErrorLayer::DataLayer,
+        _ => ErrorLayer::UnknownLayer,
+    }
+}
+
+impl RecordedError {
+    fn from_error(err: &ServerError) -> RecordedError {
+        match err {
+            ServerError::StickyProblem(details) => RecordedError::Sticky {
+                layer: layer_name(&details.layer),
+                message: details.message.clone(),
+            },
+            ServerError::TransientProblem(details) => RecordedError::Transient {
+                layer: layer_name(&details.layer),
+                message: details.message.clone(),
+            },
+            ServerError::Unsupported => RecordedError::Unsupported,
+        }
+    }
+
+    fn to_error(&self) -> ServerError {
+        match self {
+            RecordedError::Sticky { layer, message } => ServerError::StickyProblem(ErrorDetails {
+                layer: layer_from_name(layer),
+                message: message.clone(),
+            }),
+            RecordedError::Transient { layer, message } => {
+                ServerError::TransientProblem(ErrorDetails {
+                    layer: layer_from_name(layer),
+                    message: message.clone(),
+                })
+            }
+            RecordedError::Unsupported => ServerError::Unsupported,
+        }
+    }
+}
+
+/// The outcome of a recorded request.  Streams are recorded in their entirety:
+/// raw analysis as an array of records and HTML as a single string.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum RecordedResult {
+    Ok(Value),
+    Err(RecordedError),
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct FixtureEntry {
+    request: Request,
+    result: RecordedResult,
+}
+
+fn fixture_error(message: String) -> ServerError {
+    ServerError::StickyProblem(ErrorDetails {
+        layer: ErrorLayer::BadInput,
+        message,
+    })
+}
+
+/// Load the entries of the fixture in `fixture_dir`, if it exists.
+fn load_fixture(fixture_dir: &str) -> Result<Option<Vec<FixtureEntry>>> {
+    let path = PathBuf::from(fixture_dir).join(FIXTURE_FILE_NAME);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let contents = std::fs::read_to_string(&path)
+        .map_err(|err| fixture_error(format!("Unable to read {}: {}", path.display(), err)))?;
+    Ok(Some(from_str(&contents)?))
+}
+
+fn stream_values(values: Vec<Value>) -> BoxStream<'static, Result<Value>> {
+    Box::pin(stream::iter(values.into_iter().map(Ok)))
+}
+
+/// `AbstractServer` decorator that records every request made of the wrapped
+/// server and its result into `fixture.json` in a fixture directory so that
+/// `make_replay_server` can serve them back later.
+///
+/// Streams are read in their entirety before being returned so that they can
+/// be recorded, except for `stream_crossref` which is passed through.  The
+/// fixture is rewritten after every request so that nothing is lost if the
+/// process exits without dropping the server; the entries are sorted by
+/// request so that re-recording produces minimal diffs.
+struct RecordingServer {
+    inner: Box<dyn AbstractServer + Send + Sync>,
+    fixture_path: PathBuf,
+    entries: Mutex<BTreeMap<String, FixtureEntry>>,
+}
+
+impl RecordingServer {
+    fn record<T: Serialize>(&self, request: Request, result: &Result<T>) -> Result<()> {
+        let result = match result {
+            Ok(value) => RecordedResult::Ok(to_value(value)?),
+            Err(err) => RecordedResult::Err(RecordedError::from_error(err)),
+        };
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.insert(request.key(), FixtureEntry { request, result });
+        let all: Vec<&FixtureEntry> = entries.values().collect();
+        std::fs::write(&self.fixture_path, to_string_pretty(&all)?).map_err(|err| {
+            fixture_error(format!(
+                "Unable to write {}: {}",
+                self.fixture_path.display(),
+                err
+            ))
+        })
+    }
+}
+
+#[async_trait]
+impl AbstractServer for RecordingServer {
+    fn translate_analysis_path(&self, sf_path: &str) -> Result<String> {
+        let result = self.inner.translate_analysis_path(sf_path);
+        self.record(
+            Request::TranslateAnalysisPath {
+                path: sf_path.to_string(),
+            },
+            &result,
+        )?;
+        result
+    }
+
+    async fn fetch_raw_analysis(&self, sf_path: &str) -> Result<BoxStream<Result<Value>>> {
+        let result = match self.inner.fetch_raw_analysis(sf_path).await {
+            Ok(values) => values.collect::<Result<Vec<Value>>>().await,
+            Err(err) => Err(err),
+        };
+        self.record(
+            Request::FetchRawAnalysis {
+                path: sf_path.to_string(),
+            },
+            &result,
+        )?;
+        Ok(stream_values(result?))
+    }
+
+    async fn source_records_at(&self, sf_path: &str, lineno: u32, col: u32) -> Result<Vec<Value>> {
+        let result = self.inner.source_records_at(sf_path, lineno, col).await;
+        self.record(
+            Request::SourceRecordsAt {
+                path: sf_path.to_string(),
+                lineno,
+                col,
+            },
+            &result,
+        )?;
+        result
+    }
+
+    async fn fetch_html(&self, sf_path: &str) -> Result<BoxStream<Result<Vec<u8>>>> {
+        let result = match self.inner.fetch_html(sf_path).await {
+            Ok(chunks) => chunks
+                .collect::<Result<Vec<Vec<u8>>>>()
+                .await
+                .map(|chunks| String::from_utf8_lossy(&chunks.concat()).to_string()),
+            Err(err) => Err(err),
+        };
+        self.record(
+            Request::FetchHtml {
+                path: sf_path.to_string(),
+            },
+            &result,
+        )?;
+        let html = result?.into_bytes();
+        Ok(Box::pin(stream::iter(vec![Ok(html)])))
+    }
+
+    async fn fetch_file_list(&self, list: FileListKind) -> Result<Vec<String>> {
+        let result = self.inner.fetch_file_list(list).await;
+        self.record(
+            Request::FetchFileList {
+                list: list.file_name().to_string(),
+            },
+            &result,
+        )?;
+        result
+    }
+
+    async fn crossref_lookup(
+        &self,
+        symbol: &str,
+        options: &CrossrefLookupOptions,
+    ) -> Result<Value> {
+        let result = self.inner.crossref_lookup(symbol, options).await;
+        self.record(
+            Request::CrossrefLookup {
+                symbol: symbol.to_string(),
+                options: options.clone(),
+            },
+            &result,
+        )?;
+        result
+    }
+
+    async fn stream_crossref(&self) -> Result<BoxStream<Result<(String, Value)>>> {
+        // Recording the entire crossref database would make for an absurd
+        // fixture, so this isn't recorded.
+        self.inner.stream_crossref().await
+    }
+
+    async fn jump_lookup(&self, symbol: &str) -> Result<Option<Jump>> {
+        let result = self.inner.jump_lookup(symbol).await;
+        self.record(
+            Request::JumpLookup {
+                symbol: symbol.to_string(),
+            },
+            &result,
+        )?;
+        result
+    }
+
+    async fn search_identifiers(
+        &self,
+        needle: &str,
+        exact_match: bool,
+        ignore_case: bool,
+        match_limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        let result = self
+            .inner
+            .search_identifiers(needle, exact_match, ignore_case, match_limit)
+            .await;
+        self.record(
+            Request::SearchIdentifiers {
+                needle: needle.to_string(),
+                exact_match,
+                ignore_case,
+                match_limit,
+            },
+            &result,
+        )?;
+        result
+    }
+
+    async fn search_text(
+        &self,
+        pattern: &str,
+        fold_case: bool,
+        pathre: Option<&str>,
+        context_lines: u32,
+        max_matches: usize,
+    ) -> Result<Value> {
+        let result = self
+            .inner
+            .search_text(pattern, fold_case, pathre, context_lines, max_matches)
+            .await;
+        self.record(
+            Request::SearchText {
+                pattern: pattern.to_string(),
+                fold_case,
+                pathre: pathre.map(|s| s.to_string()),
+                context_lines,
+                max_matches,
+            },
+            &result,
+        )?;
+        result
+    }
+
+    async fn perform_query(&self, q: &str) -> Result<Value> {
+        let result = self.inner.perform_query(q).await;
+        self.record(Request::PerformQuery { q: q.to_string() }, &result)?;
+        result
+    }
+}
+
+/// `AbstractServer` that serves the responses recorded by a recording server,
+/// allowing pipelines to be tested without an index or the network.  Requests
+/// that weren't recorded fail with a `BadInput` problem naming the request.
+struct ReplayServer {
+    results: HashMap<String, RecordedResult>,
+}
+
+impl ReplayServer {
+    fn replay(&self, request: Request) -> Result<Value> {
+        match self.results.get(&request.key()) {
+            Some(RecordedResult::Ok(value)) => Ok(value.clone()),
+            Some(RecordedResult::Err(err)) => Err(err.to_error()),
+            None => Err(fixture_error(format!(
+                "No recorded result for request {}",
+                request.key()
+            ))),
+        }
+    }
+
+    fn replay_as<T: DeserializeOwned>(&self, request: Request) -> Result<T> {
+        Ok(from_value(self.replay(request)?)?)
+    }
+}
+
+#[async_trait]
+impl AbstractServer for ReplayServer {
+    fn translate_analysis_path(&self, sf_path: &str) -> Result<String> {
+        self.replay_as(Request::TranslateAnalysisPath {
+            path: sf_path.to_string(),
+        })
+    }
+
+    async fn fetch_raw_analysis(&self, sf_path: &str) -> Result<BoxStream<Result<Value>>> {
+        let values: Vec<Value> = self.replay_as(Request::FetchRawAnalysis {
+            path: sf_path.to_string(),
+        })?;
+        Ok(stream_values(values))
+    }
+
+    async fn source_records_at(&self, sf_path: &str, lineno: u32, col: u32) -> Result<Vec<Value>> {
+        self.replay_as(Request::SourceRecordsAt {
+            path: sf_path.to_string(),
+            lineno,
+            col,
+        })
+    }
+
+    async fn fetch_html(&self, sf_path: &str) -> Result<BoxStream<Result<Vec<u8>>>> {
+        let html: String = self.replay_as(Request::FetchHtml {
+            path: sf_path.to_string(),
+        })?;
+        Ok(Box::pin(stream::iter(vec![Ok(html.into_bytes())])))
+    }
+
+    async fn fetch_file_list(&self, list: FileListKind) -> Result<Vec<String>> {
+        self.replay_as(Request::FetchFileList {
+            list: list.file_name().to_string(),
+        })
+    }
+
+    async fn crossref_lookup(
+        &self,
+        symbol: &str,
+        options: &CrossrefLookupOptions,
+    ) -> Result<Value> {
+        self.replay(Request::CrossrefLookup {
+            symbol: symbol.to_string(),
+            options: options.clone(),
+        })
+    }
+
+    async fn stream_crossref(&self) -> Result<BoxStream<Result<(String, Value)>>> {
+        Err(ServerError::Unsupported)
+    }
+
+    async fn jump_lookup(&self, symbol: &str) -> Result<Option<Jump>> {
+        self.replay_as(Request::JumpLookup {
+            symbol: symbol.to_string(),
+        })
+    }
+
+    async fn search_identifiers(
+        &self,
+        needle: &str,
+        exact_match: bool,
+        ignore_case: bool,
+        match_limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        self.replay_as(Request::SearchIdentifiers {
+            needle: needle.to_string(),
+            exact_match,
+            ignore_case,
+            match_limit,
+        })
+    }
+
+    async fn search_text(
+        &self,
+        pattern: &str,
+        fold_case: bool,
+        pathre: Option<&str>,
+        context_lines: u32,
+        max_matches: usize,
+    ) -> Result<Value> {
+        self.replay(Request::SearchText {
+            pattern: pattern.to_string(),
+            fold_case,
+            pathre: pathre.map(|s| s.to_string()),
+            context_lines,
+            max_matches,
+        })
+    }
+
+    async fn perform_query(&self, q: &str) -> Result<Value> {
+        self.replay(Request::PerformQuery { q: q.to_string() })
+    }
+}
+
+/// Wrap the given server so that every request and its result are recorded
+/// into the fixture in `fixture_dir`, which is created if needed.  Any existing
+/// recordings in the fixture are preserved unless the same request is made
+/// again.
+pub fn make_recording_server(
+    inner: Box<dyn AbstractServer + Send + Sync>,
+    fixture_dir: &str,
+) -> Result<Box<dyn AbstractServer + Send + Sync>> {
+    std::fs::create_dir_all(fixture_dir)
+        .map_err(|err| fixture_error(format!("Unable to create {}: {}", fixture_dir, err)))?;
+
+    let entries = load_fixture(fixture_dir)?
+        .unwrap_or_default()
+        .into_iter()
+        .map(|entry| (entry.request.key(), entry))
+        .collect();
+
+    Ok(Box::new(RecordingServer {
+        inner,
+        fixture_path: PathBuf::from(fixture_dir).join(FIXTURE_FILE_NAME),
+        entries: Mutex::new(entries),
+    }))
+}
+
+/// Create a server that replays the fixture recorded in `fixture_dir`.
+pub fn make_replay_server(fixture_dir: &str) -> Result<Box<dyn AbstractServer + Send + Sync>> {
+    let entries = load_fixture(fixture_dir)?.ok_or_else(|| {
+        fixture_error(format!(
+            "There is no {} in {}",
+            FIXTURE_FILE_NAME, fixture_dir
+        ))
+    })?;
+
+    Ok(Box::new(ReplayServer {
+        results: entries
+            .into_iter()
+            .map(|entry| (entry.request.key(), entry.result))
+            .collect(),
+    }))
+}
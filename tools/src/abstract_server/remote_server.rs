@@ -1,9 +1,18 @@
+use async_stream::try_stream;
 use async_trait::async_trait;
 use futures_core::stream::BoxStream;
-use serde_json::{from_str, Value};
+use serde_json::{from_slice, from_str, Value};
+use tokio_stream::StreamExt;
 use url::{ParseError, Url};
 
-use super::server_interface::{AbstractServer, ErrorDetails, ErrorLayer, Result, ServerError};
+use super::local_index::collect_source_records_at;
+use super::server_interface::{
+    AbstractServer, CrossrefLookupOptions, ErrorDetails, ErrorLayer, FileListKind, Result,
+    ServerError,
+};
+
+use crate::file_format::analysis::Jump;
+use crate::file_format::identifiers::IdentResult;
 
 /// reqwest won't return an error for an unhappy status code itself; someone
 /// would need to call `Response::error_from_status`, so for now we'll generally
@@ -33,7 +42,11 @@ struct RemoteServer {
     tree_base_url: Url,
     source_base_url: Url,
     raw_analysis_base_url: Url,
+    file_lists_base_url: Url,
     search_url: Url,
+    crossref_lookup_url: Url,
+    search_identifiers_url: Url,
+    source_records_at_url: Url,
 }
 
 async fn get(url: Url) -> Result<reqwest::Response> {
@@ -89,39 +102,127 @@ impl AbstractServer for RemoteServer {
         Err(ServerError::Unsupported)
     }
 
-    async fn fetch_raw_analysis(&self, sf_path: &str) -> Result<BoxStream<Value>> {
+    async fn fetch_raw_analysis(&self, sf_path: &str) -> Result<BoxStream<Result<Value>>> {
         let url = self.raw_analysis_base_url.join(sf_path)?;
-        let raw_str = get(url).await?.text().await?;
-        let values: Result<Vec<Value>> = raw_str
-            .lines()
-            .map(|s| from_str(s).map_err(|e| ServerError::from(e)))
-            .collect();
-        Ok(Box::pin(tokio_stream::iter(values?)))
+        let mut chunks = get(url).await?.bytes_stream();
+        Ok(Box::pin(try_stream! {
+            // Bytes we've received that aren't yet a complete line.
+            let mut pending = vec![];
+            while let Some(chunk) = chunks.next().await {
+                pending.extend_from_slice(&chunk?);
+                let mut start = 0;
+                while let Some(offset) = pending[start..].iter().position(|b| *b == b'\n') {
+                    let value: Value = from_slice(&pending[start..start + offset])?;
+                    start += offset + 1;
+                    yield value;
+                }
+                pending.drain(..start);
+            }
+            // The final line may not have been newline-terminated.
+            if !pending.iter().all(u8::is_ascii_whitespace) {
+                let value: Value = from_slice(&pending)?;
+                yield value;
+            }
+        }))
+    }
+
+    async fn source_records_at(&self, sf_path: &str, lineno: u32, col: u32) -> Result<Vec<Value>> {
+        let mut url = self.source_records_at_url.clone();
+        url.query_pairs_mut()
+            .append_pair("path", sf_path)
+            .append_pair("line", &lineno.to_string())
+            .append_pair("col", &col.to_string());
+        match get_json(url).await {
+            Ok(res) => Ok(from_str(&res.text().await?)?),
+            // Servers deployed before the endpoint existed will 404, so filter
+            // the whole analysis file ourselves.  If the file really doesn't
+            // exist, that will fail the same way.
+            Err(ServerError::StickyProblem(_)) => {
+                let values = self.fetch_raw_analysis(sf_path).await?;
+                collect_source_records_at(values, lineno, col).await
+            }
+            Err(err) => Err(err),
+        }
     }
 
-    async fn fetch_html(&self, sf_path: &str) -> Result<String> {
+    async fn fetch_html(&self, sf_path: &str) -> Result<BoxStream<Result<Vec<u8>>>> {
         let url = self.source_base_url.join(sf_path)?;
-        let html = get(url).await?.text().await?;
-        Ok(html)
+        let chunks = get(url).await?.bytes_stream();
+        Ok(Box::pin(chunks.map(|chunk| Ok(chunk?.to_vec()))))
     }
 
-    async fn crossref_lookup(&self, _symbol: &str) -> Result<Value> {
-        // Let's require local index for now; we'll expose this once this
-        // mechanism is exposed to the web so we can talk to the corresponding
-        // local server over https.
-        //
-        // That is, we could build this on top of the existing router.py, but
-        // the legacy rep is definitely not what we want and although the
-        // "sorch" endpoint that's an artifact of the fancy-branch prototype
-        // is closer, it's probably better if that doesn't get stabilized.
+    async fn fetch_file_list(&self, list: FileListKind) -> Result<Vec<String>> {
+        let url = self.file_lists_base_url.join(list.file_name())?;
+        let raw_str = get(url).await?.text().await?;
+        Ok(raw_str.lines().map(|s| s.to_string()).collect())
+    }
+
+    async fn crossref_lookup(
+        &self,
+        symbol: &str,
+        options: &CrossrefLookupOptions,
+    ) -> Result<Value> {
+        let mut url = self.crossref_lookup_url.clone();
+        {
+            let mut query = url.query_pairs_mut();
+            query.append_pair("q", symbol);
+            if let Some(path_prefix) = &options.path_prefix {
+                query.append_pair("path_prefix", path_prefix);
+            }
+            if options.offset > 0 {
+                query.append_pair("offset", &options.offset.to_string());
+            }
+            if let Some(limit) = options.limit {
+                query.append_pair("limit", &limit.to_string());
+            }
+        }
+        let raw_str = get_json(url).await?.text().await?;
+        Ok(from_str(&raw_str)?)
+    }
+
+    async fn stream_crossref(&self) -> Result<BoxStream<Result<(String, Value)>>> {
+        // There's no way to enumerate the crossref database remotely.
         Err(ServerError::Unsupported)
     }
 
-    async fn search_identifiers(&self, _needle: &str, _exact_match: bool, _ignore_case: bool, _match_limit: usize) -> Result<Vec<(String, String)>> {
-        // Same rationale as crossref_lookup.
+    async fn jump_lookup(&self, _symbol: &str) -> Result<Option<Jump>> {
+        // The jumps file isn't exposed by the web server.
         Err(ServerError::Unsupported)
     }
 
+    async fn search_identifiers(
+        &self,
+        needle: &str,
+        exact_match: bool,
+        ignore_case: bool,
+        match_limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        let mut url = self.search_identifiers_url.clone();
+        url.query_pairs_mut()
+            .append_pair("q", needle)
+            .append_pair("exact", if exact_match { "true" } else { "false" })
+            .append_pair("case", if ignore_case { "false" } else { "true" })
+            .append_pair("limit", &match_limit.to_string());
+        let raw_str = get_json(url).await?.text().await?;
+        let ident_results: Vec<IdentResult> = from_str(&raw_str)?;
+        Ok(ident_results
+            .into_iter()
+            .map(|ir| (ir.symbol, ir.id))
+            .collect())
+    }
+
+    async fn search_text(
+        &self,
+        _pattern: &str,
+        _fold_case: bool,
+        _pathre: Option<&str>,
+        _context_lines: u32,
+        _max_matches: usize,
+    ) -> Result<Value> {
+        // The web server only exposes text search as part of the categorized
+        // `perform_query` results.
+        Err(ServerError::Unsupported)
+    }
 
     async fn perform_query(&self, q: &str) -> Result<Value> {
         let mut url = self.search_url.clone();
@@ -145,13 +246,21 @@ pub fn make_remote_server(
     let tree_base_url = server_base_url.join(&format!("{}/", tree_name))?;
     let source_base_url = tree_base_url.join("source/")?;
     let raw_analysis_base_url = tree_base_url.join("raw-analysis/")?;
+    let file_lists_base_url = tree_base_url.join("file-lists/")?;
     let search_url = tree_base_url.join("search")?;
+    let crossref_lookup_url = tree_base_url.join("crossref-lookup")?;
+    let search_identifiers_url = tree_base_url.join("search-identifiers")?;
+    let source_records_at_url = tree_base_url.join("source-records-at")?;
 
     Ok(Box::new(RemoteServer {
         server_base_url,
         tree_base_url,
         source_base_url,
         raw_analysis_base_url,
+        file_lists_base_url,
         search_url,
+        crossref_lookup_url,
+        search_identifiers_url,
+        source_records_at_url,
     }))
 }
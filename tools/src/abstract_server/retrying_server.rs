@@ -0,0 +1,419 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures_core::stream::BoxStream;
+use rand::Rng;
+use serde_json::Value;
+use tokio::time::sleep;
+
+use super::server_interface::{
+    AbstractServer, CrossrefLookupOptions, FileListKind, Result, ServerError,
+};
+use crate::file_format::analysis::Jump;
+
+/// How `ServerError::TransientProblem` failures should be retried.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// The total number of attempts to make, including the initial one, so a
+    /// value of 1 disables retries.
+    pub max_attempts: u32,
+    /// The delay before the first retry, which doubles for each subsequent
+    /// retry.
+    pub initial_backoff: Duration,
+    /// The upper bound on the delay between attempts.
+    pub max_backoff: Duration,
+    /// The fraction of each delay, from 0 to 1, that is randomly shaved off so
+    /// that concurrent requests that failed together don't retry together.
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 4,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(10),
+            jitter: 0.5,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The delay before the given retry, where 1 is the first retry.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let factor = 2u32.saturating_pow(retry.saturating_sub(1));
+        let delay = self
+            .initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff);
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        if jitter == 0.0 {
+            return delay;
+        }
+        delay.mul_f64(1.0 - jitter * rand::thread_rng().gen::<f64>())
+    }
+}
+
+/// The retries performed for a single `AbstractServer` method.
+#[derive(Clone, Debug, Default)]
+pub struct OperationRetries {
+    /// The number of retries performed.
+    pub retries: u32,
+    /// The number of calls that succeeded after being retried.
+    pub recovered: u32,
+    /// The number of calls that were retried but still failed.
+    pub failed: u32,
+    /// The total time spent waiting between attempts.
+    pub total_backoff: Duration,
+}
+
+/// The retries performed while running a pipeline, keyed by server method.
+#[derive(Clone, Debug, Default)]
+pub struct RetrySummary {
+    pub by_operation: BTreeMap<&'static str, OperationRetries>,
+}
+
+impl RetrySummary {
+    pub fn is_empty(&self) -> bool {
+        self.by_operation.is_empty()
+    }
+
+    pub fn total_retries(&self) -> u32 {
+        self.by_operation.values().map(|op| op.retries).sum()
+    }
+}
+
+impl fmt::Display for RetrySummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Retried transient failures {} time(s):",
+            self.total_retries()
+        )?;
+        for (operation, op) in &self.by_operation {
+            writeln!(
+                f,
+                "  {}: {} retries, {} recovered, {} failed, {:.1}s waiting",
+                operation,
+                op.retries,
+                op.recovered,
+                op.failed,
+                op.total_backoff.as_secs_f64()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Shared handle to the retries a `RetryingServer` has performed, allowing the
+/// pipeline to report on and reset them after each run.
+#[derive(Clone, Debug, Default)]
+pub struct RetryLog {
+    summary: Arc<Mutex<RetrySummary>>,
+}
+
+impl RetryLog {
+    /// Return the retries performed since the last call and reset the log.
+    pub fn take(&self) -> RetrySummary {
+        std::mem::take(&mut *self.summary.lock().unwrap())
+    }
+
+    fn record_retry(&self, operation: &'static str, delay: Duration) {
+        let mut summary = self.summary.lock().unwrap();
+        let op = summary.by_operation.entry(operation).or_default();
+        op.retries += 1;
+        op.total_backoff += delay;
+    }
+
+    fn record_outcome(&self, operation: &'static str, succeeded: bool) {
+        let mut summary = self.summary.lock().unwrap();
+        let op = summary.by_operation.entry(operation).or_default();
+        if succeeded {
+            op.recovered += 1;
+        } else {
+            op.failed += 1;
+        }
+    }
+}
+
+/// `AbstractServer` decorator that retries calls that fail with a
+/// `ServerError::TransientProblem` according to its `RetryPolicy`.
+///
+/// For the streaming methods only opening the stream is retried; errors that
+/// happen partway through a stream are passed through because the consumer has
+/// already seen some of the data.
+struct RetryingServer {
+    inner: Box<dyn AbstractServer + Send + Sync>,
+    policy: RetryPolicy,
+    log: RetryLog,
+}
+
+impl RetryingServer {
+    async fn retry<T, F, Fut>(&self, operation: &'static str, mut attempt: F) -> Result<T>
+    where
+        T: Send,
+        F: FnMut() -> Fut + Send,
+        Fut: Future<Output = Result<T>> + Send,
+    {
+        let mut retries = 0;
+        loop {
+            match attempt().await {
+                Err(ServerError::TransientProblem(_)) if retries + 1 < self.policy.max_attempts => {
+                    retries += 1;
+                    let delay = self.policy.backoff(retries);
+                    self.log.record_retry(operation, delay);
+                    sleep(delay).await;
+                }
+                result => {
+                    if retries > 0 {
+                        self.log.record_outcome(operation, result.is_ok());
+                    }
+                    return result;
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl AbstractServer for RetryingServer {
+    fn translate_analysis_path(&self, sf_path: &str) -> Result<String> {
+        self.inner.translate_analysis_path(sf_path)
+    }
+
+    async fn fetch_raw_analysis(&self, sf_path: &str) -> Result<BoxStream<Result<Value>>> {
+        self.retry("fetch_raw_analysis", || {
+            self.inner.fetch_raw_analysis(sf_path)
+        })
+        .await
+    }
+
+    async fn source_records_at(&self, sf_path: &str, lineno: u32, col: u32) -> Result<Vec<Value>> {
+        self.retry("source_records_at", || {
+            self.inner.source_records_at(sf_path, lineno, col)
+        })
+        .await
+    }
+
+    async fn fetch_html(&self, sf_path: &str) -> Result<BoxStream<Result<Vec<u8>>>> {
+        self.retry("fetch_html", || self.inner.fetch_html(sf_path))
+            .await
+    }
+
+    async fn fetch_file_list(&self, list: FileListKind) -> Result<Vec<String>> {
+        self.retry("fetch_file_list", || self.inner.fetch_file_list(list))
+            .await
+    }
+
+    async fn crossref_lookup(
+        &self,
+        symbol: &str,
+        options: &CrossrefLookupOptions,
+    ) -> Result<Value> {
+        self.retry("crossref_lookup", || {
+            self.inner.crossref_lookup(symbol, options)
+        })
+        .await
+    }
+
+    async fn stream_crossref(&self) -> Result<BoxStream<Result<(String, Value)>>> {
+        self.retry("stream_crossref", || self.inner.stream_crossref())
+            .await
+    }
+
+    async fn jump_lookup(&self, symbol: &str) -> Result<Option<Jump>> {
+        self.retry("jump_lookup", || self.inner.jump_lookup(symbol))
+            .await
+    }
+
+    async fn search_identifiers(
+        &self,
+        needle: &str,
+        exact_match: bool,
+        ignore_case: bool,
+        match_limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        self.retry("search_identifiers", || {
+            self.inner
+                .search_identifiers(needle, exact_match, ignore_case, match_limit)
+        })
+        .await
+    }
+
+    async fn search_text(
+        &self,
+        pattern: &str,
+        fold_case: bool,
+        pathre: Option<&str>,
+        context_lines: u32,
+        max_matches: usize,
+    ) -> Result<Value> {
+        self.retry("search_text", || {
+            self.inner
+                .search_text(pattern, fold_case, pathre, context_lines, max_matches)
+        })
+        .await
+    }
+
+    async fn perform_query(&self, q: &str) -> Result<Value> {
+        self.retry("perform_query", || self.inner.perform_query(q))
+            .await
+    }
+}
+
+/// Wrap the given server so that transient failures are retried according to
+/// `policy`, returning the log of the retries performed alongside it.
+pub fn make_retrying_server(
+    inner: Box<dyn AbstractServer + Send + Sync>,
+    policy: RetryPolicy,
+) -> (Box<dyn AbstractServer + Send + Sync>, RetryLog) {
+    let log = RetryLog::default();
+    let server = RetryingServer {
+        inner,
+        policy,
+        log: log.clone(),
+    };
+    (Box::new(server), log)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::abstract_server::{ErrorDetails, ErrorLayer};
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// Fails `crossref_lookup` with a transient problem the given number of
+    /// times before succeeding.
+    struct FlakyServer {
+        failures_left: AtomicU32,
+    }
+
+    #[async_trait]
+    impl AbstractServer for FlakyServer {
+        fn translate_analysis_path(&self, _sf_path: &str) -> Result<String> {
+            Err(ServerError::Unsupported)
+        }
+
+        async fn fetch_raw_analysis(&self, _sf_path: &str) -> Result<BoxStream<Result<Value>>> {
+            Err(ServerError::Unsupported)
+        }
+
+        async fn source_records_at(
+            &self,
+            _sf_path: &str,
+            _lineno: u32,
+            _col: u32,
+        ) -> Result<Vec<Value>> {
+            Err(ServerError::Unsupported)
+        }
+
+        async fn fetch_html(&self, _sf_path: &str) -> Result<BoxStream<Result<Vec<u8>>>> {
+            Err(ServerError::Unsupported)
+        }
+
+        async fn fetch_file_list(&self, _list: FileListKind) -> Result<Vec<String>> {
+            Err(ServerError::Unsupported)
+        }
+
+        async fn crossref_lookup(
+            &self,
+            _symbol: &str,
+            _options: &CrossrefLookupOptions,
+        ) -> Result<Value> {
+            if self.failures_left.load(Ordering::SeqCst) == 0 {
+                return Ok(Value::Null);
+            }
+            self.failures_left.fetch_sub(1, Ordering::SeqCst);
+            Err(ServerError::TransientProblem(ErrorDetails {
+                layer: ErrorLayer::ServerLayer,
+                message: "Server status of 504 Gateway Timeout".to_string(),
+            }))
+        }
+
+        async fn stream_crossref(&self) -> Result<BoxStream<Result<(String, Value)>>> {
+            Err(ServerError::Unsupported)
+        }
+
+        async fn jump_lookup(&self, _symbol: &str) -> Result<Option<Jump>> {
+            Err(ServerError::Unsupported)
+        }
+
+        async fn search_identifiers(
+            &self,
+            _needle: &str,
+            _exact_match: bool,
+            _ignore_case: bool,
+            _match_limit: usize,
+        ) -> Result<Vec<(String, String)>> {
+            Err(ServerError::Unsupported)
+        }
+
+        async fn search_text(
+            &self,
+            _pattern: &str,
+            _fold_case: bool,
+            _pathre: Option<&str>,
+            _context_lines: u32,
+            _max_matches: usize,
+        ) -> Result<Value> {
+            Err(ServerError::Unsupported)
+        }
+
+        async fn perform_query(&self, _q: &str) -> Result<Value> {
+            Err(ServerError::Unsupported)
+        }
+    }
+
+    fn flaky_server(failures: u32) -> (Box<dyn AbstractServer + Send + Sync>, RetryLog) {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(0),
+            max_backoff: Duration::from_millis(0),
+            jitter: 0.0,
+        };
+        make_retrying_server(
+            Box::new(FlakyServer {
+                failures_left: AtomicU32::new(failures),
+            }),
+            policy,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_retries() {
+        let (server, log) = flaky_server(2);
+        assert!(server
+            .crossref_lookup("foo", &CrossrefLookupOptions::default())
+            .await
+            .is_ok());
+        let summary = log.take();
+        let op = &summary.by_operation["crossref_lookup"];
+        assert_eq!((op.retries, op.recovered, op.failed), (2, 1, 0));
+        assert!(log.take().is_empty());
+
+        let (server, log) = flaky_server(3);
+        assert!(matches!(
+            server
+                .crossref_lookup("foo", &CrossrefLookupOptions::default())
+                .await,
+            Err(ServerError::TransientProblem(_))
+        ));
+        let op = &log.take().by_operation["crossref_lookup"];
+        assert_eq!((op.retries, op.recovered, op.failed), (2, 0, 1));
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            jitter: 0.0,
+            ..RetryPolicy::default()
+        };
+        assert_eq!(policy.backoff(1), Duration::from_millis(500));
+        assert_eq!(policy.backoff(3), Duration::from_secs(2));
+        assert_eq!(policy.backoff(10), Duration::from_secs(10));
+    }
+}
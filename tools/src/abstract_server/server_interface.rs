@@ -1,7 +1,10 @@
 use async_trait::async_trait;
 use futures_core::stream::BoxStream;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
+use crate::file_format::analysis::Jump;
+
 pub type Result<T> = std::result::Result<T, ServerError>;
 
 // JSON parse errors are sticky data problems.
@@ -64,9 +67,10 @@ pub struct ErrorDetails {
 /// Actually performing retries could of course happen either below this
 /// abstraction layer or above it.  The argument for above is that the
 /// `cmd_pipeline` could make more informed scheduling decisions with
-/// appropriately long back-offs than this lower layer would be able to.  But
-/// that's all speculative at this point and this type is really being
-/// introduced because we need a unifying error type.
+/// appropriately long back-offs than this lower layer would be able to.  For
+/// now `make_retrying_server` wraps a server so that `TransientProblem`s are
+/// retried with exponential back-off, and the pipeline reports on the retries
+/// via the resulting `RetryLog`.
 #[derive(Debug)]
 pub enum ServerError {
     /// An error that will persist for at least this index.  For example a 404.
@@ -77,6 +81,50 @@ pub enum ServerError {
     Unsupported,
 }
 
+/// The file lists produced by indexing, which are served under `file-lists/`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FileListKind {
+    /// Source files under version control.
+    Repo,
+    /// Generated files from the objdir, prefixed with `__GENERATED__/`.
+    Objdir,
+}
+
+impl FileListKind {
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            FileListKind::Repo => "repo-files",
+            FileListKind::Objdir => "objdir-files",
+        }
+    }
+}
+
+/// Options for narrowing down the `uses` returned by `crossref_lookup`, which
+/// for popular symbols can number in the hundreds of thousands.  Uses are
+/// grouped by path in sorted path order, and the options select whole path
+/// groups.  The other kinds of hits are always returned in full.
+///
+/// When any option is set, the result gains a `uses_total` key holding the
+/// number of path groups matching `path_prefix` so that callers can tell
+/// whether there are more pages.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct CrossrefLookupOptions {
+    /// Only return the uses in paths starting with this prefix.
+    pub path_prefix: Option<String>,
+    /// The number of (prefix-matching) path groups to skip.
+    pub offset: usize,
+    /// The maximum number of path groups to return.
+    pub limit: Option<usize>,
+}
+
+impl CrossrefLookupOptions {
+    /// True if the options select all of the uses, in which case the crossref
+    /// JSON is returned unmodified.
+    pub fn is_unfiltered(&self) -> bool {
+        *self == CrossrefLookupOptions::default()
+    }
+}
+
 /// Unified exposure for interacting with a local Searchfox index on disk or
 /// a remote searchfox server over HTTPS talking to the web-server.
 ///
@@ -101,15 +149,14 @@ pub enum ServerError {
 /// Currently existing analysis-file processing and other logic:
 /// - Uses synchronous I/O
 ///
-/// In the end, it likely would make sense for the analysis mechanism to:
-/// - Support async I/O
-/// - Use async streams via https://docs.rs/tokio-stream/0.1.5/tokio_stream/
-///   on a per-record or per-line granularity, quite possibly using our analysis
-///   types for analysis records instead of untyped JSON.
+/// Raw analysis records and HTML are exposed as async streams (per-record and
+/// per-chunk, respectively) that decompress as they go, so that huge files can
+/// be processed with bounded memory.  Conversion to our typed analysis records
+/// happens in the pipeline rather than here.
 ///
 /// But I'm introducing this interface right now in an attempt to provide
 /// increased test coverage before making more extensive refactorings.  So for
-/// now, this interface will do the simplest thing possible.
+/// now, the rest of this interface will do the simplest thing possible.
 ///
 #[async_trait]
 pub trait AbstractServer {
@@ -117,13 +164,40 @@ pub trait AbstractServer {
     /// disk.  This fundamentally only works for local indices.
     fn translate_analysis_path(&self, sf_path: &str) -> Result<String>;
 
-    async fn fetch_raw_analysis(&self, sf_path: &str) -> Result<BoxStream<Value>>;
+    /// Stream the raw analysis records for the given file a record at a time.
+    /// Errors opening the file are returned directly, but errors encountered
+    /// while reading or parsing the records show up in the stream.
+    async fn fetch_raw_analysis(&self, sf_path: &str) -> Result<BoxStream<Result<Value>>>;
+
+    /// Return the raw source records in the given file's analysis that cover
+    /// the 0-based column `col` of the 1-based line `lineno`, as determined by
+    /// `Location::covers`.
+    async fn source_records_at(&self, sf_path: &str, lineno: u32, col: u32) -> Result<Vec<Value>>;
 
-    async fn fetch_html(&self, sf_path: &str) -> Result<String>;
+    /// Stream the rendered HTML for the given file in arbitrarily sized
+    /// chunks which are not guaranteed to fall on UTF-8 character boundaries.
+    async fn fetch_html(&self, sf_path: &str) -> Result<BoxStream<Result<Vec<u8>>>>;
+
+    /// Retrieve the tree-relative paths in the given file list.
+    async fn fetch_file_list(&self, list: FileListKind) -> Result<Vec<String>>;
 
     /// Retrieve the JSON contents of the crossref database for the given
-    /// symbol.
-    async fn crossref_lookup(&self, symbol: &str) -> Result<Value>;
+    /// symbol, with its uses narrowed down by `options`.
+    async fn crossref_lookup(
+        &self,
+        symbol: &str,
+        options: &CrossrefLookupOptions,
+    ) -> Result<Value>;
+
+    /// Stream every symbol in the crossref database along with its crossref
+    /// JSON in sorted symbol order.  This fundamentally only works for local
+    /// indices.
+    async fn stream_crossref(&self) -> Result<BoxStream<Result<(String, Value)>>>;
+
+    /// Look up the symbol's entry in the jumps file, which only has entries
+    /// for symbols with a single definition.  Returns None if the symbol has
+    /// no entry.
+    async fn jump_lookup(&self, symbol: &str) -> Result<Option<Jump>>;
 
     /// Given an identifier (prefix), return pairs of matching identifiers and
     /// symbols that correspond to those identifiers.
@@ -136,5 +210,19 @@ pub trait AbstractServer {
     /// indications of hierarchy traversal.
     async fn search_identifiers(&self, needle: &str, exact_match: bool, ignore_case: bool, match_limit: usize) -> Result<Vec<(String, String)>>;
 
+    /// Search the lines of the tree's files for the regular expression
+    /// `pattern`, optionally limited to the files whose paths match the
+    /// regular expression `pathre`.  At most `max_matches` lines are returned,
+    /// in the format of the "Textual Occurrences" of `perform_query`, each with
+    /// up to `context_lines` lines of context on either side.
+    async fn search_text(
+        &self,
+        pattern: &str,
+        fold_case: bool,
+        pathre: Option<&str>,
+        context_lines: u32,
+        max_matches: usize,
+    ) -> Result<Value>;
+
     async fn perform_query(&self, q: &str) -> Result<Value>;
 }
@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use futures_core::stream::BoxStream;
+use futures_util::stream;
+use serde_json::Value;
+
+use super::server_interface::{
+    AbstractServer, CrossrefLookupOptions, ErrorDetails, ErrorLayer, FileListKind, Result,
+    ServerError,
+};
+use crate::file_format::analysis::Jump;
+
+/// In-memory `AbstractServer` for unit tests.  Paths and symbols that weren't
+/// provided are sticky data problems like they'd be for a `LocalIndex`, and
+/// anything there's no field for is unsupported.
+#[derive(Default)]
+pub struct StubServer {
+    pub repo_files: Vec<String>,
+    pub objdir_files: Vec<String>,
+    /// Raw analysis records by path.
+    pub analysis: HashMap<String, Vec<Value>>,
+    /// Rendered HTML by path.
+    pub html: HashMap<String, String>,
+    /// Crossref JSON by symbol.  Lookup options are ignored.
+    pub crossref: HashMap<String, Value>,
+    /// `perform_query` results by query.
+    pub queries: HashMap<String, Value>,
+    /// The number of times `perform_query` has been called, shared so that it
+    /// can still be checked once the server has been wrapped.
+    pub query_count: Arc<AtomicUsize>,
+}
+
+fn missing(what: &str) -> ServerError {
+    ServerError::StickyProblem(ErrorDetails {
+        layer: ErrorLayer::DataLayer,
+        message: format!("No such stub data: {}", what),
+    })
+}
+
+#[async_trait]
+impl AbstractServer for StubServer {
+    fn translate_analysis_path(&self, _sf_path: &str) -> Result<String> {
+        Err(ServerError::Unsupported)
+    }
+
+    async fn fetch_raw_analysis(&self, sf_path: &str) -> Result<BoxStream<Result<Value>>> {
+        let values = self.analysis.get(sf_path).ok_or_else(|| missing(sf_path))?;
+        Ok(Box::pin(stream::iter(values.clone().into_iter().map(Ok))))
+    }
+
+    async fn source_records_at(
+        &self,
+        _sf_path: &str,
+        _lineno: u32,
+        _col: u32,
+    ) -> Result<Vec<Value>> {
+        Err(ServerError::Unsupported)
+    }
+
+    async fn fetch_html(&self, sf_path: &str) -> Result<BoxStream<Result<Vec<u8>>>> {
+        let html = self.html.get(sf_path).ok_or_else(|| missing(sf_path))?;
+        Ok(Box::pin(stream::iter(vec![Ok(html.clone().into_bytes())])))
+    }
+
+    async fn fetch_file_list(&self, list: FileListKind) -> Result<Vec<String>> {
+        Ok(match list {
+            FileListKind::Repo => self.repo_files.clone(),
+            FileListKind::Objdir => self.objdir_files.clone(),
+        })
+    }
+
+    async fn crossref_lookup(
+        &self,
+        symbol: &str,
+        _options: &CrossrefLookupOptions,
+    ) -> Result<Value> {
+        Ok(self.crossref.get(symbol).cloned().unwrap_or(Value::Null))
+    }
+
+    async fn stream_crossref(&self) -> Result<BoxStream<Result<(String, Value)>>> {
+        // Like the real thing, the entries are sorted by symbol.
+        let mut entries: Vec<(String, Value)> = self
+            .crossref
+            .iter()
+            .map(|(sym, value)| (sym.clone(), value.clone()))
+            .collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(Box::pin(stream::iter(entries.into_iter().map(Ok))))
+    }
+
+    async fn jump_lookup(&self, _symbol: &str) -> Result<Option<Jump>> {
+        Err(ServerError::Unsupported)
+    }
+
+    async fn search_identifiers(
+        &self,
+        _needle: &str,
+        _exact_match: bool,
+        _ignore_case: bool,
+        _match_limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        Err(ServerError::Unsupported)
+    }
+
+    async fn search_text(
+        &self,
+        _pattern: &str,
+        _fold_case: bool,
+        _pathre: Option<&str>,
+        _context_lines: u32,
+        _max_matches: usize,
+    ) -> Result<Value> {
+        Err(ServerError::Unsupported)
+    }
+
+    async fn perform_query(&self, q: &str) -> Result<Value> {
+        self.query_count.fetch_add(1, Ordering::SeqCst);
+        self.queries.get(q).cloned().ok_or_else(|| missing(q))
+    }
+}
@@ -0,0 +1,59 @@
+use std::env;
+use std::fs;
+
+extern crate env_logger;
+#[macro_use]
+extern crate log;
+extern crate tools;
+use tools::config;
+use tools::file_format::trigram_index::TrigramIndexBuilder;
+use tools::find_source_file;
+
+/// Build the trigram index used for full-text search of local indexes from
+/// the files listed in the tree's `repo-files` and `objdir-files`.
+///
+/// Usage: build-trigram-index <config-file> <tree-name>
+fn main() {
+    env_logger::init();
+    let args: Vec<_> = env::args().collect();
+
+    let cfg = config::load(&args[1], false);
+
+    let tree_name = &args[2];
+    let tree_config = cfg.trees.get(tree_name).unwrap();
+    let paths = &tree_config.paths;
+
+    let mut builder = TrigramIndexBuilder::new();
+    let mut skipped = 0;
+    for list_name in &["repo-files", "objdir-files"] {
+        let list_path = format!("{}/{}", paths.index_path, list_name);
+        let list = match fs::read_to_string(&list_path) {
+            Ok(list) => list,
+            Err(err) => {
+                warn!("Unable to read {}: {}", list_path, err);
+                continue;
+            }
+        };
+        for path in list.lines() {
+            let source_path = find_source_file(path, &paths.files_path, &paths.objdir_path);
+            let contents = match fs::read(&source_path) {
+                Ok(contents) => contents,
+                Err(err) => {
+                    warn!("Unable to read {}: {}", source_path, err);
+                    skipped += 1;
+                    continue;
+                }
+            };
+            if !builder.add_file(path, &contents) {
+                skipped += 1;
+            }
+        }
+    }
+
+    info!(
+        "Indexed {} files, skipped {} unreadable, binary or oversized files",
+        builder.file_count(),
+        skipped
+    );
+    builder.write(&paths.index_path).unwrap();
+}
@@ -1,34 +1,71 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::hash_map::DefaultHasher;
 use std::collections::BTreeMap;
 use std::collections::BTreeSet;
+use std::collections::BinaryHeap;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::env;
+use std::fs;
 use std::fs::File;
+use std::hash::{Hash, Hasher};
 use std::io::BufRead;
 use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Lines;
 use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender};
+use std::thread;
+use std::thread::JoinHandle;
 
 extern crate env_logger;
+extern crate flate2;
 
-use serde::Serialize;
-use serde_json::{json, Map};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use serde_json::{from_str, from_value, json, Value};
 extern crate tools;
 use tools::config;
+use tools::config::TreeConfigPaths;
 use tools::file_format::analysis::LineRange;
-use tools::file_format::analysis::{read_analysis, read_structured, read_target, AnalysisKind};
+use tools::file_format::analysis::{
+    read_analysis, read_structured, read_target, AnalysisKind, AnalysisStructured,
+};
+use tools::file_format::crossref_binary::CrossrefBinaryWriter;
+use tools::file_format::crossref_lookup::{CrossrefLookupMap, GZIP_COMPRESSED_FLAG};
 use tools::find_source_file;
 use ustr::{ustr, Ustr};
 
 /// The size for a payload line (inclusive of leading indicating character and
 /// newline) at which we store it externally in `crossref-extra` instead of
-/// inline in the `crossref` file itself.
+/// inline in the `crossref` file itself.  Externally stored payloads are
+/// gzip-compressed.
 const EXTERNAL_STORAGE_THRESHOLD: usize = 1024 * 3;
 
-#[derive(Clone, Debug, Serialize)]
-struct SearchResult {
+/// In sharded mode, the number of bytes of serialized hits a shard thread
+/// buffers before sorting them and spilling them to disk as a run.  This can be
+/// overridden with the `CROSSREF_SHARD_SPILL_THRESHOLD` environment variable,
+/// which is mostly useful for exercising the spilling in tests.
+const SHARD_SPILL_THRESHOLD: usize = 64 * 1024 * 1024;
+
+/// In sharded mode, the number of hit records that can be queued up for a
+/// shard thread before the readers block.
+const SHARD_QUEUE_SIZE: usize = 4096;
+
+/// In sharded mode, the number of files each reader thread is asked to read
+/// ahead of the file currently being merged.
+const READ_AHEAD_PER_THREAD: usize = 10;
+
+/// The line is generic so that sharded mode can avoid interning the lines,
+/// as interned strings are retained for the life of the process.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct SearchResult<L = Ustr> {
     #[serde(rename = "lno")]
     lineno: u32,
     bounds: (u32, u32),
-    line: Ustr,
+    line: L,
     context: Ustr,
     contextsym: Ustr,
     // We use to build up "peekLines" which we excerpted from the file here, but
@@ -68,333 +105,1110 @@ fn split_scopes(id: &str) -> Vec<String> {
     return result;
 }
 
-/// Process all analysis files, deriving the `crossref`, `jumps`, and `identifiers` output files.
-/// See https://github.com/mozsearch/mozsearch/blob/master/docs/crossref.md for high-level
-/// documentation on how this works (locally, `docs/crossref.md`).
-///
-/// ## Implementation
-/// There are 2 phases of processing:
-/// 1. The analysis files are read, populating `table`, `pretty_table`, `id_table`, and
-///    `meta_table` incrementally.  Primary cross-reference information comes from target records,
-///    but the file is also processed for source records in order to populate `meta_table` with
-///    meta-information about the symbol.
-/// 2. The table is consumed with jumps generated as a byproduct.
-///
-/// ### Memory Management
-/// Memory usage grows continually throughout phase 1.  Because we load many identical strings,
-/// we use string interning so that all long-lived strings are reference-counted interned strings.
-fn main() {
-    env_logger::init();
-    let args: Vec<_> = env::args().collect();
+/// The name of the key under which hits of the given kind are stored in the
+/// crossref JSON.
+fn kind_key(kind: AnalysisKind) -> &'static str {
+    match kind {
+        AnalysisKind::Use => "uses",
+        AnalysisKind::Def => "defs",
+        AnalysisKind::Assign => "assignments",
+        AnalysisKind::Decl => "decls",
+        AnalysisKind::Forward => "forwards",
+        AnalysisKind::Idl => "idl",
+        AnalysisKind::IPC => "ipc",
+    }
+}
 
-    let cfg = config::load(&args[1], false);
+fn kind_from_key(key: &str) -> Option<AnalysisKind> {
+    match key {
+        "uses" => Some(AnalysisKind::Use),
+        "defs" => Some(AnalysisKind::Def),
+        "assignments" => Some(AnalysisKind::Assign),
+        "decls" => Some(AnalysisKind::Decl),
+        "forwards" => Some(AnalysisKind::Forward),
+        "idl" => Some(AnalysisKind::Idl),
+        "ipc" => Some(AnalysisKind::IPC),
+        _ => None,
+    }
+}
 
-    let tree_name = &args[2];
-    let tree_config = cfg.trees.get(tree_name).unwrap();
+/// Everything a single analysis file contributes to the cross-referencing
+/// tables.  Contributions are merged into the tables in file order, which
+/// matters because the first file to provide a symbol's meta wins and the last
+/// file to provide its pretty name wins.
+///
+/// The contributions other than the hits are saved in `crossref-state` so that
+/// an incremental rebuild can reuse the contributions of unchanged files.  The
+/// hits don't need to be saved because they can be recovered from `crossref`.
+#[derive(Deserialize, Serialize)]
+struct FileContribution<L = Ustr> {
+    path: String,
+    /// Hits keyed by [symbol, kind], in the order they occur in the file.
+    #[serde(skip)]
+    hits: BTreeMap<(Ustr, AnalysisKind), Vec<SearchResult<L>>>,
+    /// Distinct (symbol, pretty symbol) pairs ordered by their last occurrence
+    /// in the file.
+    pretties: Vec<(Ustr, Ustr)>,
+    /// Distinct (context symbol, callee symbol) pairs.
+    callees: Vec<(Ustr, Ustr)>,
+    /// The first structured record for each symbol, in file order.
+    metas: Vec<AnalysisStructured>,
+}
 
-    let filenames_file = &args[3];
+/// Read the contribution of a single analysis file, returning None if the
+/// source file is unavailable.
+fn read_contribution<L>(paths: &TreeConfigPaths, path: &str) -> Option<FileContribution<L>>
+where
+    L: for<'a> From<&'a str>,
+{
+    print!("File {}\n", path);
 
-    let file_paths: Vec<String> = BufReader::new(File::open(filenames_file).unwrap())
+    let analysis_fname = format!("{}/analysis/{}", paths.index_path, path);
+    let analysis = read_analysis(&analysis_fname, &mut read_target);
+
+    // Load the source file and chop it up into `lines` so that we extract
+    // the `line` for each result.  In the future this could move to
+    // dynamic extraction that uses the `peek_range` if available and this
+    // line if it's not.
+    let source_fname = find_source_file(path, &paths.files_path, &paths.objdir_path);
+    let source_file = match File::open(source_fname) {
+        Ok(f) => f,
+        Err(_) => {
+            println!("Unable to open source file");
+            return None;
+        }
+    };
+    let reader = BufReader::new(&source_file);
+    // We operate in String space here on a per-file basis, but these will
+    // (outside of sharded mode) be flattened to ustrs when converted into a
+    // SearchResult.  The intent here is that because Ustr instances
+    // permanently retain all provided strings that we don't tell it about
+    // Strings until we're sure they'll be retained be a SearchResult.
+    let lines: Vec<_> = reader
         .lines()
-        .map(|x| x.unwrap())
+        .map(|l| match l {
+            Ok(line) => {
+                let line_cut = line.trim_end();
+                let len = line_cut.len();
+                let line_cut = line_cut.trim_start();
+                let offset = (len - line_cut.len()) as u32;
+                let buf: String = line_cut.chars().take(100).collect();
+                (buf, offset)
+            }
+            Err(_) => (String::from(""), 0),
+        })
         .collect();
-    let xref_file = format!("{}/crossref", tree_config.paths.index_path);
-    let xref_ext_file = format!("{}/crossref-extra", tree_config.paths.index_path);
-    let jump_file = format!("{}/jumps", tree_config.paths.index_path);
-    let id_file = format!("{}/identifiers", tree_config.paths.index_path);
 
-    // Nested table hierarchy keyed by: [symbol, kind, path] with Vec<SearchResult> as the leaf
-    // values.
-    let mut table = BTreeMap::new();
-    // Maps (raw) symbol to interned-pretty symbol string.  Each raw symbol is unique, but there
-    // may be many raw symbols that map to the same pretty symbol string.
-    let mut pretty_table = HashMap::new();
-    // Reverse of pretty_table.  The key is the pretty symbol, and the value is a BTreeSet of all
-    // of the raw symbols that map to the pretty symbol.  Pretty symbols that start with numbers or
-    // include whitespace are considered illegal and not included in the map.
-    let mut id_table = BTreeMap::new();
-    // Maps (raw) symbol to `SymbolMeta` info for this symbol.  This information is currently
-    // extracted from the source records during an additional pass of the analysis file, looking
-    // only at defs.  However, in the future, this will likely come from a new type of record.
-    let mut meta_table = BTreeMap::new();
-    // Maps (raw) symbol to a BTreeSet of the (raw) symbols it "calls".  (The
-    // term makes most sense when dealing with functions/similar.  This was
-    // formerly dubbed "consumes" in prototyping, but that was even more
-    // confusing.  This may want to get renamed again.)
-    let mut callees_table = BTreeMap::new();
-    // Not populated until phase 2 when we walk the above data-structures.
-    let mut jumps = Vec::new();
+    let mut contribution = FileContribution {
+        path: path.to_string(),
+        hits: BTreeMap::new(),
+        pretties: vec![],
+        callees: vec![],
+        metas: vec![],
+    };
+    let mut pretties = vec![];
+    let mut callees = BTreeSet::new();
+    for datum in analysis {
+        // pieces are all `AnalysisTarget` instances.
+        for piece in datum.data {
+            let results = contribution
+                .hits
+                .entry((piece.sym, piece.kind))
+                .or_insert(Vec::new());
+            let lineno = (datum.loc.lineno - 1) as usize;
+            if lineno >= lines.len() {
+                print!("Bad line number in file {} (line {})\n", path, lineno);
+                continue;
+            }
 
-    // As we process the source entries and build the SourceMeta, we keep a running list of what
-    // cross-SourceMeta links need to be established.  We then process this after all of the files
-    // have been processed and we know all symbols are known.
+            let (line, offset) = lines[lineno].clone();
 
-    // Pairs of [parent class sym, subclass sym] to add subclass to parent.
-    let mut xref_link_subclass = Vec::new();
-    // Pairs of [parent method sym, overridden by sym] to add the override to the parent.
-    let mut xref_link_override = Vec::new();
+            pretties.push((piece.sym, piece.pretty));
 
-    // Triples of [ipc sym, src src, target sym].
-    let mut xref_link_ipc = Vec::new();
+            // If this is a use and there's a contextsym, we want to create a "callees"
+            // entry under the contextsym.  We also want to invert the use of "context"
+            // to be the symbol in question; it's not useful to name the context symbol
+            // redundantly when it's the symbol we're attaching data to.
+            if piece.kind == AnalysisKind::Use && !piece.contextsym.is_empty() {
+                callees.insert((piece.contextsym, piece.sym));
+            }
 
-    for path in &file_paths {
-        print!("File {}\n", path);
+            results.push(SearchResult {
+                lineno: datum.loc.lineno,
+                bounds: (datum.loc.col_start - offset, datum.loc.col_end - offset),
+                line: L::from(line.as_str()),
+                context: piece.context,
+                contextsym: piece.contextsym,
+                peek_range: piece.peek_range,
+            });
+        }
+    }
 
-        let analysis_fname = format!("{}/analysis/{}", tree_config.paths.index_path, path);
-        let analysis = read_analysis(&analysis_fname, &mut read_target);
+    // Only the last pretty for each symbol can matter for `pretty_table`, and
+    // `id_table` doesn't care about order, so only keep the last occurrence of
+    // each pair.
+    let mut seen = HashSet::new();
+    for pair in pretties.into_iter().rev() {
+        if seen.insert(pair) {
+            contribution.pretties.push(pair);
+        }
+    }
+    contribution.pretties.reverse();
+    contribution.callees = callees.into_iter().collect();
 
-        // Load the source file and chop it up into `lines` so that we extract
-        // the `line` for each result.  In the future this could move to
-        // dynamic extraction that uses the `peek_range` if available and this
-        // line if it's not.
-        let source_fname = find_source_file(
-            path,
-            &tree_config.paths.files_path,
-            &tree_config.paths.objdir_path,
-        );
-        let source_file = match File::open(source_fname) {
-            Ok(f) => f,
-            Err(_) => {
-                println!("Unable to open source file");
-                continue;
+    let structured_analysis = read_analysis(&analysis_fname, &mut read_structured);
+    let mut meta_syms = HashSet::new();
+    for datum in structured_analysis {
+        // pieces are all `AnalysisStructured` instances that were generated alongside source
+        // definition records.
+        for piece in datum.data {
+            if meta_syms.insert(piece.sym) {
+                contribution.metas.push(piece);
             }
+        }
+    }
+
+    Some(contribution)
+}
+
+/// Read the numeric environment variable `name`, returning `default` if it's not set and exiting
+/// if it's not a number.
+fn env_usize(name: &str, default: usize) -> usize {
+    match env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(|_| {
+            eprintln!("{} must be a non-negative integer, not {:?}", name, value);
+            std::process::exit(1);
+        }),
+        Err(_) => default,
+    }
+}
+
+/// The outputs of a previous run that an incremental rebuild reuses.
+struct PreviousOutputs {
+    /// The serialized `FileContribution` from `crossref-state` for each path.
+    state: HashMap<String, String>,
+    /// The previous `crossref`, or None if it was empty (and so can't be mapped).
+    crossref: Option<CrossrefLookupMap>,
+}
+
+/// Open the outputs of the previous run for an incremental rebuild, returning a description of
+/// the problem if any of them are missing or unreadable, in which case a full rebuild is needed.
+fn open_previous_outputs(
+    state_file: &str,
+    xref_file: &str,
+    xref_ext_file: &str,
+) -> Result<PreviousOutputs, String> {
+    let state_contents =
+        fs::read_to_string(state_file).map_err(|err| format!("{}: {}", state_file, err))?;
+    let mut state = HashMap::new();
+    for line in state_contents.lines() {
+        let contribution: FileContribution =
+            from_str(line).map_err(|err| format!("{}: {}", state_file, err))?;
+        state.insert(contribution.path, line.to_string());
+    }
+
+    let xref_len = fs::metadata(xref_file)
+        .map_err(|err| format!("{}: {}", xref_file, err))?
+        .len();
+    let crossref = if xref_len == 0 {
+        None
+    } else {
+        // The previous crossref may be in either format.
+        match CrossrefLookupMap::new(xref_file, xref_ext_file) {
+            Some(map) => Some(map),
+            None => return Err(format!("{}: unable to open", xref_file)),
+        }
+    };
+
+    // Check the whole crossref decodes before any of its hits get used, since after that it's
+    // too late to fall back to a full rebuild.
+    if let Some(map) = &crossref {
+        for entry in map.entries() {
+            if let Err(err) = entry {
+                return Err(format!("{}: {:?}", xref_file, err));
+            }
+        }
+    }
+
+    Ok(PreviousOutputs { state, crossref })
+}
+
+/// Read the hits from a previously written `crossref` (and `crossref-extra`),
+/// passing the [symbol, kind, path] and JSON array of results for each path in
+/// `keep_paths` to `add_hits`.
+fn read_previous_hits<F>(
+    map: &CrossrefLookupMap,
+    keep_paths: &HashSet<&str>,
+    mut add_hits: F,
+) -> Result<(), String>
+where
+    F: FnMut(Ustr, AnalysisKind, String, Value),
+{
+    for entry in map.entries() {
+        let (id, kindmap) = entry.map_err(|err| format!("{:?}", err))?;
+        let id = ustr(&id);
+        let kindmap = match kindmap {
+            Value::Object(kindmap) => kindmap,
+            _ => continue,
         };
-        let reader = BufReader::new(&source_file);
-        // We operate in String space here on a per-file basis, but these will be
-        // flattened to ustrs when converted into a SearchResult.  The intent here
-        // is that because Ustr instances permanently retain all provided strings
-        // that we don't tell it about Strings until we're sure they'll be retained
-        // be a SearchResult.
-        let lines: Vec<_> = reader
-            .lines()
-            .map(|l| match l {
-                Ok(line) => {
-                    let line_cut = line.trim_end();
-                    let len = line_cut.len();
-                    let line_cut = line_cut.trim_start();
-                    let offset = (len - line_cut.len()) as u32;
-                    let buf: String = line_cut.chars().take(100).collect();
-                    (buf, offset)
-                }
-                Err(_) => (String::from(""), 0),
-            })
-            .collect();
+        for (key, path_hits) in kindmap {
+            let kind = match kind_from_key(&key) {
+                Some(kind) => kind,
+                // "callees" and "meta" get rederived.
+                None => continue,
+            };
+            for mut path_hit in value_array(path_hits) {
+                let path = match path_hit["path"].as_str() {
+                    Some(path) if keep_paths.contains(path) => path.to_string(),
+                    _ => continue,
+                };
+                add_hits(id, kind, path, path_hit["lines"].take());
+            }
+        }
+    }
+    Ok(())
+}
 
-        for datum in analysis {
-            // pieces are all `AnalysisTarget` instances.
-            for piece in datum.data {
-                let t1 = table.entry(piece.sym).or_insert(BTreeMap::new());
-                let t2 = t1.entry(piece.kind).or_insert(BTreeMap::new());
-                let p: &str = &path;
-                let t3 = t2.entry(p).or_insert(Vec::new());
-                let lineno = (datum.loc.lineno - 1) as usize;
-                if lineno >= lines.len() {
-                    print!("Bad line number in file {} (line {})\n", path, lineno);
-                    continue;
-                }
+fn value_array(value: Value) -> Vec<Value> {
+    match value {
+        Value::Array(values) => values,
+        _ => vec![],
+    }
+}
 
-                let (line, offset) = lines[lineno].clone();
+/// Nested table hierarchy keyed by: [symbol, kind, path] with Vec<SearchResult> as the leaf
+/// values.
+type HitTable = BTreeMap<Ustr, BTreeMap<AnalysisKind, BTreeMap<String, Vec<SearchResult>>>>;
 
-                // Idempotently insert the symbol -> pretty symbol mapping into `pretty_table`.
-                pretty_table.insert(piece.sym, piece.pretty);
+/// The hits for a single symbol keyed by: [kind, path] with the JSON serialization of the
+/// Vec<SearchResult> as the leaf values.
+type SymbolHits = BTreeMap<AnalysisKind, BTreeMap<String, String>>;
 
-                // If this is a use and there's a contextsym, we want to create a "callees"
-                // entry under the contextsym.  We also want to invert the use of "context"
-                // to be the symbol in question; it's not useful to name the context symbol
-                // redundantly when it's the symbol we're attaching data to.
-                if piece.kind == AnalysisKind::Use && !piece.contextsym.is_empty() {
-                    let callees = callees_table
-                        .entry(piece.contextsym)
-                        .or_insert(BTreeSet::new());
-                    callees.insert(piece.sym);
-                }
+/// All of the cross-referencing tables other than the hits.  These are always kept in memory,
+/// but they're much smaller than the hits.
+#[derive(Default)]
+struct SymbolTables {
+    /// Maps (raw) symbol to interned-pretty symbol string.  Each raw symbol is unique, but there
+    /// may be many raw symbols that map to the same pretty symbol string.
+    pretty_table: HashMap<Ustr, Ustr>,
+    /// Reverse of pretty_table.  The key is the pretty symbol, and the value is a BTreeSet of all
+    /// of the raw symbols that map to the pretty symbol.  Pretty symbols that start with numbers
+    /// or include whitespace are considered illegal and not included in the map.
+    id_table: BTreeMap<Ustr, BTreeSet<Ustr>>,
+    /// Maps (raw) symbol to `SymbolMeta` info for this symbol.  This information is currently
+    /// extracted from the source records during an additional pass of the analysis file, looking
+    /// only at defs.  However, in the future, this will likely come from a new type of record.
+    meta_table: BTreeMap<Ustr, AnalysisStructured>,
+    /// Maps (raw) symbol to a BTreeSet of the (raw) symbols it "calls".  (The
+    /// term makes most sense when dealing with functions/similar.  This was
+    /// formerly dubbed "consumes" in prototyping, but that was even more
+    /// confusing.  This may want to get renamed again.)
+    callees_table: BTreeMap<Ustr, BTreeSet<Ustr>>,
 
-                t3.push(SearchResult {
-                    lineno: datum.loc.lineno,
-                    bounds: (datum.loc.col_start - offset, datum.loc.col_end - offset),
-                    line: ustr(&line),
-                    context: piece.context,
-                    contextsym: piece.contextsym,
-                    peek_range: piece.peek_range,
-                });
-
-                // Idempotently insert the pretty symbol -> symbol mapping as long as the pretty
-                // symbol looks sane.  (Whitespace breaks the `identifiers` file's text format, so
-                // we can't include them.)
-                let ch = piece.sym.chars().nth(0).unwrap();
-                if !(ch >= '0' && ch <= '9') && !piece.sym.contains(' ') {
-                    let t1 = id_table.entry(piece.pretty).or_insert(BTreeSet::new());
-                    t1.insert(piece.sym);
-                }
+    // As we process the source entries and build the SourceMeta, we keep a running list of what
+    // cross-SourceMeta links need to be established.  We then process this after all of the
+    // files have been processed and we know all symbols are known.
+    /// Pairs of [parent class sym, subclass sym] to add subclass to parent.
+    xref_link_subclass: Vec<(Ustr, Ustr)>,
+    /// Pairs of [parent method sym, overridden by sym] to add the override to the parent.
+    xref_link_override: Vec<(Ustr, Ustr)>,
+    /// Triples of [ipc sym, src src, target sym].
+    xref_link_ipc: Vec<(Ustr, Ustr, Ustr)>,
+}
+
+impl SymbolTables {
+    /// Merge everything but the hits of a file's contribution into the tables.
+    fn merge<L>(&mut self, contribution: FileContribution<L>) {
+        for (sym, pretty) in contribution.pretties {
+            // Idempotently insert the symbol -> pretty symbol mapping into `pretty_table`.
+            self.pretty_table.insert(sym, pretty);
+
+            // Idempotently insert the pretty symbol -> symbol mapping as long as the pretty
+            // symbol looks sane.  (Whitespace breaks the `identifiers` file's text format, so
+            // we can't include them.)
+            let ch = sym.chars().nth(0).unwrap();
+            if !(ch >= '0' && ch <= '9') && !sym.contains(' ') {
+                let t1 = self.id_table.entry(pretty).or_insert(BTreeSet::new());
+                t1.insert(sym);
             }
         }
 
-        let structured_analysis = read_analysis(&analysis_fname, &mut read_structured);
-        for datum in structured_analysis {
-            // pieces are all `AnalysisStructured` instances that were generated alongside source
-            // definition records.
-            for piece in datum.data {
-                meta_table.entry(piece.sym).or_insert_with(|| {
-                    // XXX these now either need to come from the dynamic
-                    // "extra" or the "supers"/"overrides" should be explicitly
-                    // mapped.
-                    if !piece.supers.is_empty() {
-                        for super_info in &piece.supers {
-                            xref_link_subclass.push((super_info.sym, piece.sym));
-                        }
-                    }
+        for (contextsym, sym) in contribution.callees {
+            let callees = self
+                .callees_table
+                .entry(contextsym)
+                .or_insert(BTreeSet::new());
+            callees.insert(sym);
+        }
 
-                    if !piece.overrides.is_empty() {
-                        for override_info in &piece.overrides {
-                            xref_link_override.push((override_info.sym, piece.sym));
-                        }
+        let xref_link_subclass = &mut self.xref_link_subclass;
+        let xref_link_override = &mut self.xref_link_override;
+        let xref_link_ipc = &mut self.xref_link_ipc;
+        for piece in contribution.metas {
+            self.meta_table.entry(piece.sym).or_insert_with(|| {
+                // XXX these now either need to come from the dynamic
+                // "extra" or the "supers"/"overrides" should be explicitly
+                // mapped.
+                if !piece.supers.is_empty() {
+                    for super_info in &piece.supers {
+                        xref_link_subclass.push((super_info.sym, piece.sym));
                     }
+                }
 
-                    if let ("ipc", Some(src_sym), Some(target_sym)) =
-                        (piece.kind.as_str(), piece.src_sym, piece.target_sym)
-                    {
-                        xref_link_ipc.push((piece.sym, src_sym, target_sym));
+                if !piece.overrides.is_empty() {
+                    for override_info in &piece.overrides {
+                        xref_link_override.push((override_info.sym, piece.sym));
                     }
+                }
 
-                    piece
-                });
-            }
-        }
-    }
+                if let ("ipc", Some(src_sym), Some(target_sym)) =
+                    (piece.kind.as_str(), piece.src_sym, piece.target_sym)
+                {
+                    xref_link_ipc.push((piece.sym, src_sym, target_sym));
+                }
 
-    // ## Process deferred meta cross-referencing
-    for (super_sym, sub_sym) in xref_link_subclass {
-        if let Some(super_meta) = meta_table.get_mut(&super_sym) {
-            super_meta.subclass_syms.push(sub_sym);
+                piece
+            });
         }
     }
 
-    for (method_sym, override_sym) in xref_link_override {
-        if let Some(method_meta) = meta_table.get_mut(&method_sym) {
-            method_meta.overridden_by_syms.push(override_sym);
+    /// Process the deferred meta cross-referencing once all files have been merged.
+    fn link_metas(&mut self) {
+        for (super_sym, sub_sym) in self.xref_link_subclass.drain(..) {
+            if let Some(super_meta) = self.meta_table.get_mut(&super_sym) {
+                super_meta.subclass_syms.push(sub_sym);
+            }
         }
-    }
 
-    for (ipc_sym, src_sym, target_sym) in xref_link_ipc {
-        if let Some(src_meta) = meta_table.get_mut(&src_sym) {
-            src_meta.idl_sym = Some(ipc_sym);
-            src_meta.target_sym = Some(target_sym);
+        for (method_sym, override_sym) in self.xref_link_override.drain(..) {
+            if let Some(method_meta) = self.meta_table.get_mut(&method_sym) {
+                method_meta.overridden_by_syms.push(override_sym);
+            }
         }
 
-        if let Some(target_meta) = meta_table.get_mut(&target_sym) {
-            target_meta.idl_sym = Some(ipc_sym);
-            target_meta.src_sym = Some(src_sym);
+        for (ipc_sym, src_sym, target_sym) in self.xref_link_ipc.drain(..) {
+            if let Some(src_meta) = self.meta_table.get_mut(&src_sym) {
+                src_meta.idl_sym = Some(ipc_sym);
+                src_meta.target_sym = Some(target_sym);
+            }
+
+            if let Some(target_meta) = self.meta_table.get_mut(&target_sym) {
+                target_meta.idl_sym = Some(ipc_sym);
+                target_meta.src_sym = Some(src_sym);
+            }
         }
     }
 
-    // ## Write out the crossref database.
-    let mut xref_out = File::create(xref_file).unwrap();
-    let mut xref_ext_out = File::create(xref_ext_file).unwrap();
-    // We need to know offset positions in the `-extra` file.  File::tell is a
-    // nightly-only experimental API as documented at
-    // https://github.com/rust-lang/rust/issues/71213 which makes it preferable
-    // to avoid (although I think we may already be dependent on use of nightly
-    // for save-analysis purposes?).  Seek::seek with a relative offset of 0
-    // seems to be the standard fallback but there are suggestions that can
-    // trigger flushes in buffered writers, etc.  So for now we're just keeping
-    // track of offsets ourselves and relying on our tests to make sure we don't
-    // mess up.
-    let mut xref_ext_offset: usize = 0;
-
-    for (id, id_data) in table {
-        let mut kindmap = Map::new();
-        for (kind, kind_data) in &id_data {
-            let mut result = Vec::new();
-            for (path, results) in kind_data {
-                result.push(json!({
-                    "path": path,
-                    "lines": results,
-                }));
+    /// Build the JSON payload stored in the crossref for a symbol from its hits plus its callees
+    /// and meta.  The uses are left out if `include_uses` is false because the binary format
+    /// stores them separately.
+    fn symbol_payload(&self, id: Ustr, hits: &SymbolHits, include_uses: bool) -> String {
+        // The hits are already serialized, so we assemble the object by hand.  This produces the
+        // same output as serializing a `Map` with the same keys.
+        let mut payload = String::from("{");
+        for (kind, kind_data) in hits {
+            if !include_uses && *kind == AnalysisKind::Use {
+                continue;
             }
-            let kindstr = match *kind {
-                AnalysisKind::Use => "uses",
-                AnalysisKind::Def => "defs",
-                AnalysisKind::Assign => "assignments",
-                AnalysisKind::Decl => "decls",
-                AnalysisKind::Forward => "forwards",
-                AnalysisKind::Idl => "idl",
-                AnalysisKind::IPC => "ipc",
-            };
-            kindmap.insert(kindstr.to_string(), json!(result));
+            if payload.len() > 1 {
+                payload.push(',');
+            }
+            payload.push_str(&format!("{}:[", json!(kind_key(*kind))));
+            for (i, (path, results)) in kind_data.iter().enumerate() {
+                if i > 0 {
+                    payload.push(',');
+                }
+                payload.push_str(&format!(
+                    "{{\"path\":{},\"lines\":{}}}",
+                    json!(path),
+                    results
+                ));
+            }
+            payload.push(']');
         }
-        if let Some(callee_syms) = callees_table.get(&id) {
+        if let Some(callee_syms) = self.callees_table.get(&id) {
             let mut callees = Vec::new();
             for callee_sym in callee_syms {
-                if let Some(meta) = meta_table.get(callee_sym) {
+                if let Some(meta) = self.meta_table.get(callee_sym) {
                     let mut obj = BTreeMap::new();
                     obj.insert("sym".to_string(), callee_sym);
-                    if let Some(pretty) = pretty_table.get(callee_sym) {
+                    if let Some(pretty) = self.pretty_table.get(callee_sym) {
                         obj.insert("pretty".to_string(), pretty);
                     }
                     obj.insert("kind".to_string(), &meta.kind);
                     callees.push(json!(obj));
                 }
             }
-            kindmap.insert("callees".to_string(), json!(callees));
+            payload.push_str(&format!(",\"callees\":{}", json!(callees)));
         }
         // Put the metadata in there too.
-        if let Some(meta) = meta_table.get(&id) {
-            kindmap.insert("meta".to_string(), json!(meta));
+        if let Some(meta) = self.meta_table.get(&id) {
+            payload.push_str(&format!(",\"meta\":{}", json!(meta)));
         }
+        payload.push('}');
+        payload
+    }
 
-        let kindmap = json!(kindmap);
+    /// Return the `jumps` entry for a symbol, which it has if it has exactly one definition.
+    fn symbol_jump(&self, id: Ustr, hits: &SymbolHits) -> Option<Value> {
+        let defs = hits.get(&AnalysisKind::Def)?;
+        if defs.len() != 1 {
+            return None;
+        }
+        let (path, results) = defs.iter().next().unwrap();
+        let results: Vec<Value> = from_str(results).unwrap();
+        if results.len() != 1 {
+            return None;
+        }
+        let pretty = self.pretty_table.get(&id).unwrap();
+        Some(json!([id, path, results[0]["lno"], pretty]))
+    }
+}
+
+/// Writes the `crossref` and `crossref-extra` files in the text format.
+struct TextCrossrefWriter {
+    xref_out: File,
+    xref_ext_out: File,
+    /// We need to know offset positions in the `-extra` file.  File::tell is a
+    /// nightly-only experimental API as documented at
+    /// https://github.com/rust-lang/rust/issues/71213 which makes it preferable
+    /// to avoid (although I think we may already be dependent on use of nightly
+    /// for save-analysis purposes?).  Seek::seek with a relative offset of 0
+    /// seems to be the standard fallback but there are suggestions that can
+    /// trigger flushes in buffered writers, etc.  So for now we're just keeping
+    /// track of offsets ourselves and relying on our tests to make sure we don't
+    /// mess up.
+    xref_ext_offset: usize,
+}
+
+impl TextCrossrefWriter {
+    fn new(xref_file: &str, xref_ext_file: &str) -> Self {
+        TextCrossrefWriter {
+            xref_out: File::create(xref_file).unwrap(),
+            xref_ext_out: File::create(xref_ext_file).unwrap(),
+            xref_ext_offset: 0,
+        }
+    }
+
+    fn write_symbol(&mut self, id: Ustr, payload: &str) {
         let id_line = format!("!{}\n", id);
-        let inline_line = format!(":{}\n", kindmap.to_string());
+        let inline_line = format!(":{}\n", payload);
         if inline_line.len() >= EXTERNAL_STORAGE_THRESHOLD {
             // ### External storage.
-            xref_out.write_all(id_line.as_bytes()).unwrap();
+            self.xref_out.write_all(id_line.as_bytes()).unwrap();
             // We write out the identifier in the extra file as well so that it
             // can be interpreted in the same fashion.
-            xref_ext_out.write_all(id_line.as_bytes()).unwrap();
-            xref_ext_offset += id_line.len();
+            self.xref_ext_out.write_all(id_line.as_bytes()).unwrap();
+            self.xref_ext_offset += id_line.len();
+
+            // Externally stored payloads are big enough that they're always
+            // worth compressing, but we check anyway.
+            let mut encoder = GzEncoder::new(vec![b':'], Compression::default());
+            encoder.write_all(payload.as_bytes()).unwrap();
+            let mut ext_line = encoder.finish().unwrap();
+            ext_line.push(b'\n');
+            let flag = if ext_line.len() < inline_line.len() {
+                format!(" {}", GZIP_COMPRESSED_FLAG)
+            } else {
+                ext_line = inline_line.into_bytes();
+                String::new()
+            };
 
             let ext_offset_line = format!(
-                "@{:x} {:x}\n",
+                "@{:x} {:x}{}\n",
                 // Skip the leading ":"
-                xref_ext_offset + 1,
+                self.xref_ext_offset + 1,
                 // Subtract off the leading ":" but keep the newline.
-                inline_line.len() - 1
+                ext_line.len() - 1,
+                flag
             );
-            xref_out.write_all(ext_offset_line.as_bytes()).unwrap();
+            self.xref_out.write_all(ext_offset_line.as_bytes()).unwrap();
 
-            xref_ext_out.write_all(inline_line.as_bytes()).unwrap();
-            xref_ext_offset += inline_line.len();
+            self.xref_ext_out.write_all(&ext_line).unwrap();
+            self.xref_ext_offset += ext_line.len();
         } else {
             // ### Inline storage.
-            xref_out.write_all(id_line.as_bytes()).unwrap();
-            xref_out.write_all(inline_line.as_bytes()).unwrap();
-        }
-
-        if id_data.contains_key(&AnalysisKind::Def) {
-            let defs = id_data.get(&AnalysisKind::Def).unwrap();
-            if defs.len() == 1 {
-                for (path, results) in defs {
-                    if results.len() == 1 {
-                        let mut v = Vec::new();
-                        v.push(json!(id));
-                        v.push(json!(path));
-                        v.push(json!(results[0].lineno));
-                        let pretty = pretty_table.get(&id).unwrap();
-                        v.push(json!(pretty));
-                        jumps.push(json!(v));
+            self.xref_out.write_all(id_line.as_bytes()).unwrap();
+            self.xref_out.write_all(inline_line.as_bytes()).unwrap();
+        }
+    }
+}
+
+/// Writes the crossref in either the text format or the binary format from
+/// `crossref_binary.rs`.  Symbols must be written in sorted order so that the crossref can be
+/// bisected.
+enum CrossrefWriter {
+    Text(TextCrossrefWriter),
+    Binary(Box<CrossrefBinaryWriter>),
+}
+
+impl CrossrefWriter {
+    fn write_symbol(&mut self, tables: &SymbolTables, id: Ustr, hits: &SymbolHits) {
+        match self {
+            CrossrefWriter::Text(writer) => {
+                writer.write_symbol(id, &tables.symbol_payload(id, hits, true))
+            }
+            CrossrefWriter::Binary(writer) => {
+                // The uses are grouped by path so that they can be looked up piecemeal.
+                let use_groups: Vec<(&str, &[u8])> = match hits.get(&AnalysisKind::Use) {
+                    Some(uses) => uses
+                        .iter()
+                        .map(|(path, lines)| (path.as_str(), lines.as_bytes()))
+                        .collect(),
+                    None => vec![],
+                };
+                let payload = tables.symbol_payload(id, hits, false);
+                writer
+                    .add_symbol(&id, &use_groups, payload.as_bytes())
+                    .unwrap()
+            }
+        }
+    }
+
+    fn finish(self) {
+        if let CrossrefWriter::Binary(writer) = self {
+            writer.finish().unwrap();
+        }
+    }
+}
+
+/// In sharded mode, the hits of a single kind for a single symbol from a single path, as routed
+/// to the shard thread responsible for the symbol.
+struct ShardRecord {
+    sym: Ustr,
+    kind: AnalysisKind,
+    path: String,
+    /// The 1-based position of the path in the file list, or 0 for hits reused from a previous
+    /// run.  If a path is listed more than once, this makes the last listing win, just like when
+    /// the hits are merged into the in-memory table.
+    seq: usize,
+    /// The JSON serialization of the Vec<SearchResult>.
+    lines: String,
+}
+
+impl ShardRecord {
+    fn key(&self) -> (Ustr, AnalysisKind, &str, usize) {
+        (self.sym, self.kind, &self.path, self.seq)
+    }
+
+    /// Records are stored on disk as a JSON header line followed by the `lines` JSON.
+    fn write<W: Write>(&self, out: &mut W) {
+        let header = json!([self.sym, self.kind, self.path, self.seq]);
+        out.write_all(format!("{}\n{}\n", header, self.lines).as_bytes())
+            .unwrap();
+    }
+
+    fn read(lines: &mut Lines<BufReader<File>>) -> Option<ShardRecord> {
+        let header = lines.next()?.unwrap();
+        let (sym, kind, path, seq) = from_str(&header).unwrap();
+        Some(ShardRecord {
+            sym,
+            kind,
+            path,
+            seq,
+            lines: lines.next().unwrap().unwrap(),
+        })
+    }
+}
+
+impl PartialEq for ShardRecord {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for ShardRecord {}
+
+impl PartialOrd for ShardRecord {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ShardRecord {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+fn shard_for(sym: Ustr, shard_count: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    sym.as_str().hash(&mut hasher);
+    (hasher.finish() % shard_count as u64) as usize
+}
+
+/// Sort the buffered records and write them out to `path` as a run, emptying the buffer.
+fn spill_run(path: PathBuf, buffer: &mut Vec<ShardRecord>) -> PathBuf {
+    buffer.sort();
+    let mut out = BufWriter::new(File::create(&path).unwrap());
+    for record in buffer.drain(..) {
+        record.write(&mut out);
+    }
+    out.flush().unwrap();
+    path
+}
+
+/// K-way merge the records from the given sorted runs, passing them to `emit` in sorted order.
+fn merge_runs<F>(runs: &[PathBuf], mut emit: F)
+where
+    F: FnMut(ShardRecord),
+{
+    let mut readers: Vec<_> = runs
+        .iter()
+        .map(|path| BufReader::new(File::open(path).unwrap()).lines())
+        .collect();
+    let mut heap = BinaryHeap::new();
+    for (i, reader) in readers.iter_mut().enumerate() {
+        if let Some(record) = ShardRecord::read(reader) {
+            heap.push(Reverse((record, i)));
+        }
+    }
+    while let Some(Reverse((record, i))) = heap.pop() {
+        if let Some(next) = ShardRecord::read(&mut readers[i]) {
+            heap.push(Reverse((next, i)));
+        }
+        emit(record);
+    }
+}
+
+/// Receives the records for a shard, spilling sorted runs to disk as its buffer fills up, and
+/// then merges the runs into a single sorted shard file whose path is returned.
+fn shard_thread_main(
+    dir: &Path,
+    shard: usize,
+    spill_threshold: usize,
+    record_rx: Receiver<ShardRecord>,
+) -> PathBuf {
+    let mut buffer = Vec::new();
+    let mut buffered = 0;
+    let mut runs = Vec::new();
+    while let Ok(record) = record_rx.recv() {
+        buffered += record.path.len() + record.lines.len();
+        buffer.push(record);
+        if buffered >= spill_threshold {
+            let run_path = dir.join(format!("shard-{}-run-{}", shard, runs.len()));
+            runs.push(spill_run(run_path, &mut buffer));
+            buffered = 0;
+        }
+    }
+
+    let shard_path = dir.join(format!("shard-{}", shard));
+    if runs.is_empty() {
+        return spill_run(shard_path, &mut buffer);
+    }
+    if !buffer.is_empty() {
+        let run_path = dir.join(format!("shard-{}-run-{}", shard, runs.len()));
+        runs.push(spill_run(run_path, &mut buffer));
+    }
+
+    println!("Shard {} merging {} runs", shard, runs.len());
+    let mut out = BufWriter::new(File::create(&shard_path).unwrap());
+    merge_runs(&runs, |record| record.write(&mut out));
+    out.flush().unwrap();
+    for run in runs {
+        fs::remove_file(run).unwrap();
+    }
+    shard_path
+}
+
+/// In sharded mode, a thread that reads analysis files.  It routes the hits to the shard
+/// threads itself and responds with the rest of each file's contribution.
+struct ReaderThread {
+    query_tx: Sender<(usize, String)>,
+    response_rx: Receiver<Option<FileContribution>>,
+}
+
+impl ReaderThread {
+    fn new(paths: TreeConfigPaths, shard_txs: Vec<SyncSender<ShardRecord>>) -> Self {
+        let (query_tx, query_rx) = channel();
+        let (response_tx, response_rx) = channel();
+        thread::spawn(move || {
+            reader_thread_main(query_rx, response_tx, paths, shard_txs);
+        });
+
+        ReaderThread {
+            query_tx,
+            response_rx,
+        }
+    }
+}
+
+fn reader_thread_main(
+    query_rx: Receiver<(usize, String)>,
+    response_tx: Sender<Option<FileContribution>>,
+    paths: TreeConfigPaths,
+    shard_txs: Vec<SyncSender<ShardRecord>>,
+) {
+    while let Ok((seq, path)) = query_rx.recv() {
+        // Reading the lines as Strings instead of Ustrs means they're freed once they've been
+        // spilled to disk.
+        let contribution = read_contribution::<String>(&paths, &path).map(|contribution| {
+            for ((sym, kind), results) in contribution.hits {
+                let record = ShardRecord {
+                    sym,
+                    kind,
+                    path: path.clone(),
+                    seq,
+                    lines: serde_json::to_string(&results).unwrap(),
+                };
+                shard_txs[shard_for(sym, shard_txs.len())]
+                    .send(record)
+                    .unwrap();
+            }
+            FileContribution {
+                path: contribution.path,
+                hits: BTreeMap::new(),
+                pretties: contribution.pretties,
+                callees: contribution.callees,
+                metas: contribution.metas,
+            }
+        });
+        response_tx.send(contribution).unwrap();
+    }
+}
+
+/// In sharded mode, hands out the files that need to be read to the reader threads and returns
+/// their contributions in file order so they can be merged in order.
+struct ShardedReaders {
+    threads: Vec<ReaderThread>,
+    /// The (seq, path) of every file to read, in order.
+    queue: Vec<(usize, String)>,
+    /// The number of files in `queue` that have been handed out.
+    requested: usize,
+    /// The number of contributions that have been returned.
+    received: usize,
+}
+
+impl ShardedReaders {
+    fn new(
+        thread_count: usize,
+        paths: &TreeConfigPaths,
+        shard_txs: &[SyncSender<ShardRecord>],
+        queue: Vec<(usize, String)>,
+    ) -> Self {
+        println!("Starting {} reader threads...", thread_count);
+        let threads = (0..thread_count)
+            .map(|_| ReaderThread::new(paths.clone(), shard_txs.to_vec()))
+            .collect();
+        let mut readers = ShardedReaders {
+            threads,
+            queue,
+            requested: 0,
+            received: 0,
+        };
+        let initial_request_count = readers
+            .queue
+            .len()
+            .min(READ_AHEAD_PER_THREAD * thread_count);
+        while readers.requested < initial_request_count {
+            readers.request();
+        }
+        readers
+    }
+
+    /// Hand out the next file.  Files are handed out round-robin and each thread processes them
+    /// in FIFO order, so we know exactly which thread will produce each contribution.
+    fn request(&mut self) {
+        let thread = &self.threads[self.requested % self.threads.len()];
+        thread
+            .query_tx
+            .send(self.queue[self.requested].clone())
+            .unwrap();
+        self.requested += 1;
+    }
+
+    fn next_contribution(&mut self) -> Option<FileContribution> {
+        let thread = &self.threads[self.received % self.threads.len()];
+        let contribution = thread.response_rx.recv().unwrap();
+        self.received += 1;
+        if self.requested < self.queue.len() {
+            self.request();
+        }
+        contribution
+    }
+}
+
+/// Process all analysis files, deriving the `crossref`, `jumps`, and `identifiers` output files.
+/// See https://github.com/mozsearch/mozsearch/blob/master/docs/crossref.md for high-level
+/// documentation on how this works (locally, `docs/crossref.md`).
+///
+/// Usage: `crossref <config-file> <tree-name> <filenames-file> [<changed-filenames-file>]`
+///
+/// The filenames file lists every analysis file to process.  If a changed filenames file is also
+/// provided, an incremental rebuild is performed where only the analysis files listed in it are
+/// reread, with the contributions of the other files coming from the previous `crossref` and
+/// `crossref-state` outputs.  Files in the changed list that aren't in the filenames file are
+/// treated as removed (as is any previously processed file not in the filenames file).  The
+/// result is identical to a full rebuild as long as the unchanged analysis and source files
+/// really are unchanged.  If the previous outputs are missing or unreadable, a full rebuild is
+/// done instead.
+///
+/// If the `CROSSREF_SHARDS` environment variable is set to a number greater than 0, the hits are
+/// processed in sharded mode as described below.  The output is identical either way.
+///
+/// If the `CROSSREF_FORMAT` environment variable is set to `binary`, `crossref` is written in the
+/// binary format from `crossref_binary.rs` instead of the default `text` format, and there is no
+/// `crossref-extra`.  Only the rust tools can read the binary format.
+///
+/// ## Implementation
+/// There are 2 phases of processing:
+/// 1. The analysis files are read, producing a `FileContribution` per file.  Its hits are merged
+///    into `table` and the rest of it into the `SymbolTables`, in file order.  Primary
+///    cross-reference information comes from target records, but the file is also processed for
+///    structured records in order to populate `meta_table` with meta-information about the
+///    symbol.
+/// 2. The table is consumed with jumps generated as a byproduct.
+///
+/// ### Memory Management
+/// Memory usage grows continually throughout phase 1.  Because we load many identical strings,
+/// we use string interning so that all long-lived strings are reference-counted interned strings.
+/// The contributions written to `crossref-state` and the jumps are written out as they're
+/// produced rather than accumulated.
+///
+/// In sharded mode, the hits, which are the bulk of the data, aren't kept in memory.  Instead the
+/// analysis files are read by a pool of reader threads which partition the hits by a hash of
+/// their symbol across `CROSSREF_SHARDS` shard threads.  Each shard thread buffers its hits,
+/// spilling them to disk as sorted runs under `crossref-shards` in the index directory whenever
+/// its buffer reaches `SHARD_SPILL_THRESHOLD`, and then merges its runs into a sorted shard file.
+/// Phase 2 then k-way merges the shard files to consume the hits in symbol order.  The rest of
+/// each contribution is still merged into the `SymbolTables` in file order by the main thread,
+/// and those tables are kept in memory in full, so sharding only bounds the memory used by the
+/// hits.
+fn main() {
+    env_logger::init();
+    let args: Vec<_> = env::args().collect();
+
+    let cfg = config::load(&args[1], false);
+
+    let tree_name = &args[2];
+    let tree_config = cfg.trees.get(tree_name).unwrap();
+
+    let filenames_file = &args[3];
+
+    let shard_count = env_usize("CROSSREF_SHARDS", 0);
+    let spill_threshold = env_usize("CROSSREF_SHARD_SPILL_THRESHOLD", SHARD_SPILL_THRESHOLD);
+    let binary_format = match env::var("CROSSREF_FORMAT") {
+        Ok(format) if format == "binary" => true,
+        Ok(format) if format == "text" => false,
+        Ok(format) => panic!("Unknown CROSSREF_FORMAT {}", format),
+        Err(_) => false,
+    };
+
+    let file_paths: Vec<String> = BufReader::new(File::open(filenames_file).unwrap())
+        .lines()
+        .map(|x| x.unwrap())
+        .collect();
+    let xref_file = format!("{}/crossref", tree_config.paths.index_path);
+    let xref_ext_file = format!("{}/crossref-extra", tree_config.paths.index_path);
+    let jump_file = format!("{}/jumps", tree_config.paths.index_path);
+    let id_file = format!("{}/identifiers", tree_config.paths.index_path);
+    let state_file = format!("{}/crossref-state", tree_config.paths.index_path);
+    let shard_dir = PathBuf::from(format!("{}/crossref-shards", tree_config.paths.index_path));
+
+    // The hits, when not in sharded mode.
+    let mut table: HitTable = BTreeMap::new();
+    let mut tables = SymbolTables::default();
+
+    let mut shard_txs = Vec::new();
+    let mut shard_threads = Vec::new();
+    if shard_count > 0 {
+        println!("Starting {} shard threads...", shard_count);
+        let _ = fs::remove_dir_all(&shard_dir);
+        fs::create_dir_all(&shard_dir).unwrap();
+        for shard in 0..shard_count {
+            let (record_tx, record_rx) = sync_channel(SHARD_QUEUE_SIZE);
+            let dir = shard_dir.clone();
+            shard_threads.push(thread::spawn(move || {
+                shard_thread_main(&dir, shard, spill_threshold, record_rx)
+            }));
+            shard_txs.push(record_tx);
+        }
+    }
+
+    // For an incremental rebuild, the saved `crossref-state` lines for the
+    // files that we don't need to reread, keyed by path.
+    let mut reusable_state: HashMap<String, String> = HashMap::new();
+    if let Some(changed_file) = args.get(4) {
+        match open_previous_outputs(&state_file, &xref_file, &xref_ext_file) {
+            Ok(previous) => {
+                let changed: HashSet<String> = BufReader::new(File::open(changed_file).unwrap())
+                    .lines()
+                    .map(|x| x.unwrap())
+                    .collect();
+                reusable_state = previous.state;
+                reusable_state.retain(|path, _| !changed.contains(path));
+                // Files that were removed also won't be in the new file list,
+                // so this takes care of subtracting their hits.
+                let keep_paths: HashSet<&str> = file_paths
+                    .iter()
+                    .map(|path| path.as_str())
+                    .filter(|path| reusable_state.contains_key(*path))
+                    .collect();
+                // An empty crossref doesn't have any hits.
+                if let Some(map) = &previous.crossref {
+                    read_previous_hits(map, &keep_paths, |sym, kind, path, results| {
+                        if shard_txs.is_empty() {
+                            let results: Vec<SearchResult> = from_value(results).unwrap();
+                            table
+                                .entry(sym)
+                                .or_default()
+                                .entry(kind)
+                                .or_default()
+                                .insert(path, results);
+                        } else {
+                            let record = ShardRecord {
+                                sym,
+                                kind,
+                                path,
+                                seq: 0,
+                                lines: results.to_string(),
+                            };
+                            shard_txs[shard_for(sym, shard_count)].send(record).unwrap();
+                        }
+                    })
+                    .expect("previous crossref changed while reading it");
+                }
+                println!(
+                    "Incremental rebuild reusing {} of {} files",
+                    keep_paths.len(),
+                    file_paths.len()
+                );
+            }
+            Err(problem) => {
+                println!(
+                    "Unable to do an incremental rebuild ({}), doing a full rebuild",
+                    problem
+                );
+            }
+        }
+    }
+
+    let mut readers = if shard_count > 0 {
+        let queue = file_paths
+            .iter()
+            .enumerate()
+            .filter(|(_, path)| !reusable_state.contains_key(*path))
+            .map(|(i, path)| (i + 1, path.clone()))
+            .collect();
+        let thread_count = num_cpus::get().max(2) - 1; // 1 for the main thread
+        Some(ShardedReaders::new(
+            thread_count,
+            &tree_config.paths,
+            &shard_txs,
+            queue,
+        ))
+    } else {
+        None
+    };
+    // The reader threads have their own senders, so the shard threads will
+    // finish once the readers are dropped.
+    drop(shard_txs);
+
+    // The serialized contributions of the files we've processed are written to `crossref-state`
+    // as we go.  They're written to a temporary file that only replaces the previous state once
+    // the new `crossref` has been written, so that the two are always consistent.
+    let state_temp_file = format!("{}.tmp", state_file);
+    let mut statef = BufWriter::new(File::create(&state_temp_file).unwrap());
+
+    for path in &file_paths {
+        let mut contribution = match reusable_state.get(path) {
+            Some(line) => {
+                writeln!(statef, "{}", line).unwrap();
+                from_str(line).unwrap()
+            }
+            None => {
+                let contribution = match readers.as_mut() {
+                    Some(readers) => readers.next_contribution(),
+                    None => read_contribution(&tree_config.paths, path),
+                };
+                match contribution {
+                    Some(contribution) => {
+                        serde_json::to_writer(&mut statef, &contribution).unwrap();
+                        statef.write_all(b"\n").unwrap();
+                        contribution
                     }
+                    None => continue,
                 }
             }
+        };
+
+        for ((sym, kind), results) in std::mem::take(&mut contribution.hits) {
+            let t1 = table.entry(sym).or_insert(BTreeMap::new());
+            let t2 = t1.entry(kind).or_insert(BTreeMap::new());
+            t2.insert(contribution.path.clone(), results);
         }
+
+        tables.merge(contribution);
     }
+    drop(readers);
+    statef.flush().unwrap();
+    drop(statef);
+
+    tables.link_metas();
+
+    // ## Write out the crossref database.
+    let mut xref_writer = if binary_format {
+        // There's no `crossref-extra` in the binary format, so don't leave a stale one around.
+        let _ = fs::remove_file(&xref_ext_file);
+        CrossrefWriter::Binary(Box::new(CrossrefBinaryWriter::create(&xref_file).unwrap()))
+    } else {
+        CrossrefWriter::Text(TextCrossrefWriter::new(&xref_file, &xref_ext_file))
+    };
+    let mut jumpf = BufWriter::new(File::create(jump_file).unwrap());
+    let mut write_symbol = |id: Ustr, hits: &SymbolHits| {
+        xref_writer.write_symbol(&tables, id, hits);
+        if let Some(jump) = tables.symbol_jump(id, hits) {
+            writeln!(jumpf, "{}", jump).unwrap();
+        }
+    };
 
-    let mut jumpf = File::create(jump_file).unwrap();
-    for jump in jumps {
-        let _ = jumpf.write_all((jump.to_string() + "\n").as_bytes());
+    if shard_count > 0 {
+        let shard_paths: Vec<PathBuf> = shard_threads
+            .into_iter()
+            .map(|shard_thread: JoinHandle<PathBuf>| shard_thread.join().unwrap())
+            .collect();
+        let mut current_id = None;
+        let mut current_hits = SymbolHits::new();
+        merge_runs(&shard_paths, |record| {
+            if current_id != Some(record.sym) {
+                if let Some(id) = current_id {
+                    write_symbol(id, &current_hits);
+                }
+                current_id = Some(record.sym);
+                current_hits.clear();
+            }
+            current_hits
+                .entry(record.kind)
+                .or_default()
+                .insert(record.path, record.lines);
+        });
+        if let Some(id) = current_id {
+            write_symbol(id, &current_hits);
+        }
+        fs::remove_dir_all(&shard_dir).unwrap();
+    } else {
+        for (id, id_data) in table {
+            let hits = id_data
+                .into_iter()
+                .map(|(kind, kind_data)| {
+                    let kind_data = kind_data
+                        .into_iter()
+                        .map(|(path, results)| (path, serde_json::to_string(&results).unwrap()))
+                        .collect();
+                    (kind, kind_data)
+                })
+                .collect();
+            write_symbol(id, &hits);
+        }
     }
 
+    xref_writer.finish();
+    jumpf.flush().unwrap();
+
+    fs::rename(&state_temp_file, &state_file).unwrap();
+
     let mut idf = File::create(id_file).unwrap();
-    for (id, syms) in id_table {
+    for (id, syms) in tables.id_table {
         for sym in syms {
             let components = split_scopes(&id.as_str());
             for i in 0..components.len() {
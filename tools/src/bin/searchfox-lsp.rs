@@ -0,0 +1,568 @@
+//! A Language Server Protocol bridge that answers go-to-definition,
+//! find-references, hover and workspace symbol requests from a searchfox index
+//! rather than a local build.
+//!
+//! The server speaks LSP over stdio.  Editor file URIs are mapped to
+//! tree-relative paths by stripping the workspace root, which should be the
+//! root of a checkout of the indexed tree.  Example:
+//!
+//! `searchfox-lsp --server=https://searchfox.org/ --tree=mozilla-central`
+
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use serde_json::{from_slice, from_value, json, to_vec, Value};
+use structopt::StructOpt;
+use tokio::io::{stdin, stdout, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, Stdout};
+use tools::{
+    abstract_server::{
+        AbstractServer, CrossrefLookupOptions, ErrorDetails, ErrorLayer, Result, RetryLog,
+        ServerError,
+    },
+    cmd_pipeline::{builder::make_server, parser::ServerOpts},
+    file_format::analysis::{AnalysisSource, WithLocation},
+};
+use url::Url;
+
+/// Crossref lookup options for when only the definitions and metadata are
+/// needed, which avoids fetching the uses of popular symbols.
+fn without_uses() -> CrossrefLookupOptions {
+    CrossrefLookupOptions {
+        limit: Some(0),
+        ..CrossrefLookupOptions::default()
+    }
+}
+
+// JSON-RPC error codes used by LSP.
+const INVALID_PARAMS: i64 = -32602;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INTERNAL_ERROR: i64 = -32603;
+
+// LSP `SymbolKind` values.
+const SYMBOL_KIND_CLASS: u64 = 5;
+const SYMBOL_KIND_METHOD: u64 = 6;
+const SYMBOL_KIND_FIELD: u64 = 8;
+const SYMBOL_KIND_ENUM: u64 = 10;
+const SYMBOL_KIND_FUNCTION: u64 = 12;
+const SYMBOL_KIND_VARIABLE: u64 = 13;
+const SYMBOL_KIND_STRUCT: u64 = 23;
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "searchfox-lsp",
+    about = "Language server backed by a searchfox index."
+)]
+struct LspOpts {
+    #[structopt(flatten)]
+    server_opts: ServerOpts,
+
+    /// The local checkout of the tree that editor paths are relative to.  By
+    /// default the workspace root from the `initialize` request is used.
+    #[structopt(long)]
+    root: Option<PathBuf>,
+
+    /// The maximum number of results to return for `workspace/symbol`.
+    #[structopt(long, default_value = "50")]
+    symbol_limit: usize,
+}
+
+/// The outcome of handling a request: its JSON result or a JSON-RPC error
+/// code and message.
+type Reply = std::result::Result<Value, (i64, String)>;
+
+fn internal_error(err: ServerError) -> (i64, String) {
+    (INTERNAL_ERROR, format!("{:?}", err))
+}
+
+fn lsp_kind_for(kind: &str) -> u64 {
+    match kind {
+        "class" => SYMBOL_KIND_CLASS,
+        "struct" => SYMBOL_KIND_STRUCT,
+        "enum" => SYMBOL_KIND_ENUM,
+        "method" => SYMBOL_KIND_METHOD,
+        "function" => SYMBOL_KIND_FUNCTION,
+        "field" => SYMBOL_KIND_FIELD,
+        _ => SYMBOL_KIND_VARIABLE,
+    }
+}
+
+/// Map an editor URI to a path relative to the tree checkout at `root`.
+fn tree_path(root: &Path, uri: &str) -> Option<String> {
+    let file_path = Url::parse(uri).ok()?.to_file_path().ok()?;
+    let relative = file_path.strip_prefix(root).ok()?;
+    Some(relative.to_str()?.replace('\\', "/"))
+}
+
+/// Map a tree-relative path to an editor URI for the checkout at `root`.
+fn file_uri(root: &Path, path: &str) -> Option<String> {
+    Some(Url::from_file_path(root.join(path)).ok()?.to_string())
+}
+
+/// Extract the URI and 0-based line and character from a
+/// `TextDocumentPositionParams`.
+fn document_position(params: &Value) -> std::result::Result<(&str, u64, u64), (i64, String)> {
+    match (
+        params["textDocument"]["uri"].as_str(),
+        params["position"]["line"].as_u64(),
+        params["position"]["character"].as_u64(),
+    ) {
+        (Some(uri), Some(line), Some(character)) => Ok((uri, line, character)),
+        _ => Err((INVALID_PARAMS, "Missing document position".to_string())),
+    }
+}
+
+/// Convert an LSP character offset in UTF-16 code units into the byte offset
+/// that analysis columns use.  Offsets past the end of the line are clamped to
+/// it, and an offset in the middle of a surrogate pair maps to its character.
+fn utf16_to_byte_col(line: &str, character: u64) -> u64 {
+    let mut units = 0;
+    for (byte_offset, c) in line.char_indices() {
+        units += c.len_utf16() as u64;
+        if units > character {
+            return byte_offset as u64;
+        }
+    }
+    line.len() as u64
+}
+
+/// The source record(s) under a cursor.
+struct SymbolsAtPosition {
+    symbols: Vec<String>,
+    pretty: String,
+}
+
+struct Bridge {
+    server: Box<dyn AbstractServer + Send + Sync>,
+    retry_log: RetryLog,
+    root: Option<PathBuf>,
+    symbol_limit: usize,
+    /// Whether the client agreed to UTF-8 positions.  Otherwise positions are
+    /// in UTF-16 code units and need converting to analysis byte columns.
+    utf8_positions: bool,
+    shutdown_requested: bool,
+}
+
+impl Bridge {
+    fn tree_path(&self, uri: &str) -> Option<String> {
+        tree_path(self.root.as_ref()?, uri)
+    }
+
+    fn file_uri(&self, path: &str) -> Option<String> {
+        file_uri(self.root.as_ref()?, path)
+    }
+
+    /// Convert an LSP character offset on the given 0-based line of a
+    /// tree-relative path into an analysis column.  UTF-16 offsets are
+    /// converted using the line from the local checkout; if that can't be read,
+    /// the line is assumed to be ASCII.
+    async fn analysis_column(&self, path: &str, line: u64, character: u64) -> u64 {
+        if self.utf8_positions {
+            return character;
+        }
+        let root = match self.root.as_ref() {
+            Some(root) => root,
+            None => return character,
+        };
+        match tokio::fs::read(root.join(path)).await {
+            Ok(contents) => match String::from_utf8_lossy(&contents)
+                .lines()
+                .nth(line as usize)
+            {
+                Some(text) => utf16_to_byte_col(text, character),
+                None => character,
+            },
+            Err(_) => character,
+        }
+    }
+
+    /// Convert a crossref hit list into LSP locations.  The crossref only
+    /// records column bounds relative to the trimmed line, so locations span
+    /// the start of the line.
+    fn hit_locations(&self, hits: &Value, locations: &mut Vec<Value>) {
+        for path_hits in hits.as_array().into_iter().flatten() {
+            let uri = match path_hits["path"].as_str().and_then(|p| self.file_uri(p)) {
+                Some(uri) => uri,
+                None => continue,
+            };
+            for line in path_hits["lines"].as_array().into_iter().flatten() {
+                if let Some(lno) = line["lno"].as_u64() {
+                    let position = json!({ "line": lno.saturating_sub(1), "character": 0 });
+                    locations.push(json!({
+                        "uri": uri,
+                        "range": { "start": position, "end": position },
+                    }));
+                }
+            }
+        }
+    }
+
+    /// Find the symbols of the source records covering the position in a
+    /// `TextDocumentPositionParams`.
+    async fn symbols_at(
+        &self,
+        params: &Value,
+    ) -> std::result::Result<Option<SymbolsAtPosition>, (i64, String)> {
+        let (uri, line, character) = document_position(params)?;
+        let path = match self.tree_path(uri) {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let col = self.analysis_column(&path, line, character).await;
+
+        // LSP lines are 0-based while analysis lines are 1-based.
+        let values = match self
+            .server
+            .source_records_at(&path, line as u32 + 1, col as u32)
+            .await
+        {
+            Ok(values) => values,
+            // The file isn't in the index or has no analysis data.
+            Err(ServerError::StickyProblem(_)) => return Ok(None),
+            Err(err) => return Err(internal_error(err)),
+        };
+
+        let mut symbols = BTreeSet::new();
+        let mut pretty = String::new();
+        for value in values {
+            if let Ok(source) = from_value::<WithLocation<AnalysisSource>>(value) {
+                symbols.extend(source.data.sym.iter().map(|s| s.to_string()));
+                pretty = source.data.pretty.to_string();
+            }
+        }
+
+        if symbols.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(SymbolsAtPosition {
+            symbols: symbols.into_iter().collect(),
+            pretty,
+        }))
+    }
+
+    /// Gather the LSP locations of the given crossref kinds for the symbols at
+    /// the requested position.
+    async fn locations_at(&self, params: &Value, kinds: &[&str]) -> Reply {
+        let at = match self.symbols_at(params).await? {
+            Some(at) => at,
+            None => return Ok(Value::Null),
+        };
+        let mut locations = vec![];
+        for sym in &at.symbols {
+            let crossref = self
+                .server
+                .crossref_lookup(sym, &CrossrefLookupOptions::default())
+                .await
+                .map_err(internal_error)?;
+            for kind in kinds {
+                self.hit_locations(&crossref[*kind], &mut locations);
+            }
+        }
+        locations.dedup();
+        Ok(Value::Array(locations))
+    }
+
+    async fn hover(&self, params: &Value) -> Reply {
+        let at = match self.symbols_at(params).await? {
+            Some(at) => at,
+            None => return Ok(Value::Null),
+        };
+
+        let mut lines = vec![format!("`{}`", at.pretty)];
+        for sym in &at.symbols {
+            let crossref = self
+                .server
+                .crossref_lookup(sym, &without_uses())
+                .await
+                .map_err(internal_error)?;
+            let meta = &crossref["meta"];
+            let mut details = vec![];
+            if let Some(kind) = meta["kind"].as_str() {
+                details.push(kind.to_string());
+            }
+            if let Some(size) = meta["sizeBytes"].as_u64() {
+                details.push(format!("{} bytes", size));
+            }
+            if let Some(def) = crossref["defs"].as_array().and_then(|defs| defs.first()) {
+                if let (Some(path), Some(lno)) =
+                    (def["path"].as_str(), def["lines"][0]["lno"].as_u64())
+                {
+                    details.push(format!("defined at {}:{}", path, lno));
+                }
+            }
+            if !details.is_empty() {
+                lines.push(details.join(", "));
+            }
+        }
+
+        Ok(json!({
+            "contents": { "kind": "markdown", "value": lines.join("\n\n") },
+        }))
+    }
+
+    async fn workspace_symbol(&self, params: &Value) -> Reply {
+        let query = params["query"].as_str().unwrap_or("");
+        if query.is_empty() {
+            return Ok(json!([]));
+        }
+        let matches = self
+            .server
+            .search_identifiers(query, false, true, self.symbol_limit)
+            .await
+            .map_err(internal_error)?;
+
+        let mut symbols = vec![];
+        for (sym, id) in matches {
+            let crossref = self
+                .server
+                .crossref_lookup(&sym, &without_uses())
+                .await
+                .map_err(internal_error)?;
+            // Symbols without a definition can't be navigated to.
+            let mut locations = vec![];
+            self.hit_locations(&crossref["defs"], &mut locations);
+            if let Some(location) = locations.into_iter().next() {
+                symbols.push(json!({
+                    "name": id,
+                    "kind": lsp_kind_for(crossref["meta"]["kind"].as_str().unwrap_or("")),
+                    "location": location,
+                }));
+            }
+        }
+        Ok(Value::Array(symbols))
+    }
+
+    fn initialize(&mut self, params: &Value) -> Reply {
+        if self.root.is_none() {
+            let root_uri = params["rootUri"]
+                .as_str()
+                .or_else(|| params["workspaceFolders"][0]["uri"].as_str());
+            self.root = root_uri
+                .and_then(|uri| Url::parse(uri).ok())
+                .and_then(|url| url.to_file_path().ok())
+                .or_else(|| params["rootPath"].as_str().map(PathBuf::from));
+        }
+        if self.root.is_none() {
+            eprintln!("No workspace root was provided; requests will have no results.");
+        }
+
+        // Analysis columns are bytes, so prefer UTF-8 positions if the client
+        // supports them.  UTF-16 is the default that every client supports.
+        self.utf8_positions = params["capabilities"]["general"]["positionEncodings"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .any(|encoding| encoding == "utf-8");
+
+        Ok(json!({
+            "capabilities": {
+                "positionEncoding": if self.utf8_positions { "utf-8" } else { "utf-16" },
+                "definitionProvider": true,
+                "referencesProvider": true,
+                "hoverProvider": true,
+                "workspaceSymbolProvider": true,
+            },
+            "serverInfo": { "name": "searchfox-lsp" },
+        }))
+    }
+
+    async fn handle_request(&mut self, method: &str, params: &Value) -> Reply {
+        match method {
+            "initialize" => self.initialize(params),
+            "shutdown" => {
+                self.shutdown_requested = true;
+                Ok(Value::Null)
+            }
+            "textDocument/definition" => self.locations_at(params, &["defs"]).await,
+            "textDocument/references" => {
+                if params["context"]["includeDeclaration"]
+                    .as_bool()
+                    .unwrap_or(false)
+                {
+                    self.locations_at(params, &["defs", "decls", "uses"]).await
+                } else {
+                    self.locations_at(params, &["uses"]).await
+                }
+            }
+            "textDocument/hover" => self.hover(params).await,
+            "workspace/symbol" => self.workspace_symbol(params).await,
+            _ => Err((METHOD_NOT_FOUND, format!("Unsupported method: {}", method))),
+        }
+    }
+}
+
+/// Read a single `Content-Length` framed message, returning None at EOF.
+async fn read_message<R: AsyncBufReadExt + Unpin>(reader: &mut R) -> Result<Option<Value>> {
+    let mut content_length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).await? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+
+    let content_length = match content_length {
+        Some(len) => len,
+        None => {
+            return Err(ServerError::StickyProblem(ErrorDetails {
+                layer: ErrorLayer::BadInput,
+                message: "Message is missing a Content-Length header".to_string(),
+            }))
+        }
+    };
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).await?;
+    Ok(Some(from_slice(&body)?))
+}
+
+async fn write_message(out: &mut Stdout, message: &Value) -> Result<()> {
+    let body = to_vec(message)?;
+    out.write_all(format!("Content-Length: {}\r\n\r\n", body.len()).as_bytes())
+        .await?;
+    out.write_all(&body).await?;
+    out.flush().await?;
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    let opts = LspOpts::from_args();
+    let (server, retry_log) = match make_server(&opts.server_opts) {
+        Ok(made) => made,
+        Err(err) => {
+            eprintln!("Unable to create server: {:?}", err);
+            std::process::exit(1);
+        }
+    };
+    let mut bridge = Bridge {
+        server,
+        retry_log,
+        root: opts.root,
+        symbol_limit: opts.symbol_limit,
+        utf8_positions: false,
+        shutdown_requested: false,
+    };
+
+    let mut reader = BufReader::new(stdin());
+    let mut out = stdout();
+    loop {
+        let message = match read_message(&mut reader).await {
+            Ok(Some(message)) => message,
+            Ok(None) => break,
+            Err(err) => {
+                eprintln!("Unable to read message: {:?}", err);
+                break;
+            }
+        };
+
+        let method = message["method"].as_str().unwrap_or("");
+        if method == "exit" {
+            std::process::exit(if bridge.shutdown_requested { 0 } else { 1 });
+        }
+        // Notifications (like `initialized` and `textDocument/didOpen`) don't
+        // have an id and don't get a response.  We don't need any of them.
+        let id = match message.get("id") {
+            Some(id) => id.clone(),
+            None => continue,
+        };
+
+        let response = match bridge.handle_request(method, &message["params"]).await {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err((code, message)) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": code, "message": message },
+            }),
+        };
+        let summary = bridge.retry_log.take();
+        if !summary.is_empty() {
+            eprint!("{}", summary);
+        }
+        if let Err(err) = write_message(&mut out, &response).await {
+            eprintln!("Unable to write response: {:?}", err);
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_message() {
+        let body = r#"{"jsonrpc":"2.0","id":1,"method":"shutdown"}"#;
+        let input = format!(
+            "Content-Length: {}\r\nContent-Type: application/vscode-jsonrpc\r\n\r\n{}\
+             content-length: 2\r\n\r\n{{}}",
+            body.len(),
+            body
+        );
+        let mut reader = input.as_bytes();
+        let first = read_message(&mut reader).await.unwrap().unwrap();
+        assert_eq!(first["method"], "shutdown");
+        let second = read_message(&mut reader).await.unwrap().unwrap();
+        assert_eq!(second, json!({}));
+        assert!(read_message(&mut reader).await.unwrap().is_none());
+
+        let mut missing_length = "Content-Type: x\r\n\r\n{}".as_bytes();
+        assert!(read_message(&mut missing_length).await.is_err());
+
+        let mut truncated = "Content-Length: 10\r\n\r\n{}".as_bytes();
+        assert!(read_message(&mut truncated).await.is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_path_mapping() {
+        let root = Path::new("/src/gecko");
+        assert_eq!(
+            tree_path(root, "file:///src/gecko/dom/base/Element.cpp").as_deref(),
+            Some("dom/base/Element.cpp")
+        );
+        assert_eq!(
+            tree_path(root, "file:///src/gecko/dom/My%20File.cpp").as_deref(),
+            Some("dom/My File.cpp")
+        );
+        assert_eq!(tree_path(root, "file:///src/other/a.cpp"), None);
+        assert_eq!(tree_path(root, "untitled:Untitled-1"), None);
+
+        assert_eq!(
+            file_uri(root, "dom/My File.cpp").as_deref(),
+            Some("file:///src/gecko/dom/My%20File.cpp")
+        );
+    }
+
+    #[test]
+    fn test_document_position() {
+        let params = json!({
+            "textDocument": { "uri": "file:///a.cpp" },
+            "position": { "line": 3, "character": 7 },
+        });
+        assert_eq!(document_position(&params), Ok(("file:///a.cpp", 3, 7)));
+
+        let params = json!({ "textDocument": { "uri": "file:///a.cpp" } });
+        assert_eq!(document_position(&params).unwrap_err().0, INVALID_PARAMS);
+        let params = json!({ "position": { "line": 3, "character": 7 } });
+        assert_eq!(document_position(&params).unwrap_err().0, INVALID_PARAMS);
+    }
+
+    #[test]
+    fn test_utf16_to_byte_col() {
+        assert_eq!(utf16_to_byte_col("int foo;", 4), 4);
+        // "é" is 2 bytes and 1 UTF-16 unit.
+        assert_eq!(utf16_to_byte_col("// é foo", 5), 6);
+        // "😀" is 4 bytes and 2 UTF-16 units.
+        assert_eq!(utf16_to_byte_col("\"😀\" + foo", 6), 8);
+        assert_eq!(utf16_to_byte_col("\"😀\"", 2), 1);
+        assert_eq!(utf16_to_byte_col("foo", 10), 3);
+    }
+}
@@ -1,7 +1,33 @@
 use std::env::args_os;
 
-use serde_json::to_string_pretty;
-use tools::{cmd_pipeline::{builder::build_pipeline, parser::OutputFormat, PipelineValues}, abstract_server::{ServerError, ErrorLayer, ErrorDetails}};
+use rustyline::error::ReadlineError;
+use rustyline::Editor;
+use serde::Serialize;
+use serde_json::{to_string, to_string_pretty};
+use structopt::StructOpt;
+use tokio::task::block_in_place;
+use tools::{
+    abstract_server::{ErrorDetails, ErrorLayer, Result, RetryLog, ServerError},
+    cmd_pipeline::{
+        builder::{build_pipeline, make_server, parse_commands},
+        interface::ServerPipeline,
+        parser::{OutputFormat, ReplOpts},
+        repl::ReplHelper,
+        PipelineValues,
+    },
+};
+
+/// Print a JSON-serializable result.  The graph output formats aren't
+/// meaningful for non-graph results, so those get concise JSON.
+fn print_json<T: Serialize>(value: &T, output_format: &OutputFormat) {
+    if *output_format == OutputFormat::Pretty {
+        if let Ok(pretty) = to_string_pretty(value) {
+            println!("{}", pretty);
+        }
+    } else if let Ok(concise) = to_string(value) {
+        println!("{}", concise);
+    }
+}
 
 #[tokio::main]
 async fn main() {
@@ -9,6 +35,11 @@ async fn main() {
         .map(|os| os.into_string().unwrap_or("".to_string()))
         .collect();
 
+    if os_args.len() > 1 && os_args[1] == "--repl" {
+        run_repl(os_args).await;
+        return;
+    }
+
     // We're expecting a single argument
     if os_args.len() == 1 {
         println!("!!! NOTE !!!");
@@ -40,26 +71,48 @@ async fn main() {
 
     let results = pipeline.run().await;
 
-    std::process::exit(match results {
+    let exit_code = print_results(&results, &output_format);
+    print_retry_summary(&pipeline.retry_log);
+    std::process::exit(exit_code);
+}
+
+/// Report any retries performed since the last report on stderr so that they
+/// don't interfere with the results.
+fn print_retry_summary(retry_log: &RetryLog) {
+    let summary = retry_log.take();
+    if !summary.is_empty() {
+        eprint!("{}", summary);
+    }
+}
+
+/// Print the results of a pipeline, returning the exit code to use.
+fn print_results(results: &Result<PipelineValues>, output_format: &OutputFormat) -> i32 {
+    match results {
         Ok(PipelineValues::Void) => {
             println!("Void result.");
             0
         }
         Ok(PipelineValues::IdentifierList(il)) => {
-            for identifier in il.identifiers {
+            for identifier in &il.identifiers {
                 println!("{}", identifier);
             }
             0
         }
+        Ok(PipelineValues::FileList(fl)) => {
+            for file in &fl.files {
+                println!("{}", file);
+            }
+            0
+        }
         Ok(PipelineValues::SymbolList(sl)) => {
-            match sl.from_identifiers {
+            match &sl.from_identifiers {
                 Some(identifiers) => {
                     for (sym, ident) in sl.symbols.iter().zip(identifiers.iter()) {
                         println!("{} from {}", sym, ident);
                     }
                 }
                 None => {
-                    for sym in sl.symbols {
+                    for sym in &sl.symbols {
                         println!("{}", sym);
                     }
                 }
@@ -67,54 +120,163 @@ async fn main() {
             0
         }
         Ok(PipelineValues::SymbolCrossrefInfoList(sl)) => {
-            for symbol_info in sl.symbol_crossref_infos {
-                if output_format == OutputFormat::Concise {
-                    println!("{}", symbol_info.crossref_info);
-                } else if output_format == OutputFormat::Pretty {
-                    if let Ok(pretty) = to_string_pretty(&symbol_info.crossref_info) {
-                        println!("{}", pretty);
-                    }
-                }
+            for symbol_info in &sl.symbol_crossref_infos {
+                print_json(&symbol_info.crossref_info, output_format);
+            }
+            0
+        }
+        Ok(PipelineValues::SymbolInfoList(sil)) => {
+            for symbol_info in &sil.symbol_infos {
+                print_json(symbol_info, output_format);
             }
             0
         }
         Ok(PipelineValues::HtmlExcerpts(he)) => {
-            for file_excerpts in he.by_file {
+            for file_excerpts in &he.by_file {
                 //println!("HTML excerpts from: {}", file_excerpts.file);
-                for str in file_excerpts.excerpts {
+                for str in &file_excerpts.excerpts {
                     println!("{}", str);
                 }
             }
             0
         }
-        Ok(PipelineValues::JsonRecords(jr)) => {
-            for file_records in jr.by_file {
-                for value in file_records.records {
-                    if output_format == OutputFormat::Concise {
-                        println!("{}", value);
-                    } else if output_format == OutputFormat::Pretty {
-                        if let Ok(pretty) = to_string_pretty(&value) {
-                            println!("{}", pretty);
-                        }
-                    }
+        Ok(PipelineValues::AnalysisRecords(ar)) => {
+            for file_records in &ar.by_file {
+                for record in &file_records.records {
+                    print_json(&record.json, output_format);
                 }
             }
             0
         }
-        Ok(PipelineValues::JsonValue(jv)) => {
-            if output_format == OutputFormat::Concise {
-                println!("{}", jv.value);
-            } else if output_format == OutputFormat::Pretty {
-                if let Ok(pretty) = to_string_pretty(&jv.value) {
-                    println!("{}", pretty);
+        Ok(PipelineValues::JsonRecords(jr)) => {
+            for file_records in &jr.by_file {
+                for value in &file_records.records {
+                    print_json(value, output_format);
                 }
             }
             0
         }
+        Ok(PipelineValues::SymbolGraph(graph)) => {
+            match output_format {
+                OutputFormat::Dot => print!("{}", graph.to_dot()),
+                OutputFormat::Mermaid => print!("{}", graph.to_mermaid()),
+                _ => print_json(graph, output_format),
+            }
+            0
+        }
+        Ok(PipelineValues::TextBlocks(tb)) => {
+            for block in &tb.blocks {
+                print!("{}", block);
+            }
+            0
+        }
+        Ok(PipelineValues::JsonValue(jv)) => {
+            print_json(&jv.value, output_format);
+            0
+        }
         Err(err) => {
             println!("Pipeline Error!");
             println!("{:?}", err);
             1
         }
-    });
+    }
+}
+
+/// Run an interactive REPL where each line is a pipeline that's run against a
+/// single long-lived server.  A line starting with `|` receives the previous
+/// line's results as its input.
+async fn run_repl(os_args: Vec<String>) {
+    let opts = ReplOpts::from_iter(os_args.iter());
+    let (server, retry_log) = match make_server(&opts.server_opts) {
+        Ok(made) => made,
+        Err(err) => {
+            println!("Unable to create server: {:?}", err);
+            std::process::exit(1);
+        }
+    };
+    let pipeline = ServerPipeline {
+        server,
+        commands: vec![],
+        retry_log,
+    };
+
+    let mut editor = Editor::<ReplHelper>::new();
+    editor.set_helper(Some(ReplHelper {
+        server: pipeline.server.as_ref(),
+    }));
+    let history_path = std::env::var("HOME")
+        .ok()
+        .map(|home| format!("{}/.searchfox-tool-history", home));
+    if let Some(path) = &history_path {
+        // It's fine for there to be no history yet.
+        let _ = editor.load_history(path);
+    }
+
+    println!(
+        "Pipelines are run against {} tree {}.",
+        opts.server_opts.server, opts.server_opts.tree
+    );
+    println!("Start a line with `|` to pipe in the previous result.  Ctrl-D exits.");
+
+    let mut previous: Option<PipelineValues> = None;
+    loop {
+        // Tab-completion needs to block on the server, which is only allowed
+        // from inside `block_in_place`.
+        let line = match block_in_place(|| editor.readline("searchfox> ")) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(err) => {
+                println!("Readline error: {:?}", err);
+                break;
+            }
+        };
+
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            continue;
+        }
+        editor.add_history_entry(trimmed);
+
+        let (piped, pipeline_str) = match trimmed.strip_prefix('|') {
+            Some(rest) => (true, rest),
+            None => (false, trimmed),
+        };
+        if piped && previous.is_none() {
+            println!("There is no previous result to pipe in.");
+            continue;
+        }
+
+        let (line_opts, commands) = match parse_commands(&os_args[0], pipeline_str) {
+            Ok(parsed) => parsed,
+            Err(ServerError::StickyProblem(ErrorDetails { message, .. })) => {
+                println!("{}", message);
+                continue;
+            }
+            Err(err) => {
+                println!("{:?}", err);
+                continue;
+            }
+        };
+
+        // The previous result is only replaced by a successful pipeline, so a
+        // typo or a failure doesn't lose it.
+        let input = match &previous {
+            Some(prev) if piped => prev.clone(),
+            _ => PipelineValues::Void,
+        };
+        let output_format = line_opts.output_format.unwrap_or(opts.output_format);
+        let results = pipeline.run_commands(&commands, input).await;
+        print_results(&results, &output_format);
+        print_retry_summary(&pipeline.retry_log);
+        if let Ok(values) = results {
+            previous = Some(values);
+        }
+    }
+
+    if let Some(path) = &history_path {
+        if let Err(err) = editor.save_history(path) {
+            println!("Unable to save history: {:?}", err);
+        }
+    }
 }
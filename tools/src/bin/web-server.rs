@@ -17,15 +17,35 @@ use hyper::mime::Mime;
 use hyper::server::{Request, Response};
 use hyper::status::StatusCode;
 use hyper::uri;
+use serde_json::Value;
+use url::form_urlencoded;
 
+use tools::abstract_server::CrossrefLookupOptions;
 use tools::blame;
 use tools::config;
+use tools::file_format::analysis;
+use tools::file_format::crossref_lookup::CrossrefLookupMap;
 use tools::file_format::identifiers::IdentMap;
 use tools::format;
 use tools::git_ops;
 
+/// The most identifier matches `search-identifiers` returns, matching the
+/// router's limit, since each one is demangled while the server is locked.
+const MAX_IDENTIFIER_RESULTS: usize = 500;
+
 struct WebRequest<'a> {
     path: &'a str,
+    /// The (still url-encoded) query string, without the leading `?`.
+    query: &'a str,
+}
+
+impl<'a> WebRequest<'a> {
+    /// Return the (decoded) value of the first query parameter named `name`.
+    fn query_param(&self, name: &str) -> Option<String> {
+        form_urlencoded::parse(self.query.as_bytes())
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.into_owned())
+    }
 }
 
 struct WebResponse {
@@ -127,6 +147,7 @@ fn handle_static(path: String, content_type: Option<&str>) -> WebResponse {
 fn handle(
     cfg: &config::Config,
     ident_map: &HashMap<String, IdentMap>,
+    crossref_map: &HashMap<String, Option<CrossrefLookupMap>>,
     req: WebRequest,
 ) -> WebResponse {
     let path = req.path.to_owned();
@@ -248,6 +269,95 @@ fn handle(
             WebResponse::json(json)
         }
 
+        // ## Stable JSON endpoints for searchfox-tool's `RemoteServer`
+        //
+        // These intentionally expose the same raw data that a `LocalIndex`
+        // would return so that pipelines behave the same locally and remotely.
+
+        // Raw crossref entry for the symbol in the `q` parameter, or `null` if
+        // the symbol is unknown.  `path_prefix=P`, `offset=N`, and `limit=N`
+        // narrow down the uses as described by `CrossrefLookupOptions`.
+        "crossref-lookup" => {
+            let symbol = match req.query_param("q") {
+                Some(symbol) => symbol,
+                None => return WebResponse::not_found(),
+            };
+            let crossref = match crossref_map.get(&tree_name.to_string()) {
+                Some(crossref) => crossref,
+                None => return WebResponse::not_found(),
+            };
+            let options = CrossrefLookupOptions {
+                path_prefix: req.query_param("path_prefix"),
+                offset: req
+                    .query_param("offset")
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(0),
+                limit: req.query_param("limit").and_then(|v| v.parse().ok()),
+            };
+            let value = match crossref {
+                Some(crossref) => match crossref.lookup_with_options(&symbol, &options) {
+                    Ok(value) => value,
+                    Err(err) => return WebResponse::internal_error(format!("{:?}", err)),
+                },
+                None => Value::Null,
+            };
+            WebResponse::json(value.to_string())
+        }
+
+        // Identifier matches for the needle in the `q` parameter as a JSON
+        // array of `{ id, symbol }` objects.  By default this is a
+        // case-insensitive prefix search returning at most
+        // `MAX_IDENTIFIER_RESULTS` matches; `exact=true`, `case=true`, and
+        // `limit=N` (which can only lower the cap) alter that.
+        "search-identifiers" => {
+            let needle = match req.query_param("q") {
+                Some(needle) => needle,
+                None => return WebResponse::not_found(),
+            };
+            let ids = match ident_map.get(&tree_name.to_string()) {
+                Some(ids) => ids,
+                None => return WebResponse::not_found(),
+            };
+            let exact_match = req.query_param("exact").map_or(false, |v| v == "true");
+            let case_sensitive = req.query_param("case").map_or(false, |v| v == "true");
+            let limit = req
+                .query_param("limit")
+                .and_then(|v| v.parse().ok())
+                .filter(|limit| *limit > 0)
+                .map_or(MAX_IDENTIFIER_RESULTS, |limit: usize| {
+                    limit.min(MAX_IDENTIFIER_RESULTS)
+                });
+            let json = ids.lookup_json(&needle, exact_match, !case_sensitive, limit);
+            WebResponse::json(json)
+        }
+
+        // Raw source records from the analysis of the file in the `path`
+        // parameter that cover the 0-based column `col` of the 1-based line
+        // `line`, as a JSON array.
+        "source-records-at" => {
+            let (sf_path, lineno, col) = match (
+                req.query_param("path"),
+                req.query_param("line").and_then(|v| v.parse().ok()),
+                req.query_param("col").and_then(|v| v.parse().ok()),
+            ) {
+                (Some(sf_path), Some(lineno), Some(col)) => (sf_path, lineno, col),
+                _ => return WebResponse::not_found(),
+            };
+            if sf_path.split('/').any(|c| c.is_empty() || c == "." || c == "..") {
+                return WebResponse::not_found();
+            }
+            let tree_config = match cfg.trees.get(*tree_name) {
+                Some(tree_config) => tree_config,
+                None => return WebResponse::not_found(),
+            };
+            let analysis_path =
+                format!("{}/analysis/{}.gz", tree_config.paths.index_path, sf_path);
+            match analysis::read_source_records_at(&analysis_path, lineno, col) {
+                Ok(records) => WebResponse::json(Value::Array(records).to_string()),
+                Err(_) => WebResponse::not_found(),
+            }
+        }
+
         _ => WebResponse::not_found(),
     }
 }
@@ -261,8 +371,9 @@ fn main() {
     println!("{}", cfg.describe_mem_usage());
 
     let ident_map = IdentMap::load(&cfg);
+    let crossref_map = CrossrefLookupMap::load(&cfg);
 
-    let internal_data = Mutex::new((cfg, ident_map));
+    let internal_data = Mutex::new((cfg, ident_map, crossref_map));
 
     let handler = move |req: Request, mut res: Response| {
         if req.method != Method::Get {
@@ -274,9 +385,14 @@ fn main() {
             return;
         }
 
-        let path = match req.uri {
-            uri::RequestUri::AbsolutePath(path) => path,
-            uri::RequestUri::AbsoluteUri(url) => url.path().to_owned(),
+        let (path, query) = match req.uri {
+            uri::RequestUri::AbsolutePath(path) => match path.find('?') {
+                Some(idx) => (path[..idx].to_owned(), path[idx + 1..].to_owned()),
+                None => (path, String::new()),
+            },
+            uri::RequestUri::AbsoluteUri(url) => {
+                (url.path().to_owned(), url.query().unwrap_or("").to_owned())
+            }
             _ => panic!("Unexpected URI"),
         };
 
@@ -284,9 +400,17 @@ fn main() {
             Ok(guard) => guard,
             Err(poisoned) => poisoned.into_inner(),
         };
-        let (ref cfg, ref ident_map) = *guard;
-
-        let response = handle(&cfg, &ident_map, WebRequest { path: &path });
+        let (ref cfg, ref ident_map, ref crossref_map) = *guard;
+
+        let response = handle(
+            &cfg,
+            &ident_map,
+            &crossref_map,
+            WebRequest {
+                path: &path,
+                query: &query,
+            },
+        );
 
         *res.status_mut() = response.status;
         let output = response.output.into_bytes();
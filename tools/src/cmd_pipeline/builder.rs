@@ -3,17 +3,65 @@ use url::Url;
 
 use crate::{
     abstract_server::{
-        make_local_server, make_remote_server, ErrorDetails, ErrorLayer, Result, ServerError,
+        make_caching_server, make_local_server, make_recording_server, make_remote_server,
+        make_replay_server, make_retrying_server, AbstractServer, ErrorDetails, ErrorLayer, Result,
+        RetryLog, ServerError,
     },
-    cmd_pipeline::parser::{Command, OutputFormat, ToolOpts},
+    cmd_pipeline::parser::{Command, OutputFormat, ServerOpts, ToolOpts, COMMAND_NAMES},
 };
 
 use super::{cmd_filter_analysis::FilterAnalysisCommand, cmd_merge_analyses::MergeAnalysesCommand, cmd_crossref_lookup::CrossrefLookupCommand, cmd_search_identifiers::SearchIdentifiersCommand};
+use super::cmd_call_graph::CallGraphCommand;
+use super::cmd_class_hierarchy::ClassHierarchyCommand;
+use super::cmd_field_layout::FieldLayoutCommand;
+use super::cmd_index_diff::IndexDiffCommand;
 use super::cmd_query::QueryCommand;
+use super::cmd_search_files::SearchFilesCommand;
+use super::cmd_search_text::SearchTextCommand;
 use super::cmd_show_html::ShowHtmlCommand;
+use super::cmd_symbol_info::SymbolInfoCommand;
+use super::cmd_symbols_at::SymbolsAtCommand;
+use super::cmd_tee::TeeCommand;
 
 use super::interface::ServerPipeline;
 
+/// Create the local or remote server for the given `--server` and `--tree`,
+/// wrapped so that transient failures are retried and, if a cache dir was
+/// specified, responses are cached.  The cache wraps the retries so that cache
+/// hits never wait on retries, and any recording wraps everything so that it
+/// captures every request the pipeline makes.
+///
+/// If a replay fixture was specified, it's used instead.
+pub fn make_server(
+    opts: &ServerOpts,
+) -> Result<(Box<dyn AbstractServer + Send + Sync>, RetryLog)> {
+    if let Some(fixture_dir) = &opts.replay_fixture {
+        return Ok((make_replay_server(fixture_dir)?, RetryLog::default()));
+    }
+
+    let server = match Url::parse(&opts.server) {
+        Ok(url) => make_remote_server(url, &opts.tree)?,
+        Err(_) => make_local_server(&opts.server, &opts.tree)?,
+    };
+    let (server, retry_log) = make_retrying_server(server, opts.retry_policy());
+    let server = match opts.cache_config()? {
+        Some(config) => make_caching_server(server, &opts.tree, config),
+        None => server,
+    };
+    let server = match &opts.record_fixture {
+        Some(fixture_dir) => make_recording_server(server, fixture_dir)?,
+        None => server,
+    };
+    Ok((server, retry_log))
+}
+
+/// The pipeline-wide options, which come from the first pipeline segment.
+pub struct PipelineOpts {
+    pub server_opts: ServerOpts,
+    /// None if not explicitly specified.
+    pub output_format: Option<OutputFormat>,
+}
+
 /// Build a command pipeline from a shell-y string where we use pipe boundaries
 /// to delineate the separate pipeline steps.
 ///
@@ -21,56 +69,202 @@ use super::interface::ServerPipeline;
 /// then break into separate sub-commands whenever we see a `|`.  We then pass
 /// these sub-commands to the structopt parsing `from_iter` method, taking care
 /// to stuff our binary name into the first arg.
+///
+/// A `tee` step is followed by one or more brace-delimited sub-pipelines, like
+/// `tee { cmd1 | cmd2 } { cmd3 }`, which are each run on a copy of the step's
+/// input and have their results joined.  The braces must be separate words.
+/// Options like `--server` that precede `tee` apply to each branch.
 pub fn build_pipeline(bin_name: &str, arg_str: &str) -> Result<(ServerPipeline, OutputFormat)> {
+    let (opts, commands) = parse_commands(bin_name, arg_str)?;
+    let (server, retry_log) = make_server(&opts.server_opts)?;
+
+    Ok((
+        ServerPipeline {
+            server,
+            commands,
+            retry_log,
+        },
+        opts.output_format.unwrap_or(OutputFormat::Concise),
+    ))
+}
+
+/// Parse a shell-y pipeline string like `build_pipeline` does, but without
+/// creating a server, so that the commands can be run against an existing
+/// server.
+pub fn parse_commands(
+    bin_name: &str,
+    arg_str: &str,
+) -> Result<(PipelineOpts, Vec<Box<dyn PipelineCommand>>)> {
     let all_args = match shell_words::split(arg_str) {
         Ok(parsed) => parsed,
         Err(err) => {
-            return Err(ServerError::StickyProblem(ErrorDetails {
-                layer: ErrorLayer::BadInput,
-                message: err.to_string(),
-            }));
+            return Err(bad_input(err.to_string()));
         }
     };
 
-    let mut server = None;
-    let mut output_format = None;
-    let mut first_time = true;
+    let mut pipeline_opts = None;
+    let commands = parse_segments(bin_name, &all_args, &mut pipeline_opts)?;
+
+    match pipeline_opts {
+        Some(opts) => Ok((opts, commands)),
+        None => Err(bad_input("The pipeline has no commands.".to_string())),
+    }
+}
+
+fn bad_input(message: String) -> ServerError {
+    ServerError::StickyProblem(ErrorDetails {
+        layer: ErrorLayer::BadInput,
+        message,
+    })
+}
+
+/// Split the words on the `|` separators that aren't nested inside `tee`
+/// branch braces.
+fn split_top_level(words: &[String]) -> Result<Vec<&[String]>> {
+    let mut segments = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, word) in words.iter().enumerate() {
+        match word.as_str() {
+            "{" => depth += 1,
+            "}" => {
+                if depth == 0 {
+                    return Err(bad_input("Unbalanced `}` in pipeline.".to_string()));
+                }
+                depth -= 1;
+            }
+            "|" if depth == 0 => {
+                segments.push(&words[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if depth != 0 {
+        return Err(bad_input("Unbalanced `{` in pipeline.".to_string()));
+    }
+    segments.push(&words[start..]);
+    Ok(segments)
+}
+
+/// Parse the `{ pipeline } { pipeline } ...` branches following a `tee`, with
+/// the `prefix` options that preceded the `tee` prepended to each branch.
+fn parse_tee_branches(
+    bin_name: &str,
+    prefix: &[String],
+    words: &[String],
+    pipeline_opts: &mut Option<PipelineOpts>,
+) -> Result<Vec<Vec<Box<dyn PipelineCommand>>>> {
+    let mut branches = vec![];
+    let mut rest = words;
+    while !rest.is_empty() {
+        if rest[0] != "{" {
+            return Err(bad_input(format!(
+                "Expected `{{` to start a tee branch but got `{}`.",
+                rest[0]
+            )));
+        }
+
+        // split_top_level already checked that the braces are balanced.
+        let mut depth = 0;
+        let mut end = 0;
+        for (i, word) in rest.iter().enumerate() {
+            match word.as_str() {
+                "{" => depth += 1,
+                "}" => {
+                    depth -= 1;
+                    if depth == 0 {
+                        end = i;
+                        break;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let mut branch_words = prefix.to_vec();
+        branch_words.extend(rest[1..end].iter().cloned());
+        branches.push(parse_segments(bin_name, &branch_words, pipeline_opts)?);
+        rest = &rest[end + 1..];
+    }
 
+    if branches.is_empty() {
+        return Err(bad_input(
+            "tee needs at least one `{ pipeline }` branch.".to_string(),
+        ));
+    }
+    Ok(branches)
+}
+
+fn parse_segments(
+    bin_name: &str,
+    words: &[String],
+    pipeline_opts: &mut Option<PipelineOpts>,
+) -> Result<Vec<Box<dyn PipelineCommand>>> {
     let mut commands: Vec<Box<dyn PipelineCommand>> = vec![];
 
-    for arg_slices in all_args.split(|v| v == "|") {
+    for arg_slices in split_top_level(words)? {
+        // Only the command word itself can be a `tee`, not an argument that
+        // happens to be "tee".  The command word is the first known command
+        // name, which skips over the leading options and their values.
+        let tee_pos = arg_slices
+            .iter()
+            .position(|w| COMMAND_NAMES.contains(&w.as_str()))
+            .filter(|pos| arg_slices[*pos] == "tee");
+        if let Some(pos) = tee_pos {
+            let branches = parse_tee_branches(
+                bin_name,
+                &arg_slices[..pos],
+                &arg_slices[pos + 1..],
+                pipeline_opts,
+            )?;
+            commands.push(Box::new(TeeCommand { branches }));
+            continue;
+        }
+
         let mut fake_args = vec![bin_name.to_string()];
         fake_args.extend(arg_slices.iter().cloned());
 
         let opts = match ToolOpts::from_iter_safe(fake_args) {
             Ok(opts) => opts,
             Err(err) => {
-                return Err(ServerError::StickyProblem(ErrorDetails {
-                    layer: ErrorLayer::BadInput,
-                    message: err.to_string(),
-                }));
+                return Err(bad_input(err.to_string()));
             }
         };
         //println!("Pipeline segment: {:?}", opts);
 
-        if first_time {
-            server = match Url::parse(&opts.server) {
-                Ok(url) => Some(make_remote_server(url, &opts.tree)?),
-                Err(_) => Some(make_local_server(&opts.server, &opts.tree)?),
-            };
-            output_format = Some(opts.output_format);
-            first_time = false;
+        if pipeline_opts.is_none() {
+            *pipeline_opts = Some(PipelineOpts {
+                server_opts: opts.server_opts,
+                output_format: opts.output_format,
+            });
         }
 
         match opts.cmd {
+            Command::CallGraph(cg) => {
+                commands.push(Box::new(CallGraphCommand { args: cg }))
+            }
+
+            Command::ClassHierarchy(ch) => {
+                commands.push(Box::new(ClassHierarchyCommand { args: ch }))
+            }
+
             Command::CrossrefLookup(cl) => {
                 commands.push(Box::new(CrossrefLookupCommand { args: cl }))
             }
 
+            Command::FieldLayout(fl) => {
+                commands.push(Box::new(FieldLayoutCommand { args: fl }))
+            }
+
             Command::FilterAnalysis(fa) => {
                 commands.push(Box::new(FilterAnalysisCommand { args: fa }));
             }
 
+            Command::IndexDiff(id) => {
+                commands.push(Box::new(IndexDiffCommand { args: id }))
+            }
+
             Command::MergeAnalyses(ma) => {
                 commands.push(Box::new(MergeAnalysesCommand{ args: ma }))
             }
@@ -83,21 +277,75 @@ pub fn build_pipeline(bin_name: &str, arg_str: &str) -> Result<(ServerPipeline,
                 commands.push(Box::new(QueryCommand { args: q }))
             }
 
+            Command::SearchFiles(sf) => {
+                commands.push(Box::new(SearchFilesCommand { args: sf }))
+            }
+
             Command::SearchIdentifiers(si) => {
                 commands.push(Box::new(SearchIdentifiersCommand { args: si }))
             },
 
+            Command::SearchText(st) => {
+                commands.push(Box::new(SearchTextCommand { args: st }))
+            }
+
             Command::ShowHtml(sh) => {
                 commands.push(Box::new(ShowHtmlCommand { args: sh }));
             }
+
+            Command::SymbolInfo(si) => {
+                commands.push(Box::new(SymbolInfoCommand { args: si }));
+            }
+
+            Command::SymbolsAt(sa) => {
+                commands.push(Box::new(SymbolsAtCommand { args: sa }));
+            }
         }
     }
 
-    Ok((
-        ServerPipeline {
-            server: server.unwrap(),
-            commands,
-        },
-        output_format.unwrap(),
-    ))
+    Ok(commands)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(arg_str: &str) -> Result<Vec<Box<dyn PipelineCommand>>> {
+        parse_commands("searchfox-tool", arg_str).map(|(_opts, commands)| commands)
+    }
+
+    #[test]
+    fn test_tee_grammar() {
+        let commands = parse(
+            "--tree=a tee { search-identifiers Foo | crossref-lookup } { search-identifiers Bar } | show-html",
+        );
+        assert_eq!(commands.map(|c| c.len()).ok(), Some(2));
+
+        let (opts, _) =
+            parse_commands("searchfox-tool", "tee { --tree=b query foo } { query bar }")
+                .ok()
+                .unwrap();
+        assert_eq!(opts.server_opts.tree, "b");
+
+        assert!(parse("tee { query foo } | show-html").is_ok());
+        assert!(parse("tee").is_err());
+        assert!(parse("tee query foo").is_err());
+        assert!(parse("tee { query foo").is_err());
+        assert!(parse("tee { query foo } }").is_err());
+    }
+
+    #[test]
+    fn test_tee_as_argument() {
+        // `tee` is only special as the command word.
+        assert_eq!(parse("search-identifiers tee").map(|c| c.len()).ok(), Some(1));
+        assert_eq!(parse("--tree=a query tee").map(|c| c.len()).ok(), Some(1));
+        assert_eq!(
+            parse("search-identifiers tee | crossref-lookup").map(|c| c.len()).ok(),
+            Some(2)
+        );
+        assert_eq!(
+            parse("tee { search-identifiers tee } { query tee }").map(|c| c.len()).ok(),
+            Some(1)
+        );
+    }
 }
@@ -0,0 +1,161 @@
+use std::collections::VecDeque;
+
+use async_trait::async_trait;
+use clap::arg_enum;
+use structopt::StructOpt;
+
+use super::interface::{PipelineCommand, PipelineValues, SymbolGraphBuilder, SymbolList};
+
+use crate::abstract_server::{AbstractServer, CrossrefLookupOptions, Result};
+
+arg_enum! {
+    #[derive(Debug, PartialEq)]
+    pub enum CallGraphDirection {
+        Callees,
+        Callers,
+        Both,
+    }
+}
+
+/// Build a call graph for one or more symbols received via pipeline or as
+/// explicit arguments.
+///
+/// Callees come from the "callees" list in the crossref data.  Callers are
+/// derived by inverting that relationship, using the `contextsym` of each of
+/// the symbol's "uses".  Note that like "callees", this includes any use of
+/// the symbol from within the caller and not just calls.
+#[derive(Debug, StructOpt)]
+pub struct CallGraph {
+    /// Explicit symbols to use as the roots of the graph.
+    symbols: Vec<String>,
+
+    /// Which direction(s) to walk from the root symbols.
+    #[structopt(long, possible_values = &CallGraphDirection::variants(), case_insensitive = true, default_value = "callees")]
+    direction: CallGraphDirection,
+
+    /// How many edges away from the root symbols to traverse.
+    #[structopt(long, default_value = "2")]
+    depth: u32,
+
+    /// Stop adding nodes to the graph once it has this many nodes.
+    #[structopt(long, default_value = "256")]
+    node_limit: usize,
+
+    /// Stop traversing once the graph has this many edges.
+    #[structopt(long, default_value = "1024")]
+    edge_limit: usize,
+}
+
+pub struct CallGraphCommand {
+    pub args: CallGraph,
+}
+
+#[async_trait]
+impl PipelineCommand for CallGraphCommand {
+    async fn execute(
+        &self,
+        server: &Box<dyn AbstractServer + Send + Sync>,
+        input: PipelineValues,
+    ) -> Result<PipelineValues> {
+        let symbol_list = match input {
+            PipelineValues::SymbolList(sl) => sl,
+            PipelineValues::Void => SymbolList {
+                symbols: self.args.symbols.clone(),
+                from_identifiers: None,
+            },
+            // TODO: Figure out a better way to handle a nonsensical pipeline
+            // configuration / usage.
+            _ => {
+                return Ok(PipelineValues::Void);
+            }
+        };
+
+        let walk_callees = self.args.direction != CallGraphDirection::Callers;
+        let walk_callers = self.args.direction != CallGraphDirection::Callees;
+
+        let mut builder = SymbolGraphBuilder::new(self.args.node_limit, self.args.edge_limit);
+
+        // Nodes are traversed breadth-first so that each node's depth is its
+        // shortest distance from a root and so that hitting a limit trims the
+        // most distant parts of the graph.  Because nodes are only traversed
+        // when they are first added, cycles are naturally handled.
+        let mut pending = VecDeque::new();
+        let identifiers = symbol_list.from_identifiers.unwrap_or_default();
+        for (i, sym) in symbol_list.symbols.iter().enumerate() {
+            let pretty = identifiers.get(i).map_or("", |s| s.as_str());
+            if let Some((idx, true)) = builder.ensure_node(sym, pretty, "", 0) {
+                pending.push_back(idx);
+            }
+        }
+
+        'traversal: while let Some(idx) = pending.pop_front() {
+            let depth = builder.node(idx).depth;
+            if depth >= self.args.depth {
+                continue;
+            }
+            let sym = builder.node(idx).sym.clone();
+            let crossref = server
+                .crossref_lookup(&sym, &CrossrefLookupOptions::default())
+                .await?;
+
+            // Fill in anything we didn't know about the node when it was added.
+            if let Some(meta) = crossref.get("meta") {
+                let node = builder.node_mut(idx);
+                if node.kind.is_empty() {
+                    node.kind = meta["kind"].as_str().unwrap_or("").to_string();
+                }
+                if node.pretty == node.sym {
+                    if let Some(pretty) = meta["pretty"].as_str() {
+                        node.pretty = pretty.to_string();
+                    }
+                }
+            }
+
+            let no_values = vec![];
+            if walk_callees {
+                for callee in crossref["callees"].as_array().unwrap_or(&no_values) {
+                    let callee_sym = match callee["sym"].as_str() {
+                        Some(s) => s,
+                        None => continue,
+                    };
+                    let pretty = callee["pretty"].as_str().unwrap_or("");
+                    let kind = callee["kind"].as_str().unwrap_or("");
+                    if let Some((callee_idx, is_new)) =
+                        builder.ensure_node(callee_sym, pretty, kind, depth + 1)
+                    {
+                        if !builder.add_edge(idx, callee_idx, "calls") {
+                            break 'traversal;
+                        }
+                        if is_new {
+                            pending.push_back(callee_idx);
+                        }
+                    }
+                }
+            }
+
+            if walk_callers {
+                for path_hits in crossref["uses"].as_array().unwrap_or(&no_values) {
+                    for line in path_hits["lines"].as_array().unwrap_or(&no_values) {
+                        let caller_sym = match line["contextsym"].as_str() {
+                            Some(s) if !s.is_empty() => s,
+                            _ => continue,
+                        };
+                        let pretty = line["context"].as_str().unwrap_or("");
+                        if let Some((caller_idx, is_new)) =
+                            builder.ensure_node(caller_sym, pretty, "", depth + 1)
+                        {
+                            if !builder.add_edge(caller_idx, idx, "calls") {
+                                break 'traversal;
+                            }
+                            if is_new {
+                                pending.push_back(caller_idx);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(PipelineValues::SymbolGraph(builder.build()))
+    }
+}
@@ -0,0 +1,161 @@
+use std::collections::VecDeque;
+
+use async_trait::async_trait;
+use clap::arg_enum;
+use serde_json::{from_value, Value};
+use structopt::StructOpt;
+
+use super::interface::{PipelineCommand, PipelineValues, SymbolGraphBuilder, SymbolList};
+
+use crate::{
+    abstract_server::{AbstractServer, CrossrefLookupOptions, Result},
+    file_format::analysis::AnalysisStructured,
+};
+
+arg_enum! {
+    #[derive(Debug, PartialEq)]
+    pub enum HierarchyDirection {
+        Ancestors,
+        Descendants,
+        Both,
+    }
+}
+
+/// Build the class hierarchy graph for one or more class symbols received via
+/// pipeline or as explicit arguments.
+///
+/// Ancestors come from the structured record's "supers" and descendants come
+/// from the "subclasses" that `crossref` derives by inverting "supers".  Edges
+/// are "inherits" edges pointing from the subclass to the superclass.  We only
+/// walk ancestors of ancestors and descendants of descendants, so siblings and
+/// the other bases of a subclass are not included.
+#[derive(Debug, StructOpt)]
+pub struct ClassHierarchy {
+    /// Explicit class symbols to use as the roots of the hierarchy.
+    symbols: Vec<String>,
+
+    /// Which direction(s) to walk from the root symbols.
+    #[structopt(long, possible_values = &HierarchyDirection::variants(), case_insensitive = true, default_value = "both")]
+    direction: HierarchyDirection,
+
+    /// How many levels of inheritance to traverse.  Unlimited by default.
+    #[structopt(long)]
+    depth: Option<u32>,
+
+    /// Stop adding nodes to the graph once it has this many nodes.
+    #[structopt(long, default_value = "256")]
+    node_limit: usize,
+
+    /// Stop traversing once the graph has this many edges.
+    #[structopt(long, default_value = "1024")]
+    edge_limit: usize,
+}
+
+pub struct ClassHierarchyCommand {
+    pub args: ClassHierarchy,
+}
+
+/// Extract the typed structured record from crossref data, if it has one.
+fn structured_meta(crossref: &Value) -> Option<AnalysisStructured> {
+    crossref
+        .get("meta")
+        .and_then(|meta| from_value(meta.clone()).ok())
+}
+
+#[async_trait]
+impl PipelineCommand for ClassHierarchyCommand {
+    async fn execute(
+        &self,
+        server: &Box<dyn AbstractServer + Send + Sync>,
+        input: PipelineValues,
+    ) -> Result<PipelineValues> {
+        let symbol_list = match input {
+            PipelineValues::SymbolList(sl) => sl,
+            PipelineValues::Void => SymbolList {
+                symbols: self.args.symbols.clone(),
+                from_identifiers: None,
+            },
+            // TODO: Figure out a better way to handle a nonsensical pipeline
+            // configuration / usage.
+            _ => {
+                return Ok(PipelineValues::Void);
+            }
+        };
+
+        let max_depth = self.args.depth.unwrap_or(u32::MAX);
+        let mut builder = SymbolGraphBuilder::new(self.args.node_limit, self.args.edge_limit);
+
+        // Each pending entry tracks whether we should walk up and/or down from
+        // the node; roots may do both, but everything else keeps going in the
+        // direction it was found.
+        let mut pending = VecDeque::new();
+        let walk_up = self.args.direction != HierarchyDirection::Descendants;
+        let walk_down = self.args.direction != HierarchyDirection::Ancestors;
+        for sym in &symbol_list.symbols {
+            if let Some((idx, true)) = builder.ensure_node(sym, "", "", 0) {
+                pending.push_back((idx, walk_up, walk_down));
+            }
+        }
+
+        'traversal: while let Some((idx, up, down)) = pending.pop_front() {
+            let sym = builder.node(idx).sym.clone();
+            let crossref = server
+                .crossref_lookup(&sym, &CrossrefLookupOptions::default())
+                .await?;
+
+            // We look up every node, even ones we won't traverse past, so that
+            // all nodes have their details filled in.
+            let file = crossref["defs"][0]["path"].as_str().map(|s| s.to_string());
+            let meta = match structured_meta(&crossref) {
+                Some(meta) => meta,
+                None => {
+                    builder.node_mut(idx).file = file;
+                    continue;
+                }
+            };
+            {
+                let node = builder.node_mut(idx);
+                node.pretty = meta.pretty.to_string();
+                node.kind = meta.kind.to_string();
+                node.size_bytes = meta.size_bytes;
+                node.file = file;
+            }
+
+            let depth = builder.node(idx).depth;
+            if depth >= max_depth {
+                continue;
+            }
+
+            if up {
+                for super_info in &meta.supers {
+                    if let Some((super_idx, is_new)) =
+                        builder.ensure_node(&super_info.sym, &super_info.pretty, "", depth + 1)
+                    {
+                        if !builder.add_edge(idx, super_idx, "inherits") {
+                            break 'traversal;
+                        }
+                        if is_new {
+                            pending.push_back((super_idx, true, false));
+                        }
+                    }
+                }
+            }
+
+            if down {
+                for sub_sym in &meta.subclass_syms {
+                    if let Some((sub_idx, is_new)) = builder.ensure_node(sub_sym, "", "", depth + 1)
+                    {
+                        if !builder.add_edge(sub_idx, idx, "inherits") {
+                            break 'traversal;
+                        }
+                        if is_new {
+                            pending.push_back((sub_idx, false, true));
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(PipelineValues::SymbolGraph(builder.build()))
+    }
+}
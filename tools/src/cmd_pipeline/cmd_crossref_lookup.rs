@@ -5,7 +5,7 @@ use super::interface::{
     PipelineCommand, PipelineValues, SymbolCrossrefInfo, SymbolCrossrefInfoList, SymbolList,
 };
 
-use crate::abstract_server::{AbstractServer, Result};
+use crate::abstract_server::{AbstractServer, CrossrefLookupOptions, Result};
 
 /// Return the crossref data for one or more symbols received via pipeline or as
 /// explicit arguments.
@@ -13,6 +13,18 @@ use crate::abstract_server::{AbstractServer, Result};
 pub struct CrossrefLookup {
     /// Explicit symbols to lookup.
     symbols: Vec<String>,
+
+    /// Only return the uses in paths starting with this prefix.
+    #[structopt(long)]
+    path_prefix: Option<String>,
+
+    /// The number of paths with uses to skip, for paging through the uses.
+    #[structopt(long, default_value = "0")]
+    offset: usize,
+
+    /// The maximum number of paths with uses to return.
+    #[structopt(long)]
+    limit: Option<usize>,
     // TODO: It might make sense to provide a way to filter the looked up data
     // by kind, although that could of course be its own command too.
 }
@@ -44,9 +56,15 @@ impl PipelineCommand for CrossrefLookupCommand {
             }
         };
 
+        let options = CrossrefLookupOptions {
+            path_prefix: self.args.path_prefix.clone(),
+            offset: self.args.offset,
+            limit: self.args.limit,
+        };
+
         let mut symbol_crossref_infos = vec![];
         for symbol in symbol_list.symbols {
-            let info = server.crossref_lookup(&symbol).await?;
+            let info = server.crossref_lookup(&symbol, &options).await?;
             symbol_crossref_infos.push(SymbolCrossrefInfo {
                 symbol,
                 crossref_info: info,
@@ -0,0 +1,360 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use async_trait::async_trait;
+use serde_json::{from_value, Value};
+use structopt::StructOpt;
+
+use super::interface::{PipelineCommand, PipelineValues, SymbolList, TextBlocks};
+
+use crate::{
+    abstract_server::{AbstractServer, CrossrefLookupOptions, Result},
+    file_format::analysis::{AnalysisStructured, StructuredFieldInfo},
+};
+
+/// Render a pahole-style memory layout table for one or more class/struct
+/// symbols received via pipeline or as explicit arguments, based on the field
+/// information in their structured records.
+///
+/// When the layout differs between platforms, the layout of each platform
+/// variant is rendered followed by a summary of the fields that differ.
+#[derive(Debug, StructOpt)]
+pub struct FieldLayout {
+    /// Explicit class/struct symbols to render the layout of.
+    symbols: Vec<String>,
+}
+
+pub struct FieldLayoutCommand {
+    pub args: FieldLayout,
+}
+
+/// Describe a span of bits in terms of bytes where possible.
+fn describe_bits(bits: u64) -> String {
+    match (bits / 8, bits % 8) {
+        (bytes, 0) => format!("{} bytes", bytes),
+        (0, bits) => format!("{} bits", bits),
+        (bytes, bits) => format!("{} bytes {} bits", bytes, bits),
+    }
+}
+
+/// The platforms list `merge-analyses` stashes in `extra` for records that
+/// differ between platforms.
+fn platforms_of(st: &AnalysisStructured) -> Vec<String> {
+    st.extra
+        .get("platforms")
+        .and_then(|p| p.as_array())
+        .map(|arr| {
+            arr.iter()
+                .filter_map(|s| s.as_str().map(|s| s.to_string()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// The field's own name without the qualifying class name prefix.
+fn short_field_name(field: &StructuredFieldInfo) -> &str {
+    field.pretty.rsplit("::").next().unwrap_or("")
+}
+
+/// Return the (start, end) of the field in bits relative to the start of the
+/// structure.  Fields whose size we don't know are treated as 0-sized.
+fn field_bit_range(field: &StructuredFieldInfo) -> (u64, u64) {
+    let start_bytes = field.offset_bytes as u64 * 8;
+    match &field.bit_positions {
+        Some(bits) => {
+            let start = start_bytes + bits.begin as u64;
+            (start, start + bits.width as u64)
+        }
+        None => (
+            start_bytes,
+            start_bytes + field.size_bytes.unwrap_or(0) as u64 * 8,
+        ),
+    }
+}
+
+/// Render the layout of a single platform variant of a structure.
+pub fn render_layout(st: &AnalysisStructured) -> String {
+    let mut out = String::new();
+    let platforms = platforms_of(st);
+    if platforms.is_empty() {
+        let _ = writeln!(out, "{} {} {{", st.kind, st.pretty);
+    } else {
+        let _ = writeln!(
+            out,
+            "{} {} {{ /* {} */",
+            st.kind,
+            st.pretty,
+            platforms.join(", ")
+        );
+    }
+
+    let mut fields: Vec<&StructuredFieldInfo> = st.fields.iter().collect();
+    fields.sort_by_key(|f| field_bit_range(f));
+
+    let mut cur_end = 0;
+    let mut hole_count = 0;
+    let mut hole_bits = 0;
+    for (i, field) in fields.iter().enumerate() {
+        let (start, end) = field_bit_range(field);
+        if start > cur_end {
+            if i == 0 {
+                // There are no field records for base classes or vtable
+                // pointers, so this isn't a hole we can do anything about.
+                let _ = writeln!(
+                    out,
+                    "\t/* {} of bases / vtable pointer */",
+                    describe_bits(start - cur_end)
+                );
+            } else {
+                hole_count += 1;
+                hole_bits += start - cur_end;
+                let _ = writeln!(out, "\t/* XXX {} hole */", describe_bits(start - cur_end));
+            }
+        }
+
+        let decl = format!("{} {}", field.type_pretty, short_field_name(field));
+        let location = match &field.bit_positions {
+            Some(bits) => format!(
+                "{:>5}:{:<2} {:>4}",
+                field.offset_bytes,
+                bits.begin,
+                format!("{}b", bits.width)
+            ),
+            None => format!(
+                "{:>8} {:>4}",
+                field.offset_bytes,
+                field
+                    .size_bytes
+                    .map_or("?".to_string(), |size| size.to_string())
+            ),
+        };
+        let _ = writeln!(out, "\t{:<48} /* {} */", decl, location);
+
+        cur_end = cur_end.max(end);
+    }
+
+    let mut summary = vec![];
+    match st.size_bytes {
+        Some(size) => {
+            summary.push(format!("size: {}", size));
+            let size_bits = size as u64 * 8;
+            if fields.is_empty() {
+                // Nothing to say about padding.
+            } else if size_bits > cur_end {
+                summary.push(format!("padding: {}", describe_bits(size_bits - cur_end)));
+            }
+        }
+        None => summary.push("size: ?".to_string()),
+    }
+    summary.push(format!("fields: {}", fields.len()));
+    if hole_count > 0 {
+        summary.push(format!(
+            "holes: {} ({})",
+            hole_count,
+            describe_bits(hole_bits)
+        ));
+    }
+    let _ = writeln!(out, "\t/* {} */", summary.join(", "));
+    let _ = writeln!(out, "}};");
+
+    out
+}
+
+/// Summarize the fields whose offset or size differs between the variants,
+/// including fields that only some of the variants have (ex: `#ifdef`'d ones).
+fn render_differences(variants: &[AnalysisStructured]) -> String {
+    // field name => (offset, size) => platforms
+    let mut by_field: BTreeMap<&str, BTreeMap<(u64, u64), Vec<String>>> = BTreeMap::new();
+    for variant in variants {
+        let platforms = platforms_of(variant).join(", ");
+        for field in &variant.fields {
+            let (start, end) = field_bit_range(field);
+            by_field
+                .entry(short_field_name(field))
+                .or_default()
+                .entry((start, end - start))
+                .or_default()
+                .push(platforms.clone());
+        }
+    }
+
+    let mut out = String::new();
+    for (name, layouts) in by_field {
+        let absent: Vec<String> = variants
+            .iter()
+            .filter(|variant| !variant.fields.iter().any(|f| short_field_name(f) == name))
+            .map(|variant| platforms_of(variant).join(", "))
+            .collect();
+        if layouts.len() < 2 && absent.is_empty() {
+            continue;
+        }
+        let mut described: Vec<String> = layouts
+            .iter()
+            .map(|((start, size), platforms)| {
+                format!(
+                    "offset {} size {} ({})",
+                    describe_bits(*start),
+                    describe_bits(*size),
+                    platforms.join("; ")
+                )
+            })
+            .collect();
+        if !absent.is_empty() {
+            described.push(format!("absent on {}", absent.join("; ")));
+        }
+        let _ = writeln!(out, "\t{}: {}", name, described.join(" vs "));
+    }
+
+    if out.is_empty() {
+        "/* Field offsets and sizes are the same on all platforms. */\n".to_string()
+    } else {
+        format!("/* Fields that differ between platforms:\n{}*/\n", out)
+    }
+}
+
+/// Render the layout for the structured record in the given crossref data,
+/// including any per-platform variants.
+fn render_crossref_layout(symbol: &str, crossref: &Value) -> String {
+    let meta: AnalysisStructured = match crossref
+        .get("meta")
+        .and_then(|meta| from_value(meta.clone()).ok())
+    {
+        Some(meta) => meta,
+        None => return format!("/* No structured information for {} */\n", symbol),
+    };
+
+    let mut variants: Vec<AnalysisStructured> = meta
+        .extra
+        .get("variants")
+        .and_then(|v| from_value(v.clone()).ok())
+        .unwrap_or_default();
+    if variants.is_empty() {
+        return render_layout(&meta);
+    }
+
+    variants.insert(0, meta);
+    let mut out = String::new();
+    for variant in &variants {
+        out.push_str(&render_layout(variant));
+    }
+    out.push_str(&render_differences(&variants));
+    out
+}
+
+#[async_trait]
+impl PipelineCommand for FieldLayoutCommand {
+    async fn execute(
+        &self,
+        server: &Box<dyn AbstractServer + Send + Sync>,
+        input: PipelineValues,
+    ) -> Result<PipelineValues> {
+        let symbol_list = match input {
+            PipelineValues::SymbolList(sl) => sl,
+            PipelineValues::Void => SymbolList {
+                symbols: self.args.symbols.clone(),
+                from_identifiers: None,
+            },
+            // TODO: Figure out a better way to handle a nonsensical pipeline
+            // configuration / usage.
+            _ => {
+                return Ok(PipelineValues::Void);
+            }
+        };
+
+        let mut blocks = vec![];
+        for symbol in symbol_list.symbols {
+            let crossref = server
+                .crossref_lookup(&symbol, &CrossrefLookupOptions::default())
+                .await?;
+            blocks.push(render_crossref_layout(&symbol, &crossref));
+        }
+
+        Ok(PipelineValues::TextBlocks(TextBlocks { blocks }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_render_layout() {
+        let st: AnalysisStructured = from_value(json!({
+            "structured": 1,
+            "pretty": "ns::Foo",
+            "sym": "T_ns::Foo",
+            "kind": "struct",
+            "sizeBytes": 24,
+            "fields": [
+                { "pretty": "ns::Foo::mB", "type": "void *", "offsetBytes": 8, "sizeBytes": 8 },
+                { "pretty": "ns::Foo::mA", "type": "int32_t", "offsetBytes": 0, "sizeBytes": 4 },
+                { "pretty": "ns::Foo::mC", "type": "unsigned int", "offsetBytes": 16,
+                  "bitPositions": { "begin": 0, "width": 3 } },
+                { "pretty": "ns::Foo::mD", "type": "unsigned int", "offsetBytes": 16,
+                  "bitPositions": { "begin": 5, "width": 2 } },
+            ],
+        }))
+        .unwrap();
+
+        let rendered = render_layout(&st);
+        let lines: Vec<&str> = rendered.lines().collect();
+        assert_eq!(lines[0], "struct ns::Foo {");
+        assert!(lines[1].starts_with("\tint32_t mA "));
+        assert_eq!(lines[2], "\t/* XXX 4 bytes hole */");
+        assert!(lines[3].starts_with("\tvoid * mB "));
+        assert!(lines[4].ends_with("/*    16:0    3b */"));
+        assert_eq!(lines[5], "\t/* XXX 2 bits hole */");
+        assert_eq!(
+            lines[7],
+            "\t/* size: 24, padding: 7 bytes 1 bits, fields: 4, holes: 2 (4 bytes 2 bits) */"
+        );
+        assert_eq!(lines[8], "};");
+    }
+
+    fn variant(platform: &str, fields: &[(&str, u32, u32)]) -> Value {
+        let fields: Vec<Value> = fields
+            .iter()
+            .map(|(name, offset, size)| {
+                json!({ "pretty": format!("Foo::{}", name), "type": "int",
+                        "offsetBytes": offset, "sizeBytes": size })
+            })
+            .collect();
+        json!({
+            "structured": 1,
+            "pretty": "Foo",
+            "sym": "T_Foo",
+            "kind": "class",
+            "sizeBytes": 16,
+            "platforms": [platform],
+            "fields": fields,
+        })
+    }
+
+    #[test]
+    fn test_render_variant_differences() {
+        let mut meta = variant("linux64", &[("mA", 0, 4), ("mDebug", 4, 4), ("mB", 8, 8)]);
+        meta["variants"] = json!([
+            variant("win64", &[("mA", 0, 4), ("mB", 8, 8)]),
+            variant("arm32", &[("mA", 0, 4), ("mDebug", 4, 4), ("mB", 8, 4)]),
+        ]);
+        let rendered = render_crossref_layout("T_Foo", &json!({ "meta": meta }));
+        assert_eq!(rendered.matches("class Foo {").count(), 3);
+        assert!(rendered.ends_with(concat!(
+            "/* Fields that differ between platforms:\n",
+            "\tmB: offset 8 bytes size 4 bytes (arm32) vs offset 8 bytes size 8 bytes (linux64; win64)\n",
+            "\tmDebug: offset 4 bytes size 4 bytes (linux64; arm32) vs absent on win64\n",
+            "*/\n"
+        )));
+
+        // Variants that only differ in their platforms have nothing to report.
+        let same: Vec<AnalysisStructured> = ["linux64", "win64"]
+            .iter()
+            .map(|platform| from_value(variant(platform, &[("mA", 0, 4)])).unwrap())
+            .collect();
+        assert_eq!(
+            render_differences(&same),
+            "/* Field offsets and sizes are the same on all platforms. */\n"
+        );
+    }
+}
@@ -1,20 +1,66 @@
+use std::collections::HashSet;
+
 use async_trait::async_trait;
+use futures_util::stream::{self, StreamExt, TryStreamExt};
+use regex::RegexBuilder;
+use serde_json::{from_value, json};
 use structopt::StructOpt;
-use tokio_stream::StreamExt;
 
 use super::interface::{
-    JsonRecords, PipelineCommand, PipelineValues, RecordType, SymbolicQueryOpts,
+    AnalysisRecord, AnalysisRecords, AnalysisRecordsByFile, PipelineCommand, PipelineValues,
+    RecordType, SymbolicQueryOpts,
 };
 use crate::{
-    abstract_server::{AbstractServer, Result},
-    cmd_pipeline::interface::JsonRecordsByFile,
+    abstract_server::{
+        parse_path_filter, AbstractServer, ErrorDetails, ErrorLayer, FileListKind, Result,
+        ServerError,
+    },
+    file_format::analysis::{AnalysisKind, AnalysisUnion},
 };
 
-/// Filter the contents of a single analysis file.
+/// Parse a line range argument of the form "N" or "START-END" (inclusive).
+fn parse_line_range(s: &str) -> std::result::Result<(u32, u32), String> {
+    let mut pieces = s.splitn(2, '-');
+    let start = pieces.next().unwrap_or("");
+    let end = pieces.next().unwrap_or(start);
+    match (start.trim().parse(), end.trim().parse()) {
+        (Ok(start), Ok(end)) if start <= end => Ok((start, end)),
+        _ => Err(format!("bad line range: {}", s)),
+    }
+}
+
+/// Does the path look like a glob rather than an exact path?
+fn is_glob(path: &str) -> bool {
+    path.contains(|c| c == '*' || c == '?' || c == '{')
+}
+
+/// Filter the contents of one or more analysis files.
+///
+/// Paths containing glob characters and any `--path-filter` are resolved
+/// against the index's file lists, in which case files without analysis data
+/// and files without any matching records are omitted from the results.
+///
+/// If a file list (ex: from `search-files`) is piped in, it's used in place of
+/// the index's file lists, and all of its files are used if no globs or path
+/// filter were specified.
 #[derive(Debug, StructOpt)]
 pub struct FilterAnalysis {
-    /// Tree-relative analysis file path
-    file: String,
+    /// Tree-relative analysis file paths or path globs (ex: "dom/**/*.cpp"),
+    /// which must match the entire path.
+    files: Vec<String>,
+
+    /// Path filter using the same syntax and unanchored semantics as `path:`
+    /// in search queries (ex: "dom/").
+    #[structopt(long)]
+    path_filter: Option<String>,
+
+    /// The maximum number of files that globs and path filters may resolve to.
+    #[structopt(long, default_value = "1000")]
+    max_files: usize,
+
+    /// The maximum number of files to fetch and filter at the same time.
+    #[structopt(long, default_value = "8")]
+    concurrency: usize,
 
     #[structopt(long, short, possible_values = &RecordType::variants(), case_insensitive = true)]
     record_type: Option<Vec<RecordType>>,
@@ -22,6 +68,15 @@ pub struct FilterAnalysis {
     #[structopt(long, short)]
     kind: Option<String>,
 
+    /// Only include target records whose `contextsym` is this exact symbol.
+    #[structopt(long)]
+    contextsym: Option<String>,
+
+    /// Only include records on the given line or inclusive "START-END" line
+    /// range.
+    #[structopt(long, parse(try_from_str = parse_line_range))]
+    lines: Option<(u32, u32)>,
+
     #[structopt(flatten)]
     query_opts: SymbolicQueryOpts,
 }
@@ -30,82 +85,352 @@ pub struct FilterAnalysisCommand {
     pub args: FilterAnalysis,
 }
 
-/// ### Implementation Note
-/// Filtering is currently performed via generic JSON rather than the strongly
-/// typed `analysis.rs` types, but this pre-dates the change to using serde-json
-/// and it probably makes sense to switch to using the raw types.
-#[async_trait]
-impl PipelineCommand for FilterAnalysisCommand {
-    async fn execute(
-        &self,
-        server: &Box<dyn AbstractServer + Send + Sync>,
-        _input: PipelineValues,
-    ) -> Result<PipelineValues> {
-        let mut filtered = server.fetch_raw_analysis(&self.args.file).await?;
+impl FilterAnalysisCommand {
+    fn matches(&self, record: &AnalysisRecord) -> bool {
+        let args = &self.args;
+
+        // ## Filter by location
+        if let (Some((start, end)), Some(lineno)) = (args.lines, record.lineno()) {
+            if lineno < start || lineno > end {
+                return false;
+            }
+        }
+
+        // The rest of the filters need a typed record, and records that aren't
+        // one are passed through rather than silently dropped.
+        let wl = match &record.record {
+            Some(wl) => wl,
+            None => return true,
+        };
 
         // ## Filter by record type
-        if let Some(record_types) = &self.args.record_type {
-            filtered = Box::pin(filtered.filter(move |val| {
-                // Record type is currently indicated via boolean presence of
-                // "source", "target", or "structured" so check for the
-                // stringified version of the enum value.
-                for rt in record_types {
-                    if val.get(rt.to_string().to_lowercase()).is_some() {
-                        return true;
-                    }
-                }
-                false
-            }));
+        if let Some(record_types) = &args.record_type {
+            let matched = record_types.iter().any(|rt| {
+                matches!(
+                    (rt, &wl.data),
+                    (RecordType::Source, AnalysisUnion::Source(_))
+                        | (RecordType::Target, AnalysisUnion::Target(_))
+                        | (RecordType::Structured, AnalysisUnion::Structured(_))
+                )
+            });
+            if !matched {
+                return false;
+            }
         }
 
         // ## Filter by kind
-        if let Some(kind) = &self.args.kind {
+        if let Some(kind) = &args.kind {
             // kind varies by record type:
             // - target: "kind" is a single valued attribute
             // - source: kind is baked into the comma-delimited "syntax"
-            filtered = Box::pin(filtered.filter(move |val| {
-                match (val["source"].is_number(), val["target"].is_number()) {
-                    // source: consult "syntax"
-                    (true, _) => match val["syntax"].as_str() {
-                        None => false,
-                        Some(actual) => actual.split(",").next().unwrap_or("") == kind,
-                    },
-                    // target: consult "kind"
-                    (false, true) => match val["kind"].as_str() {
-                        None => false,
-                        Some(actual) => actual == kind,
-                    },
-                    _ => false,
+            // - structured: "kind" is the kind of symbol, like "class"
+            let matched = match &wl.data {
+                AnalysisUnion::Source(src) => src
+                    .syntax
+                    .first()
+                    .map_or(false, |actual| actual.as_str() == kind),
+                AnalysisUnion::Target(tgt) => {
+                    from_value::<AnalysisKind>(json!(kind)).map_or(false, |kind| kind == tgt.kind)
                 }
-            }));
+                AnalysisUnion::Structured(st) => st.kind.as_str() == kind,
+            };
+            if !matched {
+                return false;
+            }
         }
 
         // ## Filter by symbol
-        if let Some(symbol) = &self.args.query_opts.symbol {
-            // "sym" is optionally
-            filtered = Box::pin(filtered.filter(move |val| match val["sym"].as_str() {
-                None => false,
-                Some(actual) => actual.split(",").any(|s| s == symbol),
-            }));
+        if let Some(symbol) = &args.query_opts.symbol {
+            let matched = match &wl.data {
+                AnalysisUnion::Source(src) => src.sym.iter().any(|s| s.as_str() == symbol),
+                AnalysisUnion::Target(tgt) => tgt.sym.as_str() == symbol,
+                AnalysisUnion::Structured(st) => st.sym.as_str() == symbol,
+            };
+            if !matched {
+                return false;
+            }
+        }
+
+        // ## Filter by contextsym
+        if let Some(contextsym) = &args.contextsym {
+            let matched = match &wl.data {
+                AnalysisUnion::Target(tgt) => tgt.contextsym.as_str() == contextsym,
+                _ => false,
+            };
+            if !matched {
+                return false;
+            }
         }
 
         // ## Filter by identifier
-        if let Some(identifier) = &self.args.query_opts.identifier {
-            filtered = Box::pin(filtered.filter(move |val| {
-                match val["pretty"].as_str() {
-                    None => false,
-                    // source records have a space-delimited prefix that we want
-                    // to skip; by using split/last we handle it being optional.
-                    Some(actual) => actual.split(" ").last().unwrap_or("") == identifier,
+        if let Some(identifier) = &args.query_opts.identifier {
+            let pretty = match &wl.data {
+                AnalysisUnion::Source(src) => &src.pretty,
+                AnalysisUnion::Target(tgt) => &tgt.pretty,
+                AnalysisUnion::Structured(st) => &st.pretty,
+            };
+            // source records have a space-delimited prefix that we want to
+            // skip; by using split/last we handle it being optional.
+            if pretty.split(' ').last().unwrap_or("") != identifier {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+impl FilterAnalysisCommand {
+    /// Resolve our path arguments into a list of files, returning true as the
+    /// second value if globs, filters or a piped file list were involved.
+    async fn resolve_files(
+        &self,
+        server: &Box<dyn AbstractServer + Send + Sync>,
+        piped_files: Option<Vec<String>>,
+    ) -> Result<(Vec<String>, bool)> {
+        let mut exact = vec![];
+        let mut patterns = vec![];
+        for file in &self.args.files {
+            if is_glob(file) {
+                patterns.push(format!("^{}$", parse_path_filter(file)));
+            } else {
+                exact.push(file.clone());
+            }
+        }
+        if let Some(filter) = &self.args.path_filter {
+            patterns.push(parse_path_filter(filter));
+        }
+
+        if patterns.is_empty() && piped_files.is_none() {
+            if exact.is_empty() {
+                return Err(ServerError::StickyProblem(ErrorDetails {
+                    layer: ErrorLayer::BadInput,
+                    message: "filter-analysis needs files, a --path-filter or a piped file list"
+                        .to_string(),
+                }));
+            }
+            return Ok((exact, false));
+        }
+
+        let mut regexes = vec![];
+        for pattern in patterns {
+            match RegexBuilder::new(&pattern).case_insensitive(true).build() {
+                Ok(re) => regexes.push(re),
+                Err(err) => {
+                    return Err(ServerError::StickyProblem(ErrorDetails {
+                        layer: ErrorLayer::BadInput,
+                        message: err.to_string(),
+                    }));
+                }
+            }
+        }
+
+        let candidates = match piped_files {
+            Some(piped_files) => piped_files,
+            None => {
+                let mut candidates = server.fetch_file_list(FileListKind::Repo).await?;
+                candidates.extend(server.fetch_file_list(FileListKind::Objdir).await?);
+                candidates
+            }
+        };
+        let too_many = || {
+            ServerError::StickyProblem(ErrorDetails {
+                layer: ErrorLayer::BadInput,
+                message: format!(
+                    "more than {} files matched, which exceeds --max-files",
+                    self.args.max_files
+                ),
+            })
+        };
+
+        let mut seen: HashSet<String> = exact.iter().cloned().collect();
+        let mut files = exact;
+        if files.len() > self.args.max_files {
+            return Err(too_many());
+        }
+        for path in candidates {
+            // Without any patterns, every piped file is used.
+            let matched = regexes.is_empty() || regexes.iter().any(|re| re.is_match(&path));
+            if matched && seen.insert(path.clone()) {
+                files.push(path);
+                // The file lists can be huge, so bail as soon as we know.
+                if files.len() > self.args.max_files {
+                    return Err(too_many());
                 }
-            }));
+            }
         }
 
-        Ok(PipelineValues::JsonRecords(JsonRecords {
-            by_file: vec![JsonRecordsByFile {
-                file: self.args.file.clone(),
-                records: filtered.collect().await,
-            }],
-        }))
+        Ok((files, true))
+    }
+
+    /// Fetch and filter a single analysis file, returning None if the file
+    /// should be omitted from the results.
+    async fn filter_file(
+        &self,
+        server: &Box<dyn AbstractServer + Send + Sync>,
+        file: String,
+        expanded: bool,
+    ) -> Result<Option<AnalysisRecordsByFile>> {
+        let mut values = match server.fetch_raw_analysis(&file).await {
+            Ok(values) => values,
+            // Plenty of files (ex: text files) have no analysis data, so
+            // when we're expanding globs we don't want that to be an error.
+            Err(ServerError::StickyProblem(_)) if expanded => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        let mut records = vec![];
+        while let Some(value) = values.next().await {
+            let record = AnalysisRecord::from_json(value?);
+            if self.matches(&record) {
+                records.push(record);
+            }
+        }
+
+        if expanded && records.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(AnalysisRecordsByFile { file, records }))
+    }
+}
+
+/// Records are converted to their typed `analysis.rs` representations as they
+/// are streamed in and only the records that pass the filters are retained.
+/// Records that don't convert (ex: from a newer indexer) are kept as JSON and
+/// are only subject to the `--lines` filter.
+///
+/// Up to `--concurrency` files are processed at once, but the results are
+/// always in the same order as the resolved files.
+#[async_trait]
+impl PipelineCommand for FilterAnalysisCommand {
+    async fn execute(
+        &self,
+        server: &Box<dyn AbstractServer + Send + Sync>,
+        input: PipelineValues,
+    ) -> Result<PipelineValues> {
+        let piped_files = match input {
+            PipelineValues::FileList(fl) => Some(fl.files),
+            _ => None,
+        };
+        let (files, expanded) = self.resolve_files(server, piped_files).await?;
+
+        let results: Vec<Option<AnalysisRecordsByFile>> = stream::iter(files)
+            .map(|file| self.filter_file(server, file, expanded))
+            .buffered(self.args.concurrency.max(1))
+            .try_collect()
+            .await?;
+        let by_file = results.into_iter().flatten().collect();
+
+        Ok(PipelineValues::AnalysisRecords(AnalysisRecords { by_file }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::abstract_server::stub_server::StubServer;
+    use serde_json::json;
+
+    fn filter_command(args: &[&str]) -> FilterAnalysisCommand {
+        FilterAnalysisCommand {
+            args: FilterAnalysis::from_iter(["filter-analysis"].iter().chain(args)),
+        }
+    }
+
+    fn resolved_files(result: Result<(Vec<String>, bool)>) -> Vec<String> {
+        match result {
+            Ok((files, expanded)) => {
+                assert!(expanded);
+                files
+            }
+            Err(err) => panic!("Expected files, got {:?}", err),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resolve_files() {
+        let stub = StubServer {
+            repo_files: vec![
+                "dom/a.cpp".to_string(),
+                "dom/b.h".to_string(),
+                "js/c.cpp".to_string(),
+            ],
+            objdir_files: vec!["__GENERATED__/dom/d.cpp".to_string()],
+            ..StubServer::default()
+        };
+        let server: Box<dyn AbstractServer + Send + Sync> = Box::new(stub);
+
+        // Exact paths come first and aren't repeated by matching globs.
+        let files = filter_command(&["js/c.cpp", "**/*.cpp"])
+            .resolve_files(&server, None)
+            .await;
+        assert_eq!(
+            resolved_files(files),
+            vec!["js/c.cpp", "dom/a.cpp", "__GENERATED__/dom/d.cpp"]
+        );
+
+        let files = filter_command(&["--path-filter", "dom/"])
+            .resolve_files(&server, None)
+            .await;
+        assert_eq!(
+            resolved_files(files),
+            vec!["dom/a.cpp", "dom/b.h", "__GENERATED__/dom/d.cpp"]
+        );
+
+        assert!(matches!(
+            filter_command(&["--max-files", "2", "**/*.cpp"])
+                .resolve_files(&server, None)
+                .await,
+            Err(ServerError::StickyProblem(ErrorDetails {
+                layer: ErrorLayer::BadInput,
+                ..
+            }))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_keeps_untyped_records() {
+        let untyped = json!({ "loc": "2:0-3", "something": "new" });
+        let mut stub = StubServer::default();
+        stub.analysis.insert(
+            "a.cpp".to_string(),
+            vec![
+                json!({ "loc": "1:0-3", "target": 1, "kind": "def", "pretty": "foo",
+                        "sym": "_Z3foov", "context": "", "contextsym": "" }),
+                untyped.clone(),
+                json!({ "loc": "3:0-3", "target": 1, "kind": "use", "pretty": "foo",
+                        "sym": "_Z3foov", "context": "", "contextsym": "" }),
+                json!({ "loc": "garbage", "target": 1 }),
+            ],
+        );
+        let server: Box<dyn AbstractServer + Send + Sync> = Box::new(stub);
+
+        let filtered = |args: &'static [&'static str]| {
+            let server = &server;
+            async move {
+                match filter_command(args)
+                    .execute(server, PipelineValues::Void)
+                    .await
+                {
+                    Ok(PipelineValues::AnalysisRecords(mut ar)) => ar.by_file.remove(0).records,
+                    _ => panic!("Expected analysis records"),
+                }
+            }
+        };
+
+        let records = filtered(&["a.cpp"]).await;
+        let lines: Vec<Option<u32>> = records.iter().map(|r| r.lineno()).collect();
+        assert_eq!(lines, vec![Some(1), Some(2), Some(3), None]);
+        assert!(records[1].record.is_none());
+        assert_eq!(records[1].json, untyped);
+
+        // The typed filters only apply to typed records.
+        let records = filtered(&["a.cpp", "--kind", "use"]).await;
+        let lines: Vec<Option<u32>> = records.iter().map(|r| r.lineno()).collect();
+        assert_eq!(lines, vec![Some(2), Some(3), None]);
+
+        let records = filtered(&["a.cpp", "--lines", "2-3"]).await;
+        let lines: Vec<Option<u32>> = records.iter().map(|r| r.lineno()).collect();
+        assert_eq!(lines, vec![Some(2), Some(3), None]);
     }
 }
@@ -0,0 +1,430 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+
+use async_trait::async_trait;
+use futures_util::future::try_join;
+use futures_util::stream::{self, StreamExt, TryStreamExt};
+use serde::Serialize;
+use serde_json::{to_value, Value};
+use structopt::StructOpt;
+
+use super::interface::{JsonValue, PipelineCommand, PipelineValues};
+use crate::abstract_server::{
+    make_local_server_for_index, AbstractServer, FileListKind, Result, ServerError,
+};
+
+/// Compare two local indexes, such as the results of indexing a tree before and
+/// after an indexer change, reporting:
+/// - symbols added to or removed from the crossref database
+/// - symbols whose per-kind hit counts (uses, defs, etc.) changed
+/// - analysis files added or removed and files whose per-type record counts
+///   changed
+///
+/// The result is a JSON report.  Each list in the report is capped at
+/// `--limit` entries, but its `total` always counts everything.
+#[derive(Debug, StructOpt)]
+pub struct IndexDiff {
+    /// The old index directory (a tree's `index_path`).
+    old_index: String,
+
+    /// The new index directory.
+    new_index: String,
+
+    /// The maximum number of entries to list in each section of the report.
+    #[structopt(long, default_value = "100")]
+    limit: usize,
+
+    /// Don't compare the crossref databases.
+    #[structopt(long)]
+    skip_crossref: bool,
+
+    /// Don't compare the analysis files.
+    #[structopt(long)]
+    skip_analysis: bool,
+
+    /// The maximum number of analysis files to read at the same time.
+    #[structopt(long, default_value = "8")]
+    concurrency: usize,
+}
+
+pub struct IndexDiffCommand {
+    pub args: IndexDiff,
+}
+
+/// A list of report entries that's capped at a limit but whose `total` counts
+/// every entry.
+#[derive(Serialize)]
+struct CappedList<T> {
+    total: usize,
+    entries: Vec<T>,
+    #[serde(skip)]
+    limit: usize,
+}
+
+impl<T> CappedList<T> {
+    fn new(limit: usize) -> Self {
+        CappedList {
+            total: 0,
+            entries: vec![],
+            limit,
+        }
+    }
+
+    fn push(&mut self, entry: T) {
+        self.total += 1;
+        if self.entries.len() < self.limit {
+            self.entries.push(entry);
+        }
+    }
+}
+
+/// Counts that changed for a symbol or file between the two indexes.
+#[derive(Serialize)]
+struct CountChange {
+    name: String,
+    old: BTreeMap<String, usize>,
+    new: BTreeMap<String, usize>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CrossrefDiff {
+    old_symbols: usize,
+    new_symbols: usize,
+    added: CappedList<String>,
+    removed: CappedList<String>,
+    changed: CappedList<CountChange>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AnalysisDiff {
+    old_files: usize,
+    new_files: usize,
+    added: CappedList<String>,
+    removed: CappedList<String>,
+    changed: CappedList<CountChange>,
+}
+
+#[derive(Serialize)]
+struct IndexDiffReport {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    crossref: Option<CrossrefDiff>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    analysis: Option<AnalysisDiff>,
+}
+
+/// Count the hits of each kind (uses, defs, etc.) in a symbol's crossref data.
+/// For "callees" we count the callees.
+fn hit_counts(crossref: &Value) -> BTreeMap<String, usize> {
+    let mut counts = BTreeMap::new();
+    if let Some(obj) = crossref.as_object() {
+        for (kind, value) in obj {
+            let entries = match value.as_array() {
+                Some(entries) if kind != "meta" => entries,
+                _ => continue,
+            };
+            let count = if kind == "callees" {
+                entries.len()
+            } else {
+                entries
+                    .iter()
+                    .filter_map(|path_hits| path_hits["lines"].as_array())
+                    .map(|lines| lines.len())
+                    .sum()
+            };
+            counts.insert(kind.clone(), count);
+        }
+    }
+    counts
+}
+
+/// Count the records of each type (source, target, structured) in the given
+/// file's analysis, returning None if the file has no analysis.
+async fn record_counts(
+    server: &(dyn AbstractServer + Send + Sync),
+    file: &str,
+) -> Result<Option<BTreeMap<String, usize>>> {
+    let mut values = match server.fetch_raw_analysis(file).await {
+        Ok(values) => values,
+        // Most files in the file lists don't have analysis data.
+        Err(ServerError::StickyProblem(_)) => return Ok(None),
+        Err(err) => return Err(err),
+    };
+
+    let mut counts = BTreeMap::new();
+    while let Some(value) = values.next().await {
+        let value = value?;
+        for record_type in ["source", "target", "structured"].iter() {
+            if value.get(record_type).is_some() {
+                *counts.entry(record_type.to_string()).or_insert(0) += 1;
+            }
+        }
+    }
+    Ok(Some(counts))
+}
+
+impl IndexDiffCommand {
+    /// Walk both crossref databases in lockstep, relying on both being sorted
+    /// by symbol.
+    async fn diff_crossref(
+        &self,
+        old: &(dyn AbstractServer + Send + Sync),
+        new: &(dyn AbstractServer + Send + Sync),
+    ) -> Result<CrossrefDiff> {
+        let limit = self.args.limit;
+        let mut diff = CrossrefDiff {
+            old_symbols: 0,
+            new_symbols: 0,
+            added: CappedList::new(limit),
+            removed: CappedList::new(limit),
+            changed: CappedList::new(limit),
+        };
+
+        let mut old_entries = old.stream_crossref().await?;
+        let mut new_entries = new.stream_crossref().await?;
+        let mut old_entry = old_entries.next().await.transpose()?;
+        let mut new_entry = new_entries.next().await.transpose()?;
+
+        loop {
+            let order = match (&old_entry, &new_entry) {
+                (None, None) => break,
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some((old_sym, _)), Some((new_sym, _))) => old_sym.cmp(new_sym),
+            };
+
+            match order {
+                Ordering::Less => {
+                    let (sym, _) = old_entry.take().unwrap();
+                    diff.old_symbols += 1;
+                    diff.removed.push(sym);
+                    old_entry = old_entries.next().await.transpose()?;
+                }
+                Ordering::Greater => {
+                    let (sym, _) = new_entry.take().unwrap();
+                    diff.new_symbols += 1;
+                    diff.added.push(sym);
+                    new_entry = new_entries.next().await.transpose()?;
+                }
+                Ordering::Equal => {
+                    let (sym, old_crossref) = old_entry.take().unwrap();
+                    let (_, new_crossref) = new_entry.take().unwrap();
+                    diff.old_symbols += 1;
+                    diff.new_symbols += 1;
+                    let old_counts = hit_counts(&old_crossref);
+                    let new_counts = hit_counts(&new_crossref);
+                    if old_counts != new_counts {
+                        diff.changed.push(CountChange {
+                            name: sym,
+                            old: old_counts,
+                            new: new_counts,
+                        });
+                    }
+                    old_entry = old_entries.next().await.transpose()?;
+                    new_entry = new_entries.next().await.transpose()?;
+                }
+            }
+        }
+
+        Ok(diff)
+    }
+
+    async fn diff_analysis(
+        &self,
+        old: &(dyn AbstractServer + Send + Sync),
+        new: &(dyn AbstractServer + Send + Sync),
+    ) -> Result<AnalysisDiff> {
+        let mut files = BTreeSet::new();
+        for server in [old, new].iter() {
+            for list in [FileListKind::Repo, FileListKind::Objdir].iter() {
+                files.extend(server.fetch_file_list(*list).await?);
+            }
+        }
+
+        let limit = self.args.limit;
+        let mut diff = AnalysisDiff {
+            old_files: 0,
+            new_files: 0,
+            added: CappedList::new(limit),
+            removed: CappedList::new(limit),
+            changed: CappedList::new(limit),
+        };
+
+        let mut results = stream::iter(files)
+            .map(|file| async move {
+                let (old_counts, new_counts) =
+                    try_join(record_counts(old, &file), record_counts(new, &file)).await?;
+                Ok::<_, ServerError>((file, old_counts, new_counts))
+            })
+            .buffered(self.args.concurrency.max(1));
+
+        while let Some((file, old_counts, new_counts)) = results.try_next().await? {
+            match (old_counts, new_counts) {
+                (None, None) => {}
+                (Some(_), None) => {
+                    diff.old_files += 1;
+                    diff.removed.push(file);
+                }
+                (None, Some(_)) => {
+                    diff.new_files += 1;
+                    diff.added.push(file);
+                }
+                (Some(old_counts), Some(new_counts)) => {
+                    diff.old_files += 1;
+                    diff.new_files += 1;
+                    if old_counts != new_counts {
+                        diff.changed.push(CountChange {
+                            name: file,
+                            old: old_counts,
+                            new: new_counts,
+                        });
+                    }
+                }
+            }
+        }
+
+        Ok(diff)
+    }
+}
+
+#[async_trait]
+impl PipelineCommand for IndexDiffCommand {
+    async fn execute(
+        &self,
+        _server: &Box<dyn AbstractServer + Send + Sync>,
+        _input: PipelineValues,
+    ) -> Result<PipelineValues> {
+        let old = make_local_server_for_index(&self.args.old_index)?;
+        let new = make_local_server_for_index(&self.args.new_index)?;
+
+        let crossref = if self.args.skip_crossref {
+            None
+        } else {
+            Some(self.diff_crossref(old.as_ref(), new.as_ref()).await?)
+        };
+        let analysis = if self.args.skip_analysis {
+            None
+        } else {
+            Some(self.diff_analysis(old.as_ref(), new.as_ref()).await?)
+        };
+
+        Ok(PipelineValues::JsonValue(JsonValue {
+            value: to_value(IndexDiffReport { crossref, analysis })?,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::abstract_server::stub_server::StubServer;
+    use serde_json::json;
+
+    fn diff_command() -> IndexDiffCommand {
+        IndexDiffCommand {
+            args: IndexDiff::from_iter(&["index-diff", "old", "new"]),
+        }
+    }
+
+    fn uses(count: usize) -> Value {
+        let lines: Vec<Value> = (0..count).map(|lno| json!({ "lno": lno })).collect();
+        json!({ "uses": [{ "path": "a.cpp", "lines": lines }] })
+    }
+
+    #[tokio::test]
+    async fn test_diff_crossref() {
+        let mut old = StubServer::default();
+        let mut new = StubServer::default();
+        for (sym, count) in &[("A", 1), ("B", 2), ("C", 1)] {
+            old.crossref.insert(sym.to_string(), uses(*count));
+        }
+        for (sym, count) in &[("D", 1), ("C", 1), ("B", 3)] {
+            new.crossref.insert(sym.to_string(), uses(*count));
+        }
+
+        let diff = diff_command().diff_crossref(&old, &new).await.unwrap();
+        assert_eq!(
+            to_value(diff).unwrap(),
+            json!({
+                "oldSymbols": 3,
+                "newSymbols": 3,
+                "added": { "total": 1, "entries": ["D"] },
+                "removed": { "total": 1, "entries": ["A"] },
+                "changed": { "total": 1, "entries": [
+                    { "name": "B", "old": { "uses": 2 }, "new": { "uses": 3 } },
+                ] },
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_diff_analysis() {
+        let source = json!({ "loc": "1:0-3", "source": 1 });
+        let target = json!({ "loc": "1:0-3", "target": 1 });
+        let old = StubServer {
+            repo_files: vec![
+                "a.cpp".to_string(),
+                "b.cpp".to_string(),
+                "c.txt".to_string(),
+            ],
+            analysis: vec![
+                ("a.cpp".to_string(), vec![source.clone(), target.clone()]),
+                ("b.cpp".to_string(), vec![source.clone()]),
+            ]
+            .into_iter()
+            .collect(),
+            ..StubServer::default()
+        };
+        let new = StubServer {
+            repo_files: vec!["a.cpp".to_string(), "b.cpp".to_string()],
+            objdir_files: vec!["__GENERATED__/d.cpp".to_string()],
+            analysis: vec![
+                (
+                    "a.cpp".to_string(),
+                    vec![source.clone(), target.clone(), target],
+                ),
+                ("__GENERATED__/d.cpp".to_string(), vec![source]),
+            ]
+            .into_iter()
+            .collect(),
+            ..StubServer::default()
+        };
+
+        let diff = diff_command().diff_analysis(&old, &new).await.unwrap();
+        assert_eq!(
+            to_value(diff).unwrap(),
+            json!({
+                "oldFiles": 2,
+                "newFiles": 2,
+                "added": { "total": 1, "entries": ["__GENERATED__/d.cpp"] },
+                "removed": { "total": 1, "entries": ["b.cpp"] },
+                "changed": { "total": 1, "entries": [
+                    {
+                        "name": "a.cpp",
+                        "old": { "source": 1, "target": 1 },
+                        "new": { "source": 1, "target": 2 },
+                    },
+                ] },
+            })
+        );
+    }
+
+    #[test]
+    fn test_hit_counts() {
+        let crossref = json!({
+            "uses": [
+                { "path": "a.cpp", "lines": [{ "lno": 1 }, { "lno": 2 }] },
+                { "path": "b.cpp", "lines": [{ "lno": 3 }] },
+            ],
+            "defs": [{ "path": "a.cpp", "lines": [{ "lno": 10 }] }],
+            "callees": [{ "sym": "foo" }, { "sym": "bar" }],
+            "meta": { "structured": 1, "fields": [] },
+        });
+        let counts = hit_counts(&crossref);
+        assert_eq!(counts.len(), 3);
+        assert_eq!(counts["uses"], 3);
+        assert_eq!(counts["defs"], 1);
+        assert_eq!(counts["callees"], 2);
+    }
+}
@@ -87,6 +87,14 @@ impl PipelineCommand for ProductionFilterCommand {
         _server: &Box<dyn AbstractServer + Send + Sync>,
         input: PipelineValues,
     ) -> Result<PipelineValues> {
+        let input = match input {
+            // Normalization mutates the JSON, so we lose the typed records.
+            PipelineValues::AnalysisRecords(ar) => {
+                PipelineValues::JsonRecords(ar.into_json_records())
+            }
+            other => other,
+        };
+
         Ok(match input {
             PipelineValues::JsonRecords(jr) => PipelineValues::JsonRecords(JsonRecords {
                 by_file: jr
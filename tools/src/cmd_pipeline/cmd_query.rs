@@ -6,9 +6,10 @@ use crate::{
     abstract_server::{AbstractServer, Result},
 };
 
-/// Run a traditional searchfox query against the web server.  This will turn
-/// into a no-op when run against a local index at this time, but in the future
-/// may be able to spin up the necessary pieces.
+/// Run a traditional searchfox query against the web server.  When run against
+/// a local index, the `router.py` search logic is emulated, with full-text
+/// search provided by the tree's trigram index.  Trees without a trigram index
+/// will fail purely textual queries as unsupported.
 #[derive(Debug, StructOpt)]
 pub struct Query {
   /// Query string
@@ -0,0 +1,185 @@
+use async_trait::async_trait;
+use regex::RegexBuilder;
+use structopt::StructOpt;
+
+use super::interface::{FileList, PipelineCommand, PipelineValues};
+use crate::abstract_server::{
+    parse_path_filter, AbstractServer, ErrorDetails, ErrorLayer, FileListKind, Result,
+    ServerError,
+};
+
+/// Search the tree's file lists for paths matching any of the given patterns,
+/// like `router.py`'s `search_files`.
+///
+/// The resulting file list can be piped into `filter-analysis` or `show-html`.
+#[derive(Debug, StructOpt)]
+pub struct SearchFiles {
+    /// Path globs which must match the entire path (ex: "dom/**/*.webidl"), or
+    /// regular expressions which can match anywhere in the path if `--regex`
+    /// is specified.
+    #[structopt(required = true)]
+    patterns: Vec<String>,
+
+    /// Treat the patterns as regular expressions rather than globs.
+    #[structopt(long)]
+    regex: bool,
+
+    /// Match case-sensitively.
+    #[structopt(long)]
+    case_sensitive: bool,
+
+    /// Don't search the generated files from the objdir.
+    #[structopt(long)]
+    skip_objdir: bool,
+
+    /// The maximum number of files to return.
+    #[structopt(long, default_value = "1000")]
+    limit: usize,
+}
+
+pub struct SearchFilesCommand {
+    pub args: SearchFiles,
+}
+
+#[async_trait]
+impl PipelineCommand for SearchFilesCommand {
+    async fn execute(
+        &self,
+        server: &Box<dyn AbstractServer + Send + Sync>,
+        _input: PipelineValues,
+    ) -> Result<PipelineValues> {
+        let mut regexes = vec![];
+        for pattern in &self.args.patterns {
+            let pattern = if self.args.regex {
+                pattern.clone()
+            } else {
+                format!("^{}$", parse_path_filter(pattern))
+            };
+            match RegexBuilder::new(&pattern)
+                .case_insensitive(!self.args.case_sensitive)
+                .build()
+            {
+                Ok(re) => regexes.push(re),
+                Err(err) => {
+                    return Err(ServerError::StickyProblem(ErrorDetails {
+                        layer: ErrorLayer::BadInput,
+                        message: err.to_string(),
+                    }));
+                }
+            }
+        }
+
+        let lists: &[FileListKind] = if self.args.skip_objdir {
+            &[FileListKind::Repo]
+        } else {
+            &[FileListKind::Repo, FileListKind::Objdir]
+        };
+
+        let mut files = vec![];
+        for list in lists {
+            for path in server.fetch_file_list(*list).await? {
+                if files.len() == self.args.limit {
+                    break;
+                }
+                if regexes.iter().any(|re| re.is_match(&path)) {
+                    files.push(path);
+                }
+            }
+        }
+
+        Ok(PipelineValues::FileList(FileList { files }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::abstract_server::stub_server::StubServer;
+
+    async fn search_files(args: &[&str]) -> Result<Vec<String>> {
+        let stub = StubServer {
+            repo_files: vec![
+                "dom/base/Element.cpp".to_string(),
+                "dom/base/Element.h".to_string(),
+                "dom/webidl/Element.webidl".to_string(),
+                "js/src/jsapi.cpp".to_string(),
+            ],
+            objdir_files: vec!["__GENERATED__/dom/bindings/ElementBinding.cpp".to_string()],
+            ..StubServer::default()
+        };
+        let server: Box<dyn AbstractServer + Send + Sync> = Box::new(stub);
+        let command = SearchFilesCommand {
+            args: SearchFiles::from_iter(["search-files"].iter().chain(args)),
+        };
+        match command.execute(&server, PipelineValues::Void).await? {
+            PipelineValues::FileList(fl) => Ok(fl.files),
+            _ => panic!("Expected a file list"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_search_files() {
+        // Globs must match the whole path.
+        assert_eq!(
+            search_files(&["dom/**/*.cpp"]).await.unwrap(),
+            vec!["dom/base/Element.cpp"]
+        );
+        assert_eq!(
+            search_files(&["**/element*.cpp"]).await.unwrap(),
+            vec![
+                "dom/base/Element.cpp",
+                "__GENERATED__/dom/bindings/ElementBinding.cpp"
+            ]
+        );
+        assert_eq!(
+            search_files(&["*.cpp"]).await.unwrap(),
+            Vec::<String>::new()
+        );
+
+        // Regular expressions can match anywhere.
+        assert_eq!(
+            search_files(&["--regex", r"element\.(h|webidl)"])
+                .await
+                .unwrap(),
+            vec!["dom/base/Element.h", "dom/webidl/Element.webidl"]
+        );
+        assert_eq!(
+            search_files(&["--regex", "--case-sensitive", "element"])
+                .await
+                .unwrap(),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            search_files(&["--case-sensitive", "**/Element.h"])
+                .await
+                .unwrap(),
+            vec!["dom/base/Element.h"]
+        );
+
+        assert_eq!(
+            search_files(&["--skip-objdir", "**/element*.cpp"])
+                .await
+                .unwrap(),
+            vec!["dom/base/Element.cpp"]
+        );
+        assert_eq!(
+            search_files(&["--limit", "2", "--regex", "."])
+                .await
+                .unwrap(),
+            vec!["dom/base/Element.cpp", "dom/base/Element.h"]
+        );
+        // Any pattern can match.
+        assert_eq!(
+            search_files(&["js/**", "**/*.webidl"]).await.unwrap(),
+            vec!["dom/webidl/Element.webidl", "js/src/jsapi.cpp"]
+        );
+
+        assert!(matches!(
+            search_files(&["--regex", "("]).await,
+            Err(ServerError::StickyProblem(ErrorDetails {
+                layer: ErrorLayer::BadInput,
+                ..
+            }))
+        ));
+    }
+}
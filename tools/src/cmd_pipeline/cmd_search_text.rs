@@ -0,0 +1,82 @@
+use async_trait::async_trait;
+use structopt::StructOpt;
+
+use super::interface::{FileList, JsonValue, PipelineCommand, PipelineValues};
+use crate::abstract_server::{parse_path_filter, AbstractServer, Result};
+
+/// Search the contents of the tree's files for a regular expression, like the
+/// "Textual Occurrences" of a `re:` query.  For local indexes this requires
+/// the tree's trigram index.
+#[derive(Debug, StructOpt)]
+pub struct SearchText {
+    /// The regular expression to search for.
+    pattern: String,
+
+    /// Treat the pattern as a literal string rather than a regular expression.
+    #[structopt(long)]
+    literal: bool,
+
+    /// Match case-sensitively.
+    #[structopt(long)]
+    case_sensitive: bool,
+
+    /// Only search files whose path matches this glob (ex: "dom/**/*.cpp").
+    #[structopt(long)]
+    path: Option<String>,
+
+    /// The number of lines of context to include around each match.
+    #[structopt(long, default_value = "0")]
+    context: u32,
+
+    /// The maximum number of matching lines to return.
+    #[structopt(long, default_value = "1000")]
+    limit: usize,
+
+    /// Output the list of files with matches, suitable for piping into
+    /// `filter-analysis` or `show-html`, instead of the matching lines.
+    #[structopt(long)]
+    files: bool,
+}
+
+pub struct SearchTextCommand {
+    pub args: SearchText,
+}
+
+#[async_trait]
+impl PipelineCommand for SearchTextCommand {
+    async fn execute(
+        &self,
+        server: &Box<dyn AbstractServer + Send + Sync>,
+        _input: PipelineValues,
+    ) -> Result<PipelineValues> {
+        let pattern = if self.args.literal {
+            regex::escape(&self.args.pattern)
+        } else {
+            self.args.pattern.clone()
+        };
+        let pathre = self.args.path.as_deref().map(parse_path_filter);
+
+        let value = server
+            .search_text(
+                &pattern,
+                !self.args.case_sensitive,
+                pathre.as_deref(),
+                self.args.context.min(10),
+                self.args.limit,
+            )
+            .await?;
+
+        if self.args.files {
+            let files = value
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|path_hits| path_hits["path"].as_str())
+                .map(|path| path.to_string())
+                .collect();
+            return Ok(PipelineValues::FileList(FileList { files }));
+        }
+
+        Ok(PipelineValues::JsonValue(JsonValue { value }))
+    }
+}
@@ -1,16 +1,21 @@
 use std::cell::Cell;
+use std::collections::HashSet;
 
 use async_trait::async_trait;
-use lol_html::{element, HtmlRewriter, Settings};
+use futures_util::stream::{self, StreamExt, TryStreamExt};
+use lol_html::{element, errors::RewritingError, HtmlRewriter, Settings};
 use structopt::StructOpt;
+use tokio::sync::mpsc::{channel, Receiver};
+use tokio::task::spawn_blocking;
 
 use super::interface::{JsonRecords, PipelineCommand, PipelineValues};
 use crate::{
-    abstract_server::{AbstractServer, Result},
+    abstract_server::{AbstractServer, ErrorDetails, ErrorLayer, Result, ServerError},
     cmd_pipeline::interface::{HtmlExcerpts, HtmlExcerptsByFile},
 };
 
-/// Output the HTML lines corresponding to the JSON records received via input.
+/// Output the HTML lines corresponding to the JSON records received via input,
+/// or every line of the files in a received file list.
 ///
 /// There's also likely a use-case to process an HTML file as a root where we
 /// then filter lines based on "data-symbols".  It's not immediately clear if
@@ -21,12 +26,202 @@ use crate::{
 /// evolving a more targeted query.  Having to modify a command up-stream should
 /// be considered undesirable.
 #[derive(Debug, StructOpt)]
-pub struct ShowHtml {}
+pub struct ShowHtml {
+    /// The maximum number of files to fetch and excerpt at the same time.
+    #[structopt(long, default_value = "8")]
+    concurrency: usize,
+}
 
 pub struct ShowHtmlCommand {
     pub args: ShowHtml,
 }
 
+/// lol_html errors mean the HTML couldn't be parsed, which is a data problem.
+fn rewriting_error(err: RewritingError) -> ServerError {
+    ServerError::StickyProblem(ErrorDetails {
+        layer: ErrorLayer::DataLayer,
+        message: err.to_string(),
+    })
+}
+
+/// The length of the line container at the start of `html`, through its
+/// closing tag and the newline after it, or all of `html` if it isn't closed.
+/// Source text is escaped, so any `<div` is markup.
+fn line_container_len(html: &[u8]) -> usize {
+    let mut depth = 0;
+    let mut i = 0;
+    while i < html.len() {
+        if html[i..].starts_with(b"<div") {
+            depth += 1;
+            i += 4;
+        } else if html[i..].starts_with(b"</div") {
+            depth -= 1;
+            i += 5;
+            if depth == 0 {
+                let end = match html[i..].iter().position(|b| *b == b'>') {
+                    Some(pos) => i + pos + 1,
+                    None => return html.len(),
+                };
+                return if html[end..].starts_with(b"\n") {
+                    end + 1
+                } else {
+                    end
+                };
+            }
+        } else {
+            i += 1;
+        }
+    }
+    html.len()
+}
+
+/// Feed the HTML chunks received over the channel through lol_html, returning
+/// the HTML excerpts for the lines in `lines_to_show`, or for every line if
+/// it's None.
+///
+/// This is synchronous because `HtmlRewriter` isn't `Send` and so can't be
+/// held across an await point; we run this via `spawn_blocking` and feed it the
+/// chunks as we receive them so that we never need to hold the whole file in
+/// memory.
+fn extract_html_lines(
+    lines_to_show: Option<HashSet<u32>>,
+    mut chunks: Receiver<Vec<u8>>,
+) -> Result<Vec<String>> {
+    let mut file_excerpts = vec![];
+
+    // ### HTML Extraction: What We Want
+    //
+    // We want the full line container which looks like:
+    // - div id="line-N" class="source-line-with-number" role="row"
+    //   - div role="cell"
+    //     - div class="cov-strip cov-uncovered cov-known"
+    //   - div role="cell"
+    //     - div class="blame-strip c2" data-blame="..."
+    //   - div role="cell" class="line-number" data-line-number="N"
+    //   - code role="cell" class="source-line"
+    //     - ex: span class="sync_comment"
+    //     - ex: span class="syn_def syn_type" data-symbols="..." data-i
+    //
+    // ### HTML Extraction Low Level Details
+    //
+    // Until https://github.com/cloudflare/lol-html/issues/40 or
+    // the spin-off https://github.com/cloudflare/lol-html/issues/78
+    // are implemented, lol_html doesn't explicitly provide a way to
+    // derive the value of an element.
+    //
+    // So we attempt a hack where we use a custom output sink that is
+    // kept aware of where we are in the file.  The good news is that
+    // since lol_html is oriented around minimal memory allocation, we
+    // can generally control when flushes happen.
+
+    let mut writing_line: u32 = 0;
+    let cur_line = Cell::new(0u32);
+    let want_cur_line = Cell::new(false);
+
+    let mut buf = vec![];
+
+    let mut rewrite = HtmlRewriter::new(
+        Settings {
+            element_content_handlers: vec![element!(
+                r#"div.source-line-with-number"#,
+                |el| {
+                    if let Some(id_str) = el.get_attribute("id") {
+                        let id_parts: Vec<&str> = id_str.split("-").collect();
+                        if id_parts.len() == 2 && id_parts[0] == "line" {
+                            let lno = id_parts[1].parse().unwrap_or(0);
+                            cur_line.set(lno);
+                            want_cur_line.set(match &lines_to_show {
+                                Some(lines) => lines.contains(&lno),
+                                None => true,
+                            });
+                        }
+                    }
+
+                    Ok(())
+                }
+            )],
+            ..Settings::default()
+        },
+        |c: &[u8]| {
+            // We were actively writing and potentially have some
+            // buffer.
+            if writing_line > 0 {
+                // We're done writing; flush!
+                if cur_line.get() != writing_line {
+                    writing_line = 0;
+                    file_excerpts.push(String::from_utf8_lossy(&buf).to_string());
+                    buf.clear();
+                }
+                // We're still writing!
+                else {
+                    // Write into the buffer and then leave, because we
+                    // don't need to consider switching into writing, as
+                    // we're still here.
+                    buf.extend_from_slice(c);
+                    return;
+                }
+            }
+            // We either closed out writing or weren't writing.  But now
+            // we need to see if we should be writing!
+            if cur_line.get() > 0 && want_cur_line.get() {
+                writing_line = cur_line.get();
+                buf.extend_from_slice(c);
+            }
+            // Otherwise, this wasn't interesting.
+        },
+    );
+
+    while let Some(chunk) = chunks.blocking_recv() {
+        rewrite.write(&chunk).map_err(rewriting_error)?;
+    }
+    rewrite.end().map_err(rewriting_error)?;
+
+    // The last line we wanted is normally flushed when the next line starts,
+    // but there's no next line after the file's final line, so the buffer
+    // also holds whatever follows the line container.
+    if writing_line > 0 {
+        let len = line_container_len(&buf);
+        file_excerpts.push(String::from_utf8_lossy(&buf[..len]).to_string());
+    }
+
+    Ok(file_excerpts)
+}
+
+/// Fetch the HTML for the file and excerpt the given lines, or every line if
+/// `lines_to_show` is None.
+async fn excerpt_file(
+    server: &Box<dyn AbstractServer + Send + Sync>,
+    file: String,
+    lines_to_show: Option<HashSet<u32>>,
+) -> Result<HtmlExcerptsByFile> {
+    // The extraction thread will consume chunks as fast as we can provide
+    // them, so we only need a little bit of buffering.
+    let (tx, rx) = channel(4);
+    let extractor = spawn_blocking(move || extract_html_lines(lines_to_show, rx));
+
+    let mut html_chunks = server.fetch_html(&file).await?;
+    while let Some(chunk) = html_chunks.next().await {
+        // If the extractor hung up on us it errored, which we'll hear about
+        // when we join it below.
+        if tx.send(chunk?).await.is_err() {
+            break;
+        }
+    }
+    drop(tx);
+
+    let file_excerpts = extractor.await.map_err(|err| {
+        ServerError::StickyProblem(ErrorDetails {
+            layer: ErrorLayer::ServerLayer,
+            message: err.to_string(),
+        })
+    })??;
+
+    Ok(HtmlExcerptsByFile {
+        file,
+        excerpts: file_excerpts,
+    })
+}
+
 #[async_trait]
 impl PipelineCommand for ShowHtmlCommand {
     async fn execute(
@@ -34,116 +229,93 @@ impl PipelineCommand for ShowHtmlCommand {
         server: &Box<dyn AbstractServer + Send + Sync>,
         input: PipelineValues,
     ) -> Result<PipelineValues> {
-        let jr = match input {
-            PipelineValues::JsonRecords(jr) => jr,
-            _ => JsonRecords { by_file: vec![] },
+        let files_and_lines: Vec<(String, Option<HashSet<u32>>)> = match input {
+            PipelineValues::FileList(fl) => fl.files.into_iter().map(|f| (f, None)).collect(),
+            input => {
+                let jr = match input {
+                    PipelineValues::JsonRecords(jr) => jr,
+                    PipelineValues::AnalysisRecords(ar) => ar.into_json_records(),
+                    _ => JsonRecords { by_file: vec![] },
+                };
+                jr.by_file
+                    .into_iter()
+                    .map(|fr| {
+                        let lines_to_show = fr.line_set();
+                        (fr.file, Some(lines_to_show))
+                    })
+                    .collect()
+            }
         };
 
-        let mut html_by_file = vec![];
-
-        for fr in jr.by_file {
-            // ## For each file!
-            let lines_to_show = fr.line_set();
-
-            // XXX Doing this as a single string received in a lump is fine for
-            // our testing use-case, but this may need to be reconsidered in
-            // production.  Or maybe production really wants the performance?
-            // Production certainly should have the RAM for our known worst
-            // case scenarios.
-            let html_str = server.fetch_html(&fr.file).await?;
-
-            let mut file_excerpts = vec![];
-
-            // ### HTML Extraction: What We Want
-            //
-            // We want the full line container which looks like:
-            // - div id="line-N" class="source-line-with-number" role="row"
-            //   - div role="cell"
-            //     - div class="cov-strip cov-uncovered cov-known"
-            //   - div role="cell"
-            //     - div class="blame-strip c2" data-blame="..."
-            //   - div role="cell" class="line-number" data-line-number="N"
-            //   - code role="cell" class="source-line"
-            //     - ex: span class="sync_comment"
-            //     - ex: span class="syn_def syn_type" data-symbols="..." data-i
-            //
-            // ### HTML Extraction Low Level Details
-            //
-            // Until https://github.com/cloudflare/lol-html/issues/40 or
-            // the spin-off https://github.com/cloudflare/lol-html/issues/78
-            // are implemented, lol_html doesn't explicitly provide a way to
-            // derive the value of an element.
-            //
-            // So we attempt a hack where we use a custom output sink that is
-            // kept aware of where we are in the file.  The good news is that
-            // since lol_html is oriented around minimal memory allocation, we
-            // can generally control when flushes happen.
-
-            let mut writing_line: u32 = 0;
-            let cur_line = Cell::new(0u32);
-            let want_cur_line = Cell::new(false);
-
-            let mut buf = vec![];
-
-            let mut rewrite = HtmlRewriter::new(
-                Settings {
-                    element_content_handlers: vec![element!(
-                        r#"div.source-line-with-number"#,
-                        |el| {
-                            if let Some(id_str) = el.get_attribute("id") {
-                                let id_parts: Vec<&str> = id_str.split("-").collect();
-                                if id_parts.len() == 2 && id_parts[0] == "line" {
-                                    let lno = id_parts[1].parse().unwrap_or(0);
-                                    cur_line.set(lno);
-                                    want_cur_line.set(lines_to_show.contains(&lno));
-                                }
-                            }
-
-                            Ok(())
-                        }
-                    )],
-                    ..Settings::default()
-                },
-                |c: &[u8]| {
-                    // We were actively writing and potentially have some
-                    // buffer.
-                    if writing_line > 0 {
-                        // We're done writing; flush!
-                        if cur_line.get() != writing_line {
-                            writing_line = 0;
-                            file_excerpts.push(String::from_utf8_lossy(&buf).to_string());
-                            buf.clear();
-                        }
-                        // We're still writing!
-                        else {
-                            // Write into the buffer and then leave, because we
-                            // don't need to consider switching into writing, as
-                            // we're still here.
-                            buf.extend_from_slice(c);
-                            return;
-                        }
-                    }
-                    // We either closed out writing or weren't writing.  But now
-                    // we need to see if we should be writing!
-                    if cur_line.get() > 0 && want_cur_line.get() {
-                        writing_line = cur_line.get();
-                        buf.extend_from_slice(c);
-                    }
-                    // Otherwise, this wasn't interesting.
-                },
-            );
-
-            rewrite.write(html_str.as_bytes()).unwrap();
-            rewrite.end().unwrap();
-
-            html_by_file.push(HtmlExcerptsByFile {
-                file: fr.file.clone(),
-                excerpts: file_excerpts,
-            });
-        }
+        // Files are excerpted concurrently, but `buffered` keeps the results
+        // in input order.
+        let html_by_file = stream::iter(files_and_lines)
+            .map(|(file, lines_to_show)| excerpt_file(server, file, lines_to_show))
+            .buffered(self.args.concurrency.max(1))
+            .try_collect()
+            .await?;
 
         Ok(PipelineValues::HtmlExcerpts(HtmlExcerpts {
             by_file: html_by_file,
         }))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HTML: &str = r#"<html><body><div id="file" role="table">
+<div role="row" id="line-1" class="source-line-with-number">
+  <div role="cell" class="line-number" data-line-number="1"></div>
+  <code role="cell" class="source-line">int a = 1 &lt;div 2;
+</code>
+</div>
+<div role="row" id="line-2" class="source-line-with-number">
+  <div role="cell" class="line-number" data-line-number="2"></div>
+  <code role="cell" class="source-line">int b;
+</code>
+</div>
+</div><footer>the end</footer></body></html>
+"#;
+
+    fn extract(lines_to_show: Option<HashSet<u32>>, chunk_size: usize) -> Vec<String> {
+        let chunks: Vec<Vec<u8>> = HTML
+            .as_bytes()
+            .chunks(chunk_size)
+            .map(|c| c.to_vec())
+            .collect();
+        let (tx, rx) = channel(chunks.len());
+        for chunk in chunks {
+            tx.try_send(chunk).unwrap();
+        }
+        drop(tx);
+        extract_html_lines(lines_to_show, rx).unwrap()
+    }
+
+    fn line_html(lineno: u32) -> String {
+        let start = HTML
+            .find(&format!(r#"<div role="row" id="line-{}""#, lineno))
+            .unwrap();
+        let len = line_container_len(HTML[start..].as_bytes());
+        HTML[start..start + len].to_string()
+    }
+
+    #[test]
+    fn test_extract_html_lines() {
+        assert!(line_html(2).ends_with("</code>\n</div>\n"));
+
+        for chunk_size in &[7, 64, HTML.len()] {
+            // Every line, including the last one, for a file list.
+            assert_eq!(extract(None, *chunk_size), vec![line_html(1), line_html(2)]);
+            assert_eq!(
+                extract(Some([2].iter().cloned().collect()), *chunk_size),
+                vec![line_html(2)]
+            );
+            assert_eq!(
+                extract(Some([1].iter().cloned().collect()), *chunk_size),
+                vec![line_html(1)]
+            );
+        }
+    }
+}
@@ -0,0 +1,276 @@
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+
+use async_trait::async_trait;
+use serde_json::Value;
+use structopt::StructOpt;
+
+use super::interface::{
+    PathUseCount, PipelineCommand, PipelineValues, PrettySymbol, SourceLocation,
+    SymbolCrossrefInfo, SymbolInfo, SymbolInfoList,
+};
+use crate::abstract_server::{AbstractServer, CrossrefLookupOptions, Result, ServerError};
+use crate::file_format::analysis::Jump;
+
+/// Summarize one or more symbols received via pipeline or as explicit
+/// arguments, merging their crossref `meta`, their crossref hits and the jumps
+/// file into a single typed result per symbol.
+///
+/// Symbols can also be piped in from `crossref-lookup` to avoid looking up
+/// their crossref data again, unless its uses were paged or filtered by path,
+/// in which case the complete uses are looked up so they can be counted.
+#[derive(Debug, StructOpt)]
+pub struct SymbolInfoArgs {
+    /// Explicit symbols to summarize.
+    symbols: Vec<String>,
+}
+
+pub struct SymbolInfoCommand {
+    pub args: SymbolInfoArgs,
+}
+
+/// Convert a crossref hit list (`[{ path, lines: [{ lno, ... }] }]`) into a
+/// location per line.
+fn hit_locations(hits: &Value) -> Vec<SourceLocation> {
+    let mut locations = vec![];
+    for path_hits in hits.as_array().into_iter().flatten() {
+        let path = match path_hits["path"].as_str() {
+            Some(path) => path,
+            None => continue,
+        };
+        for line in path_hits["lines"].as_array().into_iter().flatten() {
+            if let Some(lineno) = line["lno"].as_u64() {
+                locations.push(SourceLocation {
+                    path: path.to_string(),
+                    lineno,
+                });
+            }
+        }
+    }
+    locations
+}
+
+fn opt_string(value: &Value) -> Option<String> {
+    value.as_str().map(|s| s.to_string())
+}
+
+/// Build the summary for `sym` from its crossref data (which is null if the
+/// symbol isn't in the crossref database) and its jump, if any.
+fn summarize(sym: String, crossref: &Value, jump: Option<Jump>) -> SymbolInfo {
+    let meta = &crossref["meta"];
+
+    let definitions = hit_locations(&crossref["defs"]);
+    let definition = match &jump {
+        Some(jump) => Some(SourceLocation {
+            path: jump.path.clone(),
+            lineno: jump.lineno,
+        }),
+        None => definitions.first().cloned(),
+    };
+
+    let supers = meta["supers"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|sup| {
+            Some(PrettySymbol {
+                sym: opt_string(&sup["sym"])?,
+                pretty: opt_string(&sup["pretty"]).unwrap_or_default(),
+            })
+        })
+        .collect();
+
+    let mut uses_by_path: BTreeMap<String, usize> = BTreeMap::new();
+    for location in hit_locations(&crossref["uses"]) {
+        *uses_by_path.entry(location.path).or_insert(0) += 1;
+    }
+    let use_count = uses_by_path.values().sum();
+    let mut uses_by_path: Vec<PathUseCount> = uses_by_path
+        .into_iter()
+        .map(|(path, count)| PathUseCount { path, count })
+        .collect();
+    // The BTreeMap gave us path order, which the stable sort preserves.
+    uses_by_path.sort_by_key(|u| Reverse(u.count));
+
+    SymbolInfo {
+        pretty: opt_string(&meta["pretty"]).or_else(|| jump.map(|j| j.pretty)),
+        kind: opt_string(&meta["kind"]),
+        definition,
+        definitions,
+        declarations: hit_locations(&crossref["decls"]),
+        size_bytes: meta["sizeBytes"].as_u64().map(|size| size as u32),
+        parent_sym: opt_string(&meta["parentsym"]),
+        supers,
+        idl_sym: opt_string(&meta["idlsym"]),
+        src_sym: opt_string(&meta["srcsym"]),
+        target_sym: opt_string(&meta["targetsym"]),
+        idl: hit_locations(&crossref["idl"]),
+        ipc: hit_locations(&crossref["ipc"]),
+        use_count,
+        uses_by_path,
+        sym,
+    }
+}
+
+#[async_trait]
+impl PipelineCommand for SymbolInfoCommand {
+    async fn execute(
+        &self,
+        server: &Box<dyn AbstractServer + Send + Sync>,
+        input: PipelineValues,
+    ) -> Result<PipelineValues> {
+        let symbols = match input {
+            PipelineValues::SymbolCrossrefInfoList(scil) => {
+                return self.summarize_all(server.as_ref(), scil.symbol_crossref_infos).await;
+            }
+            PipelineValues::SymbolList(sl) => sl.symbols,
+            PipelineValues::Void => self.args.symbols.clone(),
+            // TODO: Figure out a better way to handle a nonsensical pipeline
+            // configuration / usage.
+            _ => {
+                return Ok(PipelineValues::Void);
+            }
+        };
+
+        let mut crossref_infos = vec![];
+        for symbol in symbols {
+            let crossref_info = server
+                .crossref_lookup(&symbol, &CrossrefLookupOptions::default())
+                .await?;
+            crossref_infos.push(SymbolCrossrefInfo {
+                symbol,
+                crossref_info,
+            });
+        }
+        self.summarize_all(server.as_ref(), crossref_infos).await
+    }
+}
+
+impl SymbolInfoCommand {
+    async fn summarize_all(
+        &self,
+        server: &(dyn AbstractServer + Send + Sync),
+        crossref_infos: Vec<SymbolCrossrefInfo>,
+    ) -> Result<PipelineValues> {
+        let mut symbol_infos = vec![];
+        for mut info in crossref_infos {
+            // `uses_total` means only some of the uses were returned, and it
+            // counts paths rather than uses, so we need the rest of them.
+            if info.crossref_info.get("uses_total").is_some() {
+                info.crossref_info = server
+                    .crossref_lookup(&info.symbol, &CrossrefLookupOptions::default())
+                    .await?;
+            }
+            let jump = match server.jump_lookup(&info.symbol).await {
+                Ok(jump) => jump,
+                // Remote servers don't expose the jumps file, but the crossref
+                // defs are a fine substitute.
+                Err(ServerError::Unsupported) => None,
+                Err(err) => return Err(err),
+            };
+            symbol_infos.push(summarize(info.symbol, &info.crossref_info, jump));
+        }
+
+        Ok(PipelineValues::SymbolInfoList(SymbolInfoList { symbol_infos }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::abstract_server::stub_server::StubServer;
+    use crate::cmd_pipeline::interface::SymbolCrossrefInfoList;
+    use serde_json::json;
+
+    #[test]
+    fn test_summarize() {
+        let crossref = json!({
+            "defs": [{ "path": "a.h", "lines": [{ "lno": 3 }] }],
+            "decls": [{ "path": "b.h", "lines": [{ "lno": 7 }] }],
+            "uses": [
+                { "path": "a.cpp", "lines": [{ "lno": 1 }] },
+                { "path": "b.cpp", "lines": [{ "lno": 2 }, { "lno": 4 }] },
+            ],
+            "meta": {
+                "pretty": "ns::Foo",
+                "kind": "class",
+                "sizeBytes": 16,
+                "supers": [{ "sym": "T_ns::Base", "pretty": "ns::Base", "props": [] }],
+            },
+        });
+        let info = summarize("T_ns::Foo".to_string(), &crossref, None);
+        assert_eq!(info.pretty.as_deref(), Some("ns::Foo"));
+        assert_eq!(info.kind.as_deref(), Some("class"));
+        assert_eq!(info.size_bytes, Some(16));
+        assert_eq!(
+            info.definition,
+            Some(SourceLocation {
+                path: "a.h".to_string(),
+                lineno: 3
+            })
+        );
+        assert_eq!(info.declarations.len(), 1);
+        assert_eq!(info.supers[0].sym, "T_ns::Base");
+        assert_eq!(info.use_count, 3);
+        assert_eq!(info.uses_by_path[0].path, "b.cpp");
+        assert_eq!(info.uses_by_path[0].count, 2);
+        assert!(info.idl.is_empty());
+        assert_eq!(info.src_sym, None);
+
+        let ipc = json!({
+            "ipc": [{ "path": "PFoo.ipdl", "lines": [{ "lno": 5 }] }],
+            "meta": {
+                "pretty": "PFoo::Msg_Bar",
+                "kind": "ipc",
+                "srcsym": "_ZN4PFoo7SendBarEv",
+                "targetsym": "_ZN4PFoo7RecvBarEv",
+            },
+        });
+        let info = summarize("_ZN4PFoo7Msg_BarE".to_string(), &ipc, None);
+        assert_eq!(info.src_sym.as_deref(), Some("_ZN4PFoo7SendBarEv"));
+        assert_eq!(info.target_sym.as_deref(), Some("_ZN4PFoo7RecvBarEv"));
+        assert_eq!(info.ipc.len(), 1);
+
+        let missing = summarize("T_Missing".to_string(), &Value::Null, None);
+        assert_eq!(missing.pretty, None);
+        assert_eq!(missing.definition, None);
+        assert_eq!(missing.use_count, 0);
+    }
+
+    #[tokio::test]
+    async fn test_paged_crossref_input() {
+        let uses = json!([
+            { "path": "a.cpp", "lines": [{ "lno": 1 }] },
+            { "path": "b.cpp", "lines": [{ "lno": 2 }, { "lno": 4 }] },
+        ]);
+        let mut stub = StubServer::default();
+        stub.crossref
+            .insert("T_Paged".to_string(), json!({ "uses": uses }));
+        let server: Box<dyn AbstractServer + Send + Sync> = Box::new(stub);
+
+        let input = PipelineValues::SymbolCrossrefInfoList(SymbolCrossrefInfoList {
+            symbol_crossref_infos: vec![
+                // A page of the uses is replaced with all of them.
+                SymbolCrossrefInfo {
+                    symbol: "T_Paged".to_string(),
+                    crossref_info: json!({ "uses": [uses[1]], "uses_total": 2 }),
+                },
+                // Complete uses are used as is (the stub doesn't know it).
+                SymbolCrossrefInfo {
+                    symbol: "T_Complete".to_string(),
+                    crossref_info: json!({ "uses": uses }),
+                },
+            ],
+        });
+        let command = SymbolInfoCommand {
+            args: SymbolInfoArgs { symbols: vec![] },
+        };
+        let infos = match command.execute(&server, input).await.unwrap() {
+            PipelineValues::SymbolInfoList(sil) => sil.symbol_infos,
+            _ => panic!("Expected a SymbolInfoList"),
+        };
+        assert_eq!(infos[0].use_count, 3);
+        assert_eq!(infos[0].uses_by_path.len(), 2);
+        assert_eq!(infos[1].use_count, 3);
+    }
+}
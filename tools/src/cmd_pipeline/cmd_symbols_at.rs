@@ -0,0 +1,72 @@
+use async_trait::async_trait;
+use structopt::StructOpt;
+
+use super::interface::{
+    AnalysisRecord, AnalysisRecords, AnalysisRecordsByFile, PipelineCommand, PipelineValues,
+    SymbolList,
+};
+use crate::abstract_server::{AbstractServer, Result};
+use crate::file_format::analysis::AnalysisUnion;
+
+/// Look up the symbols at a position in a file, as determined by the source
+/// records covering the position.
+#[derive(Debug, StructOpt)]
+pub struct SymbolsAt {
+    /// Tree-relative path of the file.
+    file: String,
+
+    /// The 1-based line number.
+    line: u32,
+
+    /// The 0-based column.
+    col: u32,
+
+    /// Output the covering source records rather than their symbols.
+    #[structopt(long)]
+    records: bool,
+}
+
+pub struct SymbolsAtCommand {
+    pub args: SymbolsAt,
+}
+
+#[async_trait]
+impl PipelineCommand for SymbolsAtCommand {
+    async fn execute(
+        &self,
+        server: &Box<dyn AbstractServer + Send + Sync>,
+        _input: PipelineValues,
+    ) -> Result<PipelineValues> {
+        let values = server
+            .source_records_at(&self.args.file, self.args.line, self.args.col)
+            .await?;
+        let mut records = vec![];
+        for value in values {
+            records.push(AnalysisRecord::from_json(value));
+        }
+
+        if self.args.records {
+            return Ok(PipelineValues::AnalysisRecords(AnalysisRecords {
+                by_file: vec![AnalysisRecordsByFile {
+                    file: self.args.file.clone(),
+                    records,
+                }],
+            }));
+        }
+
+        let mut symbols: Vec<String> = vec![];
+        for record in &records {
+            if let Some(AnalysisUnion::Source(source)) = record.record.as_ref().map(|wl| &wl.data) {
+                for sym in &source.sym {
+                    if !symbols.iter().any(|s| s == sym.as_str()) {
+                        symbols.push(sym.to_string());
+                    }
+                }
+            }
+        }
+        Ok(PipelineValues::SymbolList(SymbolList {
+            symbols,
+            from_identifiers: None,
+        }))
+    }
+}
@@ -0,0 +1,35 @@
+use async_trait::async_trait;
+use futures_util::future::join_all;
+
+use super::interface::{run_commands, PipelineCommand, PipelineValues};
+use crate::abstract_server::{AbstractServer, Result};
+
+/// Run multiple sub-pipelines concurrently, each receiving its own copy of our
+/// input, and join their results with `PipelineValues::join`.
+///
+/// This isn't a structopt command; the pipeline builder creates it from the
+/// grammar `tee { pipeline } { pipeline } ...`.  For example:
+///
+/// `tee { search-identifiers Foo } { search-identifiers Bar } | crossref-lookup`
+pub struct TeeCommand {
+    pub branches: Vec<Vec<Box<dyn PipelineCommand>>>,
+}
+
+#[async_trait]
+impl PipelineCommand for TeeCommand {
+    async fn execute(
+        &self,
+        server: &Box<dyn AbstractServer + Send + Sync>,
+        input: PipelineValues,
+    ) -> Result<PipelineValues> {
+        let results = join_all(
+            self.branches
+                .iter()
+                .map(|branch| run_commands(server, branch, input.clone())),
+        )
+        .await;
+
+        let values = results.into_iter().collect::<Result<Vec<_>>>()?;
+        PipelineValues::join(values)
+    }
+}
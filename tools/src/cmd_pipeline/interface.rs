@@ -1,10 +1,14 @@
 use async_trait::async_trait;
 use clap::arg_enum;
-use serde_json::Value;
-use std::collections::HashSet;
+use serde::Serialize;
+use serde_json::{from_value, Value};
+use std::collections::{HashMap, HashSet};
 use structopt::StructOpt;
 
 pub use crate::abstract_server::{AbstractServer, Result};
+use crate::abstract_server::RetryLog;
+use crate::abstract_server::{ErrorDetails, ErrorLayer, ServerError};
+use crate::file_format::analysis::{try_parse_location, AnalysisUnion, WithLocation};
 
 arg_enum! {
   #[derive(Debug, PartialEq)]
@@ -27,22 +31,174 @@ pub struct SymbolicQueryOpts {
 }
 
 /// The input and output of each pipeline segment
+#[derive(Clone)]
 pub enum PipelineValues {
     IdentifierList(IdentifierList),
+    FileList(FileList),
     SymbolList(SymbolList),
     SymbolCrossrefInfoList(SymbolCrossrefInfoList),
+    SymbolInfoList(SymbolInfoList),
     JsonValue(JsonValue),
     JsonRecords(JsonRecords),
+    AnalysisRecords(AnalysisRecords),
     HtmlExcerpts(HtmlExcerpts),
+    SymbolGraph(SymbolGraph),
+    TextBlocks(TextBlocks),
     Void,
 }
 
+/// Append the per-file groups in `groups` to `into`, merging the contents of
+/// groups whose file is already present.
+fn merge_by_file<T>(
+    into: &mut Vec<T>,
+    groups: Vec<T>,
+    file_of: impl Fn(&T) -> &str,
+    mut merge: impl FnMut(&mut T, T),
+) {
+    for group in groups {
+        match into.iter_mut().find(|g| file_of(g) == file_of(&group)) {
+            Some(existing) => merge(existing, group),
+            None => into.push(group),
+        }
+    }
+}
+
+impl PipelineValues {
+    pub fn variant_name(&self) -> &'static str {
+        match self {
+            PipelineValues::IdentifierList(_) => "IdentifierList",
+            PipelineValues::FileList(_) => "FileList",
+            PipelineValues::SymbolList(_) => "SymbolList",
+            PipelineValues::SymbolCrossrefInfoList(_) => "SymbolCrossrefInfoList",
+            PipelineValues::SymbolInfoList(_) => "SymbolInfoList",
+            PipelineValues::JsonValue(_) => "JsonValue",
+            PipelineValues::JsonRecords(_) => "JsonRecords",
+            PipelineValues::AnalysisRecords(_) => "AnalysisRecords",
+            PipelineValues::HtmlExcerpts(_) => "HtmlExcerpts",
+            PipelineValues::SymbolGraph(_) => "SymbolGraph",
+            PipelineValues::TextBlocks(_) => "TextBlocks",
+            PipelineValues::Void => "Void",
+        }
+    }
+
+    /// Join the results of multiple pipeline branches into a single value.
+    /// `Void` values are ignored and all other values must be of the same
+    /// type.  Lists are concatenated (file lists without duplicates), per-file
+    /// results are merged by file, graphs are unified by symbol, and JSON
+    /// values are gathered into an array.
+    pub fn join(values: Vec<PipelineValues>) -> Result<PipelineValues> {
+        let mut values = values
+            .into_iter()
+            .filter(|v| !matches!(v, PipelineValues::Void));
+        let mut joined = match values.next() {
+            Some(first) => first,
+            None => return Ok(PipelineValues::Void),
+        };
+        if let PipelineValues::JsonValue(jv) = joined {
+            joined = PipelineValues::JsonValue(JsonValue {
+                value: Value::Array(vec![jv.value]),
+            });
+        }
+
+        for value in values {
+            match (&mut joined, value) {
+                (PipelineValues::IdentifierList(a), PipelineValues::IdentifierList(b)) => {
+                    a.identifiers.extend(b.identifiers);
+                }
+                (PipelineValues::FileList(a), PipelineValues::FileList(b)) => {
+                    for file in b.files {
+                        if !a.files.contains(&file) {
+                            a.files.push(file);
+                        }
+                    }
+                }
+                (PipelineValues::SymbolList(a), PipelineValues::SymbolList(b)) => {
+                    a.symbols.extend(b.symbols);
+                    a.from_identifiers = match (a.from_identifiers.take(), b.from_identifiers) {
+                        (Some(mut a_ids), Some(b_ids)) => {
+                            a_ids.extend(b_ids);
+                            Some(a_ids)
+                        }
+                        // We can't keep the lists parallel.
+                        _ => None,
+                    };
+                }
+                (
+                    PipelineValues::SymbolCrossrefInfoList(a),
+                    PipelineValues::SymbolCrossrefInfoList(b),
+                ) => {
+                    a.symbol_crossref_infos.extend(b.symbol_crossref_infos);
+                }
+                (PipelineValues::SymbolInfoList(a), PipelineValues::SymbolInfoList(b)) => {
+                    a.symbol_infos.extend(b.symbol_infos);
+                }
+                (PipelineValues::JsonValue(a), PipelineValues::JsonValue(b)) => {
+                    if let Value::Array(arr) = &mut a.value {
+                        arr.push(b.value);
+                    }
+                }
+                (PipelineValues::JsonRecords(a), PipelineValues::JsonRecords(b)) => {
+                    merge_by_file(
+                        &mut a.by_file,
+                        b.by_file,
+                        |g| &g.file,
+                        |x, y| x.records.extend(y.records),
+                    );
+                }
+                (PipelineValues::AnalysisRecords(a), PipelineValues::AnalysisRecords(b)) => {
+                    merge_by_file(
+                        &mut a.by_file,
+                        b.by_file,
+                        |g| &g.file,
+                        |x, y| x.records.extend(y.records),
+                    );
+                }
+                (PipelineValues::HtmlExcerpts(a), PipelineValues::HtmlExcerpts(b)) => {
+                    merge_by_file(
+                        &mut a.by_file,
+                        b.by_file,
+                        |g| &g.file,
+                        |x, y| x.excerpts.extend(y.excerpts),
+                    );
+                }
+                (PipelineValues::SymbolGraph(a), PipelineValues::SymbolGraph(b)) => {
+                    let graph = std::mem::take(a);
+                    *a = graph.merge(b);
+                }
+                (PipelineValues::TextBlocks(a), PipelineValues::TextBlocks(b)) => {
+                    a.blocks.extend(b.blocks);
+                }
+                (a, b) => {
+                    return Err(ServerError::StickyProblem(ErrorDetails {
+                        layer: ErrorLayer::BadInput,
+                        message: format!(
+                            "Unable to join {} with {}",
+                            a.variant_name(),
+                            b.variant_name()
+                        ),
+                    }));
+                }
+            }
+        }
+
+        Ok(joined)
+    }
+}
+
 /// A list of (searchfox) identifiers.
+#[derive(Clone)]
 pub struct IdentifierList {
     pub identifiers: Vec<String>,
 }
 
+/// A list of tree-relative file paths.
+#[derive(Clone)]
+pub struct FileList {
+    pub files: Vec<String>,
+}
+
 /// A list of (searchfox) symbols.
+#[derive(Clone)]
 pub struct SymbolList {
     pub symbols: Vec<String>,
     /// If present, these correspond to the identifiers that give us the
@@ -53,17 +209,82 @@ pub struct SymbolList {
 }
 
 /// A symbol and its cross-reference information.
+#[derive(Clone)]
 pub struct SymbolCrossrefInfo {
     pub symbol: String,
     pub crossref_info: Value,
 }
 
 /// A list of `SymbolCrossrefInfo`s.
+#[derive(Clone)]
 pub struct SymbolCrossrefInfoList {
     pub symbol_crossref_infos: Vec<SymbolCrossrefInfo>,
 }
 
+/// A line in a file.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SourceLocation {
+    pub path: String,
+    pub lineno: u64,
+}
+
+/// A symbol and its pretty name.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PrettySymbol {
+    pub sym: String,
+    pub pretty: String,
+}
+
+/// The number of uses of a symbol in a single file.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PathUseCount {
+    pub path: String,
+    pub count: usize,
+}
+
+/// A summary of what we know about a symbol, merged from its crossref `meta`,
+/// its crossref hits and the jumps file.
+///
+/// This is serialized for consumption by editor integrations, so the schema
+/// should only be extended, never changed.  Every field is always present,
+/// with unknown values represented as null or an empty list.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SymbolInfo {
+    pub sym: String,
+    pub pretty: Option<String>,
+    pub kind: Option<String>,
+    /// From the jumps file if present, otherwise the first definition.
+    pub definition: Option<SourceLocation>,
+    pub definitions: Vec<SourceLocation>,
+    pub declarations: Vec<SourceLocation>,
+    pub size_bytes: Option<u32>,
+    pub parent_sym: Option<String>,
+    pub supers: Vec<PrettySymbol>,
+    pub idl_sym: Option<String>,
+    /// For IPC messages, the send method's symbol.
+    pub src_sym: Option<String>,
+    /// For IPC messages, the receive method's symbol.
+    pub target_sym: Option<String>,
+    /// Locations from the crossref "idl" hits, linking IDL definitions and
+    /// their bindings.
+    pub idl: Vec<SourceLocation>,
+    /// Locations from the crossref "ipc" hits, linking IPC senders and
+    /// receivers.
+    pub ipc: Vec<SourceLocation>,
+    pub use_count: usize,
+    /// Sorted by descending count and then by path.
+    pub uses_by_path: Vec<PathUseCount>,
+}
+
+/// A list of `SymbolInfo`s.
+#[derive(Clone)]
+pub struct SymbolInfoList {
+    pub symbol_infos: Vec<SymbolInfo>,
+}
+
 /// JSON records are raw analysis records from a single file (for now)
+#[derive(Clone)]
 pub struct JsonRecordsByFile {
     pub file: String,
     pub records: Vec<Value>,
@@ -93,26 +314,349 @@ impl JsonRecordsByFile {
 ///
 /// It might make sense to add a type-indicating value or origin of the JSON,
 /// but for now this will only be from the query.
+#[derive(Clone)]
 pub struct JsonValue {
     pub value: Value,
 }
 
 /// JSON Analysis Records grouped by (source) file.
+#[derive(Clone)]
 pub struct JsonRecords {
     pub by_file: Vec<JsonRecordsByFile>,
 }
 
+/// A typed analysis record paired with the JSON it was parsed from.
+#[derive(Clone)]
+pub struct AnalysisRecord {
+    /// None if the record doesn't deserialize into any of our typed
+    /// representations (ex: it's from a newer indexer), in which case it's
+    /// only available as `json`.
+    pub record: Option<WithLocation<AnalysisUnion>>,
+    /// Re-serializing `record` is not identical to the original JSON (ex:
+    /// `AnalysisStructured` emits its defaulted fields), so we hold onto the
+    /// original so that conversion back to JSON is lossless.
+    pub json: Value,
+}
+
+impl AnalysisRecord {
+    pub fn from_json(json: Value) -> AnalysisRecord {
+        let record = from_value(json.clone()).ok();
+        AnalysisRecord { record, json }
+    }
+
+    /// The line of the record, which for an untyped record is taken from its
+    /// `loc` if it has a usable one.
+    pub fn lineno(&self) -> Option<u32> {
+        match &self.record {
+            Some(record) => Some(record.loc.lineno),
+            None => self.json["loc"]
+                .as_str()
+                .and_then(try_parse_location)
+                .map(|loc| loc.lineno),
+        }
+    }
+}
+
+/// Typed analysis records from a single file.
+#[derive(Clone)]
+pub struct AnalysisRecordsByFile {
+    pub file: String,
+    pub records: Vec<AnalysisRecord>,
+}
+
+impl AnalysisRecordsByFile {
+    /// Return the set of lines covered by the records in this structure.  See
+    /// `JsonRecordsByFile::line_set`.
+    pub fn line_set(&self) -> HashSet<u32> {
+        self.records.iter().filter_map(|r| r.lineno()).collect()
+    }
+}
+
+/// Typed analysis records grouped by (source) file.
+#[derive(Clone)]
+pub struct AnalysisRecords {
+    pub by_file: Vec<AnalysisRecordsByFile>,
+}
+
+impl AnalysisRecords {
+    /// Convert back to the JSON records the typed records were parsed from.
+    pub fn into_json_records(self) -> JsonRecords {
+        JsonRecords {
+            by_file: self
+                .by_file
+                .into_iter()
+                .map(|arbf| JsonRecordsByFile {
+                    file: arbf.file,
+                    records: arbf.records.into_iter().map(|r| r.json).collect(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// A node in a `SymbolGraph`.
+#[derive(Clone, Serialize)]
+pub struct SymbolGraphNode {
+    pub sym: String,
+    /// The pretty identifier for the symbol, falling back to the symbol itself
+    /// if we didn't have anything better.
+    pub pretty: String,
+    /// The structured "kind" of the symbol, like "method" or "class", if known.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub kind: String,
+    /// The number of edges traversed from the nearest root to reach this node.
+    pub depth: u32,
+    /// The size of the type in bytes, if known and relevant.
+    #[serde(rename = "sizeBytes", skip_serializing_if = "Option::is_none")]
+    pub size_bytes: Option<u32>,
+    /// The path of the file containing the symbol's definition, if known and
+    /// relevant.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+}
+
+/// A directed edge in a `SymbolGraph` expressed as indices into its `nodes`.
+#[derive(Clone, Serialize)]
+pub struct SymbolGraphEdge {
+    pub from: usize,
+    pub to: usize,
+    /// What the edge means, like "calls".
+    pub kind: &'static str,
+}
+
+/// A directed graph of symbols, like a call graph.  Nodes are unique by symbol
+/// and edges are unique by (from, to, kind).
+#[derive(Clone, Default, Serialize)]
+pub struct SymbolGraph {
+    pub nodes: Vec<SymbolGraphNode>,
+    pub edges: Vec<SymbolGraphEdge>,
+    /// True if a node or edge limit was hit while building the graph, which
+    /// means that the graph is incomplete.
+    pub truncated: bool,
+}
+
+impl SymbolGraph {
+    /// Edge labels are only interesting when a graph mixes different kinds of
+    /// edges, so we only emit them in that case.
+    fn wants_edge_labels(&self) -> bool {
+        match self.edges.first() {
+            Some(first) => self.edges.iter().any(|e| e.kind != first.kind),
+            None => false,
+        }
+    }
+
+    /// Merge another graph into this one, unifying nodes by symbol and keeping
+    /// the shallowest depth for nodes present in both.
+    pub fn merge(self, other: SymbolGraph) -> SymbolGraph {
+        let truncated = self.truncated || other.truncated;
+        let mut builder = SymbolGraphBuilder::new(usize::MAX, usize::MAX);
+        for graph in [self, other] {
+            let mut remap = vec![];
+            for node in graph.nodes {
+                // There are no limits, so this can't fail.
+                let (idx, _) = builder
+                    .ensure_node(&node.sym, &node.pretty, &node.kind, node.depth)
+                    .unwrap();
+                let existing = builder.node_mut(idx);
+                existing.depth = existing.depth.min(node.depth);
+                if existing.kind.is_empty() {
+                    existing.kind = node.kind;
+                }
+                if existing.size_bytes.is_none() {
+                    existing.size_bytes = node.size_bytes;
+                }
+                if existing.file.is_none() {
+                    existing.file = node.file;
+                }
+                remap.push(idx);
+            }
+            for edge in graph.edges {
+                builder.add_edge(remap[edge.from], remap[edge.to], edge.kind);
+            }
+        }
+
+        let mut graph = builder.build();
+        graph.truncated = truncated;
+        graph
+    }
+
+    /// Render the graph in Graphviz DOT format.  Root nodes are drawn in bold.
+    pub fn to_dot(&self) -> String {
+        fn escape(s: &str) -> String {
+            s.replace('\\', "\\\\").replace('"', "\\\"")
+        }
+
+        let mut out = String::from("digraph G {\n");
+        out.push_str("  node [shape=box, fontname=\"monospace\"];\n");
+        if self.truncated {
+            out.push_str("  label=\"(truncated)\";\n");
+        }
+        for (i, node) in self.nodes.iter().enumerate() {
+            let style = if node.depth == 0 { ", style=bold" } else { "" };
+            out.push_str(&format!(
+                "  n{} [label=\"{}\"{}];\n",
+                i,
+                escape(&node.pretty),
+                style
+            ));
+        }
+        let labels = self.wants_edge_labels();
+        for edge in &self.edges {
+            if labels {
+                out.push_str(&format!(
+                    "  n{} -> n{} [label=\"{}\"];\n",
+                    edge.from, edge.to, edge.kind
+                ));
+            } else {
+                out.push_str(&format!("  n{} -> n{};\n", edge.from, edge.to));
+            }
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    /// Render the graph as a Mermaid flowchart.  Root nodes are drawn with
+    /// rounded corners.
+    pub fn to_mermaid(&self) -> String {
+        // Mermaid labels can contain entity codes, which lets us avoid
+        // confusing its parser with quotes and C++ template brackets.
+        fn escape(s: &str) -> String {
+            s.replace('#', "#35;")
+                .replace('"', "#quot;")
+                .replace('<', "#lt;")
+                .replace('>', "#gt;")
+        }
+
+        let mut out = String::from("graph TD\n");
+        if self.truncated {
+            out.push_str("  %% truncated\n");
+        }
+        for (i, node) in self.nodes.iter().enumerate() {
+            let (open, close) = if node.depth == 0 { ("(", ")") } else { ("[", "]") };
+            out.push_str(&format!(
+                "  n{}{}\"{}\"{}\n",
+                i,
+                open,
+                escape(&node.pretty),
+                close
+            ));
+        }
+        let labels = self.wants_edge_labels();
+        for edge in &self.edges {
+            if labels {
+                out.push_str(&format!(
+                    "  n{} -->|{}| n{}\n",
+                    edge.from, edge.kind, edge.to
+                ));
+            } else {
+                out.push_str(&format!("  n{} --> n{}\n", edge.from, edge.to));
+            }
+        }
+        out
+    }
+}
+
+/// Helper to build a `SymbolGraph` that de-duplicates nodes and edges and
+/// enforces node and edge limits, marking the graph as truncated if they are
+/// hit.
+pub struct SymbolGraphBuilder {
+    graph: SymbolGraph,
+    node_limit: usize,
+    edge_limit: usize,
+    sym_to_node: HashMap<String, usize>,
+    edge_set: HashSet<(usize, usize, &'static str)>,
+}
+
+impl SymbolGraphBuilder {
+    pub fn new(node_limit: usize, edge_limit: usize) -> Self {
+        SymbolGraphBuilder {
+            graph: SymbolGraph::default(),
+            node_limit,
+            edge_limit,
+            sym_to_node: HashMap::new(),
+            edge_set: HashSet::new(),
+        }
+    }
+
+    /// Return the node index for the given symbol, adding the node if this is
+    /// the first time we've seen the symbol.  The boolean is true if the node
+    /// was newly added and so needs to be traversed.  None is returned if the
+    /// node limit prevented adding the node.
+    pub fn ensure_node(
+        &mut self,
+        sym: &str,
+        pretty: &str,
+        kind: &str,
+        depth: u32,
+    ) -> Option<(usize, bool)> {
+        if let Some(idx) = self.sym_to_node.get(sym) {
+            return Some((*idx, false));
+        }
+        if self.graph.nodes.len() >= self.node_limit {
+            self.graph.truncated = true;
+            return None;
+        }
+        let idx = self.graph.nodes.len();
+        self.graph.nodes.push(SymbolGraphNode {
+            sym: sym.to_string(),
+            pretty: if pretty.is_empty() { sym } else { pretty }.to_string(),
+            kind: kind.to_string(),
+            depth,
+            size_bytes: None,
+            file: None,
+        });
+        self.sym_to_node.insert(sym.to_string(), idx);
+        Some((idx, true))
+    }
+
+    pub fn node(&self, idx: usize) -> &SymbolGraphNode {
+        &self.graph.nodes[idx]
+    }
+
+    pub fn node_mut(&mut self, idx: usize) -> &mut SymbolGraphNode {
+        &mut self.graph.nodes[idx]
+    }
+
+    /// Add an edge, returning false if the edge limit has been hit and the
+    /// traversal should stop.
+    pub fn add_edge(&mut self, from: usize, to: usize, kind: &'static str) -> bool {
+        if self.edge_set.contains(&(from, to, kind)) {
+            return true;
+        }
+        if self.graph.edges.len() >= self.edge_limit {
+            self.graph.truncated = true;
+            return false;
+        }
+        self.edge_set.insert((from, to, kind));
+        self.graph.edges.push(SymbolGraphEdge { from, to, kind });
+        true
+    }
+
+    pub fn build(self) -> SymbolGraph {
+        self.graph
+    }
+}
+
+/// Pre-rendered plain text for human consumption, like a table, with one block
+/// per input item.
+#[derive(Clone)]
+pub struct TextBlocks {
+    pub blocks: Vec<String>,
+}
+
+#[derive(Clone)]
 pub struct HtmlExcerptsByFile {
     pub file: String,
     pub excerpts: Vec<String>,
 }
 
+#[derive(Clone)]
 pub struct HtmlExcerpts {
     pub by_file: Vec<HtmlExcerptsByFile>,
 }
 
 #[async_trait]
-pub trait PipelineCommand {
+pub trait PipelineCommand: Send + Sync {
     async fn execute(
         &self,
         server: &Box<dyn AbstractServer + Send + Sync>,
@@ -120,26 +664,51 @@ pub trait PipelineCommand {
     ) -> Result<PipelineValues>;
 }
 
+/// Run the given commands in sequence against the server, providing `input` to
+/// the first command.
+pub async fn run_commands(
+    server: &Box<dyn AbstractServer + Send + Sync>,
+    commands: &[Box<dyn PipelineCommand>],
+    input: PipelineValues,
+) -> Result<PipelineValues> {
+    let mut cur_values = input;
+
+    for cmd in commands {
+        match cmd.execute(server, cur_values).await {
+            Ok(next_values) => {
+                cur_values = next_values;
+            }
+            Err(err) => {
+                return Err(err);
+            }
+        }
+    }
+
+    Ok(cur_values)
+}
+
 pub struct ServerPipeline {
     pub server: Box<dyn AbstractServer + Send + Sync>,
     pub commands: Vec<Box<dyn PipelineCommand>>,
+    /// The retries `server` has performed, which callers can `take()` after
+    /// each run to report a per-pipeline summary.
+    pub retry_log: RetryLog,
 }
 
 impl ServerPipeline {
     pub async fn run(&self) -> Result<PipelineValues> {
-        let mut cur_values = PipelineValues::Void;
-
-        for cmd in &self.commands {
-            match cmd.execute(&self.server, cur_values).await {
-                Ok(next_values) => {
-                    cur_values = next_values;
-                }
-                Err(err) => {
-                    return Err(err);
-                }
-            }
-        }
+        run_commands(&self.server, &self.commands, PipelineValues::Void).await
+    }
 
-        Ok(cur_values)
+    /// Run the given commands against our server, providing `input` to the
+    /// first command.  This is used by the REPL to run each line's commands
+    /// against a single long-lived server, potentially feeding the previous
+    /// line's results in.
+    pub async fn run_commands(
+        &self,
+        commands: &[Box<dyn PipelineCommand>],
+        input: PipelineValues,
+    ) -> Result<PipelineValues> {
+        run_commands(&self.server, commands, input).await
     }
 }
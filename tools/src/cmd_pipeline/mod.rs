@@ -4,14 +4,24 @@ extern crate structopt;
 pub mod builder;
 pub mod interface;
 pub mod parser;
+pub mod repl;
 
+mod cmd_call_graph;
+mod cmd_class_hierarchy;
 mod cmd_crossref_lookup;
+mod cmd_field_layout;
 mod cmd_filter_analysis;
+mod cmd_index_diff;
 mod cmd_merge_analyses;
 mod cmd_prod_filter;
 mod cmd_query;
+mod cmd_search_files;
 mod cmd_search_identifiers;
+mod cmd_search_text;
 mod cmd_show_html;
+mod cmd_symbol_info;
+mod cmd_symbols_at;
+mod cmd_tee;
 
 pub use builder::{build_pipeline};
 pub use interface::{PipelineCommand, PipelineValues};
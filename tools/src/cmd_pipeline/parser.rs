@@ -1,26 +1,44 @@
+use std::time::Duration;
+
 use clap::arg_enum;
 use structopt::StructOpt;
 
+use super::cmd_call_graph::CallGraph;
+use super::cmd_class_hierarchy::ClassHierarchy;
 use super::cmd_crossref_lookup::CrossrefLookup;
+use super::cmd_field_layout::FieldLayout;
 use super::cmd_filter_analysis::FilterAnalysis;
+use super::cmd_index_diff::IndexDiff;
 use super::cmd_merge_analyses::MergeAnalyses;
 use super::cmd_prod_filter::ProductionFilter;
 use super::cmd_query::Query;
+use super::cmd_search_files::SearchFiles;
 use super::cmd_search_identifiers::SearchIdentifiers;
+use super::cmd_search_text::SearchText;
 use super::cmd_show_html::ShowHtml;
+use super::cmd_symbol_info::SymbolInfoArgs;
+use super::cmd_symbols_at::SymbolsAt;
+use crate::abstract_server::{
+    CacheConfig, ErrorDetails, ErrorLayer, Result, RetryPolicy, ServerError,
+};
 
 arg_enum! {
-    #[derive(Debug, PartialEq)]
+    #[derive(Clone, Copy, Debug, PartialEq)]
     pub enum OutputFormat {
         // Pretty-printed JSON.
         Pretty,
         // Un-pretty-printed JSON.
         Concise,
+        // Graphviz DOT for graph results, concise JSON otherwise.
+        Dot,
+        // Mermaid flowchart for graph results, concise JSON otherwise.
+        Mermaid,
     }
 }
 
+/// The options that determine what server we talk to.
 #[derive(Debug, StructOpt)]
-pub struct ToolOpts {
+pub struct ServerOpts {
     /// URL of the server to query or the path to the root of the index tree if
     /// using local data.
     #[structopt(
@@ -34,20 +52,158 @@ pub struct ToolOpts {
     #[structopt(long, default_value = "mozilla-central", env = "SEARCHFOX_TREE")]
     pub tree: String,
 
-    #[structopt(long, short, possible_values = &OutputFormat::variants(), case_insensitive = true, default_value = "concise")]
-    pub output_format: OutputFormat,
+    /// The maximum number of attempts, including the first, for server
+    /// requests that fail with a transient problem like a 504.  1 disables
+    /// retries.
+    #[structopt(long, default_value = "4")]
+    pub retry_attempts: u32,
+
+    /// The delay before the first retry in milliseconds, which doubles for each
+    /// subsequent retry.
+    #[structopt(long, default_value = "500")]
+    pub retry_backoff_ms: u64,
+
+    /// The maximum delay between retries in milliseconds.
+    #[structopt(long, default_value = "10000")]
+    pub retry_max_backoff_ms: u64,
+
+    /// The fraction of each retry delay to randomize, from 0 to 1.
+    #[structopt(long, default_value = "0.5")]
+    pub retry_jitter: f64,
+
+    /// Cache HTML, raw analysis and query responses in this directory, which is
+    /// mainly useful for remote servers.
+    #[structopt(long, env = "SEARCHFOX_CACHE_DIR")]
+    pub cache_dir: Option<String>,
+
+    /// The revision of the tree being served, which is used to key the cache.
+    /// Change this when the server is re-indexed or rely on --cache-ttl-secs.
+    #[structopt(long, default_value = "latest")]
+    pub cache_revision: String,
+
+    /// How many seconds cache entries are used for before being re-fetched.
+    /// Entries never expire if this isn't specified.
+    #[structopt(long)]
+    pub cache_ttl_secs: Option<u64>,
+
+    /// Only serve responses from the cache, treating misses as errors instead
+    /// of contacting the server.
+    #[structopt(long, requires = "cache-dir")]
+    pub offline: bool,
+
+    /// Record every server request and its result into a fixture in this
+    /// directory for later use with --replay-fixture.
+    #[structopt(long, conflicts_with = "replay-fixture")]
+    pub record_fixture: Option<String>,
+
+    /// Serve requests from the fixture recorded in this directory instead of
+    /// using --server.
+    #[structopt(long)]
+    pub replay_fixture: Option<String>,
+}
+
+impl ServerOpts {
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: self.retry_attempts.max(1),
+            initial_backoff: Duration::from_millis(self.retry_backoff_ms),
+            max_backoff: Duration::from_millis(self.retry_max_backoff_ms),
+            jitter: self.retry_jitter,
+        }
+    }
+
+    pub fn cache_config(&self) -> Result<Option<CacheConfig>> {
+        let dir = match &self.cache_dir {
+            Some(dir) => dir,
+            None => return Ok(None),
+        };
+        // The revision becomes a directory name in the cache.
+        if self.cache_revision.is_empty()
+            || self.cache_revision.contains('/')
+            || self.cache_revision.starts_with('.')
+        {
+            return Err(ServerError::StickyProblem(ErrorDetails {
+                layer: ErrorLayer::BadInput,
+                message: format!("Bad --cache-revision: {:?}", self.cache_revision),
+            }));
+        }
+
+        Ok(Some(CacheConfig {
+            dir: dir.into(),
+            revision: self.cache_revision.clone(),
+            ttl: self.cache_ttl_secs.map(Duration::from_secs),
+            offline: self.offline,
+        }))
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub struct ToolOpts {
+    #[structopt(flatten)]
+    pub server_opts: ServerOpts,
+
+    /// How to output the results.  Defaults to "concise".
+    #[structopt(long, short, possible_values = &OutputFormat::variants(), case_insensitive = true)]
+    pub output_format: Option<OutputFormat>,
 
     #[structopt(subcommand)]
     pub cmd: Command,
 }
 
+/// Options for `searchfox-tool --repl ...` which creates a single server that
+/// the pipeline entered on each line is run against.  Server options specified
+/// on a line are ignored, but the output format can be overridden per-line.
+#[derive(Debug, StructOpt)]
+pub struct ReplOpts {
+    /// Run an interactive read-eval-print loop.
+    #[structopt(long)]
+    pub repl: bool,
+
+    #[structopt(flatten)]
+    pub server_opts: ServerOpts,
+
+    /// How to output the results.
+    #[structopt(long, short, possible_values = &OutputFormat::variants(), case_insensitive = true, default_value = "concise")]
+    pub output_format: OutputFormat,
+}
+
+/// The names of the `Command` variants as used on the command line plus `tee`,
+/// which the builder handles itself, used for tab-completion in the REPL.  Keep
+/// this in sync with `Command`!
+pub const COMMAND_NAMES: &[&str] = &[
+    "call-graph",
+    "class-hierarchy",
+    "crossref-lookup",
+    "field-layout",
+    "filter-analysis",
+    "index-diff",
+    "merge-analyses",
+    "production-filter",
+    "query",
+    "search-files",
+    "search-identifiers",
+    "search-text",
+    "show-html",
+    "symbol-info",
+    "symbols-at",
+    "tee",
+];
+
 #[derive(Debug, StructOpt)]
 pub enum Command {
+    CallGraph(CallGraph),
+    ClassHierarchy(ClassHierarchy),
     CrossrefLookup(CrossrefLookup),
+    FieldLayout(FieldLayout),
     FilterAnalysis(FilterAnalysis),
+    IndexDiff(IndexDiff),
     MergeAnalyses(MergeAnalyses),
     ProductionFilter(ProductionFilter),
     Query(Query),
+    SearchFiles(SearchFiles),
     SearchIdentifiers(SearchIdentifiers),
+    SearchText(SearchText),
     ShowHtml(ShowHtml),
+    SymbolInfo(SymbolInfoArgs),
+    SymbolsAt(SymbolsAt),
 }
@@ -0,0 +1,98 @@
+use rustyline::completion::{Completer, Pair};
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Context, Helper};
+use tokio::runtime::Handle;
+
+use super::parser::COMMAND_NAMES;
+use crate::abstract_server::AbstractServer;
+
+/// The maximum number of identifiers we'll offer as completions.
+const IDENTIFIER_COMPLETION_LIMIT: usize = 50;
+
+/// rustyline helper for the `searchfox-tool` REPL that tab-completes command
+/// names at the start of each pipeline segment or `tee` branch and otherwise
+/// completes identifiers using the server's `search_identifiers`.
+///
+/// Completion happens synchronously from within rustyline, so the REPL must
+/// call `readline` from within `tokio::task::block_in_place` so that we can
+/// block on the server.
+pub struct ReplHelper<'a> {
+    pub server: &'a (dyn AbstractServer + Send + Sync),
+}
+
+impl<'a> ReplHelper<'a> {
+    fn complete_identifier(&self, prefix: &str) -> Vec<Pair> {
+        let results = Handle::current().block_on(self.server.search_identifiers(
+            prefix,
+            false,
+            true,
+            IDENTIFIER_COMPLETION_LIMIT,
+        ));
+
+        let mut ids: Vec<String> = match results {
+            Ok(results) => results.into_iter().map(|(_sym, id)| id).collect(),
+            Err(_) => vec![],
+        };
+        ids.sort();
+        ids.dedup();
+        ids.into_iter()
+            .map(|id| Pair {
+                display: id.clone(),
+                replacement: id,
+            })
+            .collect()
+    }
+}
+
+impl<'a> Completer for ReplHelper<'a> {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let before = &line[..pos];
+        let start = before
+            .rfind(|c: char| c.is_whitespace() || c == '|')
+            .map_or(0, |idx| idx + 1);
+        let word = &before[start..];
+
+        // We're at a command position if everything between the word and the
+        // last pipe or tee branch brace (or the start of the line) is
+        // whitespace.
+        let preceding = before[..start].trim_end();
+        let at_command =
+            preceding.is_empty() || preceding.ends_with('|') || preceding.ends_with('{');
+
+        let candidates = if at_command {
+            COMMAND_NAMES
+                .iter()
+                .filter(|name| name.starts_with(word))
+                .map(|name| Pair {
+                    display: name.to_string(),
+                    replacement: format!("{} ", name),
+                })
+                .collect()
+        } else if word.is_empty() || word.starts_with('-') {
+            vec![]
+        } else {
+            self.complete_identifier(word)
+        };
+
+        Ok((start, candidates))
+    }
+}
+
+impl<'a> Hinter for ReplHelper<'a> {
+    type Hint = String;
+}
+
+impl<'a> Highlighter for ReplHelper<'a> {}
+
+impl<'a> Validator for ReplHelper<'a> {}
+
+impl<'a> Helper for ReplHelper<'a> {}
@@ -6,6 +6,7 @@ use std::io::Read;
 use itertools::Itertools;
 
 use flate2::read::GzDecoder;
+use serde::de::Error;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_json::{from_str, from_value, Map, Value};
 use serde_repr::*;
@@ -18,6 +19,17 @@ pub struct Location {
     pub col_end: u32,
 }
 
+impl Location {
+    /// Does this location cover column `col` of line `lineno`?  The end column
+    /// is exclusive, but a location without a column range covers its start
+    /// column.
+    pub fn covers(&self, lineno: u32, col: u32) -> bool {
+        self.lineno == lineno
+            && self.col_start <= col
+            && (col < self.col_end || col == self.col_start)
+    }
+}
+
 #[derive(Clone, Default, Eq, PartialEq, PartialOrd, Ord, Debug)]
 pub struct LineRange {
     pub start_lineno: u32,
@@ -30,7 +42,7 @@ impl LineRange {
     }
 }
 
-#[derive(Default, Eq, PartialEq, PartialOrd, Ord, Debug)]
+#[derive(Clone, Default, Eq, PartialEq, PartialOrd, Ord, Debug)]
 pub struct SourceRange {
     pub start_lineno: u32,
     pub start_col: u32,
@@ -78,7 +90,7 @@ impl SourceRange {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct WithLocation<T> {
     pub loc: Location,
     #[serde(flatten)]
@@ -102,13 +114,13 @@ pub enum AnalysisKind {
 /// single-value enum should take up no space... hopefully that's the case for
 /// this too despite the involvement of `serde_repr` to encode the value as an
 /// int.
-#[derive(Debug, PartialEq, Serialize_repr, Deserialize_repr)]
+#[derive(Clone, Debug, PartialEq, Serialize_repr, Deserialize_repr)]
 #[repr(u8)]
 pub enum TargetTag {
     Target = 1,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct AnalysisTarget {
     pub target: TargetTag,
     pub kind: AnalysisKind,
@@ -129,13 +141,13 @@ pub struct AnalysisTarget {
 }
 
 /// See TargetTag for more info
-#[derive(Debug, Eq, PartialEq, Serialize_repr, Deserialize_repr)]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize_repr, Deserialize_repr)]
 #[repr(u8)]
 pub enum StructuredTag {
     Structured = 1,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct StructuredSuperInfo {
     #[serde(default)]
     pub pretty: Ustr,
@@ -145,7 +157,7 @@ pub struct StructuredSuperInfo {
     pub props: Vec<Ustr>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct StructuredMethodInfo {
     #[serde(default)]
     pub pretty: Ustr,
@@ -155,13 +167,13 @@ pub struct StructuredMethodInfo {
     pub props: Vec<Ustr>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct StructuredBitPositionInfo {
     pub begin: u32,
     pub width: u32,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct StructuredOverrideInfo {
     #[serde(default)]
     pub pretty: Ustr,
@@ -169,7 +181,7 @@ pub struct StructuredOverrideInfo {
     pub sym: Ustr,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct StructuredFieldInfo {
     #[serde(default)]
     pub pretty: Ustr,
@@ -196,7 +208,7 @@ pub struct StructuredFieldInfo {
 /// Structured records are merged by choosing one platform rep to be the canoncial variant and
 /// embedding the other variants observed under a `variants` attribute.  See `analysis.md` and
 /// `merge-analyses.rs` for more details.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct AnalysisStructured {
     pub structured: StructuredTag,
     #[serde(default)]
@@ -296,7 +308,7 @@ mod comma_delimited_vec {
 }
 
 /// See TargetTag for more info
-#[derive(Serialize_repr, Deserialize_repr, PartialEq, Debug)]
+#[derive(Clone, Serialize_repr, Deserialize_repr, PartialEq, Debug)]
 #[repr(u8)]
 pub enum SourceTag {
     Source = 1,
@@ -306,7 +318,7 @@ fn bool_is_false(b: &bool) -> bool {
     !b
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct AnalysisSource {
     pub source: SourceTag,
     #[serde(with = "comma_delimited_vec")]
@@ -396,7 +408,7 @@ impl AnalysisSource {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum AnalysisUnion {
     Target(AnalysisTarget),
@@ -422,6 +434,18 @@ pub fn parse_location(loc: &str) -> Location {
     }
 }
 
+/// Like `parse_location`, but returns None for a malformed location instead of
+/// panicking, for use on records that haven't been validated.
+pub fn try_parse_location(loc: &str) -> Option<Location> {
+    let (lineno, cols) = loc.split_once(':')?;
+    let (col_start, col_end) = cols.split_once('-').unwrap_or((cols, cols));
+    Some(Location {
+        lineno: lineno.parse().ok()?,
+        col_start: col_start.parse().ok()?,
+        col_end: col_end.parse().ok()?,
+    })
+}
+
 impl Serialize for Location {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -442,7 +466,7 @@ impl<'de> Deserialize<'de> for Location {
         D: Deserializer<'de>,
     {
         let s = String::deserialize(deserializer)?;
-        Ok(parse_location(&s))
+        try_parse_location(&s).ok_or_else(|| D::Error::custom(format!("bad location: {}", s)))
     }
 }
 
@@ -613,6 +637,50 @@ pub fn read_analyses<T>(
     result2
 }
 
+/// Check whether a raw analysis record is a source record covering the given
+/// position.  Records with a missing or malformed location cover nothing.
+///
+/// Analysis files aren't guaranteed to be sorted by location (merged and
+/// hand-written files may not be), so callers need to check every record.
+pub fn source_record_covers(record: &Value, lineno: u32, col: u32) -> bool {
+    match record["loc"].as_str().and_then(try_parse_location) {
+        Some(loc) => record.get("source").is_some() && loc.covers(lineno, col),
+        None => false,
+    }
+}
+
+/// The line number from the leading `{"loc":"NNNNN:` of a raw analysis record
+/// line, which lets records on other lines be skipped without parsing them.
+/// Returns None if the line doesn't start that way, in which case it has to be
+/// parsed to find out.
+pub fn raw_record_lineno(line: &str) -> Option<u32> {
+    let rest = line.strip_prefix("{\"loc\":\"")?;
+    let (lineno, _) = rest.split_once(':')?;
+    lineno.parse().ok()
+}
+
+/// Read the source records covering the given position from a gzipped
+/// analysis file.  Only the records that might be on the line are parsed.
+pub fn read_source_records_at(
+    filename: &str,
+    lineno: u32,
+    col: u32,
+) -> std::io::Result<Vec<Value>> {
+    let reader = BufReader::new(GzDecoder::new(File::open(filename)?));
+    let mut records = vec![];
+    for line in reader.lines() {
+        let line = line?;
+        if matches!(raw_record_lineno(&line), Some(record_lineno) if record_lineno != lineno) {
+            continue;
+        }
+        let record: Value = from_str(&line)?;
+        if source_record_covers(&record, lineno, col) {
+            records.push(record);
+        }
+    }
+    Ok(records)
+}
+
 pub fn read_target(obj: Value, _loc: &Location, _i_size: usize) -> Option<AnalysisTarget> {
     // XXX this shouldn't be necessary thanks to our tag, so this should be removable
     if obj.get("target").is_none() {
@@ -640,7 +708,7 @@ pub fn read_source(obj: Value, _loc: &Location, _i_size: usize) -> Option<Analys
     from_value(obj).ok()
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Jump {
     pub id: Ustr,
     pub path: String,
@@ -675,3 +743,48 @@ pub fn read_jumps(filename: &str) -> UstrMap<Jump> {
     }
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    #[test]
+    fn test_read_source_records_at() {
+        // Out of order, with a malformed location and fields in an unusual
+        // order, as a merged or hand-written file might be.
+        let records = [
+            r#"{"loc":"00012:4-9","source":1,"syntax":"use","pretty":"late","sym":"a"}"#,
+            r#"{"loc":"bogus","source":1,"syntax":"use","pretty":"bad","sym":"b"}"#,
+            r#"{"loc":"00003:0-3","source":1,"syntax":"def","pretty":"early","sym":"c"}"#,
+            r#"{"loc":"00012:4-9","target":1,"kind":"use","pretty":"late","sym":"a"}"#,
+            r#"{"loc":"00012:6","source":1,"syntax":"use","pretty":"point","sym":"d"}"#,
+            r#"{"source":1,"loc":"00012:5-8","syntax":"use","pretty":"reordered","sym":"e"}"#,
+            r#"{"loc":"12:6-7","source":1,"syntax":"use","pretty":"unpadded","sym":"f"}"#,
+            // Records on other lines aren't parsed, so this doesn't matter.
+            r#"{"loc":"00007:0-1", not even json"#,
+        ];
+        let path =
+            std::env::temp_dir().join(format!("source-records-at-{}.json.gz", std::process::id()));
+        let mut gz = GzEncoder::new(File::create(&path).unwrap(), Compression::default());
+        gz.write_all(records.join("\n").as_bytes()).unwrap();
+        gz.finish().unwrap();
+        let filename = path.to_str().unwrap();
+
+        let syms = |lineno, col| -> Vec<String> {
+            read_source_records_at(filename, lineno, col)
+                .unwrap()
+                .iter()
+                .map(|r| r["sym"].as_str().unwrap().to_string())
+                .collect()
+        };
+        assert_eq!(syms(3, 1), vec!["c"]);
+        assert_eq!(syms(12, 6), vec!["a", "d", "e", "f"]);
+        assert_eq!(syms(12, 9), Vec::<String>::new());
+        assert_eq!(syms(5, 0), Vec::<String>::new());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,538 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use memmap::Mmap;
+
+// The binary crossref format is an alternative to the text `crossref` and
+// `crossref-extra` files described in `crossref.md` which doesn't require
+// scanning for line boundaries during lookup and which compresses the JSON
+// payloads.  It's written to the `crossref` path (and there's no
+// `crossref-extra`) and is distinguished from the text format by its `MAGIC`.
+// The layout is:
+//
+// - A `HEADER_SIZE` byte header of the 8 byte `MAGIC` followed by the format
+//   version, the number of symbols, the number of blocks, and the number of
+//   use groups as little-endian u32s, then the file offsets of the names, the
+//   symbol table, the block table, and the use group table as little-endian
+//   u64s.
+// - The payload blocks.  Each block is the raw-deflate compressed
+//   concatenation of consecutive payloads.  Blocks are closed once they reach
+//   `BLOCK_SIZE` bytes uncompressed, and a payload at least that large gets a
+//   block to itself so that looking up a neighboring symbol doesn't require
+//   decompressing it.
+// - The names of the symbols and of the paths of the use groups, concatenated
+//   without separators.  Each distinct path is only stored once.
+// - The symbol table, which has a `SYMBOL_ENTRY_SIZE` byte entry per symbol,
+//   sorted by the (bytes of the) symbol name.  Each entry is the offset of the
+//   name relative to the start of the names as a little-endian u64, followed
+//   by the length of the name, the index of the block holding the payload, the
+//   offset and length of the payload within the decompressed block, and the
+//   index of the symbol's first use group and its number of use groups as
+//   little-endian u32s.
+// - The block table, which is a `BLOCK_ENTRY_SIZE` byte entry per block of
+//   the file offset of the block as a little-endian u64 followed by its
+//   compressed and decompressed lengths as little-endian u32s.
+// - The use group table, which has a `USE_GROUP_ENTRY_SIZE` byte entry per
+//   use group laid out like the first 24 bytes of a symbol table entry, with
+//   the name being the path.
+//
+// A symbol's payload is its crossref JSON without the `uses`, which are
+// instead stored as a use group per path, in sorted path order.  The payload
+// of a use group is the JSON `lines` of the path, so a subset of the uses can
+// be looked up (see `CrossrefLookupOptions`) without decompressing the rest.
+//
+// The header is written last so a partially written file won't be mistaken
+// for a valid one.
+
+pub const MAGIC: &[u8; 8] = b"SFXREFBN";
+/// Bump this whenever the layout changes in a way older readers can't handle.
+pub const VERSION: u32 = 2;
+const HEADER_SIZE: usize = 56;
+const SYMBOL_ENTRY_SIZE: usize = 32;
+const USE_GROUP_ENTRY_SIZE: usize = 24;
+const BLOCK_ENTRY_SIZE: usize = 16;
+
+/// The uncompressed size at which a payload block is closed.
+const BLOCK_SIZE: usize = 32 * 1024;
+
+/// Return true if `bytes` starts with the binary crossref `MAGIC`.
+pub fn is_binary_crossref(bytes: &[u8]) -> bool {
+    bytes.len() >= MAGIC.len() && &bytes[..MAGIC.len()] == MAGIC
+}
+
+/// Writes a binary crossref.  Symbols must be added in sorted order.
+pub struct CrossrefBinaryWriter {
+    out: BufWriter<File>,
+    /// The file offset that the next write to `out` will be at.
+    offset: u64,
+    /// The uncompressed contents of the block currently being built.
+    block: Vec<u8>,
+    names: Vec<u8>,
+    /// The offsets within `names` of the paths stored so far.
+    path_offsets: HashMap<String, u64>,
+    symbol_table: Vec<u8>,
+    block_table: Vec<u8>,
+    use_group_table: Vec<u8>,
+    symbol_count: u32,
+    block_count: u32,
+    use_group_count: u32,
+}
+
+// Append a symbol or use group table entry for a name and payload location.
+fn push_entry(
+    table: &mut Vec<u8>,
+    name_offset: u64,
+    name_len: usize,
+    location: (u32, u32),
+    len: usize,
+) {
+    table.extend_from_slice(&name_offset.to_le_bytes());
+    table.extend_from_slice(&(name_len as u32).to_le_bytes());
+    table.extend_from_slice(&location.0.to_le_bytes());
+    table.extend_from_slice(&location.1.to_le_bytes());
+    table.extend_from_slice(&(len as u32).to_le_bytes());
+}
+
+impl CrossrefBinaryWriter {
+    pub fn create(path: &str) -> io::Result<CrossrefBinaryWriter> {
+        let mut out = BufWriter::new(File::create(path)?);
+        // Leave room for the header, which gets written by `finish`.
+        out.write_all(&[0; HEADER_SIZE])?;
+        Ok(CrossrefBinaryWriter {
+            out,
+            offset: HEADER_SIZE as u64,
+            block: vec![],
+            names: vec![],
+            path_offsets: HashMap::new(),
+            symbol_table: vec![],
+            block_table: vec![],
+            use_group_table: vec![],
+            symbol_count: 0,
+            block_count: 0,
+            use_group_count: 0,
+        })
+    }
+
+    /// Add a symbol given its crossref JSON without the `uses` as `payload`,
+    /// and its uses as (path, JSON lines) pairs in sorted path order.
+    pub fn add_symbol(
+        &mut self,
+        sym: &str,
+        use_groups: &[(&str, &[u8])],
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let first_use_group = self.use_group_count;
+        for (path, lines) in use_groups {
+            let location = self.add_payload(lines)?;
+            let path_offset = match self.path_offsets.get(*path) {
+                Some(offset) => *offset,
+                None => {
+                    let offset = self.names.len() as u64;
+                    self.names.extend_from_slice(path.as_bytes());
+                    self.path_offsets.insert(path.to_string(), offset);
+                    offset
+                }
+            };
+            push_entry(
+                &mut self.use_group_table,
+                path_offset,
+                path.len(),
+                location,
+                lines.len(),
+            );
+            self.use_group_count += 1;
+        }
+
+        let location = self.add_payload(payload)?;
+        push_entry(
+            &mut self.symbol_table,
+            self.names.len() as u64,
+            sym.len(),
+            location,
+            payload.len(),
+        );
+        self.symbol_table
+            .extend_from_slice(&first_use_group.to_le_bytes());
+        self.symbol_table
+            .extend_from_slice(&(use_groups.len() as u32).to_le_bytes());
+        self.names.extend_from_slice(sym.as_bytes());
+        self.symbol_count += 1;
+        Ok(())
+    }
+
+    // Append a payload to the current block, returning the index of the block
+    // and the offset of the payload within it.
+    fn add_payload(&mut self, payload: &[u8]) -> io::Result<(u32, u32)> {
+        if payload.len() >= BLOCK_SIZE && !self.block.is_empty() {
+            self.flush_block()?;
+        }
+
+        let location = (self.block_count, self.block.len() as u32);
+        self.block.extend_from_slice(payload);
+
+        if self.block.len() >= BLOCK_SIZE {
+            self.flush_block()?;
+        }
+        Ok(location)
+    }
+
+    fn flush_block(&mut self) -> io::Result<()> {
+        let mut encoder = DeflateEncoder::new(vec![], Compression::default());
+        encoder.write_all(&self.block)?;
+        let compressed = encoder.finish()?;
+        self.out.write_all(&compressed)?;
+
+        self.block_table
+            .extend_from_slice(&self.offset.to_le_bytes());
+        self.block_table
+            .extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+        self.block_table
+            .extend_from_slice(&(self.block.len() as u32).to_le_bytes());
+        self.offset += compressed.len() as u64;
+        self.block.clear();
+        self.block_count += 1;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        if !self.block.is_empty() {
+            self.flush_block()?;
+        }
+
+        let names_offset = self.offset;
+        self.out.write_all(&self.names)?;
+        let symbols_offset = names_offset + self.names.len() as u64;
+        self.out.write_all(&self.symbol_table)?;
+        let blocks_offset = symbols_offset + self.symbol_table.len() as u64;
+        self.out.write_all(&self.block_table)?;
+        let use_groups_offset = blocks_offset + self.block_table.len() as u64;
+        self.out.write_all(&self.use_group_table)?;
+
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&VERSION.to_le_bytes());
+        header.extend_from_slice(&self.symbol_count.to_le_bytes());
+        header.extend_from_slice(&self.block_count.to_le_bytes());
+        header.extend_from_slice(&self.use_group_count.to_le_bytes());
+        header.extend_from_slice(&names_offset.to_le_bytes());
+        header.extend_from_slice(&symbols_offset.to_le_bytes());
+        header.extend_from_slice(&blocks_offset.to_le_bytes());
+        header.extend_from_slice(&use_groups_offset.to_le_bytes());
+        self.out.seek(SeekFrom::Start(0))?;
+        self.out.write_all(&header)?;
+        self.out.flush()
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut buf = [0; 4];
+    buf.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(buf)
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_le_bytes(buf)
+}
+
+/// Where a symbol's or use group's payload lives.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PayloadLocation {
+    pub block: usize,
+    pub offset: usize,
+    pub len: usize,
+}
+
+/// A memory-mapped binary crossref as written by `CrossrefBinaryWriter`.
+#[derive(Debug)]
+pub struct CrossrefBinary {
+    mm: Mmap,
+    symbol_count: usize,
+    block_count: usize,
+    use_group_count: usize,
+    names_offset: usize,
+    symbols_offset: usize,
+    blocks_offset: usize,
+    use_groups_offset: usize,
+}
+
+impl CrossrefBinary {
+    /// Wrap the given map of the file at `path`, returning None if it's not a
+    /// binary crossref of a version we understand or it's truncated.
+    pub fn new(mm: Mmap, path: &str) -> Option<CrossrefBinary> {
+        let bytes: &[u8] = unsafe { mm.as_slice() };
+        if bytes.len() < HEADER_SIZE || !is_binary_crossref(bytes) {
+            warn!("Ignoring malformed binary crossref {}", path);
+            return None;
+        }
+        let version = read_u32(bytes, 8);
+        if version != VERSION {
+            warn!(
+                "Ignoring binary crossref {} with unsupported version {} (expected {})",
+                path, version, VERSION
+            );
+            return None;
+        }
+        let symbol_count = read_u32(bytes, 12) as usize;
+        let block_count = read_u32(bytes, 16) as usize;
+        let use_group_count = read_u32(bytes, 20) as usize;
+        let names_offset = read_u64(bytes, 24) as usize;
+        let symbols_offset = read_u64(bytes, 32) as usize;
+        let blocks_offset = read_u64(bytes, 40) as usize;
+        let use_groups_offset = read_u64(bytes, 48) as usize;
+        // The offsets and counts come from the file, so a corrupt header could
+        // overflow.
+        let table_fits = |offset: usize, count: usize, entry_size: usize| {
+            let end = count
+                .checked_mul(entry_size)
+                .and_then(|size| offset.checked_add(size));
+            matches!(end, Some(end) if end <= bytes.len())
+        };
+        if names_offset > bytes.len()
+            || !table_fits(symbols_offset, symbol_count, SYMBOL_ENTRY_SIZE)
+            || !table_fits(blocks_offset, block_count, BLOCK_ENTRY_SIZE)
+            || !table_fits(use_groups_offset, use_group_count, USE_GROUP_ENTRY_SIZE)
+        {
+            warn!("Ignoring truncated binary crossref {}", path);
+            return None;
+        }
+
+        Some(CrossrefBinary {
+            mm,
+            symbol_count,
+            block_count,
+            use_group_count,
+            names_offset,
+            symbols_offset,
+            blocks_offset,
+            use_groups_offset,
+        })
+    }
+
+    pub fn symbol_count(&self) -> usize {
+        self.symbol_count
+    }
+
+    fn bytes(&self) -> &[u8] {
+        unsafe { self.mm.as_slice() }
+    }
+
+    // The name of the symbol or use group table entry at the given offset, or
+    // None if the entry points outside the file.
+    fn entry_name(&self, entry: usize) -> Option<&[u8]> {
+        let bytes = self.bytes();
+        let start = self
+            .names_offset
+            .checked_add(read_u64(bytes, entry) as usize)?;
+        let len = read_u32(bytes, entry + 8) as usize;
+        bytes.get(start..start.checked_add(len)?)
+    }
+
+    fn entry_location(&self, entry: usize) -> PayloadLocation {
+        let bytes = self.bytes();
+        PayloadLocation {
+            block: read_u32(bytes, entry + 12) as usize,
+            offset: read_u32(bytes, entry + 16) as usize,
+            len: read_u32(bytes, entry + 20) as usize,
+        }
+    }
+
+    /// The name of the symbol at the given index in the symbol table, or None
+    /// if the crossref is corrupt.
+    pub fn symbol(&self, index: usize) -> Option<&[u8]> {
+        self.entry_name(self.symbols_offset + index * SYMBOL_ENTRY_SIZE)
+    }
+
+    pub fn payload_location(&self, index: usize) -> PayloadLocation {
+        self.entry_location(self.symbols_offset + index * SYMBOL_ENTRY_SIZE)
+    }
+
+    /// The indices of the use groups of the symbol at the given index, which
+    /// are in sorted path order.
+    pub fn use_groups(&self, index: usize) -> Range<usize> {
+        let bytes = self.bytes();
+        let entry = self.symbols_offset + index * SYMBOL_ENTRY_SIZE;
+        let first = read_u32(bytes, entry + 24) as usize;
+        let count = read_u32(bytes, entry + 28) as usize;
+        // Clamp so that a corrupt entry can't index past the table.
+        first.min(self.use_group_count)..(first + count).min(self.use_group_count)
+    }
+
+    /// The path of the use group at the given index in the use group table, or
+    /// None if the crossref is corrupt.
+    pub fn use_group_path(&self, index: usize) -> Option<&[u8]> {
+        self.entry_name(self.use_groups_offset + index * USE_GROUP_ENTRY_SIZE)
+    }
+
+    pub fn use_group_location(&self, index: usize) -> PayloadLocation {
+        self.entry_location(self.use_groups_offset + index * USE_GROUP_ENTRY_SIZE)
+    }
+
+    /// Bisect the symbol table for the index of the given symbol.
+    pub fn find(&self, sym: &[u8]) -> io::Result<Option<usize>> {
+        let (mut lo, mut hi) = (0, self.symbol_count);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let mid_sym = self
+                .symbol(mid)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad symbol name"))?;
+            if mid_sym < sym {
+                lo = mid + 1;
+            } else if mid_sym > sym {
+                hi = mid;
+            } else {
+                return Ok(Some(mid));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Decompress the given payload block.
+    pub fn read_block(&self, block: usize) -> io::Result<Vec<u8>> {
+        if block >= self.block_count {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "bad block index",
+            ));
+        }
+        let bytes = self.bytes();
+        let entry = self.blocks_offset + block * BLOCK_ENTRY_SIZE;
+        let start = read_u64(bytes, entry) as usize;
+        let compressed_len = read_u32(bytes, entry + 8) as usize;
+        let len = read_u32(bytes, entry + 12) as usize;
+        let compressed = start
+            .checked_add(compressed_len)
+            .and_then(|end| bytes.get(start..end))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "truncated block"))?;
+
+        let mut decompressed = Vec::with_capacity(len);
+        DeflateDecoder::new(compressed).read_to_end(&mut decompressed)?;
+        Ok(decompressed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memmap::Protection;
+
+    #[test]
+    fn test_roundtrip() {
+        let path =
+            std::env::temp_dir().join(format!("crossref-binary-test-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let big = format!("[\"{}\"]", "x".repeat(BLOCK_SIZE));
+        let mut writer = CrossrefBinaryWriter::create(path).unwrap();
+        writer.add_symbol("A", &[], b"{\"a\":1}").unwrap();
+        writer.add_symbol("B", &[], big.as_bytes()).unwrap();
+        writer
+            .add_symbol("C", &[("x.cpp", b"[1]"), ("y.cpp", b"[2]")], b"{\"c\":3}")
+            .unwrap();
+        writer.add_symbol("D", &[("y.cpp", b"[4]")], b"{}").unwrap();
+        writer.finish().unwrap();
+
+        let binary =
+            CrossrefBinary::new(Mmap::open_path(path, Protection::Read).unwrap(), path).unwrap();
+        assert_eq!(binary.symbol_count(), 4);
+        assert_eq!(binary.find(b"A").unwrap(), Some(0));
+        assert_eq!(binary.find(b"C").unwrap(), Some(2));
+        assert_eq!(binary.find(b"AA").unwrap(), None);
+        assert_eq!(binary.find(b"0").unwrap(), None);
+        assert_eq!(binary.find(b"E").unwrap(), None);
+
+        // The big payload gets a block to itself.
+        let locations: Vec<_> = (0..3).map(|i| binary.payload_location(i)).collect();
+        assert_eq!(
+            locations.iter().map(|l| l.block).collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+
+        let loc = locations[1];
+        let block = binary.read_block(loc.block).unwrap();
+        assert_eq!(&block[loc.offset..loc.offset + loc.len], big.as_bytes());
+        let loc = locations[2];
+        let block = binary.read_block(loc.block).unwrap();
+        assert_eq!(&block[loc.offset..loc.offset + loc.len], b"{\"c\":3}");
+
+        assert_eq!(binary.use_groups(0), 0..0);
+        assert_eq!(binary.use_groups(2), 0..2);
+        assert_eq!(binary.use_groups(3), 2..3);
+        assert_eq!(binary.use_group_path(1), Some(&b"y.cpp"[..]));
+        assert_eq!(binary.use_group_path(2), Some(&b"y.cpp"[..]));
+        let loc = binary.use_group_location(2);
+        let block = binary.read_block(loc.block).unwrap();
+        assert_eq!(&block[loc.offset..loc.offset + loc.len], b"[4]");
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_rejects_bad_header() {
+        let path = std::env::temp_dir().join(format!(
+            "crossref-binary-header-test-{}",
+            std::process::id()
+        ));
+        let path = path.to_str().unwrap();
+        let open = |contents: &[u8]| {
+            std::fs::write(path, contents).unwrap();
+            CrossrefBinary::new(Mmap::open_path(path, Protection::Read).unwrap(), path).is_some()
+        };
+
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&VERSION.to_le_bytes());
+        header.resize(HEADER_SIZE, 0);
+        assert!(open(&header));
+
+        header[8..12].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert!(!open(&header));
+        assert!(!open(&header[..HEADER_SIZE - 1]));
+        assert!(!open(b"not a binary crossref at all, just some text"));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_rejects_corrupt_offsets() {
+        let path = std::env::temp_dir().join(format!(
+            "crossref-binary-offsets-test-{}",
+            std::process::id()
+        ));
+        let path = path.to_str().unwrap();
+        let mut writer = CrossrefBinaryWriter::create(path).unwrap();
+        writer.add_symbol("A", &[], b"{\"a\":1}").unwrap();
+        writer.add_symbol("B", &[("x.cpp", b"[1]")], b"{}").unwrap();
+        writer.finish().unwrap();
+        let good = std::fs::read(path).unwrap();
+        let open = |contents: &[u8]| {
+            std::fs::write(path, contents).unwrap();
+            CrossrefBinary::new(Mmap::open_path(path, Protection::Read).unwrap(), path)
+        };
+
+        // Header offsets past the end of the file, or big enough to overflow.
+        let mut corrupt = good.clone();
+        corrupt[24..32].copy_from_slice(&(good.len() as u64 + 1).to_le_bytes());
+        assert!(open(&corrupt).is_none());
+        let mut corrupt = good.clone();
+        corrupt[12..16].copy_from_slice(&u32::MAX.to_le_bytes());
+        corrupt[32..40].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(open(&corrupt).is_none());
+
+        // A name offset in the symbol table that points outside the file.
+        let symbols_offset = read_u64(&good, 32) as usize;
+        let mut corrupt = good.clone();
+        corrupt[symbols_offset..symbols_offset + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        let binary = open(&corrupt).unwrap();
+        assert_eq!(binary.symbol(0), None);
+        assert_eq!(binary.symbol(1), Some(&b"B"[..]));
+        assert!(binary.find(b"A").is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
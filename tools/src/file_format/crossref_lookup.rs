@@ -1,17 +1,37 @@
 extern crate memmap;
 
 use self::memmap::{Mmap, Protection};
+use flate2::read::GzDecoder;
 use std::collections::HashMap;
+use std::io::Read;
+use std::ops::Range;
 use std::str;
 
-use serde_json::{from_slice, Value};
+use serde_json::{from_slice, json, Map, Value};
 
-use crate::{config, abstract_server::Result, abstract_server::{ServerError, ErrorDetails, ErrorLayer}};
+use super::crossref_binary::{is_binary_crossref, CrossrefBinary, PayloadLocation};
+use crate::{
+    abstract_server::Result,
+    abstract_server::{CrossrefLookupOptions, ErrorDetails, ErrorLayer, ServerError},
+    config,
+};
 
 #[derive(Debug)]
 pub struct CrossrefLookupMap {
-    inline_mm: Mmap,
-    extra_mm: Mmap,
+    storage: CrossrefStorage,
+}
+
+#[derive(Debug)]
+enum CrossrefStorage {
+    /// The newline-delimited text format described in `crossref.md`.  The
+    /// `crossref-extra` file may be missing or empty (which can't be mapped)
+    /// if no payloads were stored externally.
+    Text {
+        inline_mm: Mmap,
+        extra_mm: Option<Mmap>,
+    },
+    /// The format described in `crossref_binary.rs`.
+    Binary(CrossrefBinary),
 }
 
 const SPACE: u8 = ' ' as u8;
@@ -20,6 +40,10 @@ const ID_START: u8 = '!' as u8;
 const INLINE_STORED: u8 = ':' as u8;
 const EXTERNALLY_STORED: u8 = '@' as u8;
 
+/// The flag at the end of an externally stored payload's pointer line when the
+/// payload is gzip-compressed.
+pub const GZIP_COMPRESSED_FLAG: &str = "gz";
+
 fn make_crossref_data_error(sym: &str) -> ServerError {
     ServerError::StickyProblem(ErrorDetails {
         layer: ErrorLayer::DataLayer,
@@ -27,24 +51,26 @@ fn make_crossref_data_error(sym: &str) -> ServerError {
     })
 }
 
-// This implementation is a port of `crossrefs.py` (which was adapted from
+// The text implementation is a port of `crossrefs.py` (which was adapted from
 // `identifiers.py`) and informed by `identifiers.rs` (which presumably was
 // adapted from `identifiers.py` as well).
 impl CrossrefLookupMap {
+    /// Open the crossref at `inline_path`, which may be in either the text or
+    /// binary format.  `extra_path` is only used by the text format.
     pub fn new(inline_path: &str, extra_path: &str) -> Option<CrossrefLookupMap> {
         let inline_mm = match Mmap::open_path(inline_path, Protection::Read) {
             Ok(mmap) => mmap,
-            Err(_) => {
-                return None
+            Err(_) => return None,
+        };
+        let storage = if is_binary_crossref(unsafe { inline_mm.as_slice() }) {
+            CrossrefStorage::Binary(CrossrefBinary::new(inline_mm, inline_path)?)
+        } else {
+            CrossrefStorage::Text {
+                inline_mm,
+                extra_mm: Mmap::open_path(extra_path, Protection::Read).ok(),
             }
         };
-        let extra_mm = match Mmap::open_path(extra_path, Protection::Read) {
-          Ok(mmap) => mmap,
-          Err(_) => {
-              return None
-          }
-      };
-        Some(CrossrefLookupMap { inline_mm, extra_mm })
+        Some(CrossrefLookupMap { storage })
     }
 
     pub fn load(config: &config::Config) -> HashMap<String, Option<CrossrefLookupMap>> {
@@ -59,108 +85,47 @@ impl CrossrefLookupMap {
         result
     }
 
-    // Given a memory map and a position, expand from `pos` to find the identifier
-    // line (`!` prefixed) that covers the position.  Returns (the identifier,
-    // the offset of the `!` from the start of the identifier line, the offset of
-    // the newline ending the identifier line).
-    //
-    // `pos` is either inside an identifier line or a payload line that follows an
-    // identifier line, so we always walk backwards until we find an identifier.
-    // We should never need to walk forward (to find the start of the identifier
-    // line) because the result of any comparison should always tell the bisection
-    // to bisect in the positive direction (because the file is sorted), which
-    // should then find the subsequent record (if that's the one we're looking
-    // for, etc.).
-    fn get_id_line(&self, pos: usize) -> (&[u8], usize, usize) {
-        let mut pos = pos;
-        let bytes: &[u8] = unsafe { self.inline_mm.as_slice() };
-        if bytes[pos] == NEWLINE {
-            pos -= 1;
-        }
-
-        let mut start = pos;
-        let mut end = pos;
-
-        while start > 0 {
-            if bytes[start - 1] == NEWLINE {
-                if bytes[start] == ID_START {
-                    break;
-                } else {
-                    // We're hitting a ":" and we need to reset end to this newlin
-                    end = start - 1
-                    // and we want to keep going...
-                }
-            }
-            start -= 1;
-        }
-
-        // Start should now be pointing at the `!` of the identifier line.
-
-        let size = self.inline_mm.len();
-        while end < size && bytes[end] != NEWLINE {
-            end += 1;
-        }
-
-        // end should now be pointing at the trailing newline.
-
-        // Skip the leading `!`
-        (&bytes[start+1..end], start, end)
+    pub fn lookup(&self, sym: &str) -> Result<Value> {
+        self.lookup_with_options(sym, &CrossrefLookupOptions::default())
     }
 
-    // Bisect the mmap to look for an exact symbol match `sym`, and returning the
-    // payload line which may be either inline JSON or external offsets to be
-    // retrieved from another map.
-    fn bisect_for_payload(&self, search_sym: &[u8]) -> &[u8] {
-        let mut first = 0;
-        let mmap_end = self.inline_mm.len();
-        let bytes: &[u8] = unsafe { self.inline_mm.as_slice() };
-        let mut count = mmap_end;
-
-        while count > 0 {
-            let step = count / 2;
-            let pos = first + step;
-
-            let (line_sym, line_start, line_end) = self.get_id_line(pos);
-
-            if line_sym == search_sym {
-                // Exact Match!  Extract the payload line.
-                let payload_start = line_end + 1;
-                let mut payload_end = payload_start + 1;
-                while payload_end < mmap_end && bytes[payload_end] != NEWLINE {
-                    payload_end += 1;
-                }
-                return &bytes[payload_start..payload_end];
-            } else if line_sym < search_sym {
-                // ## Bisect latter half
-                // We might as well exclude the payload line we're skipping as well.
-                // Because payload lines are intentionally limited during the
-                // creation of `crossref`, we know this should fault an acceptable
-                // number of pages which may have already been pre-fetched.
-                while first < mmap_end && bytes[first] != NEWLINE {
-                    first += 1;
+    /// Look up the symbol with its uses narrowed down by `options`.  The
+    /// binary format stores the uses grouped by path, so only the selected
+    /// uses are decompressed, whereas the text format has to decode all of
+    /// them and then filter.
+    pub fn lookup_with_options(&self, sym: &str, options: &CrossrefLookupOptions) -> Result<Value> {
+        match &self.storage {
+            CrossrefStorage::Text { inline_mm, .. } => {
+                let bytes: &[u8] = unsafe { inline_mm.as_slice() };
+                let payload = bisect_for_payload(bytes, sym.as_bytes());
+                let mut value = self.decode_payload(sym, payload)?;
+                if !options.is_unfiltered() {
+                    select_text_uses(&mut value, options);
                 }
-                // move past the newline
-                first += 1;
-
-                // Halve count and also subtract off the parts of the identifier line
-                // and payload line we're skipping.
-                count -= step + (pos - first)
-            } else {
-                // ## Bisect first half
-                // Halve count and subtract off the part of the identifier line that
-                // we can eliminate from consideration.
-                count = step - (pos - line_start)
+                Ok(value)
             }
+            CrossrefStorage::Binary(binary) => match binary.find(sym.as_bytes()) {
+                // Finding nothing (a miss!) is not an error and so is an in-band null.
+                Ok(None) => Ok(Value::Null),
+                Ok(Some(index)) => decode_binary_payload(binary, sym, index, options, &mut None),
+                Err(_) => Err(make_crossref_data_error(sym)),
+            },
         }
+    }
 
-        &[]
+    /// Iterate over every symbol and its crossref data in the order they are
+    /// stored, which is sorted by symbol.
+    pub fn entries(&self) -> CrossrefEntries<'_> {
+        CrossrefEntries {
+            map: self,
+            pos: 0,
+            block: None,
+        }
     }
 
-    pub fn lookup(
-        &self,
-        sym: &str,
-    ) -> Result<Value> {
-        let payload = self.bisect_for_payload(sym.as_bytes());
+    // Decode a text format payload line which is either inline JSON or an
+    // external reference into the extra map.
+    fn decode_payload(&self, sym: &str, payload: &[u8]) -> Result<Value> {
         let payload_len = payload.len();
         // Finding nothing (a miss!) is not an error and so is an in-band null.
         if payload_len == 0 {
@@ -181,15 +146,443 @@ impl CrossrefLookupMap {
             return Err(make_crossref_data_error(sym));
         }
 
-        let mut space_pos = 2;
-        while space_pos < payload_len && payload[space_pos] != SPACE {
-            space_pos += 1;
+        // The pointer is `@offset length` with an optional trailing flag
+        // indicating how the payload is compressed.
+        let mut pieces = payload[1..].split(|c| *c == SPACE);
+        let mut parse_hex = || -> Result<usize> {
+            let piece = pieces.next().ok_or_else(|| make_crossref_data_error(sym))?;
+            unsafe {
+                usize::from_str_radix(str::from_utf8_unchecked(piece), 16)
+                    .map_err(|_| make_crossref_data_error(sym))
+            }
+        };
+        let brace_offset = parse_hex()?;
+        let length_with_newline = parse_hex()?;
+        let flag = pieces.next();
+
+        let extra_bytes: &[u8] = match &self.storage {
+            CrossrefStorage::Text {
+                extra_mm: Some(extra_mm),
+                ..
+            } => unsafe { extra_mm.as_slice() },
+            _ => return Err(make_crossref_data_error(sym)),
+        };
+        let stored = extra_bytes
+            .get(brace_offset..brace_offset + length_with_newline - 1)
+            .ok_or_else(|| make_crossref_data_error(sym))?;
+        match flag {
+            None => Ok(from_slice(stored)?),
+            Some(flag) if flag == GZIP_COMPRESSED_FLAG.as_bytes() => {
+                let mut json = vec![];
+                GzDecoder::new(stored)
+                    .read_to_end(&mut json)
+                    .map_err(|_| make_crossref_data_error(sym))?;
+                Ok(from_slice(&json)?)
+            }
+            Some(_) => Err(make_crossref_data_error(sym)),
+        }
+    }
+}
+
+// Given the bytes of a text crossref and a position, expand from `pos` to find
+// the identifier line (`!` prefixed) that covers the position.  Returns (the
+// identifier, the offset of the `!` from the start of the identifier line, the
+// offset of the newline ending the identifier line).
+//
+// `pos` is either inside an identifier line or a payload line that follows an
+// identifier line, so we always walk backwards until we find an identifier.
+// We should never need to walk forward (to find the start of the identifier
+// line) because the result of any comparison should always tell the bisection
+// to bisect in the positive direction (because the file is sorted), which
+// should then find the subsequent record (if that's the one we're looking
+// for, etc.).
+fn get_id_line(bytes: &[u8], pos: usize) -> (&[u8], usize, usize) {
+    let mut pos = pos;
+    if bytes[pos] == NEWLINE {
+        pos -= 1;
+    }
+
+    let mut start = pos;
+    let mut end = pos;
+
+    while start > 0 {
+        if bytes[start - 1] == NEWLINE {
+            if bytes[start] == ID_START {
+                break;
+            } else {
+                // We're hitting a ":" and we need to reset end to this newlin
+                end = start - 1
+                // and we want to keep going...
+            }
+        }
+        start -= 1;
+    }
+
+    // Start should now be pointing at the `!` of the identifier line.
+
+    let size = bytes.len();
+    while end < size && bytes[end] != NEWLINE {
+        end += 1;
+    }
+
+    // end should now be pointing at the trailing newline.
+
+    // Skip the leading `!`
+    (&bytes[start + 1..end], start, end)
+}
+
+// Bisect the bytes of a text crossref to look for an exact symbol match `sym`,
+// and returning the payload line which may be either inline JSON or external
+// offsets to be retrieved from another map.
+fn bisect_for_payload<'a>(bytes: &'a [u8], search_sym: &[u8]) -> &'a [u8] {
+    // We bisect the byte range [first, end), where `first` is always the start
+    // of an identifier line.
+    let mut first = 0;
+    let mmap_end = bytes.len();
+    let mut end = mmap_end;
+
+    while first < end {
+        let pos = first + (end - first) / 2;
+
+        let (line_sym, line_start, line_end) = get_id_line(bytes, pos);
+
+        // Find the end of the payload line.
+        let payload_start = line_end + 1;
+        let mut payload_end = payload_start + 1;
+        while payload_end < mmap_end && bytes[payload_end] != NEWLINE {
+            payload_end += 1;
+        }
+
+        if line_sym == search_sym {
+            // Exact Match!  Extract the payload line.
+            return &bytes[payload_start.min(mmap_end)..payload_end.min(mmap_end)];
+        } else if line_sym < search_sym {
+            // ## Bisect latter half
+            // We might as well exclude the payload line we're skipping as well.
+            // Because payload lines are intentionally limited during the
+            // creation of `crossref`, we know this should fault an acceptable
+            // number of pages which may have already been pre-fetched.
+            first = payload_end + 1;
+        } else {
+            // ## Bisect first half
+            // We can also eliminate the part of the identifier line before
+            // `pos` from consideration.
+            end = line_start;
+        }
+    }
+
+    &[]
+}
+
+/// Select the use groups, given by their sorted paths, that `options` asks
+/// for.  Returns the range of selected groups along with the number of groups
+/// matching the path prefix.
+fn select_use_groups(paths: &[&[u8]], options: &CrossrefLookupOptions) -> (Range<usize>, usize) {
+    // Paths sharing a prefix are contiguous since the paths are sorted.
+    let (start, end) = match &options.path_prefix {
+        Some(prefix) => {
+            let prefix = prefix.as_bytes();
+            let start = paths.partition_point(|path| *path < prefix);
+            let end = start + paths[start..].partition_point(|path| path.starts_with(prefix));
+            (start, end)
+        }
+        None => (0, paths.len()),
+    };
+    let first = start.saturating_add(options.offset).min(end);
+    let last = match options.limit {
+        Some(limit) => first.saturating_add(limit).min(end),
+        None => end,
+    };
+    (first..last, end - start)
+}
+
+// Narrow down the uses of a decoded text format payload in place.
+fn select_text_uses(value: &mut Value, options: &CrossrefLookupOptions) {
+    let obj = match value {
+        Value::Object(obj) => obj,
+        // A miss stays a miss.
+        _ => return,
+    };
+    let mut total = 0;
+    if let Some(Value::Array(uses)) = obj.get_mut("uses") {
+        let paths: Vec<&[u8]> = uses
+            .iter()
+            .map(|group| group["path"].as_str().unwrap_or("").as_bytes())
+            .collect();
+        let (selected, matching) = select_use_groups(&paths, options);
+        total = matching;
+        *uses = uses.drain(selected).collect();
+    }
+    obj.insert("uses_total".to_string(), json!(total));
+}
+
+/// Decode the payload of the symbol at `index` in a binary crossref, along with
+/// the use groups selected by `options`.  `cache` holds the most recently
+/// decompressed block so that iterating over the symbols only decompresses
+/// each block once.
+fn decode_binary_payload(
+    binary: &CrossrefBinary,
+    sym: &str,
+    index: usize,
+    options: &CrossrefLookupOptions,
+    cache: &mut Option<(usize, Vec<u8>)>,
+) -> Result<Value> {
+    let head = match read_binary_payload(binary, sym, binary.payload_location(index), cache)? {
+        Value::Object(head) => head,
+        _ => return Err(make_crossref_data_error(sym)),
+    };
+
+    let use_groups = binary.use_groups(index);
+    let paths: Vec<&[u8]> = use_groups
+        .clone()
+        .map(|group| binary.use_group_path(group))
+        .collect::<Option<_>>()
+        .ok_or_else(|| make_crossref_data_error(sym))?;
+    let (selected, total) = select_use_groups(&paths, options);
+
+    // The uses always come first, matching the order of the text format.
+    let mut result = Map::new();
+    if !use_groups.is_empty() {
+        let mut uses = vec![];
+        for i in selected {
+            let location = binary.use_group_location(use_groups.start + i);
+            let lines = read_binary_payload(binary, sym, location, cache)?;
+            uses.push(json!({
+                "path": String::from_utf8_lossy(paths[i]),
+                "lines": lines,
+            }));
+        }
+        result.insert("uses".to_string(), Value::Array(uses));
+    }
+    result.extend(head);
+    if !options.is_unfiltered() {
+        result.insert("uses_total".to_string(), json!(total));
+    }
+    Ok(Value::Object(result))
+}
+
+// Parse the JSON payload at the given location of a binary crossref.
+fn read_binary_payload(
+    binary: &CrossrefBinary,
+    sym: &str,
+    location: PayloadLocation,
+    cache: &mut Option<(usize, Vec<u8>)>,
+) -> Result<Value> {
+    let cached = matches!(cache, Some((block, _)) if *block == location.block);
+    if !cached {
+        let block = binary
+            .read_block(location.block)
+            .map_err(|_| make_crossref_data_error(sym))?;
+        *cache = Some((location.block, block));
+    }
+    let block = &cache.as_ref().unwrap().1;
+    let json = block
+        .get(location.offset..location.offset + location.len)
+        .ok_or_else(|| make_crossref_data_error(sym))?;
+    Ok(from_slice(json)?)
+}
+
+/// Iterator over the (symbol, crossref data) pairs of a `CrossrefLookupMap`.
+pub struct CrossrefEntries<'a> {
+    map: &'a CrossrefLookupMap,
+    /// The byte offset of the next line for the text format, or the index of
+    /// the next symbol for the binary format.
+    pos: usize,
+    /// The most recently decompressed block for the binary format.
+    block: Option<(usize, Vec<u8>)>,
+}
+
+impl<'a> CrossrefEntries<'a> {
+    // Return the line starting at our current position, advancing past it.
+    fn next_line(&mut self, bytes: &'a [u8]) -> Option<&'a [u8]> {
+        if self.pos >= bytes.len() {
+            return None;
+        }
+        let start = self.pos;
+        let mut end = start;
+        while end < bytes.len() && bytes[end] != NEWLINE {
+            end += 1;
+        }
+        self.pos = end + 1;
+        Some(&bytes[start..end])
+    }
+}
+
+impl<'a> Iterator for CrossrefEntries<'a> {
+    type Item = Result<(String, Value)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let inline_mm = match &self.map.storage {
+            CrossrefStorage::Text { inline_mm, .. } => inline_mm,
+            CrossrefStorage::Binary(binary) => {
+                if self.pos >= binary.symbol_count() {
+                    return None;
+                }
+                let index = self.pos;
+                self.pos += 1;
+                let sym = match binary.symbol(index) {
+                    Some(sym) => String::from_utf8_lossy(sym).to_string(),
+                    None => {
+                        // Stop rather than produce an endless stream of errors.
+                        self.pos = binary.symbol_count();
+                        return Some(Err(make_crossref_data_error(&format!("#{}", index))));
+                    }
+                };
+                let options = CrossrefLookupOptions::default();
+                return Some(
+                    decode_binary_payload(binary, &sym, index, &options, &mut self.block)
+                        .map(|value| (sym, value)),
+                );
+            }
+        };
+
+        let bytes: &'a [u8] = unsafe { inline_mm.as_slice() };
+        let id_line = self.next_line(bytes)?;
+        if id_line.first() != Some(&ID_START) {
+            // Stop rather than produce an endless stream of errors.
+            self.pos = bytes.len();
+            return Some(Err(make_crossref_data_error(&String::from_utf8_lossy(
+                id_line,
+            ))));
+        }
+        let sym = String::from_utf8_lossy(&id_line[1..]).to_string();
+        let payload = self.next_line(bytes).unwrap_or(&[]);
+        // Lookups treat an inline payload that doesn't parse as a miss, but
+        // something reading every entry needs to know the crossref is corrupt.
+        if payload.first() == Some(&INLINE_STORED) {
+            return Some(
+                from_slice(&payload[1..])
+                    .map_err(|_| make_crossref_data_error(&sym))
+                    .map(|value| (sym, value)),
+            );
+        }
+        Some(
+            self.map
+                .decode_payload(&sym, payload)
+                .map(|value| (sym, value)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_format::crossref_binary::CrossrefBinaryWriter;
+
+    const USE_PATHS: &[&str] = &["a/x.cpp", "b/y.cpp", "b/z.cpp", "c/w.cpp"];
+
+    fn use_lines(path: &str) -> Value {
+        json!([{ "lno": path.len(), "line": path }])
+    }
+
+    /// Write the same crossref in the text and binary formats, returning the
+    /// paths of the maps.
+    fn write_crossrefs(dir: &std::path::Path) -> Vec<String> {
+        let uses: Vec<Value> = USE_PATHS
+            .iter()
+            .map(|path| json!({ "path": path, "lines": use_lines(path) }))
+            .collect();
+        let defs = json!([{ "path": "a/x.h", "lines": [{ "lno": 1 }] }]);
+
+        let text_path = dir.join("crossref-text");
+        let text = format!(
+            "!A\n:{}\n!S\n:{}\n",
+            json!({ "defs": defs }),
+            json!({ "uses": uses, "defs": defs })
+        );
+        std::fs::write(&text_path, text).unwrap();
+
+        let binary_path = dir.join("crossref-binary");
+        let mut writer = CrossrefBinaryWriter::create(binary_path.to_str().unwrap()).unwrap();
+        let head = json!({ "defs": defs }).to_string();
+        writer.add_symbol("A", &[], head.as_bytes()).unwrap();
+        let lines: Vec<String> = USE_PATHS
+            .iter()
+            .map(|path| use_lines(path).to_string())
+            .collect();
+        let groups: Vec<(&str, &[u8])> = USE_PATHS
+            .iter()
+            .zip(lines.iter())
+            .map(|(path, lines)| (*path, lines.as_bytes()))
+            .collect();
+        writer.add_symbol("S", &groups, head.as_bytes()).unwrap();
+        writer.finish().unwrap();
+
+        vec![
+            text_path.to_str().unwrap().to_string(),
+            binary_path.to_str().unwrap().to_string(),
+        ]
+    }
+
+    fn use_paths(value: &Value) -> Vec<&str> {
+        value["uses"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|group| group["path"].as_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_lookup_with_options() {
+        let dir = std::env::temp_dir().join(format!("crossref-lookup-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let options = |path_prefix: Option<&str>, offset: usize, limit: Option<usize>| {
+            CrossrefLookupOptions {
+                path_prefix: path_prefix.map(|prefix| prefix.to_string()),
+                offset,
+                limit,
+            }
+        };
+
+        for path in write_crossrefs(&dir) {
+            let map = CrossrefLookupMap::new(&path, "/nonexistent/crossref-extra").unwrap();
+            let lookup =
+                |options: CrossrefLookupOptions| map.lookup_with_options("S", &options).unwrap();
+
+            // Unfiltered lookups are unchanged.
+            let all = map.lookup("S").unwrap();
+            assert_eq!(use_paths(&all), USE_PATHS, "{}", path);
+            assert_eq!(all.get("uses_total"), None);
+            assert_eq!(all["defs"][0]["path"], "a/x.h");
+
+            let page = lookup(options(None, 0, Some(2)));
+            assert_eq!(use_paths(&page), vec!["a/x.cpp", "b/y.cpp"], "{}", path);
+            assert_eq!(page["uses_total"], 4);
+            assert_eq!(page["uses"][1]["lines"], use_lines("b/y.cpp"));
+            assert_eq!(page["defs"], all["defs"]);
+
+            let page = lookup(options(None, 3, Some(2)));
+            assert_eq!(use_paths(&page), vec!["c/w.cpp"], "{}", path);
+            let page = lookup(options(None, 10, None));
+            assert_eq!(use_paths(&page), Vec::<&str>::new(), "{}", path);
+            assert_eq!(page["uses_total"], 4);
+
+            let page = lookup(options(Some("b/"), 0, None));
+            assert_eq!(use_paths(&page), vec!["b/y.cpp", "b/z.cpp"], "{}", path);
+            assert_eq!(page["uses_total"], 2);
+            let page = lookup(options(Some("b/"), 1, Some(5)));
+            assert_eq!(use_paths(&page), vec!["b/z.cpp"], "{}", path);
+            assert_eq!(page["uses_total"], 2);
+            let page = lookup(options(Some("a/x.cpp"), 0, Some(1)));
+            assert_eq!(use_paths(&page), vec!["a/x.cpp"], "{}", path);
+            let page = lookup(options(Some("d/"), 0, None));
+            assert_eq!(use_paths(&page), Vec::<&str>::new(), "{}", path);
+            assert_eq!(page["uses_total"], 0);
+
+            // Symbols without uses and misses.
+            let none = map
+                .lookup_with_options("A", &options(Some("b/"), 0, Some(1)))
+                .unwrap();
+            assert_eq!(none["uses_total"], 0);
+            assert_eq!(none["defs"], all["defs"]);
+            assert_eq!(
+                map.lookup_with_options("Z", &options(None, 0, Some(1)))
+                    .unwrap(),
+                Value::Null
+            );
         }
 
-        let brace_offset = unsafe { usize::from_str_radix(str::from_utf8_unchecked(&payload[1..space_pos]), 16).map_err(|_| make_crossref_data_error(sym))? };
-        let length_with_newline = unsafe { usize::from_str_radix(str::from_utf8_unchecked(&payload[space_pos+1..]), 16).map_err(|_| make_crossref_data_error(sym))? };
-        
-        let extra_bytes: &[u8] = unsafe { self.extra_mm.as_slice() };
-        return Ok(from_slice(&extra_bytes[brace_offset..brace_offset + length_with_newline - 1])?);
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -1,4 +1,6 @@
 pub mod analysis;
+pub mod crossref_binary;
 pub mod crossref_lookup;
 pub mod identifiers;
 pub mod merger;
+pub mod trigram_index;
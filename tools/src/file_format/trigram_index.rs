@@ -0,0 +1,429 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use memmap::{Mmap, Protection};
+
+// The trigram index lets us answer regular expression searches over small
+// trees without the `codesearch` daemon.  It's made up of two files in the
+// index directory:
+//
+// - `trigram-files`: The newline-delimited tree-relative paths of the indexed
+//   files.  A file's id is its (0-based) line number in this file.
+// - `trigram-index`: A sorted table of the trigrams occurring in any of the
+//   files followed by the posting lists of file ids for each trigram.  The
+//   layout is:
+//   - The 8 byte `MAGIC`.
+//   - The number of trigrams as a little-endian u32.
+//   - For each trigram, a `TABLE_ENTRY_SIZE` byte entry of the trigram, the
+//     number of files containing it, and the byte offset of its posting list
+//     relative to the end of the table, as little-endian u32, u32, and u64.
+//   - The posting lists, which are sorted file ids, each stored as the
+//     LEB128-encoded delta from the previous id (or from 0 for the first).
+//
+// Trigrams are taken from the bytes of the file with ASCII letters lowercased
+// so the same index can serve case-sensitive and case-insensitive searches.
+// Searches match a line at a time, so trigrams never span a newline.
+
+const MAGIC: &[u8; 8] = b"SFTRIGR1";
+const HEADER_SIZE: usize = 12;
+const TABLE_ENTRY_SIZE: usize = 16;
+
+/// Files larger than this aren't indexed and so can't be found by text
+/// search.
+pub const MAX_INDEXED_FILE_SIZE: usize = 8 * 1024 * 1024;
+
+/// Three bytes packed into the low 24 bits of a u32, with ASCII letters
+/// lowercased.
+pub type Trigram = u32;
+
+pub fn pack_trigram(bytes: &[u8]) -> Trigram {
+    (bytes[0].to_ascii_lowercase() as u32) << 16
+        | (bytes[1].to_ascii_lowercase() as u32) << 8
+        | bytes[2].to_ascii_lowercase() as u32
+}
+
+/// Return the trigrams of `bytes` that don't span a newline, possibly with
+/// duplicates.
+pub fn line_trigrams(bytes: &[u8]) -> impl Iterator<Item = Trigram> + '_ {
+    bytes
+        .windows(3)
+        .filter(|w| !w.contains(&b'\n'))
+        .map(pack_trigram)
+}
+
+/// Accumulates the trigrams of a tree's files in memory to be written out as
+/// a trigram index.
+#[derive(Default)]
+pub struct TrigramIndexBuilder {
+    paths: Vec<String>,
+    postings: HashMap<Trigram, Vec<u32>>,
+}
+
+impl TrigramIndexBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Index the contents of the file at tree-relative `path`.  Returns false
+    /// if the file was skipped because it's too big or looks like a binary
+    /// file (because it contains a NUL byte).
+    pub fn add_file(&mut self, path: &str, contents: &[u8]) -> bool {
+        if contents.len() > MAX_INDEXED_FILE_SIZE || contents.contains(&0) {
+            return false;
+        }
+
+        let file_id = self.paths.len() as u32;
+        self.paths.push(path.to_string());
+        for trigram in line_trigrams(contents) {
+            let ids = self.postings.entry(trigram).or_default();
+            // Ids are pushed in increasing order, so this catches duplicates.
+            if ids.last() != Some(&file_id) {
+                ids.push(file_id);
+            }
+        }
+        true
+    }
+
+    pub fn file_count(&self) -> usize {
+        self.paths.len()
+    }
+
+    /// Write `trigram-files` and `trigram-index` to the given index directory.
+    pub fn write(self, index_path: &str) -> io::Result<()> {
+        let mut files = BufWriter::new(File::create(format!("{}/trigram-files", index_path))?);
+        for path in &self.paths {
+            writeln!(files, "{}", path)?;
+        }
+        files.flush()?;
+
+        let mut trigrams: Vec<(Trigram, Vec<u32>)> = self.postings.into_iter().collect();
+        trigrams.sort_unstable_by_key(|(trigram, _)| *trigram);
+
+        let mut table = Vec::with_capacity(HEADER_SIZE + trigrams.len() * TABLE_ENTRY_SIZE);
+        table.extend_from_slice(MAGIC);
+        table.extend_from_slice(&(trigrams.len() as u32).to_le_bytes());
+        let mut postings = vec![];
+        for (trigram, ids) in &trigrams {
+            table.extend_from_slice(&trigram.to_le_bytes());
+            table.extend_from_slice(&(ids.len() as u32).to_le_bytes());
+            table.extend_from_slice(&(postings.len() as u64).to_le_bytes());
+            let mut prev = 0;
+            for id in ids {
+                write_varint(&mut postings, id - prev);
+                prev = *id;
+            }
+        }
+
+        let mut index = BufWriter::new(File::create(format!("{}/trigram-index", index_path))?);
+        index.write_all(&table)?;
+        index.write_all(&postings)?;
+        index.flush()
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u32) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(bytes: &[u8], pos: &mut usize) -> Option<u32> {
+    let mut value = 0u32;
+    let mut shift = 0;
+    loop {
+        let byte = *bytes.get(*pos)?;
+        *pos += 1;
+        value |= ((byte & 0x7f) as u32).checked_shl(shift)?;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+        shift += 7;
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut buf = [0; 4];
+    buf.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(buf)
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_le_bytes(buf)
+}
+
+/// A boolean combination of trigrams that a file must contain in order to
+/// possibly match a search.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TrigramQuery {
+    /// Every file is a candidate.
+    All,
+    Trigram(Trigram),
+    And(Vec<TrigramQuery>),
+    Or(Vec<TrigramQuery>),
+}
+
+impl TrigramQuery {
+    /// Build a conjunction, dropping `All` terms.
+    pub fn and(queries: Vec<TrigramQuery>) -> TrigramQuery {
+        let mut terms = vec![];
+        for query in queries {
+            match query {
+                TrigramQuery::All => {}
+                TrigramQuery::And(mut more) => terms.append(&mut more),
+                _ => {
+                    if !terms.contains(&query) {
+                        terms.push(query);
+                    }
+                }
+            }
+        }
+        match terms.len() {
+            0 => TrigramQuery::All,
+            1 => terms.pop().unwrap(),
+            _ => TrigramQuery::And(terms),
+        }
+    }
+
+    /// Build a disjunction, which is `All` if any of the terms are.
+    pub fn or(queries: Vec<TrigramQuery>) -> TrigramQuery {
+        let mut terms = vec![];
+        for query in queries {
+            match query {
+                TrigramQuery::All => return TrigramQuery::All,
+                TrigramQuery::Or(mut more) => terms.append(&mut more),
+                _ => {
+                    if !terms.contains(&query) {
+                        terms.push(query);
+                    }
+                }
+            }
+        }
+        match terms.len() {
+            // An empty disjunction can't come from a regular expression, so
+            // err on the side of searching everything.
+            0 => TrigramQuery::All,
+            1 => terms.pop().unwrap(),
+            _ => TrigramQuery::Or(terms),
+        }
+    }
+
+    /// The query requiring every trigram of `s`, which is `All` if `s` is
+    /// shorter than a trigram.
+    pub fn for_string(s: &str) -> TrigramQuery {
+        TrigramQuery::and(
+            line_trigrams(s.as_bytes())
+                .map(TrigramQuery::Trigram)
+                .collect(),
+        )
+    }
+}
+
+fn intersect(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut result = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i] < b[j] {
+            i += 1;
+        } else if a[i] > b[j] {
+            j += 1;
+        } else {
+            result.push(a[i]);
+            i += 1;
+            j += 1;
+        }
+    }
+    result
+}
+
+fn union(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut result = Vec::with_capacity(a.len().max(b.len()));
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if j == b.len() || (i < a.len() && a[i] < b[j]) {
+            result.push(a[i]);
+            i += 1;
+        } else {
+            if i < a.len() && a[i] == b[j] {
+                i += 1;
+            }
+            result.push(b[j]);
+            j += 1;
+        }
+    }
+    result
+}
+
+/// A memory-mapped trigram index as written by `TrigramIndexBuilder`.
+#[derive(Debug)]
+pub struct TrigramIndex {
+    mm: Mmap,
+    trigram_count: usize,
+    paths: Vec<String>,
+}
+
+impl TrigramIndex {
+    /// Open the trigram index in the given index directory, returning None if
+    /// the tree doesn't have one.
+    pub fn open(index_path: &str) -> Option<TrigramIndex> {
+        let paths = std::fs::read_to_string(format!("{}/trigram-files", index_path)).ok()?;
+        let paths: Vec<String> = paths.lines().map(|s| s.to_string()).collect();
+        let mm = Mmap::open_path(format!("{}/trigram-index", index_path), Protection::Read).ok()?;
+
+        let bytes: &[u8] = unsafe { mm.as_slice() };
+        if bytes.len() < HEADER_SIZE || &bytes[..MAGIC.len()] != MAGIC {
+            warn!("Ignoring malformed trigram index in {}", index_path);
+            return None;
+        }
+        let trigram_count = read_u32(bytes, MAGIC.len()) as usize;
+        if bytes.len() < HEADER_SIZE + trigram_count * TABLE_ENTRY_SIZE {
+            warn!("Ignoring truncated trigram index in {}", index_path);
+            return None;
+        }
+
+        Some(TrigramIndex {
+            mm,
+            trigram_count,
+            paths,
+        })
+    }
+
+    pub fn file_count(&self) -> usize {
+        self.paths.len()
+    }
+
+    pub fn file_path(&self, file_id: u32) -> Option<&str> {
+        self.paths.get(file_id as usize).map(|s| s.as_str())
+    }
+
+    /// Return the sorted ids of the files containing the given trigram.
+    pub fn postings(&self, trigram: Trigram) -> Vec<u32> {
+        let bytes: &[u8] = unsafe { self.mm.as_slice() };
+        let entry_at = |i: usize| HEADER_SIZE + i * TABLE_ENTRY_SIZE;
+
+        let (mut lo, mut hi) = (0, self.trigram_count);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let entry = entry_at(mid);
+            let mid_trigram = read_u32(bytes, entry);
+            if mid_trigram < trigram {
+                lo = mid + 1;
+            } else if mid_trigram > trigram {
+                hi = mid;
+            } else {
+                let count = read_u32(bytes, entry + 4) as usize;
+                let postings_start = entry_at(self.trigram_count);
+                let mut pos = postings_start + read_u64(bytes, entry + 8) as usize;
+                // Each id takes at least a byte, which bounds a corrupt count.
+                let mut ids = Vec::with_capacity(count.min(bytes.len().saturating_sub(pos)));
+                let mut prev = 0u32;
+                for _ in 0..count {
+                    // A corrupt posting list just gets cut short.
+                    match read_varint(bytes, &mut pos).and_then(|delta| prev.checked_add(delta)) {
+                        Some(id) => {
+                            prev = id;
+                            ids.push(id);
+                        }
+                        None => break,
+                    }
+                }
+                return ids;
+            }
+        }
+        vec![]
+    }
+
+    /// Return the sorted ids of the files that might match the query, or None
+    /// if every file might.
+    pub fn evaluate(&self, query: &TrigramQuery) -> Option<Vec<u32>> {
+        match query {
+            TrigramQuery::All => None,
+            TrigramQuery::Trigram(trigram) => Some(self.postings(*trigram)),
+            TrigramQuery::And(terms) => {
+                let mut result: Option<Vec<u32>> = None;
+                for term in terms {
+                    if let Some(ids) = self.evaluate(term) {
+                        result = Some(match result {
+                            Some(prev) => intersect(&prev, &ids),
+                            None => ids,
+                        });
+                    }
+                    if result.as_ref().map(|ids| ids.is_empty()) == Some(true) {
+                        break;
+                    }
+                }
+                result
+            }
+            TrigramQuery::Or(terms) => {
+                let mut result = vec![];
+                for term in terms {
+                    result = union(&result, &self.evaluate(term)?);
+                }
+                Some(result)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("trigram-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let index_path = dir.to_str().unwrap();
+
+        let mut builder = TrigramIndexBuilder::new();
+        assert!(builder.add_file("a.cpp", b"int Foo;\nbar baz"));
+        assert!(builder.add_file("b.cpp", b"FOOD\n"));
+        assert!(!builder.add_file("c.bin", b"foo\0"));
+        assert!(builder.add_file("d.cpp", b"ba\nr"));
+        builder.write(index_path).unwrap();
+
+        let index = TrigramIndex::open(index_path).unwrap();
+        assert_eq!(index.file_count(), 3);
+        assert_eq!(index.file_path(1), Some("b.cpp"));
+        assert_eq!(index.postings(pack_trigram(b"foo")), vec![0, 1]);
+        assert_eq!(index.postings(pack_trigram(b"bar")), vec![0]);
+        assert!(index.postings(pack_trigram(b"zzz")).is_empty());
+
+        let query = TrigramQuery::or(vec![
+            TrigramQuery::for_string("food"),
+            TrigramQuery::for_string("baz"),
+        ]);
+        assert_eq!(index.evaluate(&query), Some(vec![0, 1]));
+        assert_eq!(index.evaluate(&TrigramQuery::for_string("fo")), None);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_corrupt_postings() {
+        let dir = std::env::temp_dir().join(format!("trigram-corrupt-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let index_path = dir.to_str().unwrap();
+
+        let mut builder = TrigramIndexBuilder::new();
+        assert!(builder.add_file("a.cpp", b"foo"));
+        builder.write(index_path).unwrap();
+
+        // Claim more ids than are stored, with deltas that overflow.
+        let path = format!("{}/trigram-index", index_path);
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[HEADER_SIZE + 4..HEADER_SIZE + 8].copy_from_slice(&100u32.to_le_bytes());
+        write_varint(&mut bytes, u32::MAX);
+        write_varint(&mut bytes, 1);
+        std::fs::write(&path, bytes).unwrap();
+
+        let index = TrigramIndex::open(index_path).unwrap();
+        assert_eq!(index.postings(pack_trigram(b"foo")), vec![0, u32::MAX]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
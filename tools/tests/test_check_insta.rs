@@ -77,6 +77,9 @@ async fn test_check_glob() -> Result<(), std::io::Error> {
                         Ok(PipelineValues::IdentifierList(il)) => {
                             insta::assert_json_snapshot!(json!(il.identifiers));
                         }
+                        Ok(PipelineValues::FileList(fl)) => {
+                            insta::assert_json_snapshot!(json!(fl.files));
+                        }
                         Ok(PipelineValues::SymbolList(sl)) => match sl.from_identifiers {
                             Some(identifiers) => {
                                 let mut pairs = vec![];
@@ -100,6 +103,9 @@ async fn test_check_glob() -> Result<(), std::io::Error> {
                                 .collect::<Value>());
                             insta::assert_json_snapshot!(crossref_json);
                         }
+                        Ok(PipelineValues::SymbolInfoList(sil)) => {
+                            insta::assert_json_snapshot!(sil.symbol_infos);
+                        }
                         Ok(PipelineValues::HtmlExcerpts(he)) => {
                             let mut aggr_str = String::new();
                             for file_excerpts in he.by_file {
@@ -109,6 +115,14 @@ async fn test_check_glob() -> Result<(), std::io::Error> {
                             }
                             insta::assert_snapshot!(&aggr_str);
                         }
+                        Ok(PipelineValues::AnalysisRecords(ar)) => {
+                            let mut json_results = vec![];
+                            for file_records in ar.into_json_records().by_file {
+                                json_results.extend(file_records.records);
+                            }
+
+                            insta::assert_json_snapshot!(&json_results);
+                        }
                         Ok(PipelineValues::JsonRecords(jr)) => {
                             let mut json_results = vec![];
                             for file_records in jr.by_file {
@@ -117,6 +131,12 @@ async fn test_check_glob() -> Result<(), std::io::Error> {
 
                             insta::assert_json_snapshot!(&json_results);
                         }
+                        Ok(PipelineValues::SymbolGraph(graph)) => {
+                            insta::assert_json_snapshot!(&graph);
+                        }
+                        Ok(PipelineValues::TextBlocks(tb)) => {
+                            insta::assert_snapshot!(&tb.blocks.join(""));
+                        }
                         Ok(PipelineValues::JsonValue(jv)) => {
                             insta::assert_json_snapshot!(&jv.value);
                         }
@@ -124,7 +144,8 @@ async fn test_check_glob() -> Result<(), std::io::Error> {
                             // We're intentionally skipping doing anything here.
                             // Our assumption is that this error will only be
                             // returned in cases like the local index server
-                            // being unable to handle "query" commands.
+                            // being unable to handle full-text "query"
+                            // commands.
                         }
                         Err(err) => {
                             insta::assert_snapshot!(format!("Pipeline Error: {:?}", err));
@@ -0,0 +1,274 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+use serde_json::{json, Value};
+
+/// The outputs that should be identical however they were built.
+const OUTPUTS: &[&str] = &[
+    "crossref",
+    "crossref-extra",
+    "crossref-state",
+    "jumps",
+    "identifiers",
+];
+
+/// A scratch tree with a config for running the `crossref` binary on, which is
+/// removed when dropped.
+struct TestTree {
+    root: PathBuf,
+}
+
+/// The result of running `crossref`: the contents of each of `OUTPUTS` (empty
+/// if it wasn't written) and what was printed.
+struct CrossrefRun {
+    outputs: BTreeMap<&'static str, Vec<u8>>,
+    stdout: String,
+}
+
+impl TestTree {
+    fn new(name: &str) -> TestTree {
+        let root =
+            std::env::temp_dir().join(format!("crossref-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        for dir in &["files/src", "index/analysis/src", "objdir"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        let config = json!({
+            "mozsearch_path": env!("CARGO_MANIFEST_DIR"),
+            "trees": {
+                "test": {
+                    "index_path": root.join("index"),
+                    "files_path": root.join("files"),
+                    "objdir_path": root.join("objdir"),
+                },
+            },
+        });
+        fs::write(root.join("config.json"), config.to_string()).unwrap();
+        TestTree { root }
+    }
+
+    /// Write the source and analysis for `src/<name>`, which vary with `seed`.
+    /// Every file uses some shared symbols, and `M_popular` gets enough uses
+    /// that its payload is stored in `crossref-extra`.
+    fn write_file(&self, name: &str, seed: usize) {
+        let lines: Vec<String> = (0..30)
+            .map(|i| format!("    line {} of {} version {}  ", i, name, seed))
+            .collect();
+        fs::write(
+            self.root.join("files/src").join(name),
+            lines.join("\n") + "\n",
+        )
+        .unwrap();
+
+        let local = format!("V_{}", name);
+        let syms = ["F_shared", "T_Thing", "M_popular", local.as_str()];
+        let kinds = ["use", "def", "decl", "use", "assign"];
+        let mut records: Vec<Value> = vec![];
+        for i in 0..20 {
+            let sym = syms[(i * 7 + seed) % syms.len()];
+            let mut record = json!({
+                "loc": format!("{}:4-9", (i * 3 + seed) % 30 + 1),
+                "target": 1,
+                "kind": kinds[(i + seed) % kinds.len()],
+                "pretty": format!("{} {}", &sym[2..], seed % 2),
+                "sym": sym,
+            });
+            if i % 2 == 0 {
+                record["context"] = json!("shared");
+                record["contextsym"] = json!("F_shared");
+            }
+            records.push(record);
+        }
+        for i in 0..(40 + seed) {
+            records.push(json!({
+                "loc": format!("{}:4-9", i % 30 + 1),
+                "target": 1,
+                "kind": "use",
+                "pretty": "popular",
+                "sym": "M_popular",
+                "context": "a rather long context to make the payload big",
+                "contextsym": "F_shared",
+            }));
+        }
+        records.push(json!({
+            "loc": "1:4-9",
+            "structured": 1,
+            "pretty": "Thing",
+            "sym": "T_Thing",
+            "kind": "class",
+            "sizeBytes": seed * 8,
+        }));
+        records.push(json!({
+            "loc": "2:4-9",
+            "structured": 1,
+            "pretty": &local[2..],
+            "sym": local,
+            "kind": "field",
+        }));
+        let records: Vec<String> = records.iter().map(|r| r.to_string()).collect();
+        fs::write(
+            self.root.join("index/analysis/src").join(name),
+            records.join("\n") + "\n",
+        )
+        .unwrap();
+    }
+
+    fn remove_file(&self, name: &str) {
+        fs::remove_file(self.root.join("files/src").join(name)).unwrap();
+        fs::remove_file(self.root.join("index/analysis/src").join(name)).unwrap();
+    }
+
+    fn write_list(&self, list_name: &str, names: &[&str]) -> PathBuf {
+        let path = self.root.join(list_name);
+        let lines: Vec<String> = names.iter().map(|name| format!("src/{}\n", name)).collect();
+        fs::write(&path, lines.concat()).unwrap();
+        path
+    }
+
+    /// Run `crossref` over the given files, incrementally if `changed` is
+    /// provided.
+    fn run(&self, names: &[&str], changed: Option<&[&str]>, env: &[(&str, &str)]) -> CrossrefRun {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_crossref"));
+        command
+            .arg(self.root.join("config.json"))
+            .arg("test")
+            .arg(self.write_list("list", names));
+        if let Some(changed) = changed {
+            command.arg(self.write_list("changed", changed));
+        }
+        let output = command.envs(env.iter().cloned()).output().unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+        assert!(
+            output.status.success(),
+            "crossref failed:\n{}\n{}",
+            stdout,
+            String::from_utf8_lossy(&output.stderr)
+        );
+
+        let outputs = OUTPUTS
+            .iter()
+            .map(|name| {
+                let contents = fs::read(self.root.join("index").join(name)).unwrap_or_default();
+                (*name, contents)
+            })
+            .collect();
+        CrossrefRun { outputs, stdout }
+    }
+}
+
+impl Drop for TestTree {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+fn assert_same_outputs(actual: &CrossrefRun, expected: &CrossrefRun) {
+    for name in OUTPUTS {
+        assert!(
+            actual.outputs[name] == expected.outputs[name],
+            "{} differs:\n{}\nvs\n{}",
+            name,
+            String::from_utf8_lossy(&actual.outputs[name]),
+            String::from_utf8_lossy(&expected.outputs[name])
+        );
+    }
+}
+
+#[test]
+fn test_incremental_matches_full() {
+    let tree = TestTree::new("incremental");
+    for (name, seed) in &[("a.cpp", 1), ("b.cpp", 2), ("c.cpp", 3)] {
+        tree.write_file(name, *seed);
+    }
+    tree.run(&["a.cpp", "b.cpp", "c.cpp"], None, &[]);
+
+    // Change b.cpp, remove c.cpp and add d.cpp.
+    tree.write_file("b.cpp", 20);
+    tree.remove_file("c.cpp");
+    tree.write_file("d.cpp", 4);
+    let final_files = ["a.cpp", "b.cpp", "d.cpp"];
+    let incremental = tree.run(&final_files, Some(&["b.cpp", "c.cpp", "d.cpp"]), &[]);
+    assert!(incremental
+        .stdout
+        .contains("Incremental rebuild reusing 1 of 3 files"));
+    assert!(!incremental.outputs["crossref-extra"].is_empty());
+
+    let fresh = TestTree::new("incremental-fresh");
+    for (name, seed) in &[("a.cpp", 1), ("b.cpp", 20), ("d.cpp", 4)] {
+        fresh.write_file(name, *seed);
+    }
+    let full = fresh.run(&final_files, None, &[]);
+    assert_same_outputs(&incremental, &full);
+
+    // Without a usable previous crossref we fall back to a full rebuild.
+    fs::remove_file(tree.root.join("index/crossref")).unwrap();
+    let fallback = tree.run(&final_files, Some(&[]), &[]);
+    assert!(fallback.stdout.contains("doing a full rebuild"));
+    assert_same_outputs(&fallback, &full);
+
+    fs::write(tree.root.join("index/crossref-state"), "not json\n").unwrap();
+    let fallback = tree.run(&final_files, Some(&[]), &[]);
+    assert!(fallback.stdout.contains("doing a full rebuild"));
+    assert_same_outputs(&fallback, &full);
+
+    // A crossref that's cut off partway through its last payload only fails
+    // to decode at the end, which must still be caught before any hits are
+    // used.
+    let mut crossref = fs::read(tree.root.join("index/crossref")).unwrap();
+    let last_payload_start = crossref[..crossref.len() - 1]
+        .iter()
+        .rposition(|b| *b == b'\n')
+        .unwrap()
+        + 1;
+    crossref.truncate(last_payload_start + (crossref.len() - last_payload_start) / 2);
+    fs::write(tree.root.join("index/crossref"), crossref).unwrap();
+    let fallback = tree.run(&final_files, Some(&[]), &[]);
+    assert!(fallback.stdout.contains("doing a full rebuild"));
+    assert_same_outputs(&fallback, &full);
+}
+
+#[test]
+fn test_sharded_matches_unsharded() {
+    let tree = TestTree::new("sharded");
+    let names = ["a.cpp", "b.cpp", "c.cpp", "d.cpp", "e.cpp"];
+    for (seed, name) in names.iter().enumerate() {
+        tree.write_file(name, seed);
+    }
+    let unsharded = tree.run(&names, None, &[]);
+
+    let sharded = tree.run(&names, None, &[("CROSSREF_SHARDS", "3")]);
+    assert!(!sharded.stdout.contains("merging"));
+    assert_same_outputs(&sharded, &unsharded);
+
+    // A tiny spill threshold makes every shard spill runs and merge them.
+    let spill_env = [
+        ("CROSSREF_SHARDS", "3"),
+        ("CROSSREF_SHARD_SPILL_THRESHOLD", "1000"),
+    ];
+    let spilled = tree.run(&names, None, &spill_env);
+    assert!(spilled.stdout.contains("merging"));
+    assert_same_outputs(&spilled, &unsharded);
+
+    tree.write_file("b.cpp", 30);
+    let incremental = tree.run(&names, Some(&["b.cpp"]), &spill_env);
+    assert!(incremental
+        .stdout
+        .contains("Incremental rebuild reusing 4 of 5 files"));
+    let full = tree.run(&names, None, &[]);
+    assert_same_outputs(&incremental, &full);
+
+    // A bad setting is reported by name rather than with a panic.
+    let output = Command::new(env!("CARGO_BIN_EXE_crossref"))
+        .arg(tree.root.join("config.json"))
+        .arg("test")
+        .arg(tree.write_list("list", &names))
+        .env("CROSSREF_SHARDS", "lots")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("CROSSREF_SHARDS"));
+    assert!(!stderr.contains("panicked"));
+}
@@ -0,0 +1,74 @@
+use tools::{
+    abstract_server::{ErrorDetails, ErrorLayer, ServerError},
+    cmd_pipeline::{build_pipeline, PipelineValues},
+};
+
+/// Run the given pipeline against the recorded fixture in
+/// `tests/fixtures/replay`, which can be extended by running searchfox-tool
+/// with `--record-fixture` against a real index or server.
+async fn run_replay(pipeline: &str) -> Result<PipelineValues, ServerError> {
+    let fixture_dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/replay");
+    let (pipeline, _output_format) = build_pipeline(
+        "searchfox-tool",
+        &format!(
+            "--replay-fixture={} {}",
+            shell_words::quote(fixture_dir),
+            pipeline
+        ),
+    )?;
+    pipeline.run().await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_replay_call_graph() {
+    let graph = match run_replay("call-graph --depth 2 _ZN2ns5Outer3runEv").await {
+        Ok(PipelineValues::SymbolGraph(graph)) => graph,
+        other => panic!(
+            "Expected a graph, got {:?}",
+            other.map(|v| v.variant_name())
+        ),
+    };
+
+    let prettys: Vec<&str> = graph.nodes.iter().map(|n| n.pretty.as_str()).collect();
+    assert_eq!(
+        prettys,
+        vec!["ns::Outer::run", "ns::Inner::step", "ns::leaf"]
+    );
+    let edges: Vec<(usize, usize)> = graph.edges.iter().map(|e| (e.from, e.to)).collect();
+    assert_eq!(edges, vec![(0, 1), (1, 2), (1, 0)]);
+    assert!(!graph.truncated);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_replay_show_html() {
+    let excerpts = match run_replay("filter-analysis src/outer.cpp --kind use | show-html").await {
+        Ok(PipelineValues::HtmlExcerpts(excerpts)) => excerpts,
+        other => panic!(
+            "Expected excerpts, got {:?}",
+            other.map(|v| v.variant_name())
+        ),
+    };
+
+    assert_eq!(excerpts.by_file.len(), 1);
+    assert_eq!(excerpts.by_file[0].file, "src/outer.cpp");
+    assert_eq!(excerpts.by_file[0].excerpts.len(), 1);
+    assert!(excerpts.by_file[0].excerpts[0].contains("step();"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_replay_errors() {
+    // Recorded errors are replayed.
+    assert!(matches!(
+        run_replay("crossref-lookup _ZN2ns7MissingEv").await,
+        Err(ServerError::TransientProblem(_))
+    ));
+
+    // Requests that weren't recorded are errors too.
+    assert!(matches!(
+        run_replay("crossref-lookup _ZN2ns9UnrecordedEv").await,
+        Err(ServerError::StickyProblem(ErrorDetails {
+            layer: ErrorLayer::BadInput,
+            ..
+        }))
+    ));
+}